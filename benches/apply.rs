@@ -0,0 +1,75 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::path::PathBuf;
+
+use patcher::apply::{apply_patch, ApplyOptions};
+use patcher::create::{create_patch, CreateOptions};
+
+/// Size of each added file; the file count comes from `PATCHER_BENCH_APPLY_MB`
+/// (default 256). The content compresses, so apply streams it out of its zstd frame,
+/// the path that sizes each file before writing it.
+const ADDED_FILE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Text-like content that zstd shrinks but doesn't collapse: numbered lines with a
+/// varying payload.
+fn compressible(len: usize, seed: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len + 64);
+    let mut state = seed | 1;
+    let mut line = 0u64;
+    while out.len() < len {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        out.extend_from_slice(format!("{:08} {:05} entry\n", line, state % 100_000).as_bytes());
+        line += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+fn bench_large_adds(c: &mut Criterion) {
+    let total_mb: usize = std::env::var("PATCHER_BENCH_APPLY_MB")
+        .ok()
+        .and_then(|mb| mb.parse().ok())
+        .unwrap_or(256);
+    let files = (total_mb * 1024 * 1024).div_ceil(ADDED_FILE_SIZE).max(1);
+    let total_bytes = files * ADDED_FILE_SIZE;
+
+    let temp: PathBuf = std::env::temp_dir().join("patcher_bench_large_adds");
+    let _ = std::fs::remove_dir_all(&temp);
+    let (old, new, patch, target) =
+        (temp.join("old"), temp.join("new"), temp.join("p.patch"), temp.join("target"));
+    std::fs::create_dir_all(&old).unwrap();
+    std::fs::create_dir_all(&new).unwrap();
+    for i in 0..files {
+        let data = compressible(ADDED_FILE_SIZE, i as u64);
+        std::fs::write(new.join(format!("{:04}.log", i)), data).unwrap();
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime
+        .block_on(create_patch(&old, &new, &patch, &CreateOptions::default()))
+        .unwrap();
+    let options = ApplyOptions::default();
+
+    let mut group = c.benchmark_group("apply");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(total_bytes as u64));
+    group.bench_function(format!("large_adds_{}mb", total_bytes >> 20), |b| {
+        b.iter(|| {
+            let _ = std::fs::remove_dir_all(&target);
+            std::fs::create_dir_all(&target).unwrap();
+            runtime
+                .block_on(apply_patch(&target, &patch, &options))
+                .unwrap()
+        })
+    });
+    group.finish();
+
+    let _ = std::fs::remove_dir_all(&temp);
+}
+
+criterion_group!(benches, bench_large_adds);
+criterion_main!(benches);
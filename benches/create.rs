@@ -0,0 +1,127 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::path::{Path, PathBuf};
+
+use patcher::create::{create_patch, CreateOptions};
+
+/// One large modified file among many tiny modified ones: the shape where scheduling
+/// the diff phase badly leaves a single thread working on the big file at the end.
+const HUGE_FILE_SIZE: usize = 32 * 1024 * 1024;
+const TINY_FILE_COUNT: usize = 2_000;
+const TINY_FILE_SIZE: usize = 2 * 1024;
+
+/// Size of each added file in the large-patch benchmark; the file count comes from
+/// `PATCHER_BENCH_PATCH_MB` (default 512), so multi-GB patches are one variable away.
+const LARGE_PATCH_FILE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Deterministic pseudo-random bytes (xorshift64) so runs are comparable.
+fn pseudo_random(len: usize, mut seed: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        out.extend_from_slice(&seed.to_le_bytes());
+    }
+    out.truncate(len);
+    out
+}
+
+/// Write a tree, flipping one byte per file when `modified` so every file needs a diff.
+fn write_tree(root: &Path, modified: bool) {
+    let flip = |mut data: Vec<u8>| {
+        if modified {
+            let mid = data.len() / 2;
+            data[mid] ^= 0xFF;
+        }
+        data
+    };
+    std::fs::create_dir_all(root.join("tiny")).unwrap();
+    std::fs::write(root.join("huge.bin"), flip(pseudo_random(HUGE_FILE_SIZE, 1))).unwrap();
+    for i in 0..TINY_FILE_COUNT {
+        let data = flip(pseudo_random(TINY_FILE_SIZE, i as u64 + 2));
+        std::fs::write(root.join(format!("tiny/{:05}.bin", i)), data).unwrap();
+    }
+}
+
+fn bench_skewed_tree(c: &mut Criterion) {
+    let temp: PathBuf = std::env::temp_dir().join("patcher_bench_skewed_tree");
+    let _ = std::fs::remove_dir_all(&temp);
+    let (old, new, output) = (temp.join("old"), temp.join("new"), temp.join("out.patch"));
+    write_tree(&old, false);
+    write_tree(&new, true);
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    // Every iteration writes over the previous one's patch.
+    let options = CreateOptions {
+        force: true,
+        ..CreateOptions::default()
+    };
+
+    let mut group = c.benchmark_group("create");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(
+        (HUGE_FILE_SIZE + TINY_FILE_COUNT * TINY_FILE_SIZE) as u64,
+    ));
+    group.bench_function("one_huge_many_tiny", |b| {
+        b.iter(|| {
+            runtime
+                .block_on(create_patch(&old, &new, &output, &options))
+                .unwrap()
+        })
+    });
+    group.finish();
+
+    let _ = std::fs::remove_dir_all(&temp);
+}
+
+/// Create a patch that is nearly as large as the new tree: every file is added and
+/// incompressible, so the time goes to reading, framing and writing the output. Operations
+/// are streamed through zstd into a buffered file, so memory stays at about one file.
+fn bench_large_patch(c: &mut Criterion) {
+    let total_mb: usize = std::env::var("PATCHER_BENCH_PATCH_MB")
+        .ok()
+        .and_then(|mb| mb.parse().ok())
+        .unwrap_or(512);
+    let files = (total_mb * 1024 * 1024).div_ceil(LARGE_PATCH_FILE_SIZE).max(1);
+    let total_bytes = files * LARGE_PATCH_FILE_SIZE;
+
+    let temp: PathBuf = std::env::temp_dir().join("patcher_bench_large_patch");
+    let _ = std::fs::remove_dir_all(&temp);
+    let (old, new, output) = (temp.join("old"), temp.join("new"), temp.join("out.patch"));
+    std::fs::create_dir_all(&old).unwrap();
+    std::fs::create_dir_all(&new).unwrap();
+    for i in 0..files {
+        let data = pseudo_random(LARGE_PATCH_FILE_SIZE, i as u64 + 100);
+        std::fs::write(new.join(format!("{:04}.bin", i)), data).unwrap();
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    // Every iteration writes over the previous one's patch.
+    let options = CreateOptions {
+        force: true,
+        ..CreateOptions::default()
+    };
+
+    let mut group = c.benchmark_group("create");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(total_bytes as u64));
+    group.bench_function(format!("large_patch_{}mb", total_bytes >> 20), |b| {
+        b.iter(|| {
+            runtime
+                .block_on(create_patch(&old, &new, &output, &options))
+                .unwrap()
+        })
+    });
+    group.finish();
+
+    let _ = std::fs::remove_dir_all(&temp);
+}
+
+criterion_group!(benches, bench_skewed_tree, bench_large_patch);
+criterion_main!(benches);
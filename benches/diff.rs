@@ -0,0 +1,211 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+use patcher::binary_diff::{
+    block_size_for, compute_diff, compute_diff_with_block_size, compute_diff_with_min_match, text_block_size_for,
+};
+use patcher::binary_patch::apply_diff;
+use patcher::patch_format::DiffChunk;
+use patcher::rolling_hash::RollingHash;
+
+const BASE_SIZE: usize = 4 * 1024 * 1024;
+
+/// The block size used for BASE_SIZE files, as the scatter spacing and hash window.
+fn block_size() -> usize {
+    block_size_for(BASE_SIZE)
+}
+
+/// Deterministic pseudo-random bytes (xorshift64) so runs are comparable.
+fn pseudo_random(len: usize, mut seed: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        out.extend_from_slice(&seed.to_le_bytes());
+    }
+    out.truncate(len);
+    out
+}
+
+/// (name, old, new) pairs covering the common edit shapes.
+fn edit_cases() -> Vec<(&'static str, Vec<u8>, Vec<u8>)> {
+    let old = pseudo_random(BASE_SIZE, 0x9E37_79B9_7F4A_7C15);
+    let patch = pseudo_random(64 * 1024, 0xD1B5_4A32_D192_ED03);
+
+    let mut append = old.clone();
+    append.extend_from_slice(&patch);
+
+    let mut prepend = patch.clone();
+    prepend.extend_from_slice(&old);
+
+    let mut middle = old.clone();
+    let mid = old.len() / 2;
+    middle.splice(mid..mid, patch.iter().copied());
+
+    // One small overwrite every 64 blocks, spread across the whole file.
+    let mut scatter = old.clone();
+    for (i, pos) in (0..scatter.len()).step_by(block_size() * 64).enumerate() {
+        let end = (pos + 16).min(scatter.len());
+        for b in &mut scatter[pos..end] {
+            *b = b.wrapping_add(i as u8 | 1);
+        }
+    }
+
+    vec![
+        ("append", old.clone(), append),
+        ("prepend", old.clone(), prepend),
+        ("middle_insert", old.clone(), middle),
+        ("random_scatter", old, scatter),
+    ]
+}
+
+fn bench_compute_diff(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compute_diff");
+    for (name, old, new) in edit_cases() {
+        group.throughput(Throughput::Bytes(new.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &(old, new), |b, (old, new)| {
+            b.iter(|| compute_diff(black_box(old), black_box(new)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_apply_diff(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply_diff");
+    for (name, old, new) in edit_cases() {
+        let chunks = compute_diff(&old, &new);
+        group.throughput(Throughput::Bytes(new.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &(old, chunks), |b, (old, chunks)| {
+            b.iter(|| apply_diff(black_box(old), black_box(chunks)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_rolling_hash(c: &mut Criterion) {
+    let data = pseudo_random(BASE_SIZE, 0x2545_F491_4F6C_DD1D);
+    let mut group = c.benchmark_group("rolling_hash");
+    let window = block_size();
+
+    group.throughput(Throughput::Bytes(window as u64));
+    group.bench_function("init", |b| {
+        b.iter(|| {
+            let mut h = RollingHash::new();
+            h.init(black_box(&data[..window]));
+            h.digest()
+        })
+    });
+
+    group.throughput(Throughput::Bytes((data.len() - window) as u64));
+    group.bench_function("rotate", |b| {
+        b.iter(|| {
+            let mut h = RollingHash::new();
+            h.init(&data[..window]);
+            for i in window..data.len() {
+                h.rotate(data[i - window], data[i]);
+            }
+            black_box(h.digest())
+        })
+    });
+
+    group.finish();
+}
+
+/// Adaptive vs the former fixed 4 KiB block size, across file sizes. Time is measured
+/// by criterion; the encoded diff size of each variant is printed once up front.
+fn bench_block_sizes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_size");
+    group.sample_size(10);
+    for size in [256 * 1024, 4 << 20, 32 << 20] {
+        let old = pseudo_random(size, 0x5851_F42D_4C95_7F2D ^ size as u64);
+        // 16-byte overwrites every 64 KiB, so every block size sees the same edits.
+        let mut new = old.clone();
+        for pos in (0..new.len()).step_by(64 * 1024) {
+            for b in &mut new[pos..(pos + 16).min(size)] {
+                *b ^= 0x5a;
+            }
+        }
+        for (name, block_size) in [("fixed_4k", 4096), ("adaptive", block_size_for(size))] {
+            let chunks = compute_diff_with_block_size(&old, &new, block_size);
+            eprintln!(
+                "block_size/{}/{} KiB: {} byte diff with {} byte blocks",
+                name,
+                size / 1024,
+                bincode::serialized_size(&chunks).unwrap(),
+                block_size
+            );
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(name, size / 1024),
+                &(&old, &new),
+                |b, (old, new)| b.iter(|| compute_diff_with_block_size(black_box(old), black_box(new), block_size)),
+            );
+        }
+    }
+    group.finish();
+}
+
+/// Chunk count and stored size of text diffs with scattered one-word edits and of
+/// loosely related data, the shapes where block matching fragments the output into
+/// short alternating Copies and Inserts. Each case is diffed with the default minimum
+/// match length and with one raised to 4 KiB (`create --min-match`).
+fn bench_fragmentation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fragmentation");
+    group.sample_size(10);
+    let text: String = (0..20_000).map(|i| format!("line {} value = {}\n", i, i * 31 % 977)).collect();
+    let mut edited = text.clone();
+    for i in (0..20_000).step_by(97) {
+        edited = edited.replace(&format!("line {} value", i), &format!("line {} Value", i));
+    }
+    // Every third 512-byte stretch starts with a fragment of the old data.
+    let base = pseudo_random(1 << 20, 7);
+    let fresh = pseudo_random(1 << 20, 9);
+    let mixed = |fragment: usize| {
+        let mut out = Vec::new();
+        for k in 0..(1 << 20) / 512 {
+            if k % 3 == 0 {
+                out.extend_from_slice(&base[k * 512..k * 512 + fragment]);
+            } else {
+                out.extend_from_slice(&fresh[k * 512..k * 512 + 512]);
+            }
+        }
+        out
+    };
+    let cases = [
+        ("text_edits", text.into_bytes(), edited.into_bytes()),
+        ("mixed_100", base.clone(), mixed(100)),
+        ("mixed_80", base.clone(), mixed(80)),
+    ];
+    for (name, old, new) in &cases {
+        let block_size = text_block_size_for(old.len());
+        for (variant, min_match_len) in [("default", 0), ("min_match_4k", 4096)] {
+            let chunks = compute_diff_with_min_match(old, new, block_size, min_match_len);
+            let encoded = bincode::serialize(&chunks).unwrap();
+            eprintln!(
+                "fragmentation/{}/{}: {} chunks ({} Inserts), {} byte diff, {} bytes compressed",
+                name,
+                variant,
+                chunks.len(),
+                chunks.iter().filter(|c| matches!(c, DiffChunk::Insert { .. })).count(),
+                encoded.len(),
+                zstd::bulk::compress(&encoded, 3).unwrap().len()
+            );
+            group.throughput(Throughput::Bytes(new.len() as u64));
+            group.bench_with_input(BenchmarkId::new(*name, variant), &(old, new), |b, (old, new)| {
+                b.iter(|| compute_diff_with_min_match(black_box(old), black_box(new), block_size, min_match_len))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_compute_diff,
+    bench_apply_diff,
+    bench_rolling_hash,
+    bench_block_sizes,
+    bench_fragmentation
+);
+criterion_main!(benches);
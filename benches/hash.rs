@@ -0,0 +1,59 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+use std::path::PathBuf;
+
+use patcher::util::{self, HashAlgo};
+
+/// Files in the synthetic tree, and the size of each.
+const FILE_COUNT: usize = 64;
+const FILE_SIZE: usize = 1024 * 1024;
+
+/// Deterministic pseudo-random bytes (xorshift64) so runs are comparable.
+fn pseudo_random(len: usize, mut seed: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        out.extend_from_slice(&seed.to_le_bytes());
+    }
+    out.truncate(len);
+    out
+}
+
+/// Write a flat tree of FILE_COUNT files under the temp dir and return their paths.
+fn build_tree() -> Vec<PathBuf> {
+    let root = std::env::temp_dir().join("patcher_bench_hash_tree");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    (0..FILE_COUNT)
+        .map(|i| {
+            let path = root.join(format!("file_{:03}.bin", i));
+            std::fs::write(&path, pseudo_random(FILE_SIZE, i as u64 + 1)).unwrap();
+            path
+        })
+        .collect()
+}
+
+/// Hash the whole tree with each `--read-buffer` size. The tree is warm in the page
+/// cache after the first iteration, so this measures syscall and copy overhead rather
+/// than device throughput.
+fn bench_read_buffer(c: &mut Criterion) {
+    let files = build_tree();
+    let mut group = c.benchmark_group("hash_read_buffer");
+    group.throughput(Throughput::Bytes((FILE_COUNT * FILE_SIZE) as u64));
+    for (name, size) in [("64K", 64 << 10), ("256K", 256 << 10), ("4M", 4 << 20)] {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &size, |b, &size| {
+            b.iter(|| {
+                for path in &files {
+                    black_box(util::hash_file_buffered(HashAlgo::Blake3, path, size).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_dir_all(std::env::temp_dir().join("patcher_bench_hash_tree"));
+}
+
+criterion_group!(benches, bench_read_buffer);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::path::PathBuf;
+
+/// Files per directory in the generated tree; the total comes from
+/// `PATCHER_BENCH_WALK_FILES` (default 100,000), e.g. 1000000 for a 1M-file tree.
+const FILES_PER_DIR: usize = 100;
+
+/// Walk a wide tree of empty files, two levels of directories deep. The tree is
+/// built once and reused across runs, since creating a million files dwarfs walking them.
+fn bench_walk(c: &mut Criterion) {
+    let files: usize = std::env::var("PATCHER_BENCH_WALK_FILES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(100_000);
+    let root: PathBuf = std::env::temp_dir().join(format!("patcher_bench_walk_{}", files));
+    let marker = root.join(".complete");
+    if !marker.exists() {
+        let _ = std::fs::remove_dir_all(&root);
+        for i in 0..files {
+            let dir = root.join(format!("{:03}/{:05}", i / FILES_PER_DIR / 100, i / FILES_PER_DIR));
+            if i % FILES_PER_DIR == 0 {
+                std::fs::create_dir_all(&dir).unwrap();
+            }
+            std::fs::write(dir.join(format!("{}.txt", i)), b"").unwrap();
+        }
+        std::fs::write(&marker, b"").unwrap();
+    }
+
+    let mut group = c.benchmark_group("walk");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(files as u64));
+    group.bench_function(format!("{}_files", files), |b| {
+        b.iter(|| patcher::util::walk_directory(&root).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_walk);
+criterion_main!(benches);
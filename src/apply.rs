@@ -1,34 +1,1373 @@
 use anyhow::{bail, Context, Result};
 use rayon::prelude::*;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::binary_patch;
-use crate::patch_format::{ApplySummary, PatchManifest, PatchOp, MAGIC};
-use crate::util;
+use crate::error::PatchError;
+use crate::patch_format::{
+    self, chunk_counts, ApplySummary, MacKey, PartHeader, PatchHeader, PatchManifest, PatchOp,
+    GZIP_MAGIC, HEADER_LEN, PART_HEADER_LEN, PART_MAGIC,
+};
+use crate::recompress;
+use crate::util::{self, OpLog};
+
+/// Resource limits enforced before a patch is allowed to allocate memory or touch the
+/// target, guarding against decompression bombs and hostile manifests.
+#[derive(Debug, Clone, Copy)]
+pub struct ApplyLimits {
+    /// Maximum number of operations in the manifest.
+    pub max_files: u64,
+    /// Maximum size of any single added or patched file.
+    pub max_file_size: u64,
+    /// Maximum size of the decompressed manifest.
+    pub max_total_size: u64,
+}
+
+impl Default for ApplyLimits {
+    fn default() -> Self {
+        Self {
+            max_files: 1_000_000,
+            max_file_size: 4 << 30,
+            max_total_size: 4 << 30,
+        }
+    }
+}
+
+impl ApplyLimits {
+    /// Check operation count and per-file output sizes.
+    fn check(&self, operations: &[PatchOp]) -> Result<()> {
+        if operations.len() as u64 > self.max_files {
+            bail!(PatchError::LimitExceeded(format!(
+                "Patch has {} operations, exceeding the limit of {} (see --max-files)",
+                operations.len(),
+                self.max_files
+            )));
+        }
+        for op in operations {
+            let (path, size) = match op {
+                PatchOp::AddFile {
+                    path,
+                    data,
+                    compressed,
+                    ..
+                } => (path, patch_format::add_file_len(data, *compressed)?),
+                PatchOp::ModifyFile {
+                    path, diff_chunks, ..
+                } => (path, binary_patch::output_len(diff_chunks)),
+                PatchOp::ModifyFileMulti { path, variants, .. } => (
+                    path,
+                    variants
+                        .iter()
+                        .map(|v| binary_patch::output_len(&v.diff_chunks))
+                        .max()
+                        .unwrap_or(0),
+                ),
+                _ => continue,
+            };
+            self.check_size(path, size)?;
+        }
+        Ok(())
+    }
+
+    /// Check one file's output size. External AddFile content is checked by apply,
+    /// once the objects directory is known.
+    fn check_size(&self, path: &str, size: u64) -> Result<()> {
+        if size > self.max_file_size {
+            bail!(PatchError::LimitExceeded(format!(
+                "File {} would be {} bytes, exceeding the limit of {} (see --max-file-size)",
+                path,
+                size,
+                self.max_file_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Default number of retries for transient filesystem errors.
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Name of the scratch directory apply stages rewritten files in, inside the tree being
+/// patched, unless `--temp-dir` says otherwise. See [`ApplyOptions::temp_dir`].
+pub const DEFAULT_TEMP_DIR: &str = ".patcher-tmp";
+
+/// First retry delay; doubled after each failed attempt up to `RETRY_MAX_DELAY`.
+const RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_millis(10);
+const RETRY_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Opens a fresh reader over some content; called once per write attempt.
+type OpenReader<'a> = dyn Fn() -> std::io::Result<Box<dyn Read + 'a>> + 'a;
+
+/// The filesystem calls apply retries. A trait so tests can inject failures.
+trait Fs: Sync {
+    fn write(&self, path: &Path, data: &[u8]) -> std::io::Result<()>;
+    /// Write `data` like [`Fs::write`], but leave the `holes` (offset, length) ranges,
+    /// which must be all zeros in `data`, unwritten so they stay sparse.
+    fn write_sparse(&self, path: &Path, data: &[u8], holes: &[(u64, u64)]) -> std::io::Result<()>;
+    /// Write the `len` bytes of content read from `open()`, which is called again on
+    /// each retry. The file is sized to `len` before anything is written.
+    fn write_from(&self, path: &Path, len: u64, open: &OpenReader<'_>) -> std::io::Result<()>;
+    fn remove_file(&self, path: &Path) -> std::io::Result<()>;
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+}
+
+#[derive(Clone, Copy)]
+struct RealFs;
+
+impl Fs for RealFs {
+    fn write(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        std::fs::write(path, data)
+    }
+
+    fn write_sparse(&self, path: &Path, data: &[u8], holes: &[(u64, u64)]) -> std::io::Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+        // Extending with set_len creates the file as one hole; only the data between
+        // the zero ranges is then written.
+        let mut file = std::fs::File::create(path)?;
+        file.set_len(data.len() as u64)?;
+        let mut pos = 0u64;
+        for &(start, len) in holes.iter().chain(std::iter::once(&(data.len() as u64, 0))) {
+            if start > pos {
+                file.seek(SeekFrom::Start(pos))?;
+                file.write_all(&data[pos as usize..start as usize])?;
+            }
+            pos = start + len;
+        }
+        Ok(())
+    }
+
+    fn write_from(&self, path: &Path, len: u64, open: &OpenReader<'_>) -> std::io::Result<()> {
+        // Sizing the file up front lets the filesystem lay it out in one go instead of
+        // growing it a buffer at a time.
+        let mut file = std::fs::File::create(path)?;
+        file.set_len(len)?;
+        let written = std::io::copy(&mut open()?, &mut file)?;
+        if written != len {
+            file.set_len(written)?;
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(from, to)
+    }
+}
+
+/// Wraps an [`Fs`] for `--force`: a write, delete or rename that fails with permission
+/// denied on a read-only file (which Windows refuses to overwrite or delete even for its
+/// owner) is retried once with the file made writable. See [`forcing_writable`].
+#[derive(Clone, Copy)]
+struct ForceFs<F> {
+    inner: F,
+    force: bool,
+}
+
+impl<F: Fs> ForceFs<F> {
+    fn attempt(
+        &self,
+        path: &Path,
+        restore: bool,
+        mut op: impl FnMut() -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        if self.force {
+            forcing_writable(path, restore, op)
+        } else {
+            op()
+        }
+    }
+}
+
+impl<F: Fs> Fs for ForceFs<F> {
+    fn write(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        self.attempt(path, true, || self.inner.write(path, data))
+    }
+
+    fn write_sparse(&self, path: &Path, data: &[u8], holes: &[(u64, u64)]) -> std::io::Result<()> {
+        self.attempt(path, true, || self.inner.write_sparse(path, data, holes))
+    }
+
+    fn write_from(&self, path: &Path, len: u64, open: &OpenReader<'_>) -> std::io::Result<()> {
+        self.attempt(path, true, || self.inner.write_from(path, len, open))
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        self.attempt(path, false, || self.inner.remove_file(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        match self.inner.remove_dir_all(path) {
+            Err(e) if self.force && e.kind() == std::io::ErrorKind::PermissionDenied => {
+                // Whatever read-only files held it up are being deleted anyway.
+                for entry in walkdir::WalkDir::new(path).into_iter().flatten() {
+                    if entry.file_type().is_file() {
+                        let _ = make_writable(entry.path());
+                    }
+                }
+                self.inner.remove_dir_all(path)
+            }
+            result => result,
+        }
+    }
+
+    /// The file renamed over `to` brings its own permissions, so `to`'s aren't restored.
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        self.attempt(to, false, || self.inner.rename(from, to))
+    }
+}
+
+/// Make `path` writable by its owner, returning the permissions it had, or `None` if
+/// it wasn't read-only (so something other than the read-only flag denied access).
+fn make_writable(path: &Path) -> std::io::Result<Option<std::fs::Permissions>> {
+    let original = std::fs::metadata(path)?.permissions();
+    if !original.readonly() {
+        return Ok(None);
+    }
+    #[cfg(unix)]
+    let writable = {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::Permissions::from_mode(original.mode() | 0o200)
+    };
+    // Elsewhere the read-only attribute is all there is, so clearing it can't widen access.
+    #[cfg(not(unix))]
+    #[allow(clippy::permissions_set_readonly_false)]
+    let writable = {
+        let mut writable = original.clone();
+        writable.set_readonly(false);
+        writable
+    };
+    std::fs::set_permissions(path, writable)?;
+    Ok(Some(original))
+}
+
+/// `--force`: run `op` on `path`; if it fails with permission denied and the file is
+/// read-only, make the file writable and run `op` once more. The read-only permissions
+/// are put back afterwards when `restore` is set (a rewritten file keeps them), and
+/// whenever the second attempt fails too, so a failed op leaves the file as it was.
+fn forcing_writable<T>(
+    path: &Path,
+    restore: bool,
+    mut op: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let err = match op() {
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => e,
+        result => return result,
+    };
+    let Ok(Some(original)) = make_writable(path) else {
+        return Err(err);
+    };
+    let result = op();
+    if restore || result.is_err() {
+        let _ = std::fs::set_permissions(path, original);
+    }
+    result
+}
+
+/// Errors worth retrying: the kind a network filesystem (SMB/NFS) or a virus scanner
+/// holding a file open produces transiently. Everything else, including NotFound,
+/// surfaces on the first attempt.
+fn is_retriable(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION.
+    #[cfg(windows)]
+    if matches!(err.raw_os_error(), Some(32) | Some(33)) {
+        return true;
+    }
+    matches!(
+        err.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ResourceBusy
+    )
+}
+
+/// Wraps an [`Fs`], retrying transient failures up to `retries` times with
+/// exponential backoff.
+#[derive(Clone, Copy)]
+struct RetryingFs<F> {
+    inner: F,
+    retries: u32,
+}
+
+impl<F: Fs> RetryingFs<F> {
+    fn retry(&self, mut op: impl FnMut(&F) -> std::io::Result<()>) -> std::io::Result<()> {
+        let mut delay = RETRY_BASE_DELAY;
+        let mut attempt = 0;
+        loop {
+            match op(&self.inner) {
+                Err(e) if attempt < self.retries && is_retriable(&e) => {
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(RETRY_MAX_DELAY);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        self.retry(|fs| fs.write(path, data))
+    }
+
+    fn write_sparse(&self, path: &Path, data: &[u8], holes: &[(u64, u64)]) -> std::io::Result<()> {
+        self.retry(|fs| fs.write_sparse(path, data, holes))
+    }
+
+    fn write_from(&self, path: &Path, len: u64, open: &OpenReader<'_>) -> std::io::Result<()> {
+        self.retry(|fs| fs.write_from(path, len, open))
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        self.retry(|fs| fs.remove_file(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        self.retry(|fs| fs.remove_dir_all(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        self.retry(|fs| fs.rename(from, to))
+    }
+}
+
+/// The scratch directory (`--temp-dir`) where an added file, or a modified file that is
+/// rewritten whole, is written first and then renamed into place, so a failed or
+/// interrupted write leaves the old content (or no file) rather than a truncated one. In
+/// the default location inside the patched tree the rename stays on one filesystem and
+/// is atomic; from a directory on another filesystem the staged file is copied into
+/// place, which isn't. Removed when apply finishes if apply created it.
+///
+/// On Windows, `std::fs::rename` replaces an existing file too (`MoveFileExW` with
+/// `MOVEFILE_REPLACE_EXISTING`), but is denied when the file is read-only, which
+/// `--force` handles like any other denied write, or held open without delete sharing
+/// by another process, which fails the operation with the original still intact.
+struct Staging {
+    dir: PathBuf,
+    created: bool,
+    next: AtomicU64,
+}
+
+impl Staging {
+    fn new(dir: PathBuf) -> Result<Self> {
+        let created = !dir.exists();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create temp directory: {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            created,
+            next: AtomicU64::new(0),
+        })
+    }
+
+    /// Put the file `write` creates at the path it is given at `full`, replacing any
+    /// file there and keeping its permission bits. An existing file with other hard
+    /// links is written in place instead, since replacing it would leave the other names
+    /// holding the old content. A symbolic link is always replaced itself, never written
+    /// through.
+    fn replace<F: Fs>(
+        &self,
+        fs: &RetryingFs<F>,
+        full: &Path,
+        write: impl Fn(&Path) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let meta = match std::fs::metadata(full) {
+            Ok(meta) => Some(meta),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let is_link = std::fs::symlink_metadata(full).is_ok_and(|meta| meta.file_type().is_symlink());
+        if !is_link && meta.as_ref().is_some_and(|meta| util::hardlink_id(meta).is_some()) {
+            return write(full);
+        }
+        let staged = self.dir.join(format!(
+            "{}-{}.tmp",
+            std::process::id(),
+            self.next.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = write(&staged)
+            .and_then(|()| match &meta {
+                Some(meta) => std::fs::set_permissions(&staged, meta.permissions()),
+                None => Ok(()),
+            })
+            .and_then(|()| match fs.rename(&staged, full) {
+                Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                    std::fs::copy(&staged, full).and_then(|_| std::fs::remove_file(&staged))
+                }
+                result => result,
+            });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&staged);
+        }
+        result
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if self.created {
+            // Only succeeds once empty; every staged file is renamed or removed.
+            let _ = std::fs::remove_dir(&self.dir);
+        }
+    }
+}
+
+/// Reject manifests where one path has more than one operation, e.g. `CreateDir "foo"`
+/// and `AddFile "foo"`, or two `AddFile "foo"`. Apply groups operations by type and runs
+/// the groups in a fixed order, each in parallel, so such a pair would otherwise fail
+/// obscurely midway or race two writers on one file. Create never emits one; it means
+/// a corrupt or crafted manifest.
+///
+/// Paths (and hard link targets) that are absolute or contain `..` are rejected too,
+/// since they would reach outside the target, and so are paths beneath a symbolic link
+/// the patch creates, which would lead wherever the link points.
+fn validate_operations(operations: &[PatchOp]) -> Result<()> {
+    let mut seen: std::collections::HashMap<String, &PatchOp> =
+        std::collections::HashMap::with_capacity(operations.len());
+    for op in operations {
+        // Compare canonical spellings, so `a//b` and `a/b` count as the same path.
+        let path = util::normalize_relative_path(op.path())
+            .map_err(|e| PatchError::Corrupt(format!("{}: {:#}", op.name(), e)))?;
+        if let PatchOp::CreateHardlink { target, .. } = op {
+            util::normalize_relative_path(target).map_err(|e| {
+                PatchError::Corrupt(format!("hard link {}: {:#}", op.path(), e))
+            })?;
+        }
+        if let Some(prev) = seen.insert(path, op) {
+            if prev.name() != op.name() {
+                bail!(PatchError::Corrupt(format!(
+                    "conflicting operations for {}: {} and {}",
+                    op.path(),
+                    prev.name(),
+                    op.name()
+                )));
+            }
+            // Each group runs in parallel, so two writes to one file would race.
+            bail!(PatchError::Corrupt(format!(
+                "duplicate {} operations for {}",
+                op.name(),
+                op.path()
+            )));
+        }
+    }
+    let links: std::collections::HashSet<&str> = seen
+        .iter()
+        .filter(|(_, op)| matches!(op, PatchOp::CreateSymlink { .. }))
+        .map(|(path, _)| path.as_str())
+        .collect();
+    if !links.is_empty() {
+        for (path, op) in &seen {
+            let target = match op {
+                PatchOp::CreateHardlink { target, .. } => Some(target.as_str()),
+                _ => None,
+            };
+            for path in std::iter::once(path.as_str()).chain(target) {
+                let mut cur = path;
+                while let Some(idx) = cur.rfind('/') {
+                    cur = &cur[..idx];
+                    if links.contains(cur) {
+                        bail!(PatchError::Corrupt(format!(
+                            "{} {} is beneath symbolic link {}",
+                            op.name(),
+                            path,
+                            cur
+                        )));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Options controlling patch application.
+#[derive(Debug, Clone)]
+pub struct ApplyOptions {
+    /// Print one line per applied operation.
+    pub verbose: bool,
+    /// Resource limits checked before anything is allocated or written.
+    pub limits: ApplyLimits,
+    /// Move deleted files and directories here (preserving their relative paths)
+    /// instead of removing them.
+    pub quarantine: Option<PathBuf>,
+    /// Retries for writes and deletes that fail with a transient error.
+    pub retries: u32,
+    /// Leave the target untouched and write the patched tree here instead. Unchanged
+    /// files are reflinked where the filesystem supports it.
+    pub out: Option<PathBuf>,
+    /// Re-read every added or modified file after writing it and check its hash again,
+    /// catching writes corrupted on the way to disk at the cost of a second read.
+    pub paranoid: bool,
+    /// Leave files whose hash check fails untouched and list them in
+    /// [`ApplySummary::skipped_mismatches`] instead of aborting the apply.
+    pub skip_mismatches: bool,
+    /// Set to true (e.g. from a Ctrl-C handler) to stop: no further operations start,
+    /// those in flight finish, and apply returns [`PatchError::Interrupted`].
+    pub interrupt: Option<Arc<AtomicBool>>,
+    /// Asked before anything is written whether the patch's deletions may go ahead
+    /// (`--interactive`). If it declines, everything else is applied and the files and
+    /// directories are kept, counted in [`ApplySummary::deletes_declined`].
+    pub confirm_deletes: Option<ConfirmDeletes>,
+    /// Scratch directory for staging rewritten files (`--temp-dir`): an added file, or a
+    /// modified one rewritten whole, is written there first and then moved into place,
+    /// so a failed write leaves the old content rather than a truncated file. `None`
+    /// uses [`DEFAULT_TEMP_DIR`] inside the directory being patched (the target, or
+    /// `out`), which keeps the final renames on the same filesystem and atomic; from
+    /// another filesystem the staged file is copied into place instead, which isn't.
+    pub temp_dir: Option<PathBuf>,
+    /// Require the patch to carry a MAC made with this key (`--mac-key`), checked
+    /// before anything is decompressed. Without it, a MAC trailer is ignored.
+    pub mac_key: Option<MacKey>,
+    /// Also delete target files the patch has no operation for (`--prune`), leaving
+    /// exactly the patched tree. Needs a patch with [`PatchManifest::full_file_set`].
+    pub prune: bool,
+    /// Modify and delete read-only target files (`--force`): on permission denied, the
+    /// file is made writable and the operation retried. A modified file is made
+    /// read-only again afterwards.
+    pub force: bool,
+    /// Apply only the operations on paths under one of these prefixes (`--only`), e.g.
+    /// for a staged rollout of one subtree. Prefixes match whole components, so
+    /// `assets` covers `assets` and `assets/x` but not `assets2`; a hard link is kept
+    /// only if its target is covered too. Empty applies all.
+    pub only: Vec<String>,
+    /// Objects directory holding the content of AddFile operations created with
+    /// `--objects-dir`; required for such patches.
+    pub objects_dir: Option<PathBuf>,
+    /// Before anything is written, check that every file the patch modifies or deletes
+    /// still has the hash it had when the patch was made (`--verify-before`), and fail
+    /// with [`PatchError::SourceMismatch`] listing those that don't.
+    pub verify_before: bool,
+    /// Refuse a target that is itself a symlink (`--no-follow-target`). Otherwise the
+    /// link is resolved once and the directory it points to is patched; the link itself
+    /// is never replaced or removed.
+    pub no_follow_target: bool,
+    /// Apply diffs to target files that aren't the version the patch was made from
+    /// (`--lenient-base`), trusting the check of the result instead: a multi-base
+    /// modify whose recorded bases all differ tries each of its diffs, and a diff that
+    /// doesn't fit the file counts as a hash mismatch rather than a corrupt patch.
+    pub lenient_base: bool,
+    /// Write a file the patch modifies but the target lacks from its diff alone
+    /// (`--add-missing`), when the diff copies nothing from the old file. Otherwise such
+    /// a file fails the apply with [`PatchError::MissingModifyTarget`].
+    pub add_missing: bool,
+    /// When deletions run relative to adds and modifies (`--order`).
+    pub order: ApplyOrder,
+}
+
+/// When apply deletes files and directories relative to adding and modifying the rest.
+/// The path sets are disjoint, so the patched tree is the same either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ApplyOrder {
+    /// Delete, add and modify concurrently: fastest, but the disk briefly holds both the
+    /// new content and the content being deleted.
+    #[default]
+    Default,
+    /// Finish every deletion before anything is added or modified, freeing the space
+    /// first. Useful on a nearly full disk, at the cost of no overlap between the phases.
+    DeleteFirst,
+}
+
+/// Receives the paths of every file and directory a patch deletes, in patch order, and
+/// returns whether to delete them. Only called when there is something to delete.
+#[derive(Clone)]
+pub struct ConfirmDeletes(pub Arc<ConfirmFn>);
+
+/// `(files, dirs) -> delete them?`
+pub type ConfirmFn = dyn Fn(&[String], &[String]) -> bool + Send + Sync;
+
+impl ConfirmDeletes {
+    pub fn new(f: impl Fn(&[String], &[String]) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for ConfirmDeletes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConfirmDeletes")
+    }
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            limits: ApplyLimits::default(),
+            quarantine: None,
+            retries: DEFAULT_RETRIES,
+            out: None,
+            paranoid: false,
+            skip_mismatches: false,
+            interrupt: None,
+            confirm_deletes: None,
+            temp_dir: None,
+            mac_key: None,
+            prune: false,
+            force: false,
+            only: Vec::new(),
+            objects_dir: None,
+            verify_before: false,
+            no_follow_target: false,
+            lenient_base: false,
+            add_missing: false,
+            order: ApplyOrder::default(),
+        }
+    }
+}
+
+/// `--paranoid` check: hash `full` as it now reads back from disk and compare it with
+/// the hash the in-memory content was already verified against. The re-read may be
+/// served from the OS page cache, so this catches corruption in the write path (driver,
+/// filter drivers such as antivirus, filesystem) rather than media that fails later.
+fn verify_written(
+    full: &Path,
+    path: &str,
+    hash_algo: util::HashAlgo,
+    expected: &[u8; 32],
+) -> Result<()> {
+    let on_disk = util::hash_file_streaming(hash_algo, full)
+        .with_context(|| format!("Failed to re-read written file: {}", full.display()))?;
+    if on_disk != *expected {
+        bail!(PatchError::WriteVerifyFailed {
+            path: path.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Set the extended attributes recorded for a written file. Where this build or the
+/// target's filesystem doesn't support them, warns once per apply (via `warned`) and
+/// leaves them unset.
+fn restore_xattrs(full: &Path, xattrs: &[(String, Vec<u8>)], warned: &AtomicBool) -> Result<()> {
+    if !util::write_xattrs(full, xattrs)? && !warned.swap(true, Ordering::Relaxed) {
+        eprintln!(
+            "warning: extended attributes aren't supported here ({}); the patch's are not set",
+            full.display()
+        );
+    }
+    Ok(())
+}
+
+/// Operations finished so far, reported when an apply is interrupted.
+#[derive(Default)]
+struct Done {
+    dirs_created: AtomicUsize,
+    files_added: AtomicUsize,
+    files_modified: AtomicUsize,
+    files_deleted: AtomicUsize,
+    dirs_deleted: AtomicUsize,
+    hardlinks_created: AtomicUsize,
+    symlinks_created: AtomicUsize,
+    metadata_updated: AtomicUsize,
+    bytes_processed: AtomicU64,
+}
+
+impl Done {
+    fn add(counter: &AtomicUsize, n: usize) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn add_bytes(&self, n: u64) {
+        self.bytes_processed.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn summary(&self, skipped_mismatches: Vec<String>) -> ApplySummary {
+        ApplySummary {
+            dirs_created: self.dirs_created.load(Ordering::Relaxed),
+            files_added: self.files_added.load(Ordering::Relaxed),
+            files_modified: self.files_modified.load(Ordering::Relaxed),
+            files_deleted: self.files_deleted.load(Ordering::Relaxed),
+            dirs_deleted: self.dirs_deleted.load(Ordering::Relaxed),
+            hardlinks_created: self.hardlinks_created.load(Ordering::Relaxed),
+            symlinks_created: self.symlinks_created.load(Ordering::Relaxed),
+            metadata_updated: self.metadata_updated.load(Ordering::Relaxed),
+            skipped_mismatches,
+            deletes_declined: 0,
+            bytes_processed: self.bytes_processed.load(Ordering::Relaxed),
+            timings: Vec::new(),
+            largest_ops: Vec::new(),
+            touched: Vec::new(),
+        }
+    }
+}
+
+/// What goes away with one root deleted directory: its verbose lines and the (files,
+/// dirs) it accounts for, reported only once the root is actually removed.
+#[derive(Default)]
+struct Covered {
+    lines: Vec<(String, String)>,
+    files: usize,
+    dirs: usize,
+}
+
+/// Move `src` (a file or a whole directory tree) to `dest`, creating parents as needed.
+/// A missing `src` is not an error, matching the plain delete path. When `rename` fails
+/// (typically because the quarantine is on another filesystem) the tree is copied and
+/// the original removed afterwards.
+fn quarantine_path(src: &Path, dest: &Path) -> Result<()> {
+    if std::fs::symlink_metadata(src).is_err() {
+        return Ok(());
+    }
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent).with_context(|| {
+            format!("Failed to create quarantine directory: {}", parent.display())
+        })?;
+    }
+    if std::fs::rename(src, dest).is_ok() {
+        return Ok(());
+    }
+
+    if src.is_dir() {
+        for entry in walkdir::WalkDir::new(src) {
+            let entry = entry
+                .with_context(|| format!("Failed to read directory entry in {}", src.display()))?;
+            let rel = entry
+                .path()
+                .strip_prefix(src)
+                .with_context(|| "Failed to compute relative path")?;
+            let to = util::extended_length_path(dest.join(rel));
+            if entry.file_type().is_dir() {
+                std::fs::create_dir_all(&to)
+                    .with_context(|| format!("Failed to create directory: {}", to.display()))?;
+            } else {
+                std::fs::copy(entry.path(), &to).with_context(|| {
+                    format!("Failed to copy {} to quarantine", entry.path().display())
+                })?;
+            }
+        }
+        std::fs::remove_dir_all(src)
+            .with_context(|| format!("Failed to remove directory tree: {}", src.display()))
+    } else {
+        std::fs::copy(src, dest)
+            .with_context(|| format!("Failed to copy {} to quarantine", src.display()))?;
+        std::fs::remove_file(src)
+            .with_context(|| format!("Failed to delete file: {}", src.display()))
+    }
+}
+
+/// Apply `chunks` to `full` by overwriting only the Insert and moved Copy regions through
+/// a writable mapping, when the diff allows it (see [`binary_patch::in_place_edits`]).
+/// Returns `Ok(false)` without touching the file when the diff doesn't qualify, so
+/// the caller falls back to rebuilding the file in memory.
+///
+/// The target hash is verified from the read-only mapping *before* any byte is written,
+/// since an in-place edit cannot be undone. The read-only mapping is dropped before the
+/// writable one is created, and all writes go through the mapping itself rather than
+/// `write`, so the Windows restriction on writing a mapped file does not apply.
+fn patch_in_place(
+    full: &Path,
+    chunks: &[crate::patch_format::DiffChunk],
+    hash_algo: util::HashAlgo,
+    expected_hash: &[u8; 32],
+    force: bool,
+) -> Result<bool> {
+    let len = std::fs::metadata(full)
+        .with_context(|| format!("Failed to read metadata: {}", full.display()))?
+        .len();
+    let Some(edits) = binary_patch::in_place_edits(len, chunks) else {
+        return Ok(false);
+    };
+
+    {
+        let old_mmap = util::mmap_file(full)?;
+        if old_mmap.len() as u64 != len {
+            return Ok(false);
+        }
+        let actual = binary_patch::hash_applied(hash_algo, &old_mmap, chunks);
+        if actual != *expected_hash {
+            bail!(PatchError::HashMismatch {
+                path: full.display().to_string(),
+                expected: *expected_hash,
+                actual: Some(actual),
+            });
+        }
+    }
+
+    // Once open, the handle stays writable with the read-only permissions put back.
+    let open = || std::fs::OpenOptions::new().read(true).write(true).open(full);
+    let file = if force { forcing_writable(full, true, open) } else { open() }
+        .with_context(|| format!("Failed to open file for writing: {}", full.display()))?;
+    // SAFETY: the mapping is private to this op; no other phase touches this path
+    // (ModifyFile paths are disjoint from add/delete paths) and we hold no other mapping.
+    let mut map = unsafe {
+        memmap2::MmapMut::map_mut(&file)
+            .with_context(|| format!("Failed to memory-map file: {}", full.display()))?
+    };
+    for edit in edits {
+        match edit {
+            binary_patch::InPlaceEdit::Write { offset, data } => {
+                let start = offset as usize;
+                map[start..start + data.len()].copy_from_slice(data);
+            }
+            binary_patch::InPlaceEdit::Move { from, to, length } => {
+                let from = from as usize;
+                map.copy_within(from..from + length as usize, to as usize);
+            }
+        }
+    }
+    map.flush()
+        .with_context(|| format!("Failed to flush patched file: {}", full.display()))?;
+    Ok(true)
+}
+
+/// Read a patch piped on stdin, refusing more than `max_total_size` plus the header.
+fn read_stdin(limits: &ApplyLimits) -> Result<Vec<u8>> {
+    let cap = limits.max_total_size.saturating_add(HEADER_LEN as u64 + 1);
+    let mut out = Vec::new();
+    std::io::stdin()
+        .lock()
+        .take(cap)
+        .read_to_end(&mut out)
+        .context("Failed to read patch from stdin")?;
+    if out.len() as u64 >= cap {
+        bail!(PatchError::LimitExceeded(format!(
+            "Patch on stdin exceeds the limit of {} bytes (see --max-total-size)",
+            limits.max_total_size
+        )));
+    }
+    Ok(out)
+}
+
+/// Unwrap a `--gzip` patch into the plain patch file bytes. The inner file is at most
+/// a header plus a compressed manifest no larger than the manifest itself, so its size
+/// is capped at the same limit.
+fn gunzip(data: &[u8], limits: &ApplyLimits) -> Result<Vec<u8>> {
+    let cap = limits.max_total_size.saturating_add(HEADER_LEN as u64 + 1);
+    let mut out = Vec::new();
+    let mut decoder = flate2::bufread::GzDecoder::new(data).take(cap);
+    decoder
+        .read_to_end(&mut out)
+        .map_err(|e| PatchError::Decompress(format!("gzip wrapper: {}", e)))?;
+    if out.len() as u64 >= cap {
+        bail!(PatchError::LimitExceeded(format!(
+            "Gzip-wrapped patch exceeds the limit of {} bytes (see --max-total-size)",
+            limits.max_total_size
+        )));
+    }
+    // The decoder stops at the end of the gzip member; anything after it was appended.
+    let rest = decoder.into_inner().into_inner();
+    if !rest.is_empty() {
+        bail!(PatchError::Corrupt(format!(
+            "{} unexpected bytes after the gzip wrapper",
+            rest.len()
+        )));
+    }
+    Ok(out)
+}
+
+/// `--verify-before`: hash every target file the patch modifies or deletes and compare
+/// it with the pre-patch hash recorded for it, returning the paths that differ or are
+/// missing, sorted. A multi-base diff accepts any of its old versions, and a file
+/// deleted from only some bases may be missing. An operation without a recorded hash
+/// (older or merged patches) is an error, not a pass.
+fn drifted_files(target: &Path, operations: &[PatchOp], hash_algo: util::HashAlgo) -> Result<Vec<String>> {
+    // (path, the hashes it may have, whether it may be missing instead)
+    let expected = operations
+        .iter()
+        .filter_map(|op| match op {
+            PatchOp::ModifyFile { path, old_blake3_hash, .. } => Some(match old_blake3_hash {
+                Some(hash) => Ok((path, vec![*hash], false)),
+                None => Err(needs_hash(path)),
+            }),
+            PatchOp::DeleteFile {
+                path,
+                old_blake3_hash,
+                may_be_absent,
+            } => Some(match old_blake3_hash {
+                Some(hash) => Ok((path, vec![*hash], *may_be_absent)),
+                None => Err(needs_hash(path)),
+            }),
+            PatchOp::ModifyFileMulti { path, variants, .. } => {
+                Some(Ok((path, variants.iter().map(|v| v.base_hash).collect(), false)))
+            }
+            _ => None,
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut drifted = expected
+        .par_iter()
+        .filter_map(|(path, hashes, may_be_absent)| {
+            let full = util::join_relative(target, path);
+            // A deleted link was recorded by its target; a file to modify is never one.
+            let actual = match std::fs::symlink_metadata(&full) {
+                Ok(meta) if meta.file_type().is_symlink() => util::read_link_target(&full, true)
+                    .ok()
+                    .map(|link| util::link_hash(hash_algo, &link)),
+                Ok(meta) if meta.is_file() => util::hash_file_streaming(hash_algo, &full).ok(),
+                Err(e) if *may_be_absent && e.kind() == std::io::ErrorKind::NotFound => return None,
+                _ => None,
+            };
+            let matches = actual.is_some_and(|hash| hashes.contains(&hash));
+            (!matches).then(|| path.to_string())
+        })
+        .collect::<Vec<_>>();
+    drifted.sort();
+    Ok(drifted)
+}
+
+/// Why [`drifted_files`] can't check `path`.
+fn needs_hash(path: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "--verify-before needs the pre-patch hash of {}, which this patch doesn't record (patches from before format 13.3 and merged ones have none, nor does a file deleted from bases holding different versions of it)",
+        path
+    )
+}
+
+/// How apply walks a target for `--prune` and `--out`: names that aren't valid UTF-8
+/// don't stop it, since a patch may have created them.
+const ESCAPED_WALK: util::WalkOptions = util::WalkOptions {
+    skip_unreadable: false,
+    allow_non_utf8: true,
+};
+
+/// `--prune`: files in `target` with no operation in a patch that records its whole
+/// file set, and the directories holding nothing else, as (files, dirs). Paths the
+/// patch already deletes, and the `skip` subtrees (apply's own scratch space), are left
+/// out. A directory is only pruned for a stray file inside it: the patch doesn't record
+/// directories it leaves alone, so an unknown empty one might belong to the new tree.
+fn find_strays(
+    target: &Path,
+    expected: &std::collections::HashSet<&str>,
+    deleted: &std::collections::HashSet<&str>,
+    skip: &[String],
+) -> Result<(Vec<String>, Vec<String>)> {
+    let ancestors = |path: &str| {
+        let mut parents = Vec::new();
+        let mut cur = path;
+        while let Some(idx) = cur.rfind('/') {
+            cur = &cur[..idx];
+            parents.push(cur.to_string());
+        }
+        parents
+    };
+    let within = |path: &str, root: &str| {
+        path == root || path.strip_prefix(root).is_some_and(|rest| rest.starts_with('/'))
+    };
+    let mut expected_dirs: std::collections::HashSet<String> =
+        expected.iter().flat_map(|path| ancestors(path)).collect();
+    expected_dirs.extend(expected.iter().map(|path| path.to_string()));
+
+    let (entries, _) = util::walk_directory_with(target, ESCAPED_WALK)?;
+    let is_left_alone = |path: &str| {
+        skip.iter().any(|root| within(path, root))
+            || deleted.iter().any(|root| within(path, root))
+    };
+    let mut files = Vec::new();
+    let mut dirs = std::collections::BTreeSet::new();
+    for entry in &entries {
+        let path = entry.relative_path.as_str();
+        if matches!(entry.kind, util::EntryKind::File | util::EntryKind::Symlink)
+            && !expected.contains(path)
+            && !is_left_alone(path)
+        {
+            dirs.extend(ancestors(path).into_iter().filter(|dir| !expected_dirs.contains(dir)));
+            files.push(path.to_string());
+        }
+    }
+    // Subdirectories of a pruned directory go with it.
+    let pruned_roots: Vec<String> = dirs.iter().cloned().collect();
+    for entry in &entries {
+        let path = entry.relative_path.as_str();
+        if matches!(entry.kind, util::EntryKind::Dir)
+            && !expected_dirs.contains(path)
+            && !is_left_alone(path)
+            && pruned_roots.iter().any(|root| within(path, root))
+        {
+            dirs.insert(path.to_string());
+        }
+    }
+    let mut dirs: Vec<String> = dirs.into_iter().collect();
+    util::sort_dirs_deepest_first(&mut dirs);
+    Ok((files, dirs))
+}
+
+/// Materialize `base` into the empty (or missing) directory `out` for `--out` mode,
+/// skipping everything the patch deletes or replaces wholesale. Files are cloned with
+/// [`util::clone_file`], so on CoW filesystems this costs almost nothing. Returns the
+/// canonical `out`, which the rest of apply then patches in place of the target.
+fn materialize_copy(
+    base: &Path,
+    out: &Path,
+    deleted: &std::collections::HashSet<&str>,
+    replaced: &std::collections::HashSet<&str>,
+) -> Result<PathBuf> {
+    if out.exists() {
+        let mut contents = std::fs::read_dir(out)
+            .with_context(|| format!("Failed to read output directory: {}", out.display()))?;
+        if contents.next().is_some() {
+            bail!("Output directory is not empty: {}", out.display());
+        }
+    }
+    std::fs::create_dir_all(out)
+        .with_context(|| format!("Failed to create output directory: {}", out.display()))?;
+    let out = out
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize output: {}", out.display()))?;
+
+    let is_deleted = |path: &str| {
+        let mut cur = path;
+        loop {
+            if deleted.contains(cur) {
+                return true;
+            }
+            match cur.rfind('/') {
+                Some(idx) => cur = &cur[..idx],
+                None => return false,
+            }
+        }
+    };
+
+    let (entries, _) = util::walk_directory_with(base, ESCAPED_WALK)?;
+    let mut files = Vec::new();
+    for entry in &entries {
+        if is_deleted(&entry.relative_path) {
+            continue;
+        }
+        let dest = util::join_relative(&out, &entry.relative_path);
+        match entry.kind {
+            util::EntryKind::Dir => std::fs::create_dir_all(&dest)
+                .with_context(|| format!("Failed to create directory: {}", dest.display()))?,
+            _ if replaced.contains(entry.relative_path.as_str()) => {}
+            util::EntryKind::File | util::EntryKind::Symlink => files.push((entry, dest)),
+        }
+    }
+    // Parents exist now, so files can be cloned in any order. Links are copied as links.
+    files.par_iter().try_for_each(|(entry, dest)| match &entry.link_target {
+        Some(link) => util::create_symlink(link, dest)
+            .with_context(|| format!("Failed to create symbolic link: {}", dest.display())),
+        None => util::clone_file(&entry.full_path, dest).map(|_| ()),
+    })?;
+    Ok(out)
+}
+
+/// Read, decompress and decode a patch file, enforcing `limits` at each step.
+pub fn read_manifest(patch_path: &Path, limits: &ApplyLimits) -> Result<PatchManifest> {
+    load_manifest(patch_path, limits, None, &mut util::PhaseTimer::new())
+}
+
+/// [`read_manifest`] over a patch already in memory (plain or gzip-wrapped).
+pub fn read_manifest_bytes(source: &[u8], limits: &ApplyLimits) -> Result<PatchManifest> {
+    decode_manifest(source, limits, None, &mut util::PhaseTimer::new())
+}
+
+/// [`read_manifest`], timing the read, decompress and decode phases.
+fn load_manifest(
+    patch_path: &Path,
+    limits: &ApplyLimits,
+    mac_key: Option<&MacKey>,
+    timer: &mut util::PhaseTimer,
+) -> Result<PatchManifest> {
+    // mmap the patch file, check magic, then decompress into a buffer preallocated from
+    // the header's uncompressed length (one allocation, no regrowth) and deserialize it.
+    // The declared length is checked against the limit before allocating, and the
+    // decoder is capped at one byte past it so a lying header can't inflate further.
+    // stdin (`-`) can't be mapped, so it is read into memory under the same limit.
+    // A split patch is named by its base, which itself doesn't exist.
+    if !util::is_stdio(patch_path)
+        && !patch_path.exists()
+        && patch_format::part_path(patch_path, 1).is_file()
+    {
+        return load_split_manifest(patch_path, limits, mac_key, timer);
+    }
+    let mapped;
+    let buffered;
+    let source: &[u8] = if util::is_stdio(patch_path) {
+        buffered = read_stdin(limits)?;
+        &buffered
+    } else {
+        mapped = util::mmap_file(patch_path)?;
+        &mapped
+    };
+    decode_manifest(source, limits, mac_key, timer)
+}
+
+/// Read the parts of a split patch (`create --split-size`) in order and join their
+/// operations. Every part must come from the same run and agree on the part count, so a
+/// missing or foreign part is caught before anything is applied. `limits` hold for the
+/// whole patch, not each part.
+fn load_split_manifest(
+    base: &Path,
+    limits: &ApplyLimits,
+    mac_key: Option<&MacKey>,
+    timer: &mut util::PhaseTimer,
+) -> Result<PatchManifest> {
+    let mut first: Option<PartHeader> = None;
+    let mut joined: Option<PatchManifest> = None;
+    let mut remaining = *limits;
+    for index in 1.. {
+        let path = patch_format::part_path(base, index);
+        let mapped = util::mmap_file(&path)?;
+        let part = PartHeader::parse(&mapped).with_context(|| path.display().to_string())?;
+        let expected = first.unwrap_or(part);
+        if part.set_id != expected.set_id || part.count != expected.count {
+            bail!(PatchError::Corrupt(format!(
+                "{} belongs to another split patch than {}",
+                path.display(),
+                patch_format::part_path(base, 1).display()
+            )));
+        }
+        if part.index != index || part.count < index {
+            bail!(PatchError::Corrupt(format!(
+                "{} is part {} of {}, expected part {}",
+                path.display(),
+                part.index,
+                part.count,
+                index
+            )));
+        }
+        first = Some(part);
+
+        let inner = &mapped[PART_HEADER_LEN..];
+        let uncompressed_len = PatchHeader::parse(inner)?.uncompressed_len;
+        let manifest = decode_manifest(inner, &remaining, mac_key, timer)
+            .with_context(|| path.display().to_string())?;
+        remaining.max_total_size = remaining.max_total_size.saturating_sub(uncompressed_len);
+        match &mut joined {
+            None => joined = Some(manifest),
+            Some(joined) => {
+                if (manifest.version, manifest.hash_algo, manifest.full_file_set)
+                    != (joined.version, joined.hash_algo, joined.full_file_set)
+                {
+                    bail!(PatchError::Corrupt(format!(
+                        "{} has a different preamble than the first part",
+                        path.display()
+                    )));
+                }
+                joined.operations.extend(manifest.operations);
+            }
+        }
+        if index == part.count {
+            break;
+        }
+    }
+    let joined = joined.expect("at least one part");
+    limits.check(&joined.operations)?;
+    Ok(joined)
+}
+
+/// Fail unless `data` is exactly one zstd frame.
+fn check_single_frame(data: &[u8], what: &str) -> Result<()> {
+    let frame_len = zstd::zstd_safe::find_frame_compressed_size(data)
+        .map_err(|code| PatchError::Decompress(zstd::zstd_safe::get_error_name(code).to_string()))?;
+    if frame_len != data.len() {
+        bail!(PatchError::Corrupt(format!(
+            "{} unexpected bytes after the {}",
+            data.len() - frame_len,
+            what
+        )));
+    }
+    Ok(())
+}
+
+fn decode_manifest(
+    source: &[u8],
+    limits: &ApplyLimits,
+    mac_key: Option<&MacKey>,
+    timer: &mut util::PhaseTimer,
+) -> Result<PatchManifest> {
+    let unwrapped;
+    let raw: &[u8] = if source.starts_with(&GZIP_MAGIC) {
+        unwrapped = gunzip(source, limits)?;
+        &unwrapped
+    } else {
+        source
+    };
+    timer.mark("read");
+    if raw.starts_with(PART_MAGIC) {
+        bail!(PatchError::Corrupt(
+            "this is one part of a split patch; pass its base name, without the .NNN suffix"
+                .to_string()
+        ));
+    }
+    let header = PatchHeader::parse(raw)?;
+    if header.uncompressed_len > limits.max_total_size {
+        bail!(PatchError::LimitExceeded(format!(
+            "Patch manifest is {} bytes uncompressed, exceeding the limit of {} (see --max-total-size)",
+            header.uncompressed_len,
+            limits.max_total_size
+        )));
+    }
+
+    // The payload is a single zstd frame that must run up to the index frame, and that
+    // one to the end of the file. The decoder alone would carry on past a frame,
+    // skipping skippable frames and decoding empty ones, so bytes appended to a patch
+    // could otherwise go unnoticed.
+    let header_len = header.encoded_len();
+    let (body, mac) = patch_format::split_mac_trailer(&raw[header_len..]);
+    if let Some(key) = mac_key {
+        let Some(mac) = mac else {
+            bail!(PatchError::MacMismatch(
+                "the patch has no MAC (create it with --mac-key)".to_string()
+            ));
+        };
+        if !patch_format::verify_mac(key, &raw[..header_len], body, &mac) {
+            bail!(PatchError::MacMismatch(
+                "MAC doesn't match (wrong key, or the patch was altered)".to_string()
+            ));
+        }
+        timer.mark("authenticate");
+    }
+    let (payload, index) = header.split_index(body)?;
+    check_single_frame(payload, "compressed payload")?;
+    if header.index_offset.is_some() {
+        check_single_frame(index, "index")?;
+    }
+
+    let mut decoded = Vec::with_capacity(header.uncompressed_len as usize);
+    zstd::Decoder::new(payload)
+        .and_then(|decoder| {
+            decoder
+                .take(header.uncompressed_len + 1)
+                .read_to_end(&mut decoded)
+        })
+        .map_err(|e| PatchError::Decompress(e.to_string()))?;
+    if decoded.len() as u64 != header.uncompressed_len {
+        bail!(PatchError::Corrupt(format!(
+            "header declares {} uncompressed bytes, payload has {}",
+            header.uncompressed_len,
+            decoded.len()
+        )));
+    }
+    timer.mark("decompress");
+    let manifest = patch_format::decode_payload(&decoded, header.encoding)?;
+    drop(decoded);
+
+    limits.check(&manifest.operations)?;
+    timer.mark("decode");
+
+    Ok(manifest)
+}
 
 /// Apply a patch file to the target directory.
 /// Uses Rayon for parallel file operations where safe.
-pub async fn apply_patch(target_dir: &Path, patch_path: &Path) -> Result<ApplySummary> {
-    // mmap the patch file, check magic, then stream-decompress into bincode
-    // (avoids allocating a full decompressed Vec)
-    let raw = util::mmap_file(patch_path)?;
+pub async fn apply_patch(
+    target_dir: &Path,
+    patch_path: &Path,
+    options: &ApplyOptions,
+) -> std::result::Result<ApplySummary, PatchError> {
+    let mut timer = util::PhaseTimer::new();
+    let manifest =
+        load_manifest(patch_path, &options.limits, options.mac_key.as_ref(), &mut timer)?;
+    Ok(apply(target_dir, manifest, options, timer).await?)
+}
 
-    if raw.len() < MAGIC.len() || &raw[..MAGIC.len()] != MAGIC {
-        bail!("Invalid patch file: missing magic header");
-    }
+/// Like [`apply_patch`], for a patch already in memory (e.g. downloaded over HTTP), so
+/// it needn't be written to a file first. The checks and limits are the same.
+pub async fn apply_patch_bytes(
+    target_dir: &Path,
+    patch: &[u8],
+    options: &ApplyOptions,
+) -> std::result::Result<ApplySummary, PatchError> {
+    let mut timer = util::PhaseTimer::new();
+    let manifest =
+        decode_manifest(patch, &options.limits, options.mac_key.as_ref(), &mut timer)?;
+    Ok(apply(target_dir, manifest, options, timer).await?)
+}
+
+/// `--only`: keep the operations on paths under one of `prefixes`, matched by whole
+/// components, so `assets` covers `assets` and `assets/x` but not `assets2`. A deleted
+/// directory is therefore only removed wholesale when it lies under a prefix itself,
+/// never because something under a prefix is inside it. A hard link is kept only if its
+/// target is covered too, since an uncovered target may still hold its old content.
+fn retain_under(operations: &mut Vec<PatchOp>, prefixes: &[String]) -> Result<()> {
+    let prefixes = prefixes
+        .iter()
+        .map(|p| util::normalize_relative_path(&p.replace('\\', "/")))
+        .collect::<Result<Vec<_>>>()
+        .context("Invalid --only prefix")?;
+    let covered = |path: &str| {
+        prefixes.iter().any(|p| {
+            path == p || (path.starts_with(p.as_str()) && path.as_bytes()[p.len()] == b'/')
+        })
+    };
+    operations.retain(|op| {
+        covered(op.path())
+            && match op {
+                PatchOp::CreateHardlink { target, .. } => covered(target),
+                _ => true,
+            }
+    });
+    Ok(())
+}
 
-    let decoder =
-        zstd::Decoder::new(&raw[MAGIC.len()..]).context("Failed to create zstd decoder")?;
-    let manifest: PatchManifest =
-        bincode::deserialize_from(decoder).context("Failed to deserialize patch manifest")?;
+/// Map the object holding the content of the external AddFile for `path`.
+fn open_external(objects_dir: Option<&Path>, path: &str, hash: &[u8; 32]) -> Result<util::FileData> {
+    let Some(dir) = objects_dir else {
+        bail!(
+            "The content of {} is stored in an objects directory; pass it with --objects-dir",
+            path
+        );
+    };
+    patch_format::open_object(dir, path, hash)
+}
 
-    if manifest.version != crate::patch_format::FORMAT_VERSION {
+async fn apply(
+    target_dir: &Path,
+    mut manifest: PatchManifest,
+    options: &ApplyOptions,
+    mut timer: util::PhaseTimer,
+) -> Result<ApplySummary> {
+    util::ensure_dir(target_dir, "target")?;
+    // Through components, which drop a trailing slash: `lstat("link/")` follows the link.
+    let unresolved: PathBuf = target_dir.components().collect();
+    if options.no_follow_target
+        && std::fs::symlink_metadata(&unresolved).is_ok_and(|m| m.file_type().is_symlink())
+    {
         bail!(
-            "Unsupported patch version: {} (expected {})",
-            manifest.version,
-            crate::patch_format::FORMAT_VERSION
+            "Target is a symlink: {} (patch the directory it points to, or drop --no-follow-target)",
+            target_dir.display()
         );
     }
+    validate_operations(&manifest.operations)?;
+    if !options.only.is_empty() {
+        if options.prune {
+            bail!("--prune can't be combined with --only, which leaves the rest of the tree as it is");
+        }
+        retain_under(&mut manifest.operations, &options.only)?;
+        manifest.full_file_set = false;
+    }
+    if options.prune && !manifest.full_file_set {
+        bail!("--prune needs a patch listing every file of the patched tree (create it with --full-verify and no filters)");
+    }
+    if options.verify_before {
+        if options.lenient_base {
+            bail!("--verify-before can't be combined with --lenient-base, which accepts a drifted base");
+        }
+        let drifted = drifted_files(target_dir, &manifest.operations, manifest.hash_algo)?;
+        if !drifted.is_empty() {
+            bail!(PatchError::SourceMismatch { paths: drifted });
+        }
+        timer.mark("verify before");
+    }
+
+    // Content kept in an objects directory is found and size-checked up front, so a
+    // missing or oversized object fails before the target is touched.
+    for op in &manifest.operations {
+        if let PatchOp::AddFile {
+            path,
+            blake3_hash,
+            external: true,
+            ..
+        } = op
+        {
+            let object = open_external(options.objects_dir.as_deref(), path, blake3_hash)?;
+            options
+                .limits
+                .check_size(path, patch_format::add_file_len(&object, true)?)?;
+        }
+    }
+
+    let hash_algo = manifest.hash_algo;
 
     // Group operations by type (owned, not borrowed)
     let mut create_dirs: Vec<PatchOp> = Vec::new();
@@ -36,15 +1375,77 @@ pub async fn apply_patch(target_dir: &Path, patch_path: &Path) -> Result<ApplySu
     let mut modify_files: Vec<PatchOp> = Vec::new();
     let mut delete_files: Vec<PatchOp> = Vec::new();
     let mut delete_dirs: Vec<PatchOp> = Vec::new();
+    let mut hardlinks: Vec<(String, String)> = Vec::new();
+    let mut symlinks: Vec<(String, String)> = Vec::new();
+    let mut set_metadata: Vec<PatchOp> = Vec::new();
+    let mut verified: Vec<String> = Vec::new();
 
     for op in manifest.operations {
         match &op {
             PatchOp::CreateDir { .. } => create_dirs.push(op),
             PatchOp::AddFile { .. } => add_files.push(op),
-            PatchOp::ModifyFile { .. } => modify_files.push(op),
+            PatchOp::ModifyFile { .. } | PatchOp::ModifyFileMulti { .. } => modify_files.push(op),
             PatchOp::DeleteFile { .. } => delete_files.push(op),
             PatchOp::DeleteDir { .. } => delete_dirs.push(op),
+            PatchOp::VerifyFile { path, .. } => {
+                if options.prune {
+                    verified.push(path.clone());
+                }
+            }
+            PatchOp::CreateHardlink { path, target } => {
+                hardlinks.push((path.clone(), target.clone()))
+            }
+            PatchOp::CreateSymlink { path, target } => symlinks.push((path.clone(), target.clone())),
+            PatchOp::SetMetadata { .. } => set_metadata.push(op),
+        }
+    }
+
+    // Strays become ordinary deletions, so they are confirmed, quarantined and
+    // counted like the patch's own.
+    if options.prune {
+        let expected = create_dirs
+            .iter()
+            .chain(&add_files)
+            .chain(&modify_files)
+            .chain(&set_metadata)
+            .map(PatchOp::path)
+            .chain(hardlinks.iter().chain(&symlinks).map(|(path, _)| path.as_str()))
+            .chain(verified.iter().map(String::as_str))
+            .collect();
+        let deleted = delete_files.iter().chain(&delete_dirs).map(PatchOp::path).collect();
+        let mut skip = vec![DEFAULT_TEMP_DIR.to_string()];
+        let canonical = target_dir.canonicalize().with_context(|| {
+            format!("Failed to canonicalize target: {}", target_dir.display())
+        })?;
+        for dir in options.quarantine.iter().chain(&options.temp_dir) {
+            let dir = dir.canonicalize().unwrap_or_default();
+            if let Some(rel) = dir.strip_prefix(&canonical).ok().and_then(Path::to_str) {
+                skip.push(rel.replace('\\', "/"));
+            }
+        }
+        let (files, dirs) = find_strays(target_dir, &expected, &deleted, &skip)?;
+        delete_files.extend(files.into_iter().map(|path| PatchOp::DeleteFile {
+            path,
+            old_blake3_hash: None,
+            may_be_absent: false,
+        }));
+        delete_dirs.extend(dirs.into_iter().map(|path| PatchOp::DeleteDir { path }));
+        timer.mark("prune");
+    }
+
+    let mut deletes_declined = 0;
+    if let Some(confirm) = &options.confirm_deletes {
+        let paths = |ops: &[PatchOp]| -> Vec<String> {
+            ops.iter().map(|op| op.path().to_string()).collect()
+        };
+        let (files, dirs) = (paths(&delete_files), paths(&delete_dirs));
+        let nothing_to_delete = files.is_empty() && dirs.is_empty();
+        if !nothing_to_delete && !(confirm.0)(&files, &dirs) {
+            deletes_declined = files.len() + dirs.len();
+            delete_files.clear();
+            delete_dirs.clear();
         }
+        timer.mark("confirm");
     }
 
     let num_create_dirs = create_dirs.len();
@@ -57,14 +1458,62 @@ pub async fn apply_patch(target_dir: &Path, patch_path: &Path) -> Result<ApplySu
         .canonicalize()
         .with_context(|| format!("Failed to canonicalize target: {}", target_dir.display()))?;
 
-    // 1. Create directories (sequential, parent-first - already ordered)
+    // With --out, everything below patches a fresh copy and the target is only read.
+    let target = match &options.out {
+        None => target,
+        Some(out) => {
+            let mut deleted = std::collections::HashSet::new();
+            for op in delete_files.iter().chain(&delete_dirs) {
+                if let PatchOp::DeleteFile { path, .. } | PatchOp::DeleteDir { path } = op {
+                    deleted.insert(path.as_str());
+                }
+            }
+            let replaced = add_files
+                .iter()
+                .filter_map(|op| match op {
+                    PatchOp::AddFile { path, .. } => Some(path.as_str()),
+                    _ => None,
+                })
+                .chain(hardlinks.iter().chain(&symlinks).map(|(path, _)| path.as_str()))
+                .collect();
+            materialize_copy(&target, out, &deleted, &replaced)?
+        }
+    };
+
+    // Verbose lines from the parallel phases are buffered here and printed sorted by path.
+    let log = Arc::new(OpLog::new(options.verbose));
+
+    // Once the interrupt flag is set, every remaining operation is skipped where it
+    // would start; operations already running finish normally.
+    let interrupt = options.interrupt.clone().unwrap_or_default();
+    let stopped = |flag: &AtomicBool| flag.load(Ordering::SeqCst);
+    let done = Arc::new(Done::default());
+
+    timer.mark("prepare");
+
+    // 1. Create directories (sequential, parent-first - already ordered). Each gets its
+    // recorded mode at once, so a private directory is never open to others, but stays
+    // writable by its owner until everything inside it is in place (see step 7).
+    let mut dir_modes: Vec<(PathBuf, u32)> = Vec::new();
     for op in &create_dirs {
-        if let PatchOp::CreateDir { path } = op {
-            let full = target.join(path);
+        if let PatchOp::CreateDir { path, mode } = op {
+            if stopped(&interrupt) {
+                break;
+            }
+            let full = util::join_relative(&target, path);
             std::fs::create_dir_all(&full)
                 .with_context(|| format!("Failed to create directory: {}", full.display()))?;
+            if let Some(mode) = *mode {
+                util::set_metadata(&full, Some(mode | 0o700), None)?;
+                if mode & 0o700 != 0o700 {
+                    dir_modes.push((full, mode));
+                }
+            }
+            log.record(path, format!("+ created dir {}", path));
+            Done::add(&done.dirs_created, 1);
         }
     }
+    timer.mark("create dirs");
 
     // Pre-process deletions: if an entire directory subtree is being removed, use
     // remove_dir_all on the subtree root instead of thousands of individual deletions.
@@ -97,21 +1546,40 @@ pub async fn apply_patch(target_dir: &Path, patch_path: &Path) -> Result<ApplySu
         .cloned()
         .collect();
 
+    // Group everything removed along with each root under its topmost deleted ancestor.
+    let topmost_deleted = |path: &str| -> Option<String> {
+        let mut found = None;
+        let mut cur = std::path::Path::new(path);
+        while let Some(s) = cur.to_str().filter(|s| !s.is_empty()) {
+            if deleted_dir_set.contains(s) {
+                found = Some(s.to_string());
+            }
+            match cur.parent() {
+                Some(parent) => cur = parent,
+                None => break,
+            }
+        }
+        found
+    };
+    let mut covered: std::collections::HashMap<String, Covered> = std::collections::HashMap::new();
+    for dir in &deleted_dir_set {
+        let root = topmost_deleted(dir).expect("a deleted dir is under its own root");
+        let entry = covered.entry(root).or_default();
+        entry.lines.push((dir.clone(), format!("- deleted dir {}", dir)));
+        entry.dirs += 1;
+    }
+
     // Orphan files: individual files in kept directories not covered by any root.
     let orphan_delete_files: Vec<PatchOp> = delete_files
         .into_iter()
         .filter(|op| {
-            if let PatchOp::DeleteFile { path } = op {
-                let mut cur = std::path::Path::new(path.as_str());
-                while let Some(parent) = cur.parent() {
-                    let s = parent.to_str().unwrap_or("");
-                    if s.is_empty() {
-                        break;
-                    }
-                    if deleted_dir_set.contains(s) {
-                        return false; // covered by remove_dir_all on an ancestor
-                    }
-                    cur = parent;
+            if let PatchOp::DeleteFile { path, .. } = op {
+                let parent = std::path::Path::new(path.as_str()).parent();
+                if let Some(root) = parent.and_then(|p| p.to_str()).and_then(&topmost_deleted) {
+                    let entry = covered.entry(root).or_default();
+                    entry.lines.push((path.clone(), format!("- deleted {}", path)));
+                    entry.files += 1;
+                    return false; // covered by remove_dir_all on an ancestor
                 }
             }
             true
@@ -123,82 +1591,416 @@ pub async fn apply_patch(target_dir: &Path, patch_path: &Path) -> Result<ApplySu
     //   AddFile:    new_paths − old_paths
     //   ModifyFile: new_paths ∩ old_paths
     //   DeleteFile: old_paths − new_paths
-    // so it is safe to run them concurrently.
+    // so it is safe to run them concurrently, or deletes first (`--order delete-first`).
     let target_for_add = target.clone();
     let target_for_modify = target.clone();
     let target_for_delete = target.clone();
-    let (r_add, r_modify, r_delete) = tokio::try_join!(
-        tokio::task::spawn_blocking(move || -> Result<()> {
-            add_files.par_iter().try_for_each(|op| -> Result<()> {
-                if let PatchOp::AddFile {
-                    path,
-                    data,
-                    blake3_hash,
-                } = op
-                {
-                    let full = target_for_add.join(path);
+    let log_for_add = Arc::clone(&log);
+    let log_for_modify = Arc::clone(&log);
+    let log_for_delete = Arc::clone(&log);
+    let quarantine = match &options.quarantine {
+        Some(q) => {
+            std::fs::create_dir_all(q).with_context(|| {
+                format!("Failed to create quarantine directory: {}", q.display())
+            })?;
+            Some(q.canonicalize().with_context(|| {
+                format!("Failed to canonicalize quarantine: {}", q.display())
+            })?)
+        }
+        None => None,
+    };
+    let fs = RetryingFs {
+        inner: ForceFs {
+            inner: RealFs,
+            force: options.force,
+        },
+        retries: options.retries,
+    };
+    let force = options.force;
+    let staging = if add_files.is_empty() && modify_files.is_empty() {
+        None
+    } else {
+        let dir = match &options.temp_dir {
+            Some(dir) => dir.clone(),
+            None => target.join(DEFAULT_TEMP_DIR),
+        };
+        Some(Arc::new(Staging::new(dir)?))
+    };
+    let staging_for_add = staging.clone();
+    let paranoid = options.paranoid;
+    // With --skip-mismatches a failed hash check records the path and moves on; the
+    // check always runs before the write, so the file is left as it was.
+    let skipped_adds = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+    let skipped_modifies = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+    let skipped_for_add = Arc::clone(&skipped_adds);
+    let skipped_for_modify = Arc::clone(&skipped_modifies);
+    let skip_mismatches = options.skip_mismatches;
+    let lenient_base = options.lenient_base;
+    let add_missing = options.add_missing;
+    let interrupt_for_add = Arc::clone(&interrupt);
+    let interrupt_for_modify = Arc::clone(&interrupt);
+    let interrupt_for_delete = Arc::clone(&interrupt);
+    let done_for_add = Arc::clone(&done);
+    let done_for_modify = Arc::clone(&done);
+    let done_for_delete = Arc::clone(&done);
+    let mismatch = move |skipped: &std::sync::Mutex<Vec<String>>,
+                         path: &str,
+                         expected: &[u8; 32],
+                         actual: Option<[u8; 32]>|
+          -> Result<()> {
+        if !skip_mismatches {
+            bail!(PatchError::HashMismatch {
+                path: path.to_string(),
+                expected: *expected,
+                actual,
+            });
+        }
+        skipped.lock().unwrap().push(path.to_string());
+        Ok(())
+    };
+    let xattrs_warned = Arc::new(AtomicBool::new(false));
+    let objects_dir = options.objects_dir.clone();
+    let xattrs_warned_for_add = Arc::clone(&xattrs_warned);
+    timer.mark("plan deletes");
+    let add_task = move || -> Result<Duration> {
+        let started = Instant::now();
+        let stage = |full: &Path, write: &dyn Fn(&Path) -> std::io::Result<()>| {
+            match &staging_for_add {
+                Some(staging) => staging.replace(&fs, full, write),
+                None => write(full),
+            }
+        };
+        add_files.par_iter().try_for_each(|op| -> Result<()> {
+            if stopped(&interrupt_for_add) {
+                return Ok(());
+            }
+            if let PatchOp::AddFile {
+                path,
+                data,
+                blake3_hash,
+                compressed,
+                xattrs,
+                external,
+            } = op
+            {
+                let full = util::join_relative(&target_for_add, path);
+                // External content is an object holding a zstd frame, read exactly
+                // like compressed content in the patch.
+                let object;
+                let (data, compressed) = if *external {
+                    object = open_external(objects_dir.as_deref(), path, blake3_hash)?;
+                    (&object[..], true)
+                } else {
+                    (&data[..], *compressed)
+                };
 
-                    if let Some(parent) = full.parent() {
-                        std::fs::create_dir_all(parent)?;
-                    }
+                if let Some(parent) = full.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
 
-                    std::fs::write(&full, data)
-                        .with_context(|| format!("Failed to write file: {}", full.display()))?;
+                // Compressed content is decompressed twice, once to check the hash and
+                // once into the file, so it is never whole in memory and a mismatch
+                // still leaves the target untouched.
+                let actual_hash = if compressed {
+                    let mut hasher = util::StreamHasher::new(hash_algo);
+                    std::io::copy(
+                        &mut patch_format::add_file_reader(data, true)?,
+                        &mut hasher,
+                    )
+                    .map_err(|e| PatchError::Decompress(format!("{}: {}", path, e)))?;
+                    hasher.finalize()
+                } else {
+                    util::hash_bytes(hash_algo, data)
+                };
+                if actual_hash != *blake3_hash {
+                    return mismatch(&skipped_for_add, path, blake3_hash, Some(actual_hash));
+                }
 
-                    let actual_hash = util::hash_bytes(data);
-                    if actual_hash != *blake3_hash {
-                        bail!("Hash mismatch for added file: {}", path);
+                let len = patch_format::add_file_len(data, compressed)?;
+                stage(&full, &|dest| {
+                    if compressed {
+                        fs.write_from(dest, len, &|| {
+                            patch_format::add_file_reader(data, true)
+                                .map_err(std::io::Error::other)
+                        })
+                    } else {
+                        fs.write(dest, data)
                     }
+                })
+                .with_context(|| format!("Failed to write file: {}", full.display()))?;
+
+                if paranoid {
+                    verify_written(&full, path, hash_algo, blake3_hash)?;
                 }
-                Ok(())
-            })
-        }),
-        tokio::task::spawn_blocking(move || -> Result<()> {
-            modify_files.par_iter().try_for_each(|op| -> Result<()> {
-                if let PatchOp::ModifyFile {
+                restore_xattrs(&full, xattrs, &xattrs_warned_for_add)?;
+                Done::add(&done_for_add.files_added, 1);
+                done_for_add.add_bytes(len);
+                log_for_add.record(path, format!("+ added {}", path));
+            }
+            Ok(())
+        })?;
+        Ok(started.elapsed())
+    };
+    let modify_task = move || -> Result<Duration> {
+        let started = Instant::now();
+        let stage = |full: &Path, write: &dyn Fn(&Path) -> std::io::Result<()>| {
+            match &staging {
+                Some(staging) => staging.replace(&fs, full, write),
+                None => write(full),
+            }
+        };
+        // A modify op's base is the target file at its own path, never another op's,
+        // and `validate_operations` rejects two modifies of one path however it is
+        // spelled, so there is nothing to share between ops: each mapping is opened
+        // here, used by this op alone and dropped before the file is written (see below).
+        modify_files.par_iter().try_for_each(|op| -> Result<()> {
+            if stopped(&interrupt_for_modify) {
+                return Ok(());
+            }
+            let mut xattrs: &[(String, Vec<u8>)] = &[];
+            let full = util::join_relative(&target_for_modify, op.path());
+            // Not there at all: the target drifted or isn't the tree the patch was made
+            // from. Only a diff that copies nothing can still produce the file.
+            let missing = std::fs::symlink_metadata(&full)
+                .is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound);
+            let (path, diff_chunks, new_blake3_hash, recompress) = match op {
+                PatchOp::ModifyFile {
                     path,
                     diff_chunks,
                     new_blake3_hash,
-                } = op
-                {
-                    let full = target_for_modify.join(path);
-
-                    // Scope the mmap so it is dropped before we write back to the same file.
-                    // On Windows, writing to a file with an open mapping is an error (os error 1224).
-                    let new_data = {
-                        let old_mmap = util::mmap_file(&full)?;
-                        binary_patch::apply_diff(&old_mmap, diff_chunks)
+                    recompress,
+                    xattrs: recorded,
+                    ..
+                } => {
+                    xattrs = recorded;
+                    (path, diff_chunks, new_blake3_hash, recompress.as_ref())
+                }
+                PatchOp::ModifyFileMulti {
+                    path,
+                    variants,
+                    new_blake3_hash,
+                } if missing => {
+                    let rebuildable = variants.iter().find(|v| chunk_counts(&v.diff_chunks).0 == 0);
+                    match rebuildable.or(variants.first()) {
+                        Some(variant) => (path, &variant.diff_chunks, new_blake3_hash, None),
+                        None => bail!(PatchError::MissingModifyTarget { path: path.clone() }),
+                    }
+                }
+                PatchOp::ModifyFileMulti {
+                    path,
+                    variants,
+                    new_blake3_hash,
+                } => {
+                    // Pick the diff made from the old version this target holds.
+                    let current = util::hash_file_streaming(hash_algo, &full)?;
+                    if current == *new_blake3_hash {
+                        log_for_modify
+                            .record(path, format!("= {} already up to date", path));
+                        Done::add(&done_for_modify.files_modified, 1);
+                        return Ok(());
+                    }
+                    let variant = match variants.iter().find(|v| v.base_hash == current) {
+                        Some(variant) => Some(variant),
+                        // None of the recorded bases: keep the first diff that turns
+                        // the file as it is into the new version.
+                        None if lenient_base => {
+                            let old_mmap = util::mmap_file(&full)?;
+                            variants.iter().find(|v| {
+                                binary_patch::copies_fit(old_mmap.len() as u64, &v.diff_chunks)
+                                    && binary_patch::hash_applied(hash_algo, &old_mmap, &v.diff_chunks)
+                                        == *new_blake3_hash
+                            })
+                        }
+                        None => None,
                     };
+                    match variant {
+                        Some(variant) => (path, &variant.diff_chunks, new_blake3_hash, None),
+                        None => return mismatch(&skipped_for_modify, path, new_blake3_hash, None),
+                    }
+                }
+                _ => return Ok(()),
+            };
+
+            if missing {
+                if !add_missing || chunk_counts(diff_chunks).0 > 0 {
+                    bail!(PatchError::MissingModifyTarget { path: path.clone() });
+                }
+                // Inserts and zero runs alone: the diff is the whole new content.
+                let mut new_data = binary_patch::apply_diff(&[], diff_chunks)
+                    .map_err(|e| PatchError::Corrupt(format!("invalid diff for {}: {:#}", path, e)))?;
+                if let Some(marker) = recompress {
+                    new_data = recompress::compress(marker, &new_data)?;
+                }
+                let actual_hash = util::hash_bytes(hash_algo, &new_data);
+                if actual_hash != *new_blake3_hash {
+                    return mismatch(&skipped_for_modify, path, new_blake3_hash, Some(actual_hash));
+                }
+                if let Some(parent) = full.parent() {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+                }
+                stage(&full, &|dest| fs.write(dest, &new_data))
+                    .with_context(|| format!("Failed to write file: {}", full.display()))?;
+                if paranoid {
+                    verify_written(&full, path, hash_algo, new_blake3_hash)?;
+                }
+                restore_xattrs(&full, xattrs, &xattrs_warned)?;
+                Done::add(&done_for_modify.files_modified, 1);
+                done_for_modify.add_bytes(new_data.len() as u64);
+                log_for_modify.record(path, format!("+ added {} (missing; rebuilt from its diff)", path));
+                return Ok(());
+            }
 
-                    let actual_hash = util::hash_bytes(&new_data);
-                    if actual_hash != *new_blake3_hash {
-                        bail!("Hash mismatch after patching file: {}", path);
+            // The diff of a recompressed file is against its decompressed content.
+            let in_place = match recompress {
+                Some(_) => false,
+                None => match patch_in_place(&full, diff_chunks, hash_algo, new_blake3_hash, force) {
+                    Ok(in_place) => in_place,
+                    Err(e) => match e.downcast_ref() {
+                        Some(PatchError::HashMismatch { actual, .. }) => {
+                            return mismatch(&skipped_for_modify, path, new_blake3_hash, *actual);
+                        }
+                        _ => {
+                            return Err(e.context(format!("Failed to patch file in place: {}", path)))
+                        }
+                    },
+                },
+            };
+
+            let mut new_len = binary_patch::output_len(diff_chunks);
+            if let Some(marker) = recompress {
+                let new_data = {
+                    let old_mmap = util::mmap_file(&full)?;
+                    // Not the expected format, so not the old version either.
+                    let Some(old_content) = recompress::decompress(marker, &old_mmap) else {
+                        return mismatch(&skipped_for_modify, path, new_blake3_hash, None);
+                    };
+                    let content = match binary_patch::apply_diff(&old_content, diff_chunks) {
+                        Ok(content) => content,
+                        Err(_) if lenient_base => {
+                            return mismatch(&skipped_for_modify, path, new_blake3_hash, None)
+                        }
+                        Err(e) => bail!(PatchError::Corrupt(format!(
+                            "invalid diff for {}: {:#}",
+                            path, e
+                        ))),
+                    };
+                    recompress::compress(marker, &content)?
+                };
+                let actual_hash = util::hash_bytes(hash_algo, &new_data);
+                if actual_hash != *new_blake3_hash {
+                    return mismatch(&skipped_for_modify, path, new_blake3_hash, Some(actual_hash));
+                }
+                new_len = new_data.len() as u64;
+                stage(&full, &|dest| fs.write(dest, &new_data)).with_context(|| {
+                    format!("Failed to write patched file: {}", full.display())
+                })?;
+            } else if !in_place {
+                // Scope the mmap so it is dropped before we write back to the same file.
+                // On Windows, writing to a file with an open mapping is an error (os error 1224).
+                let new_data = {
+                    let old_mmap = util::mmap_file(&full)?;
+                    match binary_patch::apply_diff(&old_mmap, diff_chunks) {
+                        Ok(new_data) => new_data,
+                        // Against a drifted base the diff can reach past the end of
+                        // the file: not the new version, and not a corrupt patch.
+                        Err(_) if lenient_base => {
+                            return mismatch(&skipped_for_modify, path, new_blake3_hash, None)
+                        }
+                        Err(e) => bail!(PatchError::Corrupt(format!(
+                            "invalid diff for {}: {:#}",
+                            path, e
+                        ))),
                     }
+                };
 
-                    std::fs::write(&full, &new_data).with_context(|| {
-                        format!("Failed to write patched file: {}", full.display())
-                    })?;
+                let actual_hash = util::hash_bytes(hash_algo, &new_data);
+                if actual_hash != *new_blake3_hash {
+                    return mismatch(&skipped_for_modify, path, new_blake3_hash, Some(actual_hash));
                 }
-                Ok(())
-            })
-        }),
-        tokio::task::spawn_blocking(move || -> Result<()> {
-            // Bulk-remove entire deleted subtrees in parallel across roots.
-            root_deleted_dirs.par_iter().try_for_each(|dir| -> Result<()> {
-                let full = target_for_delete.join(dir);
-                match std::fs::remove_dir_all(&full) {
+
+                let holes = binary_patch::zero_ranges(diff_chunks);
+                stage(&full, &|dest| {
+                    if holes.is_empty() {
+                        fs.write(dest, &new_data)
+                    } else {
+                        fs.write_sparse(dest, &new_data, &holes)
+                    }
+                })
+                .with_context(|| {
+                    format!("Failed to write patched file: {}", full.display())
+                })?;
+            }
+            if paranoid {
+                verify_written(&full, path, hash_algo, new_blake3_hash)?;
+            }
+            restore_xattrs(&full, xattrs, &xattrs_warned)?;
+            Done::add(&done_for_modify.files_modified, 1);
+            done_for_modify.add_bytes(new_len);
+
+            if log_for_modify.enabled() {
+                let (copies, inserts) = chunk_counts(diff_chunks);
+                log_for_modify.record(
+                    path,
+                    format!(
+                        "~ modified {} ({} copy, {} insert chunks{})",
+                        path,
+                        copies,
+                        inserts,
+                        if in_place {
+                            ", in place"
+                        } else if recompress.is_some() {
+                            ", recompressed"
+                        } else {
+                            ""
+                        }
+                    ),
+                );
+            }
+            Ok(())
+        })?;
+        Ok(started.elapsed())
+    };
+    let delete_task = move || -> Result<Duration> {
+        let started = Instant::now();
+        // Bulk-remove entire deleted subtrees in parallel across roots.
+        // With a quarantine, each root is moved aside instead of removed.
+        root_deleted_dirs.par_iter().try_for_each(|dir| -> Result<()> {
+            if stopped(&interrupt_for_delete) {
+                return Ok(());
+            }
+            let full = util::join_relative(&target_for_delete, dir);
+            if let Some(q) = &quarantine {
+                quarantine_path(&full, &util::join_relative(q, dir))?;
+            } else {
+                match fs.remove_dir_all(&full) {
                     Ok(()) => Ok(()),
                     Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
                     Err(e) => Err(anyhow::Error::from(e)).with_context(|| {
                         format!("Failed to remove directory tree: {}", full.display())
                     }),
+                }?;
+            }
+            if let Some(gone) = covered.get(dir) {
+                for (path, line) in &gone.lines {
+                    log_for_delete.record(path, line.clone());
                 }
-            })?;
-            // Delete orphan files (in kept directories) in parallel.
-            orphan_delete_files.par_iter().try_for_each(|op| -> Result<()> {
-                if let PatchOp::DeleteFile { path } = op {
-                    let full = target_for_delete.join(path);
-                    match std::fs::remove_file(&full) {
+                Done::add(&done_for_delete.files_deleted, gone.files);
+                Done::add(&done_for_delete.dirs_deleted, gone.dirs);
+            }
+            Ok(())
+        })?;
+        // Delete orphan files (in kept directories) in parallel.
+        orphan_delete_files.par_iter().try_for_each(|op| -> Result<()> {
+            if stopped(&interrupt_for_delete) {
+                return Ok(());
+            }
+            if let PatchOp::DeleteFile { path, .. } = op {
+                let full = util::join_relative(&target_for_delete, path);
+                if let Some(q) = &quarantine {
+                    quarantine_path(&full, &util::join_relative(q, path))?;
+                } else {
+                    match fs.remove_file(&full) {
                         Ok(()) => Ok(()),
                         Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
                         Err(e) => Err(anyhow::Error::from(e)).with_context(|| {
@@ -206,13 +2008,129 @@ pub async fn apply_patch(target_dir: &Path, patch_path: &Path) -> Result<ApplySu
                         }),
                     }?;
                 }
-                Ok(())
-            })
-        }),
-    )?;
-    r_add?;
-    r_modify?;
-    r_delete?;
+                log_for_delete.record(path, format!("- deleted {}", path));
+                Done::add(&done_for_delete.files_deleted, 1);
+            }
+            Ok(())
+        })?;
+        Ok(started.elapsed())
+    };
+    match options.order {
+        ApplyOrder::Default => {
+            let (r_add, r_modify, r_delete) = tokio::try_join!(
+                tokio::task::spawn_blocking(add_task),
+                tokio::task::spawn_blocking(modify_task),
+                tokio::task::spawn_blocking(delete_task),
+            )?;
+            timer.concurrent(&[("add", r_add?), ("modify", r_modify?), ("delete", r_delete?)]);
+        }
+        ApplyOrder::DeleteFirst => {
+            // Nothing is added or modified until every deletion is done.
+            tokio::task::spawn_blocking(delete_task).await??;
+            timer.mark("delete");
+            let (r_add, r_modify) = tokio::try_join!(
+                tokio::task::spawn_blocking(add_task),
+                tokio::task::spawn_blocking(modify_task),
+            )?;
+            timer.concurrent(&[("add", r_add?), ("modify", r_modify?)]);
+        }
+    }
+
+    // 5. Hard links, once every target has its final content. Anything already at the
+    // link path (e.g. from an earlier partial apply) is replaced.
+    hardlinks
+        .par_iter()
+        .try_for_each(|(path, link_target)| -> Result<()> {
+            if stopped(&interrupt) {
+                return Ok(());
+            }
+            let full = util::join_relative(&target, path);
+            let original = util::join_relative(&target, link_target);
+            match fs.remove_file(&full) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(anyhow::Error::from(e))
+                        .with_context(|| format!("Failed to replace file: {}", full.display()))
+                }
+            }
+            std::fs::hard_link(&original, &full).with_context(|| {
+                format!("Failed to link {} to {}", full.display(), original.display())
+            })?;
+            log.record(path, format!("+ linked {} => {}", path, link_target));
+            Done::add(&done.hardlinks_created, 1);
+            Ok(())
+        })?;
+    timer.mark("hard links");
+
+    // 5b. Symbolic links, also last since they may point at anything the patch writes.
+    // A file or link already at the path is replaced; a directory is an error.
+    symlinks
+        .par_iter()
+        .try_for_each(|(path, link_target)| -> Result<()> {
+            if stopped(&interrupt) {
+                return Ok(());
+            }
+            let full = util::join_relative(&target, path);
+            match std::fs::symlink_metadata(&full) {
+                Ok(meta) if meta.is_dir() => {
+                    bail!("Cannot replace a directory with a symbolic link: {}", full.display())
+                }
+                Ok(_) => fs
+                    .remove_file(&full)
+                    .with_context(|| format!("Failed to replace file: {}", full.display()))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(anyhow::Error::from(e))
+                        .with_context(|| format!("Failed to read metadata: {}", full.display()))
+                }
+            }
+            util::create_symlink(link_target, &full).with_context(|| {
+                format!("Failed to create symbolic link {} -> {}", full.display(), link_target)
+            })?;
+            log.record(path, format!("+ symlink {} -> {}", path, link_target));
+            Done::add(&done.symlinks_created, 1);
+            Ok(())
+        })?;
+    timer.mark("symbolic links");
+
+    // 6. Metadata-only changes, last so no later write moves the mtime again.
+    set_metadata.par_iter().try_for_each(|op| -> Result<()> {
+        if stopped(&interrupt) {
+            return Ok(());
+        }
+        if let PatchOp::SetMetadata { path, mode, mtime } = op {
+            util::set_metadata(&util::join_relative(&target, path), *mode, *mtime)?;
+            log.record(path, format!("* metadata {}", path));
+            Done::add(&done.metadata_updated, 1);
+        }
+        Ok(())
+    })?;
+    timer.mark("metadata");
+
+    // 7. Directory modes that withhold something from the owner, deepest first, now
+    // that nothing more is written inside them.
+    for (full, mode) in dir_modes.iter().rev() {
+        util::set_metadata(full, Some(*mode), None)?;
+    }
+
+    log.flush();
+
+    let skipped_adds = std::mem::take(&mut *skipped_adds.lock().unwrap());
+    let skipped_modifies = std::mem::take(&mut *skipped_modifies.lock().unwrap());
+    let num_add_files = num_add_files - skipped_adds.len();
+    let num_modify_files = num_modify_files - skipped_modifies.len();
+    let mut skipped_mismatches = [skipped_adds, skipped_modifies].concat();
+    skipped_mismatches.sort();
+
+    if stopped(&interrupt) {
+        bail!(PatchError::Interrupted {
+            completed: ApplySummary {
+                deletes_declined,
+                ..done.summary(skipped_mismatches)
+            },
+        });
+    }
 
     let summary = ApplySummary {
         dirs_created: num_create_dirs,
@@ -220,7 +2138,903 @@ pub async fn apply_patch(target_dir: &Path, patch_path: &Path) -> Result<ApplySu
         files_modified: num_modify_files,
         files_deleted: num_delete_files,
         dirs_deleted: num_delete_dirs,
+        hardlinks_created: hardlinks.len(),
+        symlinks_created: symlinks.len(),
+        metadata_updated: set_metadata.len(),
+        skipped_mismatches,
+        deletes_declined,
+        bytes_processed: done.bytes_processed.load(Ordering::Relaxed),
+        timings: timer.into_phases(),
+        largest_ops: Vec::new(),
+        touched: Vec::new(),
     };
 
     Ok(summary)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails every call with `kind` until `failures` is used up, counting attempts.
+    struct FlakyFs {
+        failures: AtomicU32,
+        kind: std::io::ErrorKind,
+        attempts: AtomicU32,
+    }
+
+    impl FlakyFs {
+        fn new(failures: u32, kind: std::io::ErrorKind) -> Self {
+            Self {
+                failures: AtomicU32::new(failures),
+                kind,
+                attempts: AtomicU32::new(0),
+            }
+        }
+
+        fn call(&self) -> std::io::Result<()> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            let left = self.failures.load(Ordering::SeqCst);
+            if left > 0 {
+                self.failures.store(left - 1, Ordering::SeqCst);
+                return Err(std::io::Error::from(self.kind));
+            }
+            Ok(())
+        }
+    }
+
+    impl Fs for &FlakyFs {
+        fn write(&self, _: &Path, _: &[u8]) -> std::io::Result<()> {
+            self.call()
+        }
+
+        fn write_sparse(&self, _: &Path, _: &[u8], _: &[(u64, u64)]) -> std::io::Result<()> {
+            self.call()
+        }
+
+        fn write_from(&self, _: &Path, _: u64, _: &OpenReader<'_>) -> std::io::Result<()> {
+            self.call()
+        }
+
+        fn remove_file(&self, _: &Path) -> std::io::Result<()> {
+            self.call()
+        }
+
+        fn remove_dir_all(&self, _: &Path) -> std::io::Result<()> {
+            self.call()
+        }
+
+        fn rename(&self, _: &Path, _: &Path) -> std::io::Result<()> {
+            self.call()
+        }
+    }
+
+    #[test]
+    fn test_staged_replace_keeps_original_until_written() {
+        let temp = std::env::temp_dir().join("patcher_unit_staging");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let file = temp.join("a.txt");
+        std::fs::write(&file, b"old content").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let fs = RetryingFs {
+            inner: RealFs,
+            retries: 0,
+        };
+        let staging = Staging::new(temp.join("stage")).unwrap();
+        let staged_files = || std::fs::read_dir(temp.join("stage")).unwrap().count();
+
+        // A write that dies halfway leaves the original untouched and nothing staged.
+        let failed = staging.replace(&fs, &file, |dest| {
+            std::fs::write(dest, b"new c")?;
+            Err(std::io::ErrorKind::WriteZero.into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read(&file).unwrap(), b"old content");
+        assert_eq!(staged_files(), 0);
+
+        staging
+            .replace(&fs, &file, |dest| fs.write(dest, b"new content, longer"))
+            .unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"new content, longer");
+        assert_eq!(staged_files(), 0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+
+        // Replacing a hard-linked file would split the link, so it is written in place.
+        #[cfg(unix)]
+        {
+            let link = temp.join("link.txt");
+            std::fs::hard_link(&file, &link).unwrap();
+            staging
+                .replace(&fs, &file, |dest| fs.write(dest, b"via both names"))
+                .unwrap();
+            assert_eq!(std::fs::read(&link).unwrap(), b"via both names");
+        }
+
+        // A new file appears whole or not at all.
+        let added = temp.join("added.txt");
+        let failed = staging.replace(&fs, &added, |dest| {
+            std::fs::write(dest, b"trunc")?;
+            Err(std::io::ErrorKind::WriteZero.into())
+        });
+        assert!(failed.is_err());
+        assert!(!added.exists());
+        assert_eq!(staged_files(), 0);
+        staging
+            .replace(&fs, &added, |dest| fs.write(dest, b"added content"))
+            .unwrap();
+        assert_eq!(std::fs::read(&added).unwrap(), b"added content");
+
+        drop(staging);
+        assert!(!temp.join("stage").exists());
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_conflicting_operation_types_are_rejected() {
+        let ops = vec![
+            PatchOp::CreateDir {
+                path: "foo".into(),
+                mode: None,
+            },
+            PatchOp::AddFile {
+                path: "foo/inner.txt".into(),
+                data: b"ok".to_vec(),
+                blake3_hash: [0; 32],
+                compressed: false,
+                xattrs: Vec::new(),
+                external: false,
+            },
+        ];
+        validate_operations(&ops).unwrap();
+
+        let ops = vec![
+            PatchOp::CreateDir {
+                path: "foo".into(),
+                mode: None,
+            },
+            PatchOp::AddFile {
+                path: "foo".into(),
+                data: Vec::new(),
+                blake3_hash: [0; 32],
+                compressed: false,
+                xattrs: Vec::new(),
+                external: false,
+            },
+        ];
+        let err = validate_operations(&ops).unwrap_err().to_string();
+        assert!(err.contains("foo: CreateDir and AddFile"), "{}", err);
+
+        let ops = vec![
+            PatchOp::ModifyFile {
+                path: "a.bin".into(),
+                diff_chunks: Vec::new(),
+                new_blake3_hash: [0; 32],
+                block_size: 0,
+                recompress: None,
+                xattrs: Vec::new(),
+                old_blake3_hash: None,
+            },
+            PatchOp::DeleteFile {
+                path: "a.bin".into(),
+                old_blake3_hash: None,
+                may_be_absent: false,
+            },
+        ];
+        assert!(validate_operations(&ops).is_err());
+
+        let ops = vec![
+            PatchOp::CreateDir { path: "a//b".into(), mode: None },
+            PatchOp::DeleteFile { path: "a/b/".into(), old_blake3_hash: None, may_be_absent: false },
+        ];
+        assert!(validate_operations(&ops).is_err());
+        let ops = vec![PatchOp::DeleteFile {
+            path: "a/../../escape".into(),
+            old_blake3_hash: None,
+            may_be_absent: false,
+        }];
+        let err = validate_operations(&ops).unwrap_err().to_string();
+        assert!(err.contains("'..'"), "{}", err);
+    }
+
+    #[test]
+    fn test_duplicate_paths_are_rejected() {
+        let add = |path: &str, data: &[u8]| PatchOp::AddFile {
+            path: path.into(),
+            data: data.to_vec(),
+            blake3_hash: util::hash_bytes(util::HashAlgo::Blake3, data),
+            compressed: false,
+            xattrs: Vec::new(),
+            external: false,
+        };
+        let modify = |path: &str| PatchOp::ModifyFile {
+            path: path.into(),
+            diff_chunks: Vec::new(),
+            new_blake3_hash: [0; 32],
+            block_size: 0,
+            recompress: None,
+            xattrs: Vec::new(),
+            old_blake3_hash: None,
+        };
+        for (ops, expected) in [
+            (vec![add("a.txt", b"one"), add("b.txt", b"x"), add("a.txt", b"two")], "duplicate AddFile operations for a.txt"),
+            (vec![modify("m.bin"), modify("./m.bin")], "duplicate ModifyFile operations for ./m.bin"),
+            (vec![add("a.txt", b"one"), modify("a.txt")], "conflicting operations for a.txt: AddFile and ModifyFile"),
+            (
+                vec![PatchOp::DeleteDir { path: "d".into() }, PatchOp::DeleteDir { path: "d/".into() }],
+                "duplicate DeleteDir operations for d/",
+            ),
+        ] {
+            let err = validate_operations(&ops).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}", err);
+        }
+
+        // Caught before anything is written, through the public entry point too.
+        let temp = std::env::temp_dir().join("patcher_unit_duplicates");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(temp.join("target")).unwrap();
+        let manifest = PatchManifest {
+            version: patch_format::FORMAT_VERSION,
+            hash_algo: util::HashAlgo::Blake3,
+            full_file_set: false,
+            operations: vec![add("same.txt", b"first"), add("same.txt", b"second")],
+        };
+        let patch = temp.join("dup.patch");
+        crate::create::write_manifest(&patch, &manifest, false).unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let err = rt
+            .block_on(apply_patch(&temp.join("target"), &patch, &ApplyOptions::default()))
+            .unwrap_err();
+        assert!(matches!(err, PatchError::Corrupt(_)), "{}", err);
+        assert!(!temp.join("target/same.txt").exists());
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_modify_ops_never_share_a_base() {
+        // The modify phase maps each op's base afresh, which is only sound while no two
+        // ops read the same file.
+        let modify = |path: &str| PatchOp::ModifyFile {
+            path: path.into(),
+            diff_chunks: Vec::new(),
+            new_blake3_hash: [0; 32],
+            block_size: 0,
+            recompress: None,
+            xattrs: Vec::new(),
+            old_blake3_hash: None,
+        };
+        let multi = |path: &str| PatchOp::ModifyFileMulti {
+            path: path.into(),
+            variants: Vec::new(),
+            new_blake3_hash: [0; 32],
+        };
+        for ops in [
+            vec![modify("lib/core.so"), modify("lib//core.so")],
+            vec![modify("lib/core.so"), multi("./lib/core.so")],
+            vec![multi("lib/core.so"), multi("lib/core.so")],
+        ] {
+            assert!(validate_operations(&ops).is_err(), "{:?}", ops);
+        }
+        validate_operations(&[modify("lib/core.so"), multi("lib/core2.so")]).unwrap();
+    }
+
+    #[test]
+    fn test_nothing_is_written_through_a_symlink() {
+        let symlink = |path: &str, target: &str| PatchOp::CreateSymlink {
+            path: path.into(),
+            target: target.into(),
+        };
+        let dir = |path: &str| PatchOp::CreateDir {
+            path: path.into(),
+            mode: None,
+        };
+        let hardlink = |path: &str, target: &str| PatchOp::CreateHardlink {
+            path: path.into(),
+            target: target.into(),
+        };
+        for ops in [
+            vec![symlink("etc", "/etc"), dir("etc/cron.d")],
+            vec![symlink("lib", "lib64"), hardlink("lib/a.so", "b.so")],
+            vec![symlink("lib", "lib64"), hardlink("a.so", "lib/b.so")],
+        ] {
+            let err = validate_operations(&ops).unwrap_err();
+            assert!(err.to_string().contains("beneath symbolic link"), "{}", err);
+        }
+        validate_operations(&[symlink("lib", "lib64"), dir("lib64"), dir("libexec")]).unwrap();
+    }
+
+    #[test]
+    fn test_transient_errors_are_retried() {
+        let flaky = FlakyFs::new(2, std::io::ErrorKind::WouldBlock);
+        let fs = RetryingFs {
+            inner: &flaky,
+            retries: 3,
+        };
+        fs.write(Path::new("x"), b"data").unwrap();
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_retries_are_bounded() {
+        let flaky = FlakyFs::new(10, std::io::ErrorKind::Interrupted);
+        let fs = RetryingFs {
+            inner: &flaky,
+            retries: 2,
+        };
+        assert!(fs.remove_file(Path::new("x")).is_err());
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_permanent_errors_are_not_retried() {
+        let flaky = FlakyFs::new(1, std::io::ErrorKind::NotFound);
+        let fs = RetryingFs {
+            inner: &flaky,
+            retries: 5,
+        };
+        let err = fs.remove_dir_all(Path::new("x")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_apply_errors_are_typed() {
+        let temp = std::env::temp_dir().join("patcher_unit_typed_errors");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(temp.join("target")).unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let apply = |target: &Path, patch: &Path| {
+            rt.block_on(apply_patch(target, patch, &ApplyOptions::default()))
+                .unwrap_err()
+        };
+
+        let junk = temp.join("junk.patch");
+        std::fs::write(&junk, b"definitely not a patch").unwrap();
+        assert!(matches!(
+            apply(&temp.join("target"), &junk),
+            PatchError::InvalidMagic
+        ));
+
+        let patch = temp.join("bad_hash.patch");
+        let manifest = PatchManifest {
+            version: patch_format::FORMAT_VERSION,
+            hash_algo: util::HashAlgo::Blake3,
+            full_file_set: false,
+            operations: vec![PatchOp::AddFile {
+                path: "a.txt".into(),
+                data: b"content".to_vec(),
+                blake3_hash: [0; 32],
+                compressed: false,
+                xattrs: Vec::new(),
+                external: false,
+            }],
+        };
+        crate::create::write_manifest(&patch, &manifest, false).unwrap();
+        assert!(matches!(
+            apply(&temp.join("missing"), &patch),
+            PatchError::Io { context, source }
+                if source.kind() == std::io::ErrorKind::NotFound
+                    && context.starts_with("target does not exist: ")
+        ));
+        let err = apply(&temp.join("target"), &patch);
+        let actual = util::hash_bytes(util::HashAlgo::Blake3, b"content");
+        assert!(matches!(
+            &err,
+            PatchError::HashMismatch { path, expected, actual: Some(got) }
+                if path == "a.txt" && *expected == [0; 32] && *got == actual
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Hash mismatch for a.txt: expected 0000000000000000..., got {}...",
+                &util::hash_hex(&actual)[..16]
+            )
+        );
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_interrupted_apply_starts_no_operations() {
+        let temp = std::env::temp_dir().join("patcher_unit_interrupted");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(temp.join("target/old")).unwrap();
+        std::fs::write(temp.join("target/old/gone.txt"), b"old").unwrap();
+
+        let algo = util::HashAlgo::Blake3;
+        let patch = temp.join("p.patch");
+        let manifest = PatchManifest {
+            version: patch_format::FORMAT_VERSION,
+            hash_algo: algo,
+            full_file_set: false,
+            operations: vec![
+                PatchOp::CreateDir { path: "new".into(), mode: None },
+                PatchOp::AddFile {
+                    path: "new/a.txt".into(),
+                    data: b"content".to_vec(),
+                    blake3_hash: util::hash_bytes(algo, b"content"),
+                    compressed: false,
+                    xattrs: Vec::new(),
+                    external: false,
+                },
+                PatchOp::DeleteFile {
+                    path: "old/gone.txt".into(),
+                    old_blake3_hash: None,
+                    may_be_absent: false,
+                },
+                PatchOp::DeleteDir { path: "old".into() },
+            ],
+        };
+        crate::create::write_manifest(&patch, &manifest, false).unwrap();
+
+        let options = ApplyOptions {
+            interrupt: Some(Arc::new(AtomicBool::new(true))),
+            ..ApplyOptions::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let err = rt
+            .block_on(apply_patch(&temp.join("target"), &patch, &options))
+            .unwrap_err();
+        match err {
+            PatchError::Interrupted { completed } => {
+                assert_eq!(completed.dirs_created, 0);
+                assert_eq!(completed.files_added, 0);
+                assert_eq!(completed.files_deleted, 0);
+                assert_eq!(completed.dirs_deleted, 0);
+            }
+            other => panic!("expected Interrupted, got {:?}", other),
+        }
+        assert!(!temp.join("target/new").exists());
+        assert!(temp.join("target/old/gone.txt").exists());
+
+        // Without the flag the same patch applies, and the counts match.
+        let summary = rt
+            .block_on(apply_patch(&temp.join("target"), &patch, &ApplyOptions::default()))
+            .unwrap();
+        assert_eq!((summary.files_added, summary.files_deleted), (1, 1));
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_declined_deletes_are_kept() {
+        let temp = std::env::temp_dir().join("patcher_unit_declined_deletes");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(temp.join("target/old")).unwrap();
+        std::fs::write(temp.join("target/old/gone.txt"), b"old").unwrap();
+
+        let algo = util::HashAlgo::Blake3;
+        let patch = temp.join("p.patch");
+        let manifest = PatchManifest {
+            version: patch_format::FORMAT_VERSION,
+            hash_algo: algo,
+            full_file_set: false,
+            operations: vec![
+                PatchOp::AddFile {
+                    path: "a.txt".into(),
+                    data: b"content".to_vec(),
+                    blake3_hash: util::hash_bytes(algo, b"content"),
+                    compressed: false,
+                    xattrs: Vec::new(),
+                    external: false,
+                },
+                PatchOp::DeleteFile {
+                    path: "old/gone.txt".into(),
+                    old_blake3_hash: None,
+                    may_be_absent: false,
+                },
+                PatchOp::DeleteDir { path: "old".into() },
+            ],
+        };
+        crate::create::write_manifest(&patch, &manifest, false).unwrap();
+
+        let asked = Arc::new(std::sync::Mutex::new(None));
+        let asked_in_callback = Arc::clone(&asked);
+        let options = ApplyOptions {
+            confirm_deletes: Some(ConfirmDeletes::new(move |files, dirs| {
+                *asked_in_callback.lock().unwrap() = Some((files.to_vec(), dirs.to_vec()));
+                false
+            })),
+            ..ApplyOptions::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let summary = rt
+            .block_on(apply_patch(&temp.join("target"), &patch, &options))
+            .unwrap();
+        assert_eq!(
+            asked.lock().unwrap().take(),
+            Some((vec!["old/gone.txt".to_string()], vec!["old".to_string()]))
+        );
+        assert_eq!((summary.files_added, summary.files_deleted, summary.dirs_deleted), (1, 0, 0));
+        assert_eq!(summary.deletes_declined, 2);
+        assert!(temp.join("target/a.txt").exists());
+        assert!(temp.join("target/old/gone.txt").exists());
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_apply_from_in_memory_patch() {
+        let temp = std::env::temp_dir().join("patcher_unit_bytes");
+        let _ = std::fs::remove_dir_all(&temp);
+        for (dir, content) in [("old", &b"first version"[..]), ("new", b"second version")] {
+            std::fs::create_dir_all(temp.join(dir).join("sub")).unwrap();
+            std::fs::write(temp.join(dir).join("sub/file.txt"), content).unwrap();
+        }
+        std::fs::write(temp.join("new/added.bin"), [5u8; 3000]).unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        for gzip in [false, true] {
+            let options = crate::create::CreateOptions {
+                gzip,
+                ..Default::default()
+            };
+            let (bytes, created) = rt
+                .block_on(crate::create::create_patch_bytes(
+                    &temp.join("old"),
+                    &temp.join("new"),
+                    &options,
+                ))
+                .unwrap();
+            assert_eq!(bytes.starts_with(&GZIP_MAGIC), gzip);
+
+            let target = temp.join(format!("target_{}", gzip));
+            std::fs::create_dir_all(target.join("sub")).unwrap();
+            std::fs::write(target.join("sub/file.txt"), b"first version").unwrap();
+            let applied = rt
+                .block_on(apply_patch_bytes(&target, &bytes, &ApplyOptions::default()))
+                .unwrap();
+            assert_eq!(
+                (applied.files_added, applied.files_modified),
+                (created.files_added, created.files_modified)
+            );
+            // The whole new tree on create; only what was written on apply.
+            assert_eq!(created.bytes_processed, 14 + 3000);
+            assert_eq!(applied.bytes_processed, 14 + 3000);
+            assert_eq!(std::fs::read(target.join("sub/file.txt")).unwrap(), b"second version");
+            assert_eq!(std::fs::read(target.join("added.bin")).unwrap(), [5u8; 3000]);
+        }
+
+        let err = rt
+            .block_on(apply_patch_bytes(&temp.join("old"), b"junk", &ApplyOptions::default()))
+            .unwrap_err();
+        assert!(matches!(err, PatchError::InvalidMagic));
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_verify_written_reports_post_write_mismatch() {
+        let temp = std::env::temp_dir().join("patcher_unit_verify_written");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let file = temp.join("a.txt");
+        std::fs::write(&file, b"as written").unwrap();
+        let algo = util::HashAlgo::Blake3;
+
+        verify_written(&file, "a.txt", algo, &util::hash_bytes(algo, b"as written")).unwrap();
+        let err = verify_written(&file, "a.txt", algo, &util::hash_bytes(algo, b"intended"))
+            .unwrap_err();
+        assert!(matches!(
+            PatchError::from(err),
+            PatchError::WriteVerifyFailed { path } if path == "a.txt"
+        ));
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_write_sparse_matches_plain_write() {
+        let temp = std::env::temp_dir().join("patcher_unit_write_sparse");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let mut data = vec![0u8; 3 << 20];
+        data[..5].copy_from_slice(b"start");
+        data[2 << 20..(2 << 20) + 3].copy_from_slice(b"mid");
+        let holes = [(5, (2 << 20) - 5), ((2 << 20) + 3, (1 << 20) - 3)];
+
+        let file = temp.join("sparse.bin");
+        RealFs.write_sparse(&file, &data, &holes).unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), data);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // Allocated blocks (512 bytes each) well under the logical size.
+            let meta = std::fs::metadata(&file).unwrap();
+            assert!(meta.blocks() * 512 < meta.len() / 2, "{} blocks", meta.blocks());
+        }
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_only_keeps_operations_under_whole_component_prefixes() {
+        let mut operations = vec![
+            PatchOp::DeleteDir { path: "assets".into() },
+            PatchOp::DeleteDir { path: "assets/old".into() },
+            PatchOp::DeleteFile { path: "assets/old/a.png".into(), old_blake3_hash: None, may_be_absent: false },
+            PatchOp::CreateDir { path: "assets2".into(), mode: None },
+            PatchOp::CreateDir { path: "bin".into(), mode: None },
+            PatchOp::CreateHardlink { path: "assets/new/link".into(), target: "assets/new/b".into() },
+            PatchOp::CreateHardlink { path: "assets/new/out".into(), target: "bin/tool".into() },
+            PatchOp::DeleteDir { path: "lib".into() },
+            PatchOp::DeleteFile { path: "lib/x.so".into(), old_blake3_hash: None, may_be_absent: false },
+        ];
+        retain_under(&mut operations, &["assets/".into(), "lib\\x.so".into()]).unwrap();
+        let kept: Vec<&str> = operations.iter().map(PatchOp::path).collect();
+        // "lib" itself isn't under "lib/x.so", so it isn't removed wholesale.
+        assert_eq!(
+            kept,
+            ["assets", "assets/old", "assets/old/a.png", "assets/new/link", "lib/x.so"]
+        );
+        assert!(retain_under(&mut operations, &["../etc".into()]).is_err());
+    }
+
+    #[test]
+    fn test_lenient_base_patches_drifted_files_whose_result_checks_out() {
+        let temp = std::env::temp_dir().join("patcher_unit_lenient_base");
+        let _ = std::fs::remove_dir_all(&temp);
+        let algo = util::HashAlgo::Blake3;
+        let hash = |data: &[u8]| util::hash_bytes(algo, data);
+        let target = temp.join("target");
+        let reset = || {
+            std::fs::create_dir_all(&target).unwrap();
+            // Neither is a version the patch was made from.
+            std::fs::write(target.join("multi.txt"), b"hello, drifted").unwrap();
+            std::fs::write(target.join("short.txt"), b"short").unwrap();
+        };
+        let copy = |offset, length| crate::patch_format::DiffChunk::Copy { offset, length };
+        let insert = |data: &[u8]| crate::patch_format::DiffChunk::Insert { data: data.to_vec() };
+        let multi = PatchOp::ModifyFileMulti {
+            path: "multi.txt".into(),
+            // The first diff reaches past the end of the file, and only the second
+            // makes the new version out of it.
+            variants: [
+                vec![copy(0, 100)],
+                vec![copy(0, 7), insert(b"there")],
+                vec![copy(0, 5), insert(b"!")],
+            ]
+            .into_iter()
+                .enumerate()
+                .map(|(base, diff_chunks)| crate::patch_format::BaseDiff {
+                    base: base as u32,
+                    base_hash: hash(format!("version {}", base).as_bytes()),
+                    diff_chunks,
+                    block_size: 0,
+                })
+                .collect(),
+            new_blake3_hash: hash(b"hello, there"),
+        };
+        let patch = |operations| {
+            let path = temp.join("p.patch");
+            let manifest = PatchManifest {
+                version: patch_format::FORMAT_VERSION,
+                hash_algo: algo,
+                full_file_set: false,
+                operations,
+            };
+            crate::create::write_manifest(&path, &manifest, false).unwrap();
+            path
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let strict = ApplyOptions::default();
+        let lenient = ApplyOptions {
+            lenient_base: true,
+            ..ApplyOptions::default()
+        };
+
+        reset();
+        let p = patch(vec![multi]);
+        let err = rt.block_on(apply_patch(&target, &p, &strict)).unwrap_err();
+        assert!(matches!(err, PatchError::HashMismatch { .. }), "{}", err);
+        rt.block_on(apply_patch(&target, &p, &lenient)).unwrap();
+        assert_eq!(std::fs::read(target.join("multi.txt")).unwrap(), b"hello, there");
+
+        // A diff that doesn't fit is a corrupt patch normally, a mismatch when lenient.
+        reset();
+        let p = patch(vec![PatchOp::ModifyFile {
+            path: "short.txt".into(),
+            diff_chunks: vec![copy(0, 50)],
+            new_blake3_hash: [0; 32],
+            block_size: 0,
+            recompress: None,
+            xattrs: Vec::new(),
+            old_blake3_hash: None,
+        }]);
+        let err = rt.block_on(apply_patch(&target, &p, &strict)).unwrap_err();
+        assert!(matches!(err, PatchError::Corrupt(_)), "{}", err);
+        let err = rt.block_on(apply_patch(&target, &p, &lenient)).unwrap_err();
+        assert!(matches!(err, PatchError::HashMismatch { .. }), "{}", err);
+        let skip = ApplyOptions {
+            skip_mismatches: true,
+            ..lenient.clone()
+        };
+        let summary = rt.block_on(apply_patch(&target, &p, &skip)).unwrap();
+        assert_eq!(summary.skipped_mismatches, ["short.txt"]);
+        assert_eq!(std::fs::read(target.join("short.txt")).unwrap(), b"short");
+
+        let verify = ApplyOptions {
+            verify_before: true,
+            ..lenient.clone()
+        };
+        assert!(rt.block_on(apply_patch(&target, &p, &verify)).is_err());
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_missing_modify_target_fails_clearly_unless_its_diff_rebuilds_it() {
+        let temp = std::env::temp_dir().join("patcher_unit_missing_modify");
+        let _ = std::fs::remove_dir_all(&temp);
+        let algo = util::HashAlgo::Blake3;
+        let target = temp.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        let modify = |path: &str, diff_chunks| PatchOp::ModifyFile {
+            path: path.into(),
+            diff_chunks,
+            new_blake3_hash: util::hash_bytes(algo, b"fresh\0\0\0"),
+            block_size: 0,
+            recompress: None,
+            xattrs: Vec::new(),
+            old_blake3_hash: None,
+        };
+        let whole = vec![
+            crate::patch_format::DiffChunk::Insert { data: b"fresh".to_vec() },
+            crate::patch_format::DiffChunk::Zeros { length: 3 },
+        ];
+        let partial = vec![crate::patch_format::DiffChunk::Copy { offset: 0, length: 8 }];
+        let patch = |op| {
+            let path = temp.join("p.patch");
+            let manifest = PatchManifest {
+                version: patch_format::FORMAT_VERSION,
+                hash_algo: algo,
+                full_file_set: false,
+                operations: vec![op],
+            };
+            crate::create::write_manifest(&path, &manifest, false).unwrap();
+            path
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let add_missing = ApplyOptions {
+            add_missing: true,
+            ..ApplyOptions::default()
+        };
+
+        let p = patch(modify("sub/gone.bin", whole));
+        let err = rt.block_on(apply_patch(&target, &p, &ApplyOptions::default())).unwrap_err();
+        assert!(matches!(&err, PatchError::MissingModifyTarget { path } if path == "sub/gone.bin"), "{:?}", err);
+        assert_eq!(
+            err.to_string(),
+            "Cannot modify missing file: sub/gone.bin (the target may not match the patch's base version)"
+        );
+        assert!(!target.join("sub").exists());
+
+        let summary = rt.block_on(apply_patch(&target, &p, &add_missing)).unwrap();
+        assert_eq!(summary.files_modified, 1);
+        assert_eq!(std::fs::read(target.join("sub/gone.bin")).unwrap(), b"fresh\0\0\0");
+
+        // A diff that copies from the old file can't produce it.
+        let p = patch(modify("other.bin", partial));
+        let err = rt.block_on(apply_patch(&target, &p, &add_missing)).unwrap_err();
+        assert!(matches!(&err, PatchError::MissingModifyTarget { path } if path == "other.bin"), "{:?}", err);
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_verify_before_lists_files_not_in_their_pre_patch_state() {
+        let temp = std::env::temp_dir().join("patcher_unit_verify_before");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let algo = util::HashAlgo::Blake3;
+        let hash = |data: &[u8]| util::hash_bytes(algo, data);
+        std::fs::write(temp.join("same.txt"), b"old").unwrap();
+        std::fs::write(temp.join("edited.txt"), b"edited locally").unwrap();
+        std::fs::write(temp.join("multi.txt"), b"version two").unwrap();
+        std::fs::write(temp.join("gone.txt"), b"old").unwrap();
+        let modify = |path: &str, old: &[u8]| PatchOp::ModifyFile {
+            path: path.into(),
+            diff_chunks: Vec::new(),
+            new_blake3_hash: [0; 32],
+            block_size: 0,
+            recompress: None,
+            xattrs: Vec::new(),
+            old_blake3_hash: Some(hash(old)),
+        };
+        let mut operations = vec![
+            modify("same.txt", b"old"),
+            modify("edited.txt", b"old"),
+            modify("missing.txt", b"old"),
+            // Any of a multi-base diff's old versions will do.
+            PatchOp::ModifyFileMulti {
+                path: "multi.txt".into(),
+                variants: [&b"version one"[..], b"version two"]
+                    .iter()
+                    .enumerate()
+                    .map(|(base, old)| crate::patch_format::BaseDiff {
+                        base: base as u32,
+                        base_hash: hash(old),
+                        diff_chunks: Vec::new(),
+                        block_size: 0,
+                    })
+                    .collect(),
+                new_blake3_hash: [0; 32],
+            },
+            PatchOp::DeleteFile { path: "gone.txt".into(), old_blake3_hash: Some(hash(b"old")), may_be_absent: false },
+            PatchOp::CreateDir { path: "new".into(), mode: None },
+        ];
+        assert_eq!(drifted_files(&temp, &operations, algo).unwrap(), ["edited.txt", "missing.txt"]);
+
+        // Without a recorded old hash there is nothing to check against.
+        operations.push(PatchOp::DeleteFile { path: "other.txt".into(), old_blake3_hash: None, may_be_absent: false });
+        let err = drifted_files(&temp, &operations, algo).unwrap_err();
+        assert!(format!("{:#}", err).contains("other.txt"), "{:#}", err);
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_force_makes_read_only_files_writable_and_restores_them() {
+        let temp = std::env::temp_dir().join("patcher_unit_force");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let file = temp.join("locked.txt");
+        let read_only = |path: &Path| std::fs::metadata(path).unwrap().permissions().readonly();
+        let lock = |path: &Path| {
+            let mut perms = std::fs::metadata(path).unwrap().permissions();
+            perms.set_readonly(true);
+            std::fs::set_permissions(path, perms).unwrap();
+        };
+        // Refuses read-only files the way Windows does, even for a privileged user.
+        let denied = || std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let write = |path: &Path| {
+            if read_only(path) {
+                return Err(denied());
+            }
+            std::fs::write(path, b"new")
+        };
+
+        std::fs::write(&file, b"old").unwrap();
+        lock(&file);
+        forcing_writable(&file, true, || write(&file)).unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"new");
+        assert!(read_only(&file));
+
+        // A second failure leaves the file read-only even when not restoring.
+        let err = forcing_writable(&file, false, || Err::<(), _>(denied())).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(read_only(&file));
+
+        forcing_writable(&file, false, || {
+            if read_only(&file) {
+                return Err(denied());
+            }
+            std::fs::remove_file(&file)
+        })
+        .unwrap();
+        assert!(!file.exists());
+
+        // Denied for another reason than the read-only flag: passed through untouched.
+        std::fs::write(&file, b"old").unwrap();
+        let mut tries = 0;
+        let err = forcing_writable(&file, true, || {
+            tries += 1;
+            Err::<(), _>(denied())
+        })
+        .unwrap_err();
+        assert_eq!((err.kind(), tries), (std::io::ErrorKind::PermissionDenied, 1));
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+}
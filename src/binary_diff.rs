@@ -1,45 +1,220 @@
 use std::collections::HashMap;
+use std::time::Instant;
 
 use crate::patch_format::DiffChunk;
 use crate::rolling_hash::RollingHash;
 
-pub const BLOCK_SIZE: usize = 4096;
+/// Smallest block size used for matching; see [`block_size_for`].
+pub const MIN_BLOCK_SIZE: usize = 1024;
 
+/// Largest block size, reached for old files of 4 GiB and up.
+pub const MAX_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Smallest block size for text, where an edit usually touches a line or two: blocks
+/// this fine still match the unchanged lines around it.
+pub const MIN_TEXT_BLOCK_SIZE: usize = 64;
+
+/// Shortest Copy the matcher ever emits, counting how far a block match extends into
+/// the bytes around it. A lone match of a fine text block amid changed content would
+/// otherwise split one Insert into two around a Copy that saves little once
+/// compressed. Binary block sizes are all above it, so it only affects text.
+pub const MIN_MATCH_LEN: usize = 96;
+
+/// Zero runs at least this long inside inserted data become [`DiffChunk::Zeros`].
+/// Shorter runs stay literal: zstd squeezes them anyway and they're below the size of
+/// a filesystem block, so they couldn't become a hole on apply.
+pub const ZERO_RUN_MIN: usize = 4096;
+
+/// Scan positions and candidate comparisons between two looks at the clock when
+/// diffing against a deadline: a few milliseconds of work, so the check costs nothing
+/// and a deadline is overshot by little.
+const DEADLINE_CHECK_STEPS: u32 = 1 << 16;
+
+/// One old block: its rolling hash and where it starts. There is no strong hash;
+/// candidate matches are confirmed by comparing the bytes directly (see [`find_match`]),
+/// so a false positive can't produce a wrong Copy and each signature costs 16 bytes.
 struct BlockSignature {
     rolling_hash: u32,
     offset: u64,
 }
 
+/// Block size for matching against an old file of `old_len` bytes: the power of two at
+/// or above its square root, clamped to [`MIN_BLOCK_SIZE`]..=[`MAX_BLOCK_SIZE`].
+///
+/// The signature table then grows with the square root of the file rather than
+/// linearly (a 1 GiB file gets 32K blocks of 32 KiB instead of 256K of 4 KiB), while
+/// files up to a few MiB get finer blocks than a fixed 4 KiB, so small edits copy more.
+pub fn block_size_for(old_len: usize) -> usize {
+    old_len
+        .isqrt()
+        .next_power_of_two()
+        .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// [`block_size_for`] for a text file: a sixteenth of the binary block size, at least
+/// [`MIN_TEXT_BLOCK_SIZE`]. Text edits are small and scattered (a changed line, a
+/// bumped version string), so coarse blocks would send whole blocks of unchanged lines
+/// around each one. Text is rarely large enough for the extra signatures to matter.
+pub fn text_block_size_for(old_len: usize) -> usize {
+    (block_size_for(old_len) / 16).max(MIN_TEXT_BLOCK_SIZE)
+}
+
+/// The default shortest Copy for matching with `block_size` blocks: a single block match
+/// is enough, but never less than [`MIN_MATCH_LEN`]. Raising it (see
+/// [`compute_diff_with_min_match`]) keeps coincidental runs shared by otherwise
+/// unrelated data inside the Inserts instead of fragmenting them.
+pub fn min_match_len_for(block_size: usize) -> usize {
+    block_size.max(MIN_MATCH_LEN)
+}
+
 /// Compute a binary diff between `old` and `new` data.
 ///
 /// Uses a block-matching algorithm (rsync-like):
-/// 1. Split old data into fixed-size blocks
+/// 1. Split old data into blocks of [`block_size_for`] its length
 /// 2. Build a hash table from rolling hash -> block signatures
 /// 3. Scan new data with a rolling hash, matching against old blocks
 /// 4. Emit Copy chunks for matches, Insert chunks for non-matching regions
+///
+/// An empty `new` always yields no chunks, so a file truncated to zero bytes is
+/// represented by an empty chunk list rather than a zero-length Insert. Long zero runs
+/// in the inserted data are split out as Zeros chunks (see [`ZERO_RUN_MIN`]).
 pub fn compute_diff(old: &[u8], new: &[u8]) -> Vec<DiffChunk> {
+    compute_diff_with_block_size(old, new, block_size_for(old.len()))
+}
+
+/// [`compute_diff`] with an explicit block size, e.g. [`text_block_size_for`] for text,
+/// or to compare sizes in benchmarks.
+pub fn compute_diff_with_block_size(old: &[u8], new: &[u8], block_size: usize) -> Vec<DiffChunk> {
+    compute_diff_with_min_match(old, new, block_size, min_match_len_for(block_size))
+}
+
+/// [`compute_diff_with_block_size`], emitting a Copy only for matches of at least
+/// `min_match_len` bytes once extended; shorter ones stay in the surrounding Insert.
+/// Values below [`min_match_len_for`] the block size have no effect.
+pub fn compute_diff_with_min_match(old: &[u8], new: &[u8], block_size: usize, min_match_len: usize) -> Vec<DiffChunk> {
+    diff_until(old, new, block_size, min_match_len, &mut Deadline::new(None)).expect("no deadline")
+}
+
+/// [`compute_diff_with_min_match`], giving up with `None` once `deadline` has passed.
+/// Inputs with many colliding rolling hashes make every scan position compare against
+/// a long candidate list, so a diff can take far longer than the file's size suggests.
+pub fn compute_diff_before(
+    old: &[u8],
+    new: &[u8],
+    block_size: usize,
+    min_match_len: usize,
+    deadline: Instant,
+) -> Option<Vec<DiffChunk>> {
+    diff_until(old, new, block_size, min_match_len, &mut Deadline::new(Some(deadline)))
+}
+
+fn diff_until(
+    old: &[u8],
+    new: &[u8],
+    block_size: usize,
+    min_match_len: usize,
+    deadline: &mut Deadline,
+) -> Option<Vec<DiffChunk>> {
+    if new.is_empty() {
+        return Some(vec![]);
+    }
     if old.is_empty() {
-        if new.is_empty() {
-            return vec![];
-        }
-        return vec![DiffChunk::Insert {
+        return Some(split_zero_runs(vec![DiffChunk::Insert {
             data: new.to_vec(),
-        }];
+        }]));
     }
 
-    let signatures = build_signatures(old);
+    let signatures = build_signatures(old, block_size);
     let hash_table = build_hash_table(&signatures);
 
-    match_blocks(old, new, &hash_table, &signatures)
+    let min_match_len = min_match_len.max(min_match_len_for(block_size));
+    let chunks = match_blocks(old, new, &hash_table, &signatures, block_size, min_match_len, deadline)?;
+    Some(split_zero_runs(chunks))
+}
+
+/// When to give up on a diff, checked every [`DEADLINE_CHECK_STEPS`] steps of work.
+struct Deadline {
+    at: Option<Instant>,
+    steps: u32,
+    passed: bool,
+}
+
+impl Deadline {
+    fn new(at: Option<Instant>) -> Self {
+        Self {
+            at,
+            steps: 0,
+            passed: false,
+        }
+    }
+
+    /// Count one step of work; true once the deadline has passed.
+    fn step(&mut self) -> bool {
+        self.steps += 1;
+        if self.steps >= DEADLINE_CHECK_STEPS {
+            self.steps = 0;
+            self.passed = self.at.is_some_and(|at| Instant::now() >= at);
+        }
+        self.passed
+    }
+}
+
+/// Replace zero runs of at least [`ZERO_RUN_MIN`] bytes inside Insert chunks with
+/// Zeros chunks. Other chunks pass through unchanged.
+fn split_zero_runs(chunks: Vec<DiffChunk>) -> Vec<DiffChunk> {
+    let mut out = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let DiffChunk::Insert { data } = &chunk else {
+            out.push(chunk);
+            continue;
+        };
+
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            if data[i] != 0 {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            i += data[i..].iter().take_while(|&&b| b == 0).count();
+            if i - start >= ZERO_RUN_MIN {
+                runs.push((start, i));
+            }
+        }
+        if runs.is_empty() {
+            out.push(chunk);
+            continue;
+        }
+
+        let mut pos = 0;
+        for (start, end) in runs {
+            if start > pos {
+                out.push(DiffChunk::Insert {
+                    data: data[pos..start].to_vec(),
+                });
+            }
+            out.push(DiffChunk::Zeros {
+                length: (end - start) as u64,
+            });
+            pos = end;
+        }
+        if pos < data.len() {
+            out.push(DiffChunk::Insert {
+                data: data[pos..].to_vec(),
+            });
+        }
+    }
+    out
 }
 
-fn build_signatures(data: &[u8]) -> Vec<BlockSignature> {
-    let num_blocks = (data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+fn build_signatures(data: &[u8], block_size: usize) -> Vec<BlockSignature> {
+    let num_blocks = data.len().div_ceil(block_size);
     let mut sigs = Vec::with_capacity(num_blocks);
 
     for i in 0..num_blocks {
-        let start = i * BLOCK_SIZE;
-        let end = (start + BLOCK_SIZE).min(data.len());
+        let start = i * block_size;
+        let end = (start + block_size).min(data.len());
         let block = &data[start..end];
 
         let mut rolling = RollingHash::new();
@@ -67,36 +242,58 @@ fn match_blocks(
     new: &[u8],
     hash_table: &HashMap<u32, Vec<usize>>,
     signatures: &[BlockSignature],
-) -> Vec<DiffChunk> {
+    block_size: usize,
+    min_match_len: usize,
+    deadline: &mut Deadline,
+) -> Option<Vec<DiffChunk>> {
     let mut chunks: Vec<DiffChunk> = Vec::new();
     let mut insert_buf: Vec<u8> = Vec::new();
 
-    if new.len() < BLOCK_SIZE {
-        return vec![DiffChunk::Insert {
+    if new.len() < block_size {
+        return Some(vec![DiffChunk::Insert {
             data: new.to_vec(),
-        }];
+        }]);
     }
 
     let mut rolling = RollingHash::new();
-    rolling.init(&new[..BLOCK_SIZE]);
+    rolling.init(&new[..block_size]);
 
     let mut pos: usize = 0;
 
     loop {
-        let window_end = pos + BLOCK_SIZE;
+        let window_end = pos + block_size;
         if window_end > new.len() {
             break;
         }
 
+        if deadline.step() {
+            return None;
+        }
         let digest = rolling.digest();
 
-        if let Some(match_result) = find_match(
-            digest,
-            &new[pos..window_end],
-            old,
-            hash_table,
-            signatures,
-        ) {
+        // A block match is extended backwards over the bytes waiting to be inserted and
+        // forwards past the block, so a run of unchanged blocks becomes one Copy and the
+        // Inserts around an edit shrink to the bytes that actually changed.
+        let extended = find_match(digest, &new[pos..window_end], old, hash_table, signatures, deadline)
+            .map(|(offset, length)| {
+                let (offset, length) = (offset as usize, length as usize);
+                let back = insert_buf
+                    .iter()
+                    .rev()
+                    .zip(old[..offset].iter().rev())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let forward = new[pos + length..]
+                    .iter()
+                    .zip(&old[offset + length..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (back, offset, length + forward)
+            })
+            .filter(|&(back, _, length)| back + length >= min_match_len);
+
+        if let Some((back, offset, length)) = extended {
+            insert_buf.truncate(insert_buf.len() - back);
             if !insert_buf.is_empty() {
                 chunks.push(DiffChunk::Insert {
                     data: std::mem::take(&mut insert_buf),
@@ -104,22 +301,22 @@ fn match_blocks(
             }
 
             chunks.push(DiffChunk::Copy {
-                offset: match_result.0,
-                length: match_result.1,
+                offset: (offset - back) as u64,
+                length: (back + length) as u64,
             });
 
-            pos += match_result.1 as usize;
+            pos += length;
 
-            if pos + BLOCK_SIZE <= new.len() {
+            if pos + block_size <= new.len() {
                 rolling = RollingHash::new();
-                rolling.init(&new[pos..pos + BLOCK_SIZE]);
+                rolling.init(&new[pos..pos + block_size]);
             }
         } else {
             insert_buf.push(new[pos]);
             pos += 1;
 
-            if pos + BLOCK_SIZE <= new.len() {
-                rolling.rotate(new[pos - 1], new[pos + BLOCK_SIZE - 1]);
+            if pos + block_size <= new.len() {
+                rolling.rotate(new[pos - 1], new[pos + block_size - 1]);
             }
         }
     }
@@ -129,11 +326,14 @@ fn match_blocks(
         insert_buf.extend_from_slice(&new[pos..]);
     }
 
+    if deadline.passed {
+        return None;
+    }
     if !insert_buf.is_empty() {
         chunks.push(DiffChunk::Insert { data: insert_buf });
     }
 
-    chunks
+    Some(chunks)
 }
 
 /// Try to find a matching old block for the current new window.
@@ -146,10 +346,14 @@ fn find_match(
     old: &[u8],
     hash_table: &HashMap<u32, Vec<usize>>,
     signatures: &[BlockSignature],
+    deadline: &mut Deadline,
 ) -> Option<(u64, u64)> {
     let candidates = hash_table.get(&rolling_digest)?;
 
     for &sig_idx in candidates {
+        if deadline.step() {
+            return None;
+        }
         let sig = &signatures[sig_idx];
         let start = sig.offset as usize;
         let end = (start + new_block.len()).min(old.len());
@@ -169,40 +373,43 @@ mod tests {
 
     #[test]
     fn test_identical_data() {
-        let data = vec![42u8; BLOCK_SIZE * 3];
+        let data = vec![42u8; MIN_BLOCK_SIZE * 3];
         let chunks = compute_diff(&data, &data);
-        let result = apply_diff(&data, &chunks);
+        let result = apply_diff(&data, &chunks).unwrap();
         assert_eq!(result, data);
     }
 
     #[test]
     fn test_completely_different() {
-        let old = vec![0u8; BLOCK_SIZE * 2];
-        let new = vec![1u8; BLOCK_SIZE * 2];
+        let old = vec![0u8; MIN_BLOCK_SIZE * 2];
+        let new = vec![1u8; MIN_BLOCK_SIZE * 2];
         let chunks = compute_diff(&old, &new);
-        let result = apply_diff(&old, &chunks);
+        let result = apply_diff(&old, &chunks).unwrap();
         assert_eq!(result, new);
     }
 
     #[test]
     fn test_prefix_changed() {
-        let old = vec![0u8; BLOCK_SIZE * 4];
+        let old = vec![0u8; MIN_BLOCK_SIZE * 4];
         let mut new = old.clone();
         // Change only the first block
-        for b in new[..BLOCK_SIZE].iter_mut() {
+        for b in new[..MIN_BLOCK_SIZE].iter_mut() {
             *b = 0xFF;
         }
 
         let chunks = compute_diff(&old, &new);
-        let result = apply_diff(&old, &chunks);
+        let result = apply_diff(&old, &chunks).unwrap();
         assert_eq!(result, new);
 
-        // Should have Copy chunks for unchanged blocks
-        let copy_count = chunks
+        // The unchanged blocks are extended into a single Copy
+        let copies: Vec<u64> = chunks
             .iter()
-            .filter(|c| matches!(c, DiffChunk::Copy { .. }))
-            .count();
-        assert!(copy_count >= 3, "Expected at least 3 Copy chunks for unchanged blocks");
+            .filter_map(|c| match c {
+                DiffChunk::Copy { length, .. } => Some(*length),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(copies, [3 * MIN_BLOCK_SIZE as u64]);
     }
 
     #[test]
@@ -210,7 +417,7 @@ mod tests {
         let old = vec![];
         let new = vec![1u8; 100];
         let chunks = compute_diff(&old, &new);
-        let result = apply_diff(&old, &chunks);
+        let result = apply_diff(&old, &chunks).unwrap();
         assert_eq!(result, new);
     }
 
@@ -219,8 +426,16 @@ mod tests {
         let old = vec![1u8; 100];
         let new = vec![];
         let chunks = compute_diff(&old, &new);
-        let result = apply_diff(&old, &chunks);
+        let result = apply_diff(&old, &chunks).unwrap();
         assert_eq!(result, new);
+        assert!(chunks.is_empty(), "Truncation to empty should need no chunks");
+    }
+
+    #[test]
+    fn test_empty_to_empty() {
+        let chunks = compute_diff(&[], &[]);
+        assert!(chunks.is_empty());
+        assert!(apply_diff(&[], &chunks).unwrap().is_empty());
     }
 
     #[test]
@@ -228,24 +443,165 @@ mod tests {
         let old = b"Hello, World!".to_vec();
         let new = b"Hello, Rust!".to_vec();
         let chunks = compute_diff(&old, &new);
-        let result = apply_diff(&old, &chunks);
+        let result = apply_diff(&old, &chunks).unwrap();
         assert_eq!(result, new);
     }
 
     #[test]
     fn test_insertion_in_middle() {
-        let mut old = vec![0u8; BLOCK_SIZE * 4];
+        let mut old = vec![0u8; MIN_BLOCK_SIZE * 4];
         for (i, b) in old.iter_mut().enumerate() {
             *b = (i % 256) as u8;
         }
         let mut new = old.clone();
         // Insert some bytes in the middle (between block 1 and block 2)
-        let insert_pos = BLOCK_SIZE * 2;
+        let insert_pos = MIN_BLOCK_SIZE * 2;
         let insertion = vec![0xAA; 100];
         new.splice(insert_pos..insert_pos, insertion);
 
         let chunks = compute_diff(&old, &new);
-        let result = apply_diff(&old, &chunks);
+        let result = apply_diff(&old, &chunks).unwrap();
         assert_eq!(result, new);
     }
+
+    #[test]
+    fn test_matches_extend_and_short_ones_are_dropped() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let old: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let block_size = MIN_TEXT_BLOCK_SIZE;
+
+        // One byte changed mid-block: the Copies reach right up to it from both sides.
+        let mut new = old.clone();
+        new[1000] ^= 0xff;
+        let chunks = compute_diff_with_block_size(&old, &new, block_size);
+        assert_eq!(apply_diff(&old, &chunks).unwrap(), new);
+        assert!(
+            matches!(
+                chunks.as_slice(),
+                [
+                    DiffChunk::Copy { offset: 0, length: 1000 },
+                    DiffChunk::Insert { data },
+                    DiffChunk::Copy { offset: 1001, length: 3095 },
+                ] if data.len() == 1
+            ),
+            "{:?}",
+            chunks
+        );
+
+        // A lone block of old data amid new content is shorter than MIN_MATCH_LEN and
+        // stays inside the Insert instead of splitting it in two.
+        let mut new = vec![0xaau8; 500];
+        new.extend_from_slice(&old[2000..2000 + block_size]);
+        new.extend(std::iter::repeat_n(0x55u8, 500));
+        let chunks = compute_diff_with_block_size(&old, &new, block_size);
+        assert_eq!(apply_diff(&old, &chunks).unwrap(), new);
+        assert!(
+            !chunks.iter().any(|c| matches!(c, DiffChunk::Copy { .. })),
+            "{:?}",
+            chunks
+        );
+    }
+
+    #[test]
+    fn test_min_match_len_keeps_coincidental_blocks_in_the_insert() {
+        let block_size = MIN_BLOCK_SIZE;
+        let noise = |len: usize, mut state: u32| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect()
+        };
+        let old = noise(block_size * 64, 0x2545_f491);
+        // Unrelated data with a lone old block every eight blocks, then a long shared run.
+        let mut new = Vec::new();
+        for k in 0..8 {
+            new.extend(noise(block_size * 7, 0x9e37_79b9 + k));
+            new.extend_from_slice(&old[k as usize * 2 * block_size..][..block_size]);
+        }
+        new.extend_from_slice(&old[block_size * 32..block_size * 48]);
+        let copies = |chunks: &[DiffChunk]| chunks.iter().filter(|c| matches!(c, DiffChunk::Copy { .. })).count();
+
+        let default = compute_diff_with_block_size(&old, &new, block_size);
+        assert_eq!(apply_diff(&old, &default).unwrap(), new);
+        assert_eq!(copies(&default), 9);
+        assert_eq!(copies(&compute_diff_with_min_match(&old, &new, block_size, 1)), 9);
+
+        let raised = compute_diff_with_min_match(&old, &new, block_size, 4 * block_size);
+        assert_eq!(apply_diff(&old, &raised).unwrap(), new);
+        assert!(
+            matches!(
+                raised.as_slice(),
+                [DiffChunk::Insert { .. }, DiffChunk::Copy { length, .. }] if *length == 16 * block_size as u64
+            ),
+            "{:?}",
+            raised
+        );
+    }
+
+    #[test]
+    fn test_diff_gives_up_at_the_deadline() {
+        // Every old block has the same rolling hash, and none matches the new data.
+        let old = vec![0u8; MIN_BLOCK_SIZE * 256];
+        let new: Vec<u8> = (0..MIN_BLOCK_SIZE * 256).map(|i| (i % 251) as u8 | 1).collect();
+        let past = Instant::now();
+        assert!(compute_diff_before(&old, &new, MIN_BLOCK_SIZE, MIN_BLOCK_SIZE, past).is_none());
+
+        let later = Instant::now() + std::time::Duration::from_secs(3600);
+        let chunks = compute_diff_before(&old, &new, MIN_BLOCK_SIZE, MIN_BLOCK_SIZE, later).unwrap();
+        assert_eq!(apply_diff(&old, &chunks).unwrap(), new);
+    }
+
+    #[test]
+    fn test_zero_runs_become_zeros_chunks() {
+        let mut new = vec![7u8; 100];
+        new.extend(std::iter::repeat_n(0u8, 3 * ZERO_RUN_MIN));
+        new.extend_from_slice(&[1, 0, 0, 2]);
+        let chunks = compute_diff(b"", &new);
+        assert!(matches!(chunks[0], DiffChunk::Insert { ref data } if data.len() == 100));
+        let zeros = 3 * ZERO_RUN_MIN as u64;
+        assert!(matches!(chunks[1], DiffChunk::Zeros { length } if length == zeros));
+        // Short zero runs stay literal.
+        assert!(matches!(chunks[2], DiffChunk::Insert { ref data } if data == &[1, 0, 0, 2]));
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(apply_diff(b"", &chunks).unwrap(), new);
+    }
+
+    #[test]
+    fn test_block_size_scales_with_old_file() {
+        assert_eq!(block_size_for(0), MIN_BLOCK_SIZE);
+        assert_eq!(block_size_for(100_000), MIN_BLOCK_SIZE);
+        assert_eq!(block_size_for(16 << 20), 4096);
+        assert_eq!(block_size_for(1 << 30), 32 * 1024);
+        assert_eq!(block_size_for(usize::MAX), MAX_BLOCK_SIZE);
+        assert_eq!(text_block_size_for(100_000), MIN_TEXT_BLOCK_SIZE);
+        assert_eq!(text_block_size_for(1 << 30), 2048);
+
+        // Any block size yields a correct diff; smaller ones match closer to an edit.
+        let old: Vec<u8> = (0..MAX_BLOCK_SIZE * 4).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        new[MAX_BLOCK_SIZE + 10] ^= 1;
+        let inserted = |block_size| {
+            let chunks = compute_diff_with_block_size(&old, &new, block_size);
+            assert_eq!(apply_diff(&old, &chunks).unwrap(), new);
+            chunks
+                .iter()
+                .map(|c| match c {
+                    DiffChunk::Insert { data } => data.len(),
+                    _ => 0,
+                })
+                .sum::<usize>()
+        };
+        assert!(inserted(MIN_BLOCK_SIZE) < inserted(MAX_BLOCK_SIZE));
+    }
 }
@@ -1,14 +1,71 @@
+use anyhow::{bail, Result};
+
 use crate::patch_format::DiffChunk;
+use crate::util::{HashAlgo, StreamHasher};
 
-/// Reconstruct the new file from the old file data and a sequence of diff chunks.
-pub fn apply_diff(old: &[u8], chunks: &[DiffChunk]) -> Vec<u8> {
-    let estimated_size: u64 = chunks
-        .iter()
-        .map(|c| match c {
+/// Files smaller than this are always rebuilt in memory; the in-place path only pays
+/// off when rewriting the whole file would be expensive.
+pub const IN_PLACE_MIN_FILE_SIZE: u64 = 1024 * 1024;
+
+/// In-place patching is used only when the bytes it writes (Inserts and moved Copies)
+/// cover at most 1/8 of the file.
+const IN_PLACE_MAX_WRITE_RATIO: u64 = 8;
+
+/// Length of the file `chunks` would produce, saturating on overflow.
+pub fn output_len(chunks: &[DiffChunk]) -> u64 {
+    chunks.iter().fold(0u64, |acc, c| {
+        acc.saturating_add(match c {
             DiffChunk::Copy { length, .. } => *length,
             DiffChunk::Insert { data } => data.len() as u64,
+            DiffChunk::Zeros { length } => *length,
         })
-        .sum();
+    })
+}
+
+/// Output ranges (offset, length) covered by Zeros chunks, in order. Apply leaves
+/// these as holes when writing the file.
+pub fn zero_ranges(chunks: &[DiffChunk]) -> Vec<(u64, u64)> {
+    let mut pos: u64 = 0;
+    let mut ranges = Vec::new();
+    for chunk in chunks {
+        let len = output_len(std::slice::from_ref(chunk));
+        if let DiffChunk::Zeros { length } = chunk {
+            ranges.push((pos, *length));
+        }
+        pos = pos.saturating_add(len);
+    }
+    ranges
+}
+
+/// Reconstruct the new file from the old file data and a sequence of diff chunks.
+/// Every Copy chunk is bounds-checked against `old`, so a corrupt or malicious
+/// manifest yields an error instead of a panic.
+pub fn apply_diff(old: &[u8], chunks: &[DiffChunk]) -> Result<Vec<u8>> {
+    // Validate every chunk before allocating, so the output size is never taken from
+    // an unchecked length.
+    let mut estimated_size: u64 = 0;
+    for (i, chunk) in chunks.iter().enumerate() {
+        let len = match chunk {
+            DiffChunk::Copy { offset, length } => {
+                match offset.checked_add(*length) {
+                    Some(end) if end <= old.len() as u64 => {}
+                    _ => bail!(
+                        "Copy chunk {} out of bounds: offset {} + length {} exceeds old file size {}",
+                        i,
+                        offset,
+                        length,
+                        old.len()
+                    ),
+                }
+                *length
+            }
+            DiffChunk::Insert { data } => data.len() as u64,
+            DiffChunk::Zeros { length } => *length,
+        };
+        estimated_size = estimated_size
+            .checked_add(len)
+            .ok_or_else(|| anyhow::anyhow!("Diff output length overflows"))?;
+    }
 
     let mut result = Vec::with_capacity(estimated_size as usize);
 
@@ -22,10 +79,146 @@ pub fn apply_diff(old: &[u8], chunks: &[DiffChunk]) -> Vec<u8> {
             DiffChunk::Insert { data } => {
                 result.extend_from_slice(data);
             }
+            DiffChunk::Zeros { length } => {
+                result.resize(result.len() + *length as usize, 0);
+            }
         }
     }
 
-    result
+    Ok(result)
+}
+
+/// One write of an in-place patch, from [`in_place_edits`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InPlaceEdit<'a> {
+    /// Write `data` at `offset`.
+    Write { offset: u64, data: &'a [u8] },
+    /// Copy `length` bytes of the file from `from` to `to`; the two ranges may overlap
+    /// (it is a `memmove`).
+    Move { from: u64, to: u64, length: u64 },
+}
+
+/// If `chunks` can be applied by overwriting `old` in place, return the writes needed
+/// to do so, to be applied in order.
+///
+/// That is the case when the output has the same length as `old`, the bytes written
+/// are few relative to the file, and no Copy reads a range that an earlier chunk has
+/// already overwritten (see [`copy_reads_overwritten`]). A Copy onto itself (its source
+/// offset equals its output position) is a no-op; any other becomes a
+/// [`InPlaceEdit::Move`]. Anything else returns `None` and must go through
+/// [`apply_diff`], which reads from the untouched old content; so must diffs with Zeros
+/// chunks, which are written as holes by a full rewrite.
+pub fn in_place_edits(old_len: u64, chunks: &[DiffChunk]) -> Option<Vec<InPlaceEdit<'_>>> {
+    if old_len < IN_PLACE_MIN_FILE_SIZE
+        || output_len(chunks) != old_len
+        || !copies_fit(old_len, chunks)
+        || copy_reads_overwritten(chunks).is_some()
+    {
+        return None;
+    }
+
+    let mut pos: u64 = 0;
+    let mut written: u64 = 0;
+    let mut edits = Vec::new();
+    for chunk in chunks {
+        match chunk {
+            DiffChunk::Copy { offset, length } => {
+                if *offset != pos {
+                    edits.push(InPlaceEdit::Move {
+                        from: *offset,
+                        to: pos,
+                        length: *length,
+                    });
+                    written += length;
+                }
+                pos += length;
+            }
+            DiffChunk::Insert { data } => {
+                edits.push(InPlaceEdit::Write {
+                    offset: pos,
+                    data: data.as_slice(),
+                });
+                written += data.len() as u64;
+                pos += data.len() as u64;
+            }
+            DiffChunk::Zeros { .. } => return None,
+        }
+    }
+
+    if written.saturating_mul(IN_PLACE_MAX_WRITE_RATIO) > old_len {
+        return None;
+    }
+    Some(edits)
+}
+
+/// The index of the first Copy chunk that reads a range an earlier chunk writes, were
+/// `chunks` applied over the old file in place, front to back. Such a Copy would read
+/// new bytes where the diff means old ones, so the file has to be rebuilt from an
+/// untouched copy instead. A Copy onto itself writes nothing; a Copy overlapping only
+/// its own destination is safe, since it is applied as a `memmove`.
+pub fn copy_reads_overwritten(chunks: &[DiffChunk]) -> Option<usize> {
+    // Output ranges written so far: increasing, disjoint and merged where they touch.
+    let mut written: Vec<(u64, u64)> = Vec::new();
+    let mut pos: u64 = 0;
+    for (i, chunk) in chunks.iter().enumerate() {
+        let len = output_len(std::slice::from_ref(chunk));
+        if let DiffChunk::Copy { offset, length } = chunk {
+            if *offset == pos {
+                pos = pos.saturating_add(len);
+                continue;
+            }
+            let end = offset.saturating_add(*length);
+            let first_after = written.partition_point(|&(_, written_end)| written_end <= *offset);
+            if written.get(first_after).is_some_and(|&(start, _)| start < end) {
+                return Some(i);
+            }
+        }
+        let end = pos.saturating_add(len);
+        match written.last_mut() {
+            Some(last) if last.1 == pos => last.1 = end,
+            _ if len > 0 => written.push((pos, end)),
+            _ => {}
+        }
+        pos = end;
+    }
+    None
+}
+
+/// Whether every Copy chunk lies within an old file of `old_len` bytes, i.e. whether
+/// `chunks` can be applied to it at all.
+pub fn copies_fit(old_len: u64, chunks: &[DiffChunk]) -> bool {
+    chunks.iter().all(|chunk| match chunk {
+        DiffChunk::Copy { offset, length } => {
+            offset.checked_add(*length).is_some_and(|end| end <= old_len)
+        }
+        _ => true,
+    })
+}
+
+/// Hash the output `apply_diff(old, chunks)` would produce, without materializing it.
+/// Chunks must already be known to be in bounds (e.g. via [`in_place_edits`] or
+/// [`copies_fit`]).
+pub fn hash_applied(algo: HashAlgo, old: &[u8], chunks: &[DiffChunk]) -> [u8; 32] {
+    let mut hasher = StreamHasher::new(algo);
+    for chunk in chunks {
+        match chunk {
+            DiffChunk::Copy { offset, length } => {
+                let start = *offset as usize;
+                hasher.update(&old[start..start + *length as usize]);
+            }
+            DiffChunk::Insert { data } => hasher.update(data),
+            DiffChunk::Zeros { length } => {
+                static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+                let mut left = *length;
+                while left > 0 {
+                    let n = left.min(ZEROS.len() as u64) as usize;
+                    hasher.update(&ZEROS[..n]);
+                    left -= n as u64;
+                }
+            }
+        }
+    }
+    hasher.finalize()
 }
 
 #[cfg(test)]
@@ -39,7 +232,7 @@ mod tests {
             offset: 0,
             length: old.len() as u64,
         }];
-        let result = apply_diff(old, &chunks);
+        let result = apply_diff(old, &chunks).unwrap();
         assert_eq!(result, old);
     }
 
@@ -50,7 +243,7 @@ mod tests {
         let chunks = vec![DiffChunk::Insert {
             data: new_data.to_vec(),
         }];
-        let result = apply_diff(old, &chunks);
+        let result = apply_diff(old, &chunks).unwrap();
         assert_eq!(result, new_data);
     }
 
@@ -70,7 +263,7 @@ mod tests {
                 length: 4,
             },
         ];
-        let result = apply_diff(old, &chunks);
+        let result = apply_diff(old, &chunks).unwrap();
         assert_eq!(result, b"AAAA_XXXX_CCCC");
     }
 
@@ -78,7 +271,216 @@ mod tests {
     fn test_apply_empty_chunks() {
         let old = b"some data";
         let chunks: Vec<DiffChunk> = vec![];
-        let result = apply_diff(old, &chunks);
+        let result = apply_diff(old, &chunks).unwrap();
         assert!(result.is_empty());
     }
+
+    #[test]
+    fn test_apply_copy_out_of_bounds() {
+        let old = b"short";
+        let chunks = vec![DiffChunk::Copy {
+            offset: 3,
+            length: 10,
+        }];
+        let err = apply_diff(old, &chunks).unwrap_err();
+        assert!(err.to_string().contains("out of bounds"), "{}", err);
+
+        let overflow = vec![DiffChunk::Copy {
+            offset: u64::MAX,
+            length: 2,
+        }];
+        assert!(apply_diff(old, &overflow).is_err());
+    }
+
+    #[test]
+    fn test_in_place_edits_detects_self_copy() {
+        let len = IN_PLACE_MIN_FILE_SIZE;
+        let chunks = vec![
+            DiffChunk::Copy {
+                offset: 0,
+                length: 4096,
+            },
+            DiffChunk::Insert {
+                data: vec![1u8; 4096],
+            },
+            DiffChunk::Copy {
+                offset: 8192,
+                length: len - 8192,
+            },
+        ];
+        let edits = in_place_edits(len, &chunks).expect("should be in-place");
+        assert_eq!(edits.len(), 1);
+        assert!(matches!(edits[0], InPlaceEdit::Write { offset: 4096, .. }));
+
+        let old = vec![0u8; len as usize];
+        let expected = apply_diff(&old, &chunks).unwrap();
+        assert_eq!(
+            hash_applied(HashAlgo::Blake3, &old, &chunks),
+            crate::util::hash_bytes(HashAlgo::Blake3, &expected)
+        );
+    }
+
+    #[test]
+    fn test_in_place_edits_rejects_moves_and_resizes() {
+        let len = IN_PLACE_MIN_FILE_SIZE;
+        // Two blocks swapped: the second Copy reads what the first one overwrote.
+        let swapped = vec![
+            DiffChunk::Copy {
+                offset: 4096,
+                length: 4096,
+            },
+            DiffChunk::Copy {
+                offset: 0,
+                length: 4096,
+            },
+            DiffChunk::Copy {
+                offset: 8192,
+                length: len - 8192,
+            },
+        ];
+        assert_eq!(copy_reads_overwritten(&swapped), Some(1));
+        assert!(in_place_edits(len, &swapped).is_none());
+
+        // Size change.
+        let grown = vec![
+            DiffChunk::Copy {
+                offset: 0,
+                length: len,
+            },
+            DiffChunk::Insert { data: vec![0; 10] },
+        ];
+        assert!(in_place_edits(len, &grown).is_none());
+
+        // Too small to bother.
+        let tiny = vec![DiffChunk::Copy {
+            offset: 0,
+            length: 100,
+        }];
+        assert!(in_place_edits(100, &tiny).is_none());
+    }
+
+    /// Patch a copy of `old` in place with `edits` the way apply does.
+    fn patched_in_place(old: &[u8], edits: &[InPlaceEdit]) -> Vec<u8> {
+        let mut file = old.to_vec();
+        for edit in edits {
+            match *edit {
+                InPlaceEdit::Write { offset, data } => {
+                    file[offset as usize..offset as usize + data.len()].copy_from_slice(data)
+                }
+                InPlaceEdit::Move { from, to, length } => {
+                    file.copy_within(from as usize..(from + length) as usize, to as usize)
+                }
+            }
+        }
+        file
+    }
+
+    #[test]
+    fn test_in_place_edits_move_only_what_is_still_old() {
+        let len = IN_PLACE_MIN_FILE_SIZE;
+        let old: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+
+        // A block moved forward by 1000 bytes over its own tail (its first 1000 bytes
+        // end up twice): nothing before it writes its source, so it is done in place,
+        // and its overlap with its own destination is left to memmove.
+        let forward = vec![
+            DiffChunk::Copy {
+                offset: 0,
+                length: 5096,
+            },
+            DiffChunk::Copy {
+                offset: 4096,
+                length: 6000,
+            },
+            DiffChunk::Copy {
+                offset: 5096 + 6000,
+                length: len - (5096 + 6000),
+            },
+        ];
+        assert_eq!(copy_reads_overwritten(&forward), None);
+        let edits = in_place_edits(len, &forward).expect("should be in-place");
+        assert_eq!(
+            edits.last(),
+            Some(&InPlaceEdit::Move {
+                from: 4096,
+                to: 5096,
+                length: 6000,
+            })
+        );
+        assert_eq!(patched_in_place(&old, &edits), apply_diff(&old, &forward).unwrap());
+
+        // The same move making room for an Insert: the Insert lands first on bytes
+        // the Move still has to read, so in place would copy the inserted bytes.
+        let overlapping = vec![
+            DiffChunk::Copy {
+                offset: 0,
+                length: 4096,
+            },
+            DiffChunk::Insert {
+                data: vec![0xAA; 1000],
+            },
+            DiffChunk::Copy {
+                offset: 4096,
+                length: 6000,
+            },
+            DiffChunk::Copy {
+                offset: 5096 + 6000,
+                length: len - (5096 + 6000),
+            },
+        ];
+        assert_eq!(copy_reads_overwritten(&overlapping), Some(2));
+        assert!(in_place_edits(len, &overlapping).is_none());
+        let naive = patched_in_place(
+            &old,
+            &[
+                InPlaceEdit::Write {
+                    offset: 4096,
+                    data: &[0xAA; 1000],
+                },
+                InPlaceEdit::Move {
+                    from: 4096,
+                    to: 5096,
+                    length: 6000,
+                },
+            ],
+        );
+        assert_ne!(naive, apply_diff(&old, &overlapping).unwrap());
+    }
+
+    #[test]
+    fn test_zeros_chunks() {
+        let old = b"ABCD";
+        let chunks = vec![
+            DiffChunk::Copy {
+                offset: 0,
+                length: 2,
+            },
+            DiffChunk::Zeros { length: 100_000 },
+            DiffChunk::Insert {
+                data: b"xy".to_vec(),
+            },
+            DiffChunk::Zeros { length: 3 },
+        ];
+        let result = apply_diff(old, &chunks).unwrap();
+        assert_eq!(result.len(), 100_007);
+        assert_eq!(&result[..2], b"AB");
+        assert!(result[2..100_002].iter().all(|&b| b == 0));
+        assert_eq!(&result[100_002..100_004], b"xy");
+        assert_eq!(output_len(&chunks), 100_007);
+        assert_eq!(zero_ranges(&chunks), vec![(2, 100_000), (100_004, 3)]);
+        assert_eq!(
+            hash_applied(HashAlgo::Blake3, old, &chunks),
+            crate::util::hash_bytes(HashAlgo::Blake3, &result)
+        );
+
+        let len = IN_PLACE_MIN_FILE_SIZE;
+        let zeroed = vec![
+            DiffChunk::Zeros { length: 4096 },
+            DiffChunk::Copy {
+                offset: 4096,
+                length: len - 4096,
+            },
+        ];
+        assert!(in_place_edits(len, &zeroed).is_none());
+    }
 }
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+
+use crate::patch_format::PatchOp;
+
+/// One path a patch touches, as shown by `create --print-tree`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Touched {
+    pub path: String,
+    /// `+` created or added (hard links included), `~` modified, `*` metadata only,
+    /// `-` deleted.
+    pub marker: char,
+    pub dir: bool,
+}
+
+impl Touched {
+    /// What `op` does to its path, or `None` for an operation that leaves it as it is
+    /// (`VerifyFile`).
+    pub fn of(op: &PatchOp) -> Option<Self> {
+        let (marker, dir) = match op {
+            PatchOp::CreateDir { .. } => ('+', true),
+            PatchOp::AddFile { .. } | PatchOp::CreateHardlink { .. } | PatchOp::CreateSymlink { .. } => {
+                ('+', false)
+            }
+            PatchOp::ModifyFile { .. } | PatchOp::ModifyFileMulti { .. } => ('~', false),
+            PatchOp::SetMetadata { .. } => ('*', false),
+            PatchOp::DeleteFile { .. } => ('-', false),
+            PatchOp::DeleteDir { .. } => ('-', true),
+            PatchOp::VerifyFile { .. } => return None,
+        };
+        Some(Self {
+            path: op.path().to_string(),
+            marker,
+            dir,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    marker: Option<char>,
+    dir: bool,
+    children: BTreeMap<String, Node>,
+}
+
+/// Render `touched` as an indented tree, one line per node in path order, each marked as
+/// in [`Touched::marker`]. Directories end in `/`; those the patch doesn't touch are
+/// unmarked, and a chain of them holding nothing but the next directory is collapsed
+/// into a single line (`src/app/` rather than `src/` then `app/`).
+pub fn render(touched: &[Touched]) -> String {
+    let mut root = Node::default();
+    for entry in touched {
+        let mut node = &mut root;
+        for name in entry.path.split('/') {
+            node = node.children.entry(name.to_string()).or_default();
+        }
+        node.marker = Some(entry.marker);
+        node.dir |= entry.dir;
+    }
+    let mut out = String::new();
+    render_children(&root, 0, &mut out);
+    out
+}
+
+fn render_children(node: &Node, depth: usize, out: &mut String) {
+    for (name, child) in &node.children {
+        let mut name = name.clone();
+        let mut child = child;
+        while child.marker.is_none() && child.children.len() == 1 {
+            let (next_name, next) = child.children.iter().next().expect("one child");
+            if !next.dir && next.children.is_empty() {
+                break;
+            }
+            name = format!("{}/{}", name, next_name);
+            child = next;
+        }
+        let dir = child.dir || !child.children.is_empty();
+        out.push_str(&format!(
+            "{}{} {}{}\n",
+            "  ".repeat(depth),
+            child.marker.unwrap_or(' '),
+            name,
+            if dir { "/" } else { "" }
+        ));
+        render_children(child, depth + 1, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_nests_and_collapses_untouched_directories() {
+        let touched: Vec<Touched> = [
+            ('+', true, "assets"),
+            ('+', false, "assets/logo.png"),
+            ('~', false, "src/app/main.rs"),
+            ('~', false, "src/app/lib.rs"),
+            ('*', false, "src/build.sh"),
+            ('-', false, "docs/old/guide/intro.md"),
+            ('-', true, "legacy"),
+            ('~', false, "README.md"),
+        ]
+        .into_iter()
+        .map(|(marker, dir, path)| Touched {
+            path: path.to_string(),
+            marker,
+            dir,
+        })
+        .collect();
+        let rendered = render(&touched);
+        assert_eq!(
+            rendered.lines().collect::<Vec<_>>(),
+            [
+                "~ README.md",
+                "+ assets/",
+                "  + logo.png",
+                "  docs/old/guide/",
+                "  - intro.md",
+                "- legacy/",
+                "  src/",
+                "    app/",
+                "    ~ lib.rs",
+                "    ~ main.rs",
+                "  * build.sh",
+            ]
+        );
+        assert_eq!(render(&[]), "");
+    }
+}
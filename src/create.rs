@@ -1,20 +1,74 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::binary_diff;
-use crate::patch_format::{ApplySummary, DiffChunk, PatchManifest, PatchOp, FORMAT_VERSION, MAGIC};
-use crate::util::{self, EntryKind};
+use crate::change_tree::Touched;
+use crate::error::PatchError;
+use crate::filter::PathFilter;
+use crate::hash_cache::HashCache;
+use crate::patch_format::{
+    add_file_op, add_object_op, chunk_counts, ApplySummary, BaseDiff, DiffChunk, MacKey, ManifestEncoding,
+    OpSize, PatchManifest, PatchOp, PatchWriter, Recompress, SplitPatchWriter,
+};
+use crate::recompress;
+use crate::snapshot;
+use crate::util::{self, EntryKind, HashAlgo};
+
+/// User additions to and exceptions from the built-in incompressible extension list
+/// (`--incompressible-ext`, `--force-compress-ext`). Extensions are given without the
+/// dot and compared in any case.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionOverrides {
+    /// Treated as incompressible on top of the built-in list.
+    pub incompressible_exts: Vec<String>,
+    /// Diffed and compressed even when the built-in list says otherwise.
+    pub force_compress_exts: Vec<String>,
+}
+
+impl CompressionOverrides {
+    fn lists(exts: &[String], ext: &str) -> bool {
+        exts.iter().any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(ext))
+    }
+}
+
+/// The AddFile for a file's whole content: in the patch, or with `objects_dir` in the
+/// objects directory (see [`CreateOptions::objects_dir`]).
+fn content_op(
+    objects_dir: Option<&Path>,
+    path: String,
+    content: &[u8],
+    hash: [u8; 32],
+    incompressible: bool,
+    xattrs: util::Xattrs,
+) -> Result<PatchOp> {
+    match objects_dir {
+        Some(dir) => add_object_op(dir, path, content, hash, xattrs),
+        None => add_file_op(path, content, hash, incompressible, xattrs),
+    }
+}
 
 /// Returns true for file types that are already compressed or otherwise incompressible,
-/// where computing a binary diff would yield no meaningful savings.
-fn is_incompressible(path: &Path) -> bool {
+/// where computing a binary diff would yield no meaningful savings. `overrides` extends
+/// or carves exceptions out of the built-in list; a forced extension always wins.
+pub(crate) fn is_incompressible(path: &Path, overrides: &CompressionOverrides) -> bool {
     let ext = path
         .extension()
         .and_then(|e| e.to_str())
         .map(|e| e.to_ascii_lowercase());
+    if let Some(ext) = ext.as_deref() {
+        if CompressionOverrides::lists(&overrides.force_compress_exts, ext) {
+            return false;
+        }
+        if CompressionOverrides::lists(&overrides.incompressible_exts, ext) {
+            return true;
+        }
+    }
     matches!(
         ext.as_deref(),
         Some(
@@ -36,36 +90,1335 @@ fn is_incompressible(path: &Path) -> bool {
     )
 }
 
-/// Stream-hash a file using BLAKE3.
-/// Uses a 256 KB BufReader to reduce syscall overhead vs the default 8 KB.
-fn hash_file_streaming(path: &Path) -> Result<blake3::Hash> {
-    let file = std::fs::File::open(path)
-        .with_context(|| format!("Failed to open file for hashing: {}", path.display()))?;
-    let mut reader = std::io::BufReader::with_capacity(256 * 1024, file);
-    let mut hasher = blake3::Hasher::new();
-    std::io::copy(&mut reader, &mut hasher)
-        .with_context(|| format!("Failed to hash file: {}", path.display()))?;
-    Ok(hasher.finalize())
+/// Options controlling patch creation.
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Hash algorithm for all digests recorded in the patch.
+    pub hash_algo: HashAlgo,
+    /// Print one line per operation as the patch is assembled.
+    pub verbose: bool,
+    /// Glob patterns; when non-empty, only matching paths are considered.
+    pub include: Vec<String>,
+    /// Glob patterns for paths to leave out. Exclude wins over include.
+    pub exclude: Vec<String>,
+    /// Skip entries that can't be read (e.g. permission denied) with a warning instead of
+    /// failing. Skipped subtrees are left out of the patch on both sides.
+    pub skip_unreadable: bool,
+    /// Record paths that aren't valid UTF-8 with their odd bytes escaped (see
+    /// [`util::path_to_string`]) instead of failing on them.
+    pub allow_non_utf8: bool,
+    /// Record a VerifyFile op (path + hash) for every unchanged file, so `verify` can
+    /// check the entire post-patch tree rather than just the files the patch touches.
+    pub full_verify: bool,
+    /// Read buffer size in bytes for streaming hashes (see [`util::DEFAULT_READ_BUFFER`]).
+    /// Files that may be diffed are hashed from their memory mapping instead.
+    pub read_buffer: usize,
+    /// Wrap the finished patch in a gzip stream (on top of zstd, not instead of it).
+    pub gzip: bool,
+    /// Serialization of the manifest inside the zstd payload (`--output-format`).
+    pub output_format: ManifestEncoding,
+    /// Append a MAC of the patch made with this key (`--mac-key`), for apply to check.
+    pub mac_key: Option<MacKey>,
+    /// Emit CreateHardlink instead of duplicate content for added files that are hard
+    /// links to another file in the new tree (Unix only; elsewhere a no-op).
+    pub preserve_hardlinks: bool,
+    /// How symbolic links' targets are recorded (`--symlink-mode`).
+    pub symlink_mode: SymlinkMode,
+    /// Receives progress events with totals; see [`CreateProgress`].
+    pub progress: Option<ProgressCallback>,
+    /// Trust modification times: a file present on both sides with the same size and a
+    /// new-side mtime before this instant is taken as unchanged without being hashed.
+    pub since: Option<std::time::SystemTime>,
+    /// Further old directories besides `old_dir`, for a patch that applies to any of
+    /// them (`--old` given more than once). Each modified file gets one diff per
+    /// distinct old version, and apply picks the one matching the target's file.
+    pub extra_bases: Vec<PathBuf>,
+    /// For files whose content is identical on both sides, record permission or mtime
+    /// differences as `SetMetadata` operations (`--metadata`). Ignored for multi-base
+    /// patches and snapshot bases, which have no metadata to compare.
+    pub metadata: bool,
+    /// Warn about and leave out files that changed size or vanished between the walk
+    /// and being read (`--skip-changing`), instead of failing. Create otherwise assumes
+    /// the trees hold still while it runs.
+    pub skip_changing: bool,
+    /// Upper bound on the bytes of file content hashed, diffed or held for writing at
+    /// once (`--memory-budget`). A file larger than the budget is processed on its own.
+    pub memory_budget: Option<u64>,
+    /// Also write a unified diff of every modified text file to this path
+    /// (`--changelog`), for release notes. Binary files are left out; the patch is
+    /// unaffected.
+    pub changelog: Option<PathBuf>,
+    /// Extensions (without the dot, any case) of gzip files to diff by their
+    /// decompressed content (`--recompress-ext`). Files that aren't gzip, or whose
+    /// compressed bytes can't be reproduced exactly on apply, are diffed as usual.
+    pub recompress_exts: Vec<String>,
+    /// Record each added or modified file's extended attributes (`--preserve-xattrs`),
+    /// for apply to set again. Unix only, and only with the `xattrs` feature; elsewhere
+    /// create warns and records none.
+    pub preserve_xattrs: bool,
+    /// Changes to which extensions count as already compressed, and so are stored
+    /// whole instead of diffed (see [`CompressionOverrides`]).
+    pub compression_overrides: CompressionOverrides,
+    /// Reuse file hashes from earlier runs while size and mtime are unchanged, and save
+    /// this run's (`--hash-cache`); see [`HashCache`]. An unchanged file present on
+    /// both sides is then not read at all.
+    pub hash_cache: Option<PathBuf>,
+    /// Write the patch as parts `<output>.001`, `<output>.002`... of at most this many
+    /// bytes each (`--split-size`), split at operation boundaries. Needs a file output
+    /// and no `gzip`; apply and the other commands take the base name.
+    pub split_size: Option<u64>,
+    /// Store the content of added files in this objects directory, one zstd-compressed
+    /// file per distinct content named by its hash, instead of in the patch
+    /// (`--objects-dir`). Patches created into the same directory share identical
+    /// content; apply needs the directory.
+    pub objects_dir: Option<PathBuf>,
+    /// Diff every modified file with each candidate strategy, keep whichever is
+    /// smallest and print which one won and by how much (`--explain`), to tune the
+    /// choice heuristics. Slower: each file is diffed up to twice and every candidate
+    /// is compressed to be measured.
+    pub explain: bool,
+    /// Store every modified file whole, as an AddFile overwriting it, instead of as a
+    /// diff (`--force-full`). The patch is larger but doesn't depend on the old bytes
+    /// beyond their hash, which still decides whether a file changed.
+    pub force_full: bool,
+    /// Give up diffing a file after this long and store it whole instead, with a
+    /// warning (`--diff-timeout`). Guards against inputs whose colliding rolling hashes
+    /// make block matching crawl. `None` waits as long as it takes.
+    pub diff_timeout: Option<Duration>,
+    /// Shortest block match emitted as a Copy, counting how far it extends
+    /// (`--min-match`); shorter ones stay inside the surrounding Insert. `None` uses the
+    /// block size (at least [`binary_diff::MIN_MATCH_LEN`]); smaller values have no
+    /// effect. Raise it for data that shares many short coincidental runs with the old
+    /// version, which otherwise fragments the diff into alternating Copies and Inserts.
+    pub min_match_len: Option<usize>,
+    /// Report this many of the operations carrying the most content in the summary's
+    /// `largest_ops` (`--stats`), to find what dominates an unexpectedly large patch.
+    /// 0 reports none.
+    pub largest_ops: usize,
+    /// List every path the patch touches in the summary's `touched`, for rendering with
+    /// [`change_tree::render`](crate::change_tree::render) (`--print-tree`).
+    pub record_touched: bool,
+    /// Overwrite an existing output file (`--force`). Without it create refuses to
+    /// start, so a patch built earlier isn't clobbered by mistake.
+    pub force: bool,
+}
+
+impl CreateOptions {
+    fn report(&self, event: CreateProgress) {
+        if let Some(progress) = &self.progress {
+            (progress.0)(event);
+        }
+    }
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            hash_algo: HashAlgo::default(),
+            verbose: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            skip_unreadable: false,
+            allow_non_utf8: false,
+            full_verify: false,
+            read_buffer: util::DEFAULT_READ_BUFFER,
+            gzip: false,
+            output_format: ManifestEncoding::default(),
+            mac_key: None,
+            preserve_hardlinks: false,
+            symlink_mode: SymlinkMode::default(),
+            progress: None,
+            since: None,
+            extra_bases: Vec::new(),
+            metadata: false,
+            skip_changing: false,
+            memory_budget: None,
+            changelog: None,
+            recompress_exts: Vec::new(),
+            preserve_xattrs: false,
+            compression_overrides: CompressionOverrides::default(),
+            hash_cache: None,
+            split_size: None,
+            objects_dir: None,
+            explain: false,
+            force_full: false,
+            diff_timeout: None,
+            min_match_len: None,
+            largest_ops: 0,
+            record_touched: false,
+            force: false,
+        }
+    }
+}
+
+/// How create records the target of a symbolic link. Links are always stored as links
+/// (CreateSymlink), never followed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SymlinkMode {
+    /// The target exactly as the link holds it.
+    #[default]
+    Verbatim,
+    /// An absolute target inside the tree rewritten relative to the link's directory, so
+    /// the link keeps working wherever the tree is installed. Other targets are kept.
+    Relative,
+}
+
+/// `--symlink-mode relative`: the target of the link at `link_path` (relative to the
+/// tree at `root`, both forward-slash strings) as a relative path, when `target` is an
+/// absolute path inside `root`; otherwise `None`.
+fn relative_link_target(link_path: &str, target: &str, root: &str) -> Option<String> {
+    let inside = target.strip_prefix(root.trim_end_matches('/'))?;
+    if !inside.is_empty() && !inside.starts_with('/') {
+        return None;
+    }
+    let target_parts: Vec<&str> = inside.split('/').filter(|p| !p.is_empty() && *p != ".").collect();
+    // `..` can't be resolved without following the links in between.
+    if target_parts.contains(&"..") {
+        return None;
+    }
+    let mut dir_parts: Vec<&str> = link_path.split('/').collect();
+    dir_parts.pop();
+    let common = dir_parts
+        .iter()
+        .zip(&target_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let parts: Vec<&str> = std::iter::repeat_n("..", dir_parts.len() - common)
+        .chain(target_parts[common..].iter().copied())
+        .collect();
+    Some(if parts.is_empty() { ".".to_string() } else { parts.join("/") })
+}
+
+/// Progress milestones reported by [`create_patch`]. Each carries its denominator, so a
+/// front-end can show "hashed 340/5000 files" rather than an open-ended spinner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreateProgress {
+    /// Both sides walked and filtered; `files_to_hash` files will be hashed.
+    Walked {
+        old_entries: usize,
+        new_entries: usize,
+        files_to_hash: usize,
+    },
+    /// One more file hashed (and diffed, if modified).
+    Hashed { done: usize, total: usize },
+}
+
+/// Receives [`CreateProgress`] events. Called from Rayon worker threads, so it must be
+/// cheap and thread-safe.
+#[derive(Clone)]
+pub struct ProgressCallback(pub Arc<dyn Fn(CreateProgress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new(f: impl Fn(CreateProgress) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Shared counter behind the `Hashed` events of both hashing stages.
+#[derive(Clone)]
+struct HashTicker {
+    done: Arc<AtomicUsize>,
+    total: usize,
+    progress: Option<ProgressCallback>,
+}
+
+impl HashTicker {
+    fn tick(&self) {
+        if let Some(progress) = &self.progress {
+            let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+            (progress.0)(CreateProgress::Hashed {
+                done,
+                total: self.total,
+            });
+        }
+    }
+}
+
+/// How a confirmed-modified file is shipped.
+enum Change {
+    /// Binary diff against the old content (ModifyFile), with its block size, the
+    /// recompress marker when it is a diff of decompressed content, what the diffed
+    /// content was classified as (for `--verbose`) and the old file's hash.
+    Diff(Vec<DiffChunk>, u32, Option<Recompress>, ContentKind, [u8; 32]),
+    /// One diff per distinct old version across several bases (ModifyFileMulti).
+    Multi(Vec<BaseDiff>),
+    /// Full new content (AddFile overwriting the old file), used when no diff is possible.
+    /// Holds the new file's path; the content is read only when the op is written.
+    Replace(std::path::PathBuf),
+    /// Content is identical; only reported when recording VerifyFile ops.
+    Unchanged,
+    /// Content is identical but the new mode and/or mtime differ (SetMetadata).
+    Metadata(MetadataChange),
+}
+
+/// New (mode, mtime) for a content-identical file; `None` where the two sides agree.
+type MetadataChange = (Option<u32>, Option<std::time::SystemTime>);
+
+/// The metadata of `new` that differs from `old`, if any. Fields either side lacks
+/// (snapshot entries, non-Unix modes) are not compared.
+fn metadata_change(old: &util::DirEntry, new: &util::DirEntry) -> Option<MetadataChange> {
+    let mode = match (old.mode, new.mode) {
+        (Some(o), Some(n)) if o != n => Some(n),
+        _ => None,
+    };
+    let mtime = match (old.modified, new.modified) {
+        (Some(o), Some(n)) if o != n => Some(n),
+        _ => None,
+    };
+    (mode.is_some() || mtime.is_some()).then_some((mode, mtime))
+}
+
+/// (relative path, change, new hash) for a file present on both sides.
+type ModifyResult = (String, Change, [u8; 32]);
+/// A file only present on the new side.
+struct AddInput {
+    rel_path: String,
+    full_path: std::path::PathBuf,
+    size: u64,
+    modified: Option<std::time::SystemTime>,
+}
+
+/// A deleted file as written out: (path, old hash, whether some base lacks it).
+type HashedDelete = (String, Option<[u8; 32]>, bool);
+
+/// A file only present on the old side.
+struct DeleteInput {
+    rel_path: String,
+    /// The file in each base that has it, to hash for `--verify-before`.
+    copies: Vec<util::DirEntry>,
+    /// Whether some base lacks the file (see `DeleteFile::may_be_absent`).
+    may_be_absent: bool,
+}
+
+/// A file present on both sides, to hash and possibly diff.
+struct DiffInput {
+    rel_path: String,
+    old_path: std::path::PathBuf,
+    new_path: std::path::PathBuf,
+    sizes_differ: bool,
+    /// Modification times differ, or one side doesn't record them (`--compare-only --fast`).
+    mtimes_differ: bool,
+    /// Recorded hash of the old file when the old side is a snapshot.
+    old_hash: Option<[u8; 32]>,
+    new_size: u64,
+    /// Old file's size when walked; `None` for a snapshot base, which has no file.
+    old_size: Option<u64>,
+    /// Modification times when walked, to look the files up in the `--hash-cache`.
+    old_modified: Option<std::time::SystemTime>,
+    new_modified: Option<std::time::SystemTime>,
+    /// Same size and not modified since `--since`: taken as unchanged unhashed.
+    assume_unchanged: bool,
+    /// (base index, path) of the file in each further base, for multi-base patches.
+    extra_old: Vec<(u32, std::path::PathBuf)>,
+    /// With `--metadata`: what to set if the content turns out to be unchanged.
+    metadata: Option<MetadataChange>,
+}
+
+/// Added files are read and hashed in parallel batches of about this many bytes, then
+/// written in path order, so create's memory use is bounded by the batch (or by the
+/// largest single file) rather than by the total size of everything added.
+const ADD_BATCH_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Modified files are diffed in windows of about this many bytes (old plus new size) in
+/// path order, and each window's results are written before they pile up: memory holds a
+/// few windows' diffs, not every modified file's.
+const DIFF_WINDOW_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Split `inputs` into consecutive batches of at most `batch_bytes` by `size`; an input
+/// larger than that gets a batch of its own.
+fn batches<T>(inputs: &[T], batch_bytes: u64, size: impl Fn(&T) -> u64) -> Vec<&[T]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0u64;
+    for (i, input) in inputs.iter().enumerate() {
+        let input_size = size(input);
+        if i > start && bytes + input_size > batch_bytes {
+            batches.push(&inputs[start..i]);
+            start = i;
+            bytes = 0;
+        }
+        bytes += input_size;
+    }
+    if start < inputs.len() {
+        batches.push(&inputs[start..]);
+    }
+    batches
+}
+
+/// Bytes sampled from the start of a file to decide whether it is text.
+const TEXT_SAMPLE_BYTES: usize = 8192;
+
+/// Text files above this size are left out of the changelog: a line diff of them would
+/// be slow to compute and too long to read.
+const CHANGELOG_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+/// Whether a file's content is text or binary, from [`sniff_content`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ContentKind {
+    Text,
+    Binary,
+}
+
+impl std::fmt::Display for ContentKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ContentKind::Text => "text",
+            ContentKind::Binary => "binary",
+        })
+    }
+}
+
+/// Classify `data` by its first [`TEXT_SAMPLE_BYTES`], whatever the file is called: a
+/// NUL byte, or more than one control character in ten, makes it binary. Tabs, line
+/// and page breaks, backspace and escape (ANSI colours in logs) count as text, as do
+/// bytes from 0x80 up, so UTF-8 and legacy 8-bit encodings are text too.
+fn sniff_content(data: &[u8]) -> ContentKind {
+    let sample = &data[..data.len().min(TEXT_SAMPLE_BYTES)];
+    let is_control = |b: &u8| (*b < 0x20 && !b"\t\n\r\x0c\x08\x1b".contains(b)) || *b == 0x7f;
+    if sample.contains(&0) || sample.iter().filter(|b| is_control(b)).count() * 10 > sample.len() {
+        ContentKind::Binary
+    } else {
+        ContentKind::Text
+    }
+}
+
+/// Block size for diffing content of `kind` against `old_len` old bytes.
+fn diff_block_size(kind: ContentKind, old_len: usize) -> usize {
+    match kind {
+        ContentKind::Text => binary_diff::text_block_size_for(old_len),
+        ContentKind::Binary => binary_diff::block_size_for(old_len),
+    }
+}
+
+/// Size of `chunks` as they'd be stored: encoded and zstd-compressed.
+fn stored_size(chunks: &[DiffChunk]) -> Result<usize> {
+    let encoded = bincode::serialize(chunks).context("Failed to serialize diff")?;
+    Ok(zstd::bulk::compress(&encoded, 3).context("Failed to compress diff")?.len())
+}
+
+/// `--explain`: diff `new` against `old` with each candidate strategy (matching blocks
+/// of the size for `kind` and of the size for the other kind, and storing the file
+/// whole), keep the one that stores smallest and describe the choice. A tie goes to
+/// the strategy create picks without `--explain`.
+/// Block matching honours `min_match_len` and gives up with `None` once `deadline`
+/// passes, like [`diff_before`].
+fn smallest_diff(
+    old: &[u8],
+    new: &[u8],
+    kind: ContentKind,
+    min_match_len: Option<usize>,
+    deadline: Option<Instant>,
+) -> Result<Option<(Vec<DiffChunk>, u32, String)>> {
+    let mut block_sizes = vec![diff_block_size(kind, old.len())];
+    let other = match kind {
+        ContentKind::Text => binary_diff::block_size_for(old.len()),
+        ContentKind::Binary => binary_diff::text_block_size_for(old.len()),
+    };
+    if other != block_sizes[0] {
+        block_sizes.push(other);
+    }
+    let mut candidates = Vec::new();
+    for block_size in block_sizes {
+        let Some(chunks) = diff_before(old, new, block_size, min_match_len, deadline) else {
+            return Ok(None);
+        };
+        let size = stored_size(&chunks)?;
+        candidates.push((format!("{}-byte blocks", block_size), chunks, block_size as u32, size));
+    }
+    let whole = vec![DiffChunk::Insert { data: new.to_vec() }];
+    let size = stored_size(&whole)?;
+    candidates.push(("whole file".to_string(), whole, 0, size));
+
+    let best = (0..candidates.len())
+        .min_by_key(|&i| candidates[i].3)
+        .expect("at least two candidates");
+    let show = |size: usize| match size {
+        0..1024 => format!("{} B", size),
+        _ => format!("{:.1} KB", size as f64 / 1024.0),
+    };
+    let others = candidates
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != best)
+        .map(|(_, (name, _, _, size))| format!("{} {}", name, show(*size)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let (name, chunks, block_size, size) = candidates.swap_remove(best);
+    Ok(Some((chunks, block_size, format!("chose {} ({} vs {})", name, show(size), others))))
+}
+
+/// Block-match `new` against `old`, emitting Copies of at least `min_match_len` bytes
+/// (`--min-match`, otherwise the default for the block size), and giving up with `None`
+/// once `deadline` (from `--diff-timeout`) has passed.
+fn diff_before(
+    old: &[u8],
+    new: &[u8],
+    block_size: usize,
+    min_match_len: Option<usize>,
+    deadline: Option<Instant>,
+) -> Option<Vec<DiffChunk>> {
+    let min_match_len = min_match_len.unwrap_or_else(|| binary_diff::min_match_len_for(block_size));
+    match deadline {
+        None => Some(binary_diff::compute_diff_with_min_match(old, new, block_size, min_match_len)),
+        Some(deadline) => binary_diff::compute_diff_before(old, new, block_size, min_match_len, deadline),
+    }
+}
+
+/// Unified diff of a modified file for the changelog, or `None` when either side is
+/// binary or too large.
+fn text_diff(rel_path: &str, old_path: &Path, new_path: &Path) -> Result<Option<String>> {
+    let old = util::mmap_file(old_path)?;
+    let new = util::mmap_file(new_path)?;
+    if [&old, &new]
+        .iter()
+        .any(|data| data.len() > CHANGELOG_MAX_BYTES || sniff_content(data) == ContentKind::Binary)
+    {
+        return Ok(None);
+    }
+    let (old, new) = (String::from_utf8_lossy(&old), String::from_utf8_lossy(&new));
+    let diff = similar::TextDiff::from_lines(old.as_ref(), new.as_ref());
+    Ok(Some(
+        diff.unified_diff()
+            .header(&format!("a/{}", rel_path), &format!("b/{}", rel_path))
+            .to_string(),
+    ))
+}
+
+/// Map `f` over `inputs` on the Rayon pool, holding `weight(input)` bytes of `budget`
+/// for each call. Reservations are taken here on the calling thread, which must not be
+/// a Rayon worker: a worker blocked on the budget could starve the jobs that would
+/// free it. Results come back in completion order.
+fn par_map_budgeted<I: Sync, T: Send>(
+    inputs: &[I],
+    budget: &util::ByteBudget,
+    weight: impl Fn(&I) -> u64,
+    f: impl Fn(&I) -> T + Sync,
+) -> Vec<T> {
+    let results = std::sync::Mutex::new(Vec::with_capacity(inputs.len()));
+    let (results_ref, f) = (&results, &f);
+    rayon::in_place_scope(|scope| {
+        for input in inputs {
+            let reserved = budget.acquire(weight(input));
+            scope.spawn(move |_| {
+                let result = f(input);
+                drop(reserved);
+                results_ref.lock().unwrap_or_else(|e| e.into_inner()).push(result);
+            });
+        }
+    });
+    results.into_inner().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Old side of a comparison: walked entries, entries skipped as unreadable, plus recorded
+/// hashes when `old` is a snapshot.
+type OldSide = (
+    Vec<util::DirEntry>,
+    Vec<util::SkippedEntry>,
+    Option<HashMap<String, [u8; 32]>>,
+);
+
+/// Relative paths under `root` of the patch being written to `output` and of its gzip
+/// temp file, when `output` lies inside the `root` directory. The output may not exist
+/// yet, so its parent is canonicalized instead of the file itself.
+fn output_paths_inside(output: &Path, root: &Path) -> Vec<String> {
+    let Some(name) = output.file_name() else {
+        return Vec::new();
+    };
+    let parent = match output.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let (Ok(parent), Ok(root)) = (parent.canonicalize(), root.canonicalize()) else {
+        return Vec::new();
+    };
+    let Ok(dir) = parent.strip_prefix(&root) else {
+        return Vec::new();
+    };
+    let relative = dir.join(name).to_string_lossy().replace('\\', "/");
+    vec![format!("{}.tmp", relative), relative]
+}
+
+/// Whether `path` is a part file (`<base>.001`...) of the split patch `base`.
+fn is_split_part(base: &str, path: &str) -> bool {
+    path.strip_prefix(base)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .is_some_and(|n| n.len() >= 3 && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// A file no longer matches the size the walk recorded: it was written to or removed
+/// while the patch was being created.
+#[derive(Debug, thiserror::Error)]
+#[error("{path} changed while the patch was being created ({detail})")]
+struct FileChanged {
+    path: String,
+    detail: String,
+}
+
+/// Fail with [`FileChanged`] unless `path` still has the `recorded` size.
+fn ensure_size(path: &Path, rel_path: &str, recorded: u64) -> Result<()> {
+    let detail = match std::fs::metadata(path) {
+        Ok(meta) if meta.len() == recorded => return Ok(()),
+        Ok(meta) => format!("{} bytes when walked, {} now", recorded, meta.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => "deleted".to_string(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to stat file: {}", path.display()))
+        }
+    };
+    bail!(FileChanged {
+        path: rel_path.to_string(),
+        detail,
+    })
+}
+
+/// Whether `err` means the file changed or vanished under create (see [`FileChanged`]).
+fn changed_under_us(err: &anyhow::Error) -> bool {
+    err.is::<FileChanged>()
+        || err
+            .root_cause()
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// With `skip`, turn a [`changed_under_us`] failure into a warning and `None`.
+fn skip_if_changed<T>(result: Result<T>, rel_path: &str, skip: bool) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if skip && changed_under_us(&err) => {
+            let detail = match err.downcast_ref::<FileChanged>() {
+                Some(changed) => changed.detail.as_str(),
+                None => "deleted",
+            };
+            eprintln!(
+                "warning: skipped {}: changed while the patch was being created ({})",
+                rel_path, detail
+            );
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Load the old side, either by walking a directory or by reading a snapshot file.
+fn load_old_side(old: &Path, hash_algo: HashAlgo, walk: util::WalkOptions) -> Result<OldSide> {
+    if !snapshot::is_snapshot_file(old) {
+        let (entries, skipped) = util::walk_directory_with(old, walk)?;
+        return Ok((entries, skipped, None));
+    }
+
+    let snap = snapshot::read_snapshot(old)?;
+    if snap.hash_algo != hash_algo {
+        bail!(
+            "Snapshot {} was recorded with {} but the patch uses {}; pass --hash {}",
+            old.display(),
+            snap.hash_algo,
+            hash_algo,
+            snap.hash_algo
+        );
+    }
+    let mut hashes = HashMap::with_capacity(snap.entries.len());
+    let entries = snap
+        .entries
+        .into_iter()
+        .map(|e| {
+            let path = util::normalize_relative_path(&e.path)
+                .with_context(|| format!("Invalid entry in snapshot {}", old.display()))?;
+            if e.kind != EntryKind::Dir {
+                hashes.insert(path.clone(), e.hash);
+            }
+            Ok(util::DirEntry {
+                relative_path: path,
+                kind: e.kind,
+                // No content on disk: the snapshot only records paths, sizes and hashes.
+                full_path: std::path::PathBuf::new(),
+                link_target: None,
+                size: e.size,
+                hardlink_id: None,
+                modified: None,
+                mode: None,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok((entries, Vec::new(), Some(hashes)))
+}
+
+/// The extended attributes to record for `path`: none unless `preserve` is set. Where
+/// they can't be read (build or filesystem without support), warns once per create,
+/// via `warned`, and records none.
+fn xattrs_of(path: &Path, preserve: bool, warned: &AtomicBool) -> Result<util::Xattrs> {
+    if !preserve {
+        return Ok(Vec::new());
+    }
+    match util::read_xattrs(path)? {
+        Some(xattrs) => Ok(xattrs),
+        None => {
+            if !warned.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "warning: extended attributes aren't supported here ({}); none are recorded",
+                    path.display()
+                );
+            }
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// `println!` for verbose lines, switched to stderr when the patch itself goes to stdout.
+macro_rules! say {
+    ($to_stderr:expr, $($arg:tt)*) => {
+        if $to_stderr {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// Where [`create`] sends the patch: a file path (`-` for stdout), or memory.
+#[derive(Clone, Copy)]
+enum Destination<'a> {
+    Path(&'a Path),
+    Memory,
+}
+
+/// The seekable stream a [`PatchWriter`] writes to.
+enum Sink {
+    File(std::io::BufWriter<std::fs::File>),
+    Memory(std::io::Cursor<Vec<u8>>),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::File(f) => f.write(buf),
+            Sink::Memory(m) => m.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::File(f) => f.flush(),
+            Sink::Memory(m) => m.flush(),
+        }
+    }
+}
+
+impl std::io::Seek for Sink {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Sink::File(f) => f.seek(pos),
+            Sink::Memory(m) => m.seek(pos),
+        }
+    }
+}
+
+/// A batch of results handed to the writer, with the memory reservation covering them.
+type Batch<T> = (Vec<T>, Option<util::OwnedBudgetGuard>);
+
+/// The items of the batches arriving on `rx`, one at a time. A batch's reservation is
+/// held until its last item has been taken and written, and released before waiting
+/// for the next batch.
+fn received<T>(rx: &std::sync::mpsc::Receiver<Batch<T>>) -> std::iter::Peekable<Received<'_, T>> {
+    Received {
+        rx,
+        items: Vec::new().into_iter(),
+        reserved: None,
+    }
+    .peekable()
+}
+
+struct Received<'a, T> {
+    rx: &'a std::sync::mpsc::Receiver<Batch<T>>,
+    items: std::vec::IntoIter<T>,
+    reserved: Option<util::OwnedBudgetGuard>,
+}
+
+impl<T> Iterator for Received<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some(item) = self.items.next() {
+                return Some(item);
+            }
+            self.reserved = None;
+            let (items, reserved) = self.rx.recv().ok()?;
+            self.items = items.into_iter();
+            self.reserved = reserved;
+        }
+    }
+}
+
+/// What [`write_files`] needs from `create` to write a file's operation.
+struct WriteContext {
+    new_dir: PathBuf,
+    verbose: bool,
+    to_stderr: bool,
+    preserve_xattrs: bool,
+    xattrs_warned: Arc<AtomicBool>,
+    explanations: Option<Arc<std::sync::Mutex<HashMap<String, String>>>>,
+    objects_dir: Option<PathBuf>,
+    overrides: CompressionOverrides,
+}
+
+/// The modified-file results [`write_files`] leaves for later categories.
+struct Written {
+    files_modified: usize,
+    unchanged_files: Vec<(String, [u8; 32])>,
+    metadata_changes: Vec<(String, MetadataChange)>,
+}
+
+/// Write the AddFile operations in `added` and the ModifyFile operations (or full-content
+/// AddFiles) for `modified` as they arrive, merged into one path order. Both streams
+/// come in path order, so the patch is the same however the parallel stages ran.
+fn write_files(
+    writer: &mut OutputPatch,
+    mut added: std::iter::Peekable<impl Iterator<Item = PatchOp>>,
+    mut modified: std::iter::Peekable<impl Iterator<Item = ModifyResult>>,
+    context: &WriteContext,
+) -> Result<Written> {
+    let to_stderr = context.to_stderr;
+    let mut written = Written {
+        files_modified: 0,
+        unchanged_files: Vec::new(),
+        metadata_changes: Vec::new(),
+    };
+    loop {
+        let add_first = match (added.peek(), modified.peek()) {
+            (None, None) => return Ok(written),
+            (Some(op), Some((path, ..))) => op.path() < path.as_str(),
+            (add, _) => add.is_some(),
+        };
+        if add_first {
+            let op = added.next().expect("peeked");
+            if context.verbose {
+                say!(to_stderr, "+ added {}", op.path());
+            }
+            writer.write_op(&op)?;
+            continue;
+        }
+        let (path, change, new_hash) = modified.next().expect("peeked");
+        match change {
+            Change::Unchanged => written.unchanged_files.push((path, new_hash)),
+            Change::Metadata(change) => written.metadata_changes.push((path, change)),
+            Change::Diff(diff_chunks, block_size, recompress, kind, old_hash) => {
+                written.files_modified += 1;
+                if context.verbose {
+                    let (copies, inserts) = chunk_counts(&diff_chunks);
+                    say!(
+                        to_stderr,
+                        "~ modified {} ({}, {} copy, {} insert chunks{})",
+                        path,
+                        kind,
+                        copies,
+                        inserts,
+                        if recompress.is_some() { ", decompressed" } else { "" }
+                    );
+                }
+                let explanation = context
+                    .explanations
+                    .as_ref()
+                    .and_then(|e| e.lock().unwrap_or_else(|e| e.into_inner()).remove(&path));
+                if let Some(explanation) = explanation {
+                    say!(to_stderr, "{}: {}", path, explanation);
+                }
+                let xattrs = xattrs_of(
+                    &util::join_relative(&context.new_dir, &path),
+                    context.preserve_xattrs,
+                    &context.xattrs_warned,
+                )?;
+                writer.write_op(&PatchOp::ModifyFile {
+                    path,
+                    diff_chunks,
+                    new_blake3_hash: new_hash,
+                    block_size,
+                    recompress,
+                    xattrs,
+                    old_blake3_hash: Some(old_hash),
+                })?;
+            }
+            Change::Multi(variants) => {
+                written.files_modified += 1;
+                if context.verbose {
+                    say!(to_stderr, "~ modified {} ({} base versions)", path, variants.len());
+                }
+                writer.write_op(&PatchOp::ModifyFileMulti {
+                    path,
+                    variants,
+                    new_blake3_hash: new_hash,
+                })?;
+            }
+            Change::Replace(new_path) => {
+                written.files_modified += 1;
+                if context.verbose {
+                    say!(to_stderr, "~ modified {} (full content)", path);
+                }
+                let data = util::mmap_file(&new_path)?;
+                let incompressible = is_incompressible(&new_path, &context.overrides);
+                let xattrs = xattrs_of(&new_path, context.preserve_xattrs, &context.xattrs_warned)?;
+                writer.write_op(&content_op(
+                    context.objects_dir.as_deref(),
+                    path,
+                    &data,
+                    new_hash,
+                    incompressible,
+                    xattrs,
+                )?)?;
+            }
+        }
+    }
+}
+
+/// A patch being streamed to its destination. The header is only complete once the
+/// last operation is written, so a patch bound for stdout (`-`) or wrapped in `--gzip`
+/// is first written plain to a temp file (or buffer), which can seek back while a pipe
+/// or gzip stream can't; `finish` then copies (or gzips) it into place.
+/// A `--split-size` patch is written straight to its part files instead.
+struct OutputPatch {
+    writer: Writer,
+    output: Option<PathBuf>,
+    temp: Option<PathBuf>,
+    gzip: bool,
+    /// How many of the largest operations to keep in `largest` (`--stats`); 0 for none.
+    keep_largest: usize,
+    largest: Vec<OpSize>,
+    /// What each operation written does to its path, when recorded (`--print-tree`).
+    touched: Option<Vec<Touched>>,
+}
+
+enum Writer {
+    Whole(PatchWriter<Sink>),
+    Split(SplitPatchWriter),
+}
+
+impl OutputPatch {
+    fn create(
+        dest: Destination,
+        hash_algo: HashAlgo,
+        full_file_set: bool,
+        encoding: ManifestEncoding,
+        mac_key: Option<&MacKey>,
+        gzip: bool,
+        split_size: Option<u64>,
+    ) -> Result<Self> {
+        if let Some(max_part_size) = split_size {
+            let output = match dest {
+                Destination::Path(output) if !util::is_stdio(output) => output,
+                _ => bail!("--split-size needs a file --output"),
+            };
+            if gzip {
+                bail!("--split-size can't be combined with --gzip");
+            }
+            let writer = SplitPatchWriter::create(
+                output,
+                max_part_size,
+                hash_algo,
+                full_file_set,
+                encoding,
+                mac_key,
+            )?;
+            return Ok(Self {
+                writer: Writer::Split(writer),
+                output: Some(output.to_path_buf()),
+                temp: None,
+                gzip,
+                keep_largest: 0,
+                largest: Vec::new(),
+                touched: None,
+            });
+        }
+        let output = match dest {
+            Destination::Path(output) => output,
+            Destination::Memory => {
+                let sink = Sink::Memory(std::io::Cursor::new(Vec::new()));
+                return Ok(Self {
+                    writer: Writer::Whole(PatchWriter::new(
+                        sink,
+                        hash_algo,
+                        full_file_set,
+                        encoding,
+                        mac_key,
+                    )?),
+                    output: None,
+                    temp: None,
+                    gzip,
+                    keep_largest: 0,
+                    largest: Vec::new(),
+                    touched: None,
+                });
+            }
+        };
+        let temp = if util::is_stdio(output) {
+            Some(std::env::temp_dir().join(format!("patcher-{}.patch.tmp", std::process::id())))
+        } else {
+            gzip.then(|| {
+                let mut name = output.file_name().unwrap_or_default().to_os_string();
+                name.push(".tmp");
+                output.with_file_name(name)
+            })
+        };
+        let path = temp.as_deref().unwrap_or(output);
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create output file: {}", path.display()))?;
+        let sink = Sink::File(std::io::BufWriter::new(file));
+        Ok(Self {
+            writer: Writer::Whole(PatchWriter::new(sink, hash_algo, full_file_set, encoding, mac_key)?),
+            output: Some(output.to_path_buf()),
+            temp,
+            gzip,
+            keep_largest: 0,
+            largest: Vec::new(),
+            touched: None,
+        })
+    }
+
+    fn write_op(&mut self, op: &PatchOp) -> Result<()> {
+        if self.keep_largest > 0 {
+            if let Some(size) = OpSize::of(op) {
+                // Ties keep write order: a new one goes after those of equal size.
+                let at = self.largest.partition_point(|kept| kept.bytes >= size.bytes);
+                if at < self.keep_largest {
+                    self.largest.insert(at, size);
+                    self.largest.truncate(self.keep_largest);
+                }
+            }
+        }
+        if let (Some(touched), Some(entry)) = (&mut self.touched, Touched::of(op)) {
+            touched.push(entry);
+        }
+        match &mut self.writer {
+            Writer::Whole(writer) => writer.write_op(op),
+            Writer::Split(writer) => writer.write_op(op),
+        }
+    }
+
+    /// Complete the patch. Returns its bytes for [`Destination::Memory`]; `parts`
+    /// receives the part files of a split patch.
+    fn finish(self, parts: &mut Vec<PathBuf>) -> Result<Option<Vec<u8>>> {
+        let sink = match self.writer {
+            Writer::Whole(writer) => writer.finish()?,
+            Writer::Split(writer) => {
+                *parts = writer.finish()?;
+                return Ok(None);
+            }
+        };
+        if let Sink::Memory(buffer) = sink {
+            let plain = buffer.into_inner();
+            if !self.gzip {
+                return Ok(Some(plain));
+            }
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(&plain)?;
+            return Ok(Some(encoder.finish().context("Failed to finish gzip stream")?));
+        }
+        let (Some(temp), Some(output)) = (self.temp, self.output) else {
+            return Ok(None);
+        };
+        let mut plain = std::fs::File::open(&temp)
+            .with_context(|| format!("Failed to open {}", temp.display()))?;
+        let dest: Box<dyn Write> = if util::is_stdio(&output) {
+            Box::new(std::io::stdout().lock())
+        } else {
+            Box::new(std::fs::File::create(&output).with_context(|| {
+                format!("Failed to create output file: {}", output.display())
+            })?)
+        };
+        let mut dest = std::io::BufWriter::new(dest);
+        if self.gzip {
+            // The payload is already zstd-compressed, so gzip only adds a container for
+            // tooling; the fastest level costs little and gains about as much as any other.
+            let mut encoder = flate2::write::GzEncoder::new(dest, flate2::Compression::fast());
+            std::io::copy(&mut plain, &mut encoder)?;
+            encoder
+                .finish()
+                .context("Failed to finish gzip stream")?
+                .flush()?;
+        } else {
+            std::io::copy(&mut plain, &mut dest).context("Failed to write patch to stdout")?;
+            dest.flush()?;
+        }
+        std::fs::remove_file(&temp)
+            .with_context(|| format!("Failed to remove {}", temp.display()))?;
+        Ok(None)
+    }
+}
+
+/// Write `manifest` as a bincode patch file at `output`, optionally wrapped in gzip.
+pub fn write_manifest(output: &Path, manifest: &PatchManifest, gzip: bool) -> Result<()> {
+    let mut writer = OutputPatch::create(
+        Destination::Path(output),
+        manifest.hash_algo,
+        manifest.full_file_set,
+        ManifestEncoding::Bincode,
+        None,
+        gzip,
+        None,
+    )?;
+    for op in &manifest.operations {
+        writer.write_op(op)?;
+    }
+    writer.finish(&mut Vec::new())?;
+    Ok(())
 }
 
 /// Create a patch file by comparing old_dir and new_dir.
 /// Uses Tokio for concurrent directory walks and Rayon for parallel hashing/diffing.
+///
+/// `old_dir` may also be a snapshot file (see [`snapshot`]). Adds and deletes are then
+/// computed from the recorded path set and modifications detected from the recorded
+/// hashes, but since the old bytes are unavailable every modified file is shipped as a
+/// full-content AddFile instead of a binary diff.
 pub async fn create_patch(
     old_dir: &Path,
     new_dir: &Path,
     output: &Path,
-) -> Result<ApplySummary> {
+    options: &CreateOptions,
+) -> std::result::Result<ApplySummary, PatchError> {
+    let (summary, _) = create(old_dir, new_dir, Destination::Path(output), options).await?;
+    Ok(summary)
+}
+
+/// Like [`create_patch`], but returns the patch bytes instead of writing a file, e.g.
+/// to upload them or hand them to [`apply_patch_bytes`](crate::apply::apply_patch_bytes).
+/// The whole patch is held in memory.
+pub async fn create_patch_bytes(
+    old_dir: &Path,
+    new_dir: &Path,
+    options: &CreateOptions,
+) -> std::result::Result<(Vec<u8>, ApplySummary), PatchError> {
+    let (summary, bytes) = create(old_dir, new_dir, Destination::Memory, options).await?;
+    Ok((bytes.expect("memory destination returns bytes"), summary))
+}
+
+/// What create found by walking and classifying both sides, before any hashing.
+struct Plan {
+    old_count: usize,
+    new_count: usize,
+    /// Total size of the new tree's files.
+    new_bytes: u64,
+    dirs_to_create: Vec<String>,
+    /// Permission bits of the directories to create, where the platform has them.
+    dir_modes: HashMap<String, u32>,
+    /// Sorted by path.
+    add_inputs: Vec<AddInput>,
+    /// Sorted by path.
+    diff_inputs: Vec<DiffInput>,
+    /// Sorted by path.
+    files_to_delete: Vec<DeleteInput>,
+    dirs_to_delete: Vec<String>,
+    /// (path, target), sorted.
+    hardlinks: Vec<(String, String)>,
+    /// (path, target) of the symbolic links to create, sorted.
+    symlinks: Vec<(String, String)>,
+    /// Recorded hashes of the old side's files when it is a snapshot.
+    old_hashes: Option<HashMap<String, [u8; 32]>>,
+}
+
+/// Stages 1 and 2: walk both sides, drop skipped, filtered and output paths, and sort
+/// every path into a category. `output` is the patch file being written, if any.
+async fn plan(
+    old_dir: &Path,
+    new_dir: &Path,
+    output: Option<&Path>,
+    options: &CreateOptions,
+    timer: &mut util::PhaseTimer,
+) -> Result<Plan> {
+    if !snapshot::is_snapshot_file(old_dir) {
+        util::ensure_dir(old_dir, "old directory")?;
+    }
+    util::ensure_dir(new_dir, "new directory")?;
+    for base in &options.extra_bases {
+        util::ensure_dir(base, "old directory")?;
+    }
+
+    let hash_algo = options.hash_algo;
     // Stage 1: Walk both directories concurrently
     let old_dir_owned = old_dir.to_path_buf();
     let new_dir_owned = new_dir.to_path_buf();
 
-    let (old_entries, new_entries) = tokio::try_join!(
-        tokio::task::spawn_blocking(move || util::walk_directory(&old_dir_owned)),
-        tokio::task::spawn_blocking(move || util::walk_directory(&new_dir_owned)),
+    let walk = util::WalkOptions {
+        skip_unreadable: options.skip_unreadable,
+        allow_non_utf8: options.allow_non_utf8,
+    };
+    let extra_bases = options.extra_bases.clone();
+
+    let (old_side, new_side, extra_sides) = tokio::try_join!(
+        tokio::task::spawn_blocking(move || {
+            load_old_side(&old_dir_owned, hash_algo, walk)
+        }),
+        tokio::task::spawn_blocking(move || util::walk_directory_with(&new_dir_owned, walk)),
+        tokio::task::spawn_blocking(move || {
+            extra_bases
+                .iter()
+                .map(|base| {
+                    if snapshot::is_snapshot_file(base) {
+                        bail!(
+                            "Only a single --old may be a snapshot; diffs against further bases need their files: {}",
+                            base.display()
+                        );
+                    }
+                    util::walk_directory_with(base, walk)
+                })
+                .collect::<Result<Vec<_>>>()
+        }),
     )?;
 
-    let old_entries = old_entries?;
-    let new_entries = new_entries?;
+    let (mut old_entries, mut old_skipped, old_hashes) = old_side?;
+    let (mut new_entries, new_skipped) = new_side?;
+    let mut extra_entries: Vec<Vec<util::DirEntry>> = Vec::new();
+    for (entries, skipped) in extra_sides? {
+        extra_entries.push(entries);
+        old_skipped.extend(skipped);
+    }
+    if old_hashes.is_some() && !extra_entries.is_empty() {
+        bail!("Only a single --old may be a snapshot; diffs against further bases need their files");
+    }
+
+    // A subtree unreadable on either side is left out on both: otherwise its contents
+    // would look deleted (or added) when they're merely hidden from us.
+    let mut protected_dirs: HashSet<String> = HashSet::new();
+    if !old_skipped.is_empty() || !new_skipped.is_empty() {
+        let mut skipped_roots: Vec<String> = Vec::new();
+        for (side, skipped) in [("old", &old_skipped), ("new", &new_skipped)] {
+            for s in skipped.iter() {
+                eprintln!(
+                    "warning: skipped unreadable {} entry {}: {}",
+                    side, s.relative_path, s.reason
+                );
+                let mut cur = s.relative_path.as_str();
+                while let Some(idx) = cur.rfind('/') {
+                    cur = &cur[..idx];
+                    protected_dirs.insert(cur.to_string());
+                }
+                skipped_roots.push(s.relative_path.clone());
+            }
+        }
+        let is_skipped = |path: &str| {
+            skipped_roots.iter().any(|root| {
+                path == root
+                    || (path.starts_with(root.as_str()) && path.as_bytes()[root.len()] == b'/')
+            })
+        };
+        old_entries.retain(|e| !is_skipped(&e.relative_path));
+        new_entries.retain(|e| !is_skipped(&e.relative_path));
+        for entries in &mut extra_entries {
+            entries.retain(|e| !is_skipped(&e.relative_path));
+        }
+    }
+
+    // A patch written into one of the trees would otherwise diff itself, half-written.
+    // Leave it (and its gzip temp file, or its parts) out of every side; an old
+    // directory holding it is protected like one holding filtered-out entries below.
+    if let Some(output) = output {
+        let roots = std::iter::once(old_dir)
+            .chain(options.extra_bases.iter().map(PathBuf::as_path))
+            .zip(std::iter::once(&mut old_entries).chain(extra_entries.iter_mut()))
+            .chain(std::iter::once((new_dir, &mut new_entries)));
+        for (root, entries) in roots {
+            let own = output_paths_inside(output, root);
+            if own.is_empty() {
+                continue;
+            }
+            let is_own = |path: &String| {
+                own.contains(path)
+                    || (options.split_size.is_some() && is_split_part(&own[1], path))
+            };
+            if entries.iter().any(|e| is_own(&e.relative_path)) {
+                let mut cur = own[1].as_str();
+                while let Some(idx) = cur.rfind('/') {
+                    cur = &cur[..idx];
+                    protected_dirs.insert(cur.to_string());
+                }
+            }
+            entries.retain(|e| !is_own(&e.relative_path));
+        }
+    }
+
+    // Apply include/exclude filters. An old directory holding filtered-out entries must
+    // never be emitted as DeleteDir, because apply removes deleted subtrees wholesale and
+    // would take the filtered-out content with it.
+    let filter = PathFilter::new(&options.include, &options.exclude)?;
+    if !filter.is_empty() {
+        for entry in old_entries.iter().chain(extra_entries.iter().flatten()) {
+            if !filter.allows(&entry.relative_path) {
+                let mut cur = entry.relative_path.as_str();
+                while let Some(idx) = cur.rfind('/') {
+                    cur = &cur[..idx];
+                    protected_dirs.insert(cur.to_string());
+                }
+            }
+        }
+        old_entries.retain(|e| filter.allows(&e.relative_path));
+        new_entries.retain(|e| filter.allows(&e.relative_path));
+        for entries in &mut extra_entries {
+            entries.retain(|e| filter.allows(&e.relative_path));
+        }
+    }
+
+    // Links are compared, and stored, in the form --symlink-mode asks for, on every side.
+    if options.symlink_mode == SymlinkMode::Relative {
+        let sides = std::iter::once((old_dir, &mut old_entries))
+            .chain(options.extra_bases.iter().map(PathBuf::as_path).zip(extra_entries.iter_mut()))
+            .chain(std::iter::once((new_dir, &mut new_entries)));
+        for (root, entries) in sides {
+            let roots = [Some(root.to_path_buf()), root.canonicalize().ok()];
+            let roots: Vec<String> = roots
+                .iter()
+                .flatten()
+                .filter_map(|root| util::path_to_string(root, true))
+                .map(|root| root.replace('\\', "/"))
+                .collect();
+            for entry in entries.iter_mut() {
+                let Some(target) = &entry.link_target else { continue };
+                if let Some(relative) = roots
+                    .iter()
+                    .find_map(|root| relative_link_target(&entry.relative_path, target, root))
+                {
+                    entry.link_target = Some(relative);
+                }
+            }
+        }
+    }
+
+    // With several bases, each base's paths join the old side: a path any base has and
+    // `new` lacks is deleted, and a new file some base lacks is shipped in full.
+    let primary_len = old_entries.len();
+    let extra_maps: Vec<HashMap<String, usize>> = extra_entries
+        .iter()
+        .map(|entries| {
+            entries
+                .iter()
+                .enumerate()
+                .map(|(i, e)| (e.relative_path.clone(), i))
+                .collect()
+        })
+        .collect();
+    if !extra_entries.is_empty() {
+        let mut seen: HashSet<String> =
+            old_entries.iter().map(|e| e.relative_path.clone()).collect();
+        for entry in extra_entries.iter().flatten() {
+            if seen.insert(entry.relative_path.clone()) {
+                old_entries.push(entry.clone());
+            }
+        }
+    }
+    let in_every_base = |old_idx: usize, kind: &EntryKind| {
+        let path = &old_entries[old_idx].relative_path;
+        old_idx < primary_len
+            && old_entries[old_idx].kind == *kind
+            && extra_maps
+                .iter()
+                .zip(&extra_entries)
+                .all(|(map, entries)| map.get(path).is_some_and(|&i| entries[i].kind == *kind))
+    };
+
+    timer.mark("walk");
 
     // Stage 2: Classify changes using index-based lookups (no references across spawn_blocking)
     let old_map: HashMap<String, usize> = old_entries
@@ -82,182 +1435,1016 @@ pub async fn create_patch(
     let old_paths = util::path_set(&old_entries);
     let new_paths = util::path_set(&new_entries);
 
+    // Every base's entry at `path`, primary first. Primary entries come first in
+    // `old_entries`, so `old_map` gives the primary base's own entry whenever it has one.
+    let base_entries = |path: &str| -> Vec<&util::DirEntry> {
+        let primary = old_map.get(path).filter(|&&i| i < primary_len).map(|&i| &old_entries[i]);
+        let extras = extra_maps
+            .iter()
+            .zip(&extra_entries)
+            .filter_map(|(map, entries)| map.get(path).map(|&i| &entries[i]));
+        primary.into_iter().chain(extras).collect()
+    };
+    // Whether `base` is the link `new` is, by target (or, from a snapshot, its hash).
+    let same_link = |base: &util::DirEntry, new: &util::DirEntry| {
+        let target = new.link_target.as_deref().unwrap_or_default();
+        base.kind == EntryKind::Symlink
+            && match (&base.link_target, &old_hashes) {
+                (Some(old), _) => old == target,
+                (None, Some(hashes)) => {
+                    hashes.get(&base.relative_path) == Some(&util::link_hash(hash_algo, target))
+                }
+                (None, None) => false,
+            }
+    };
+
     let mut dirs_to_create: Vec<String> = Vec::new();
     let mut files_to_add: Vec<usize> = Vec::new(); // indices into new_entries
     let mut files_maybe_modified: Vec<(usize, usize)> = Vec::new(); // (old_idx, new_idx)
-    let mut files_to_delete: Vec<String> = Vec::new();
+    let mut files_to_delete: Vec<DeleteInput> = Vec::new();
     let mut dirs_to_delete: Vec<String> = Vec::new();
+    let mut symlinks: Vec<usize> = Vec::new(); // indices into new_entries
 
     for path in new_paths.difference(&old_paths) {
         let idx = new_map[path];
         match new_entries[idx].kind {
             EntryKind::Dir => dirs_to_create.push(path.clone()),
             EntryKind::File => files_to_add.push(idx),
+            EntryKind::Symlink => symlinks.push(idx),
         }
     }
 
     for path in old_paths.difference(&new_paths) {
         let idx = old_map[path];
         match old_entries[idx].kind {
+            EntryKind::Dir if protected_dirs.contains(path) => {}
             EntryKind::Dir => dirs_to_delete.push(path.clone()),
-            EntryKind::File => files_to_delete.push(path.clone()),
+            EntryKind::File | EntryKind::Symlink => {
+                let copies: Vec<util::DirEntry> = base_entries(path)
+                    .into_iter()
+                    .filter(|e| e.kind != EntryKind::Dir)
+                    .cloned()
+                    .collect();
+                files_to_delete.push(DeleteInput {
+                    rel_path: path.clone(),
+                    may_be_absent: copies.len() < 1 + extra_entries.len(),
+                    copies,
+                });
+            }
         }
     }
+    files_to_delete.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
 
     for path in old_paths.intersection(&new_paths) {
         let old_idx = old_map[path];
         let new_idx = new_map[path];
-        if old_entries[old_idx].kind == EntryKind::File
-            && new_entries[new_idx].kind == EntryKind::File
-        {
-            files_maybe_modified.push((old_idx, new_idx));
+        let new_kind = &new_entries[new_idx].kind;
+        let bases = base_entries(path);
+        // Apply replaces a file with a link and a link with a file, but a directory would
+        // have to be emptied first, and its replacement written only after that.
+        let non_dir = std::iter::once(new_kind)
+            .chain(bases.iter().map(|e| &e.kind))
+            .find(|kind| **kind != EntryKind::Dir);
+        if let Some(non_dir) = non_dir.filter(|_| {
+            *new_kind == EntryKind::Dir || bases.iter().any(|e| e.kind == EntryKind::Dir)
+        }) {
+            let other = match non_dir {
+                EntryKind::Symlink => "symbolic link",
+                _ => "file",
+            };
+            bail!("{} is a directory on one side and a {} on the other, which a patch can't express", path, other);
+        }
+        if *new_kind == EntryKind::Symlink || bases.iter().any(|e| e.kind == EntryKind::Symlink) {
+            if *new_kind == EntryKind::File {
+                files_to_add.push(new_idx);
+                continue;
+            }
+            // Listed even when unchanged with --full-verify, so verify checks the link
+            // and --prune keeps it.
+            let unchanged = bases.len() == 1 + extra_entries.len()
+                && bases.iter().all(|base| same_link(base, &new_entries[new_idx]));
+            if !unchanged || options.full_verify {
+                symlinks.push(new_idx);
+            }
+            continue;
+        }
+        if in_every_base(old_idx, new_kind) {
+            if *new_kind == EntryKind::File {
+                files_maybe_modified.push((old_idx, new_idx));
+            }
+        } else if !extra_entries.is_empty() {
+            match new_kind {
+                EntryKind::Dir => dirs_to_create.push(path.clone()),
+                _ => files_to_add.push(new_idx),
+            }
+        }
+    }
+    // (path, target), sorted.
+    let mut symlinks: Vec<(String, String)> = symlinks
+        .into_iter()
+        .map(|ni| {
+            let entry = &new_entries[ni];
+            (entry.relative_path.clone(), entry.link_target.clone().unwrap_or_default())
+        })
+        .collect();
+    symlinks.sort();
+    let dir_modes = dirs_to_create
+        .iter()
+        .filter_map(|path| Some((path.clone(), new_entries[new_map[path]].mode?)))
+        .collect();
+    files_to_add.sort_by(|&a, &b| new_entries[a].relative_path.cmp(&new_entries[b].relative_path));
+
+    // Added files that are hard links to another file in the new tree become
+    // CreateHardlink ops pointing at the group's first path, instead of duplicate content.
+    // The target either is added itself or already exists on both sides.
+    let mut hardlinks: Vec<(String, String)> = Vec::new(); // (path, target)
+    if options.preserve_hardlinks {
+        let mut link_targets: HashMap<(u64, u64), &str> = HashMap::new();
+        for entry in &new_entries {
+            if let Some(id) = entry.hardlink_id {
+                let target = link_targets.entry(id).or_insert(&entry.relative_path);
+                if entry.relative_path.as_str() < *target {
+                    *target = &entry.relative_path;
+                }
+            }
         }
+        files_to_add.retain(|&ni| {
+            let entry = &new_entries[ni];
+            match entry.hardlink_id.and_then(|id| link_targets.get(&id)) {
+                Some(&target) if target != entry.relative_path => {
+                    hardlinks.push((entry.relative_path.clone(), target.to_string()));
+                    false
+                }
+                _ => true,
+            }
+        });
+        hardlinks.sort();
     }
 
     // Stage 3+4 merged: stream-hash to confirm changes, then mmap+diff only confirmed-modified files.
     // If sizes differ the file is definitely changed: skip hashing old (saves one file read).
-    struct DiffInput {
-        rel_path: String,
-        old_path: std::path::PathBuf,
-        new_path: std::path::PathBuf,
-        sizes_differ: bool,
-    }
 
-    let diff_inputs: Vec<DiffInput> = files_maybe_modified
+    let since = options.since;
+    let record_metadata = options.metadata && extra_entries.is_empty();
+
+    let mut diff_inputs: Vec<DiffInput> = files_maybe_modified
         .iter()
-        .map(|&(oi, ni)| DiffInput {
-            rel_path: old_entries[oi].relative_path.clone(),
-            old_path: old_entries[oi].full_path.clone(),
-            new_path: new_entries[ni].full_path.clone(),
-            sizes_differ: old_entries[oi].size != new_entries[ni].size,
+        .map(|&(oi, ni)| {
+            let sizes_differ = old_entries[oi].size != new_entries[ni].size;
+            let rel_path = &old_entries[oi].relative_path;
+            let extra_old: Vec<(u32, std::path::PathBuf)> = extra_maps
+                .iter()
+                .zip(&extra_entries)
+                .enumerate()
+                .map(|(b, (map, entries))| {
+                    (b as u32 + 1, entries[map[rel_path]].full_path.clone())
+                })
+                .collect();
+            DiffInput {
+                rel_path: old_entries[oi].relative_path.clone(),
+                old_path: old_entries[oi].full_path.clone(),
+                new_path: new_entries[ni].full_path.clone(),
+                sizes_differ,
+                mtimes_differ: !matches!(
+                    (old_entries[oi].modified, new_entries[ni].modified),
+                    (Some(o), Some(n)) if o == n
+                ),
+                old_hash: old_hashes
+                    .as_ref()
+                    .and_then(|h| h.get(&old_entries[oi].relative_path).copied()),
+                new_size: new_entries[ni].size,
+                old_size: old_hashes.is_none().then_some(old_entries[oi].size),
+                old_modified: old_entries[oi].modified,
+                new_modified: new_entries[ni].modified,
+                assume_unchanged: extra_old.is_empty()
+                    && !sizes_differ
+                    && matches!(
+                        (since, new_entries[ni].modified),
+                        (Some(since), Some(modified)) if modified < since
+                    ),
+                extra_old,
+                metadata: record_metadata
+                    .then(|| metadata_change(&old_entries[oi], &new_entries[ni]))
+                    .flatten(),
+            }
         })
         .collect();
 
-    let add_inputs: Vec<(String, std::path::PathBuf)> = files_to_add
+    // Path order, so each diff window's results can be written as soon as it is done.
+    diff_inputs.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+
+    // files_to_add was sorted by path above.
+    let add_inputs: Vec<AddInput> = files_to_add
         .iter()
-        .map(|&ni| {
-            (
-                new_entries[ni].relative_path.clone(),
-                new_entries[ni].full_path.clone(),
-            )
+        .map(|&ni| AddInput {
+            rel_path: new_entries[ni].relative_path.clone(),
+            full_path: new_entries[ni].full_path.clone(),
+            size: new_entries[ni].size,
+            modified: new_entries[ni].modified,
         })
         .collect();
 
+    timer.mark("classify");
+    Ok(Plan {
+        old_count: old_entries.len(),
+        new_count: new_entries.len(),
+        new_bytes: new_entries.iter().map(|e| e.size).sum(),
+        dirs_to_create,
+        dir_modes,
+        add_inputs,
+        diff_inputs,
+        files_to_delete,
+        dirs_to_delete,
+        hardlinks,
+        symlinks,
+        old_hashes,
+    })
+}
+
+/// The changes between two trees, by category and in path order, as reported by
+/// [`compare_trees`] without building a patch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    pub dirs_created: Vec<String>,
+    pub files_added: Vec<String>,
+    pub files_modified: Vec<String>,
+    pub files_deleted: Vec<String>,
+    pub dirs_deleted: Vec<String>,
+    /// (path, target) of added files that are hard links to another new file.
+    pub hardlinks: Vec<(String, String)>,
+    /// (path, target) of symbolic links that are new or point elsewhere.
+    pub symlinks: Vec<(String, String)>,
+    /// With [`CreateOptions::metadata`]: content-identical files whose mode or mtime differ.
+    pub metadata_changed: Vec<String>,
+}
+
+impl ChangeSet {
+    /// Every change as a (status, path) pair, in the order apply handles them:
+    /// `dir-created`, `added` (hard and symbolic links included), `modified`, `metadata`, `deleted`,
+    /// `dir-deleted`, each in path order. These are the records `patcher diff` prints.
+    pub fn records(&self) -> Vec<(&'static str, &str)> {
+        fn tagged<'a>(
+            status: &'static str,
+            paths: &'a [String],
+        ) -> impl Iterator<Item = (&'static str, &'a str)> {
+            paths.iter().map(move |path| (status, path.as_str()))
+        }
+        tagged("dir-created", &self.dirs_created)
+            .chain(tagged("added", &self.files_added))
+            .chain(self.hardlinks.iter().chain(&self.symlinks).map(|(path, _)| ("added", path.as_str())))
+            .chain(tagged("modified", &self.files_modified))
+            .chain(tagged("metadata", &self.metadata_changed))
+            .chain(tagged("deleted", &self.files_deleted))
+            .chain(tagged("dir-deleted", &self.dirs_deleted))
+            .collect()
+    }
+}
+
+/// Report what a patch from `old_dir` to `new_dir` would change, without diffing or
+/// writing anything (`create --compare-only`). Files present on both sides are hashed,
+/// so only real modifications are listed. With `fast`, nothing is hashed: a file counts
+/// as modified when its size or modification time differs (or the old side, e.g. a
+/// snapshot, has no mtime), which may list files whose content is identical.
+pub async fn compare_trees(
+    old_dir: &Path,
+    new_dir: &Path,
+    options: &CreateOptions,
+    fast: bool,
+) -> std::result::Result<ChangeSet, PatchError> {
+    Ok(compare(old_dir, new_dir, options, fast).await?)
+}
+
+async fn compare(
+    old_dir: &Path,
+    new_dir: &Path,
+    options: &CreateOptions,
+    fast: bool,
+) -> Result<ChangeSet> {
+    let hash_algo = options.hash_algo;
+    let read_buffer = options.read_buffer;
+    if read_buffer == 0 {
+        bail!("Read buffer size must be greater than zero");
+    }
+    let Plan {
+        old_count,
+        new_count,
+        mut dirs_to_create,
+        add_inputs,
+        diff_inputs,
+        files_to_delete,
+        mut dirs_to_delete,
+        hardlinks,
+        symlinks,
+        ..
+    } = plan(old_dir, new_dir, None, options, &mut util::PhaseTimer::new()).await?;
+
+    let files_to_hash = if fast { 0 } else { diff_inputs.len() };
+    options.report(CreateProgress::Walked {
+        old_entries: old_count,
+        new_entries: new_count,
+        files_to_hash,
+    });
+    let ticker = HashTicker {
+        done: Arc::new(AtomicUsize::new(0)),
+        total: files_to_hash,
+        progress: options.progress.clone(),
+    };
+
+    // (path, content modified, metadata modified) for every file on both sides.
+    let checked = tokio::task::spawn_blocking(move || -> Result<Vec<(String, bool, bool)>> {
+        let content_differs = |input: &DiffInput| -> Result<bool> {
+            if input.assume_unchanged {
+                return Ok(false);
+            }
+            if fast {
+                return Ok(input.sizes_differ || input.mtimes_differ);
+            }
+            let new_hash = util::hash_file_buffered(hash_algo, &input.new_path, read_buffer)?;
+            ticker.tick();
+            if let Some(old_hash) = input.old_hash {
+                return Ok(input.sizes_differ || old_hash != new_hash);
+            }
+            if input.sizes_differ {
+                return Ok(true);
+            }
+            let old_paths = std::iter::once(&input.old_path)
+                .chain(input.extra_old.iter().map(|(_, path)| path));
+            for old_path in old_paths {
+                if util::hash_file_buffered(hash_algo, old_path, read_buffer)? != new_hash {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+        diff_inputs
+            .par_iter()
+            .map(|input| {
+                let modified = content_differs(input)?;
+                Ok((input.rel_path.clone(), modified, !modified && input.metadata.is_some()))
+            })
+            .collect()
+    })
+    .await??;
+
+    let mut changes = ChangeSet::default();
+    for (path, modified, metadata) in checked {
+        if modified {
+            changes.files_modified.push(path);
+        } else if metadata {
+            changes.metadata_changed.push(path);
+        }
+    }
+    changes.files_modified.sort();
+    changes.metadata_changed.sort();
+    dirs_to_create.sort();
+    dirs_to_delete.sort();
+    changes.dirs_created = dirs_to_create;
+    changes.files_added = add_inputs.into_iter().map(|input| input.rel_path).collect();
+    changes.files_deleted = files_to_delete.into_iter().map(|input| input.rel_path).collect();
+    changes.dirs_deleted = dirs_to_delete;
+    changes.hardlinks = hardlinks;
+    changes.symlinks = symlinks;
+    Ok(changes)
+}
+
+async fn create(
+    old_dir: &Path,
+    new_dir: &Path,
+    dest: Destination<'_>,
+    options: &CreateOptions,
+) -> Result<(ApplySummary, Option<Vec<u8>>)> {
+    let hash_algo = options.hash_algo;
+    let read_buffer = options.read_buffer;
+    if read_buffer == 0 {
+        bail!("Read buffer size must be greater than zero");
+    }
+    let overrides = &options.compression_overrides;
+    if let Some(ext) = overrides.incompressible_exts.iter().find(|ext| {
+        CompressionOverrides::lists(&overrides.force_compress_exts, ext.trim_start_matches('.'))
+    }) {
+        bail!("Extension {} is both incompressible and force-compressed", ext);
+    }
+
+    let mut timer = util::PhaseTimer::new();
+    let output = match dest {
+        Destination::Path(output) if !util::is_stdio(output) => Some(output),
+        _ => None,
+    };
+    if let Some(output) = output.filter(|_| !options.force) {
+        // A split patch is judged by its first part, the one always written.
+        let existing = match options.split_size {
+            Some(_) => crate::patch_format::part_path(output, 1),
+            None => output.to_path_buf(),
+        };
+        if existing.symlink_metadata().is_ok() {
+            bail!("Output file exists: {} (use --force to overwrite)", existing.display());
+        }
+    }
+    let Plan {
+        old_count,
+        new_count,
+        new_bytes,
+        mut dirs_to_create,
+        dir_modes,
+        add_inputs,
+        diff_inputs,
+        files_to_delete,
+        mut dirs_to_delete,
+        mut hardlinks,
+        symlinks,
+        old_hashes,
+    } = plan(old_dir, new_dir, output, options, &mut timer).await?;
+
     let num_files_added = add_inputs.len();
+    let hash_cache = options
+        .hash_cache
+        .as_deref()
+        .map(|path| HashCache::load(path, hash_algo))
+        .transpose()?
+        .map(Arc::new);
+    let cache_for_diff = hash_cache.clone();
+    let cache_for_add = hash_cache.clone();
+    let full_verify = options.full_verify;
+    let skip_changing = options.skip_changing;
+    let force_full = options.force_full;
+    let diff_timeout = options.diff_timeout;
+    let min_match_len = options.min_match_len;
+    let preserve_xattrs = options.preserve_xattrs;
+    let xattrs_warned = Arc::new(AtomicBool::new(false));
+    let xattrs_warned_for_add = Arc::clone(&xattrs_warned);
+    // Split evenly between the two hashing tasks. Sharing one budget could deadlock: the
+    // writer holds an added batch (and its reservation) while it waits for the diff of a
+    // file that sorts before it, and that diff might need the same bytes to start.
+    let half_budget = options.memory_budget.map(|limit| (limit / 2).max(1));
+    let memory_budget = half_budget.map(|limit| Arc::new(util::ByteBudget::new(limit)));
+    let add_budget = half_budget.map(|limit| Arc::new(util::ByteBudget::new(limit)));
+    let add_batch_bytes = half_budget.map_or(ADD_BATCH_BYTES, |limit| limit.min(ADD_BATCH_BYTES));
+    // (path, unified diff) of modified text files, for --changelog.
+    let changelog = options
+        .changelog
+        .as_ref()
+        .map(|_| Arc::new(std::sync::Mutex::new(Vec::<(String, String)>::new())));
+    let changelog_for_diff = changelog.clone();
+    // path -> which strategy won, for --explain.
+    let explanations = options
+        .explain
+        .then(|| Arc::new(std::sync::Mutex::new(HashMap::<String, String>::new())));
+    let explanations_for_diff = explanations.clone();
+    let overrides = options.compression_overrides.clone();
+    let overrides_for_add = overrides.clone();
+    let objects_dir_for_add = options.objects_dir.clone();
+    let recompress_exts = options.recompress_exts.clone();
+    let wants_recompress = move |path: &Path| {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|ext| recompress_exts.iter().any(|r| r.trim_start_matches('.').eq_ignore_ascii_case(ext)))
+    };
+    let unchanged = move |input: &DiffInput, hash: [u8; 32]| -> Option<ModifyResult> {
+        if let Some(change) = input.metadata {
+            return Some((input.rel_path.clone(), Change::Metadata(change), hash));
+        }
+        full_verify.then(|| (input.rel_path.clone(), Change::Unchanged, hash))
+    };
+
+    // Every file on the new side is hashed exactly once, so the denominator is known now.
+    let files_to_hash = diff_inputs.len() + add_inputs.len();
+    options.report(CreateProgress::Walked {
+        old_entries: old_count,
+        new_entries: new_count,
+        files_to_hash,
+    });
+    let diff_ticker = HashTicker {
+        done: Arc::new(AtomicUsize::new(0)),
+        total: files_to_hash,
+        progress: options.progress.clone(),
+    };
+    let add_ticker = diff_ticker.clone();
+
+    // Operations are streamed to the output as they are produced, category by category,
+    // so added content is never all in memory at once. Directories go first.
+    let verbose = options.verbose;
+    let to_stderr = matches!(dest, Destination::Path(output) if util::is_stdio(output));
+    // Filters and skipped entries leave files of the new tree without an operation.
+    let full_file_set = full_verify
+        && options.include.is_empty()
+        && options.exclude.is_empty()
+        && !options.skip_unreadable
+        && !skip_changing;
+    let mut writer = OutputPatch::create(
+        dest,
+        hash_algo,
+        full_file_set,
+        options.output_format,
+        options.mac_key.as_ref(),
+        options.gzip,
+        options.split_size,
+    )?;
+    writer.keep_largest = options.largest_ops;
+    writer.touched = options.record_touched.then(Vec::new);
+    util::sort_dirs_parent_first(&mut dirs_to_create);
+    for path in &dirs_to_create {
+        if verbose {
+            say!(to_stderr, "+ created dir {}", path);
+        }
+        writer.write_op(&PatchOp::CreateDir {
+            path: path.clone(),
+            mode: dir_modes.get(path).copied(),
+        })?;
+    }
 
-    // Stage 3+4: Hash + diff (Rayon par_iter inside spawn_blocking).
-    // Hash phase uses 256 KB BufReader to reduce syscall overhead.
+    // Stage 3+4: Hash + diff (Rayon par_iter inside spawn_blocking), while added files
+    // are read and hashed in bounded parallel batches on a second task. Both hand their
+    // results over in path order, batch by batch, to a third task that writes them as
+    // they arrive, so the diffs are never all in memory and the disk write overlaps
+    // the computation.
     // sizes_differ → skip hashing old file (definitely changed).
     // Identical hash → skip diff entirely.
-    let (diff_results, add_results) = tokio::try_join!(
+    timer.mark("write dirs");
+    let (add_tx, add_rx) = std::sync::mpsc::sync_channel::<Batch<PatchOp>>(1);
+    let (diff_tx, diff_rx) = std::sync::mpsc::sync_channel::<Batch<ModifyResult>>(1);
+    let diff_window_bytes = half_budget.map_or(DIFF_WINDOW_BYTES, |limit| limit.min(DIFF_WINDOW_BYTES));
+    let diff_weight = |input: &DiffInput| input.new_size + input.old_size.unwrap_or(0);
+    let write_context = WriteContext {
+        new_dir: new_dir.to_path_buf(),
+        verbose,
+        to_stderr,
+        preserve_xattrs,
+        xattrs_warned: Arc::clone(&xattrs_warned),
+        explanations: explanations.clone(),
+        objects_dir: options.objects_dir.clone(),
+        overrides: options.compression_overrides.clone(),
+    };
+    let (r_diff, r_add, r_write) = tokio::try_join!(
         tokio::task::spawn_blocking(
-            move || -> Result<Vec<(String, Vec<DiffChunk>, [u8; 32])>> {
-                Ok(diff_inputs
-                    .par_iter()
-                    .map(|input| -> Result<Option<(String, Vec<DiffChunk>, [u8; 32])>> {
-                        let new_hash_blake3 = hash_file_streaming(&input.new_path)?;
-                        if !input.sizes_differ {
-                            let old_hash = hash_file_streaming(&input.old_path)?;
-                            if old_hash == new_hash_blake3 {
-                                return Ok(None);
+            move || -> Result<Duration> {
+                let started = Instant::now();
+                let diff_file = |input: &DiffInput| -> Result<Option<ModifyResult>> {
+                    if input.assume_unchanged && !full_verify && input.metadata.is_none() {
+                        return Ok(None);
+                    }
+                    let cache = cache_for_diff.as_deref();
+                    let new_cached =
+                        cache.and_then(|c| c.get(&input.new_path, input.new_size, input.new_modified));
+                    let remember = |path: &Path, size, modified, hash| {
+                        if let Some(cache) = cache {
+                            cache.insert(path, size, modified, hash);
+                        }
+                    };
+                    if input.assume_unchanged || input.old_hash.is_some() {
+                        // No diff follows, so the new file is only streamed through the hash.
+                        let new_hash = match new_cached {
+                            Some(hash) => hash,
+                            None => util::hash_file_buffered(hash_algo, &input.new_path, read_buffer)?,
+                        };
+                        remember(&input.new_path, input.new_size, input.new_modified, new_hash);
+                        if input.assume_unchanged {
+                            // --full-verify still records the new hash; only the comparison is skipped.
+                            return Ok(unchanged(input, new_hash));
+                        }
+                        if !input.sizes_differ && input.old_hash == Some(new_hash) {
+                            return Ok(unchanged(input, new_hash));
+                        }
+                        // Snapshot base: no old bytes to diff against.
+                        return Ok(Some((
+                            input.rel_path.clone(),
+                            Change::Replace(input.new_path.clone()),
+                            new_hash,
+                        )));
+                    }
+
+                    // From here on the file is diffed unless it turns out unchanged, so both
+                    // sides are mapped once and hashed from the mapping, rather than read
+                    // once to hash and again to diff.
+                    let old_size = input.old_size.unwrap_or_default();
+                    let old_cached = (!input.sizes_differ && input.extra_old.is_empty())
+                        .then(|| cache.and_then(|c| c.get(&input.old_path, old_size, input.old_modified)))
+                        .flatten();
+                    if let (Some(old_hash), Some(new_hash)) = (old_cached, new_cached) {
+                        if old_hash == new_hash {
+                            return Ok(unchanged(input, new_hash));
+                        }
+                    }
+                    let new_data = util::mmap_file(&input.new_path)?;
+                    let new_hash = new_cached.unwrap_or_else(|| util::hash_bytes(hash_algo, &new_data));
+                    remember(&input.new_path, input.new_size, input.new_modified, new_hash);
+                    if force_full && !input.extra_old.is_empty() {
+                        // Changed unless every base already holds the new content.
+                        let bases = std::iter::once(&input.old_path)
+                            .chain(input.extra_old.iter().map(|(_, path)| path));
+                        for old_path in bases {
+                            if util::hash_file_buffered(hash_algo, old_path, read_buffer)? != new_hash {
+                                return Ok(Some((
+                                    input.rel_path.clone(),
+                                    Change::Replace(input.new_path.clone()),
+                                    new_hash,
+                                )));
+                            }
+                        }
+                        return Ok(unchanged(input, new_hash));
+                    }
+                    // --diff-timeout: a diff still running at the deadline is dropped, and
+                    // the file is stored whole.
+                    let deadline = diff_timeout.map(|timeout| Instant::now() + timeout);
+                    let timed_out = || -> Result<Option<ModifyResult>> {
+                        eprintln!(
+                            "warning: diffing {} took over {:?} (--diff-timeout); storing it whole",
+                            input.rel_path,
+                            diff_timeout.unwrap_or_default()
+                        );
+                        Ok(Some((input.rel_path.clone(), Change::Replace(input.new_path.clone()), new_hash)))
+                    };
+                    if !input.extra_old.is_empty() {
+                        // One diff per distinct old version; bases already holding the
+                        // new content need none.
+                        let mut variants: Vec<BaseDiff> = Vec::new();
+                        let kind = sniff_content(&new_data);
+                        let bases = std::iter::once((0, &input.old_path))
+                            .chain(input.extra_old.iter().map(|(base, path)| (*base, path)));
+                        for (base, old_path) in bases {
+                            let old_data = util::mmap_file(old_path)?;
+                            let base_hash = util::hash_bytes(hash_algo, &old_data);
+                            if base_hash == new_hash || variants.iter().any(|v| v.base_hash == base_hash) {
+                                continue;
                             }
+                            let block_size = diff_block_size(kind, old_data.len());
+                            let Some(diff_chunks) =
+                                diff_before(&old_data, &new_data, block_size, min_match_len, deadline)
+                            else {
+                                return timed_out();
+                            };
+                            variants.push(BaseDiff {
+                                base,
+                                base_hash,
+                                diff_chunks,
+                                block_size: block_size as u32,
+                            });
                         }
-                        let new_hash = *new_hash_blake3.as_bytes();
-
-                        let chunks = if is_incompressible(&input.new_path) {
-                            let new_data = util::mmap_file(&input.new_path)?;
-                            vec![DiffChunk::Insert { data: new_data.to_vec() }]
-                        } else {
-                            let old_data = util::mmap_file(&input.old_path)?;
-                            let new_data = util::mmap_file(&input.new_path)?;
-                            binary_diff::compute_diff(&old_data, &new_data)
+                        if variants.is_empty() {
+                            return Ok(unchanged(input, new_hash));
+                        }
+                        return Ok(Some((input.rel_path.clone(), Change::Multi(variants), new_hash)));
+                    }
+                    let diff = |old: &[u8], new: &[u8], kind| -> Result<Option<(Vec<DiffChunk>, u32)>> {
+                        let Some(explanations) = &explanations_for_diff else {
+                            let block_size = diff_block_size(kind, old.len());
+                            let chunks = diff_before(old, new, block_size, min_match_len, deadline);
+                            return Ok(chunks.map(|chunks| (chunks, block_size as u32)));
+                        };
+                        let Some((chunks, block_size, explanation)) =
+                            smallest_diff(old, new, kind, min_match_len, deadline)?
+                        else {
+                            return Ok(None);
                         };
+                        explanations
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .insert(input.rel_path.clone(), explanation);
+                        Ok(Some((chunks, block_size)))
+                    };
+                    // The old hash decides whether the file changed when sizes match, and
+                    // is recorded for `apply --verify-before` when it did.
+                    let old_data = util::mmap_file(&input.old_path)?;
+                    let old_hash = old_cached.unwrap_or_else(|| util::hash_bytes(hash_algo, &old_data));
+                    remember(&input.old_path, old_size, input.old_modified, old_hash);
+                    if !input.sizes_differ && old_hash == new_hash {
+                        return Ok(unchanged(input, new_hash));
+                    }
+                    if force_full {
+                        return Ok(Some((
+                            input.rel_path.clone(),
+                            Change::Replace(input.new_path.clone()),
+                            new_hash,
+                        )));
+                    }
 
-                        Ok(Some((input.rel_path.clone(), chunks, new_hash)))
-                    })
-                    .collect::<Result<Vec<_>>>()?
-                    .into_iter()
-                    .flatten()
-                    .collect())
+                    if wants_recompress(&input.new_path) {
+                        if let Some((marker, new_content)) = recompress::gzip_params(&new_data) {
+                            if let Some(old_content) = recompress::decompress(&marker, &old_data) {
+                                let kind = sniff_content(&new_content);
+                                let Some((chunks, block_size)) = diff(&old_content, &new_content, kind)? else {
+                                    return timed_out();
+                                };
+                                return Ok(Some((
+                                    input.rel_path.clone(),
+                                    Change::Diff(chunks, block_size, Some(marker), kind, old_hash),
+                                    new_hash,
+                                )));
+                            }
+                        }
+                    }
+                    // The extension check is free, so it goes first; only files it lets
+                    // through are sniffed.
+                    let (chunks, block_size, kind) = if is_incompressible(&input.new_path, &overrides) {
+                        (vec![DiffChunk::Insert { data: new_data.to_vec() }], 0, ContentKind::Binary)
+                    } else {
+                        let kind = sniff_content(&new_data);
+                        let Some((chunks, block_size)) = diff(&old_data, &new_data, kind)? else {
+                            return timed_out();
+                        };
+                        (chunks, block_size, kind)
+                    };
+
+                    Ok(Some((input.rel_path.clone(), Change::Diff(chunks, block_size, None, kind, old_hash), new_hash)))
+                };
+                let process = |input: &DiffInput| {
+                    let result = if skip_changing {
+                        // Re-stat after reading, so a file written to meanwhile is
+                        // left out rather than diffed from an inconsistent view.
+                        let result = diff_file(input).and_then(|result| {
+                            ensure_size(&input.new_path, &input.rel_path, input.new_size)?;
+                            if let Some(old_size) = input.old_size {
+                                ensure_size(&input.old_path, &input.rel_path, old_size)?;
+                            }
+                            Ok(result)
+                        });
+                        skip_if_changed(result, &input.rel_path, true).map(Option::flatten)
+                    } else {
+                        diff_file(input)
+                    };
+                    let result = match (result, &changelog_for_diff) {
+                        (Ok(Some(modified)), Some(changelog))
+                            if input.old_size.is_some()
+                                && matches!(
+                                    modified.1,
+                                    Change::Diff(..) | Change::Multi(_) | Change::Replace(_)
+                                ) =>
+                        {
+                            text_diff(&input.rel_path, &input.old_path, &input.new_path).map(|diff| {
+                                if let Some(diff) = diff {
+                                    changelog
+                                        .lock()
+                                        .unwrap_or_else(|e| e.into_inner())
+                                        .push((input.rel_path.clone(), diff));
+                                }
+                                Some(modified)
+                            })
+                        }
+                        (result, _) => result,
+                    };
+                    diff_ticker.tick();
+                    result
+                };
+                // Windows of files in path order go to the writer as each completes.
+                // Within a window the largest files start first: diff cost grows with
+                // size, so starting the giants early lets work stealing spread the small
+                // files over the remaining threads instead of leaving one thread grinding
+                // on a huge file at the end.
+                for window in batches(&diff_inputs, diff_window_bytes, diff_weight) {
+                    let mut by_size: Vec<&DiffInput> = window.iter().collect();
+                    by_size.sort_by_key(|input| std::cmp::Reverse(input.new_size));
+                    let results = match &memory_budget {
+                        None => by_size.par_iter().map(|input| process(input)).collect::<Vec<_>>(),
+                        Some(budget) => par_map_budgeted(
+                            &by_size,
+                            budget,
+                            |input| diff_weight(input),
+                            |input| process(input),
+                        ),
+                    };
+                    let mut results: Vec<ModifyResult> = results
+                        .into_iter()
+                        .collect::<Result<Vec<_>>>()?
+                        .into_iter()
+                        .flatten()
+                        .collect();
+                    results.sort_by(|a, b| a.0.cmp(&b.0));
+                    if diff_tx.send((results, None)).is_err() {
+                        // The writer stopped; its error is the one reported.
+                        break;
+                    }
+                }
+                Ok(started.elapsed())
             }
         ),
-        tokio::task::spawn_blocking(move || -> Result<Vec<(String, Vec<u8>, [u8; 32])>> {
-            add_inputs
-                .par_iter()
-                .map(|(rel_path, full_path)| -> Result<(String, Vec<u8>, [u8; 32])> {
-                    let mmap = util::mmap_file(full_path)?;
-                    let hash = util::hash_bytes(&mmap);
-                    Ok((rel_path.clone(), mmap.to_vec(), hash))
-                })
-                .collect()
+        tokio::task::spawn_blocking(move || -> Result<(Vec<String>, Duration)> {
+            let started = Instant::now();
+            let mut skipped = Vec::new();
+            for batch in batches(&add_inputs, add_batch_bytes, |input| input.size) {
+                // A batch's content is held until it is written, so it is budgeted whole
+                // (from this thread, before any Rayon worker starts on it); the
+                // reservation goes to the writer with the batch.
+                let reserved = add_budget
+                    .as_ref()
+                    .map(|budget| budget.acquire_owned(batch.iter().map(|input| input.size).sum()));
+                let add_file = |input: &AddInput| -> Result<PatchOp> {
+                    let mmap = util::mmap_file(&input.full_path)?;
+                    if skip_changing && mmap.len() as u64 != input.size {
+                        bail!(FileChanged {
+                            path: input.rel_path.clone(),
+                            detail: format!("{} bytes when walked, {} now", input.size, mmap.len()),
+                        });
+                    }
+                    let hash = util::hash_bytes(hash_algo, &mmap);
+                    if let Some(cache) = &cache_for_add {
+                        cache.insert(&input.full_path, input.size, input.modified, hash);
+                    }
+                    content_op(
+                        objects_dir_for_add.as_deref(),
+                        input.rel_path.clone(),
+                        &mmap,
+                        hash,
+                        is_incompressible(&input.full_path, &overrides_for_add),
+                        xattrs_of(&input.full_path, preserve_xattrs, &xattrs_warned_for_add)?,
+                    )
+                };
+                let ops = batch
+                    .par_iter()
+                    .map(|input| {
+                        let op = skip_if_changed(add_file(input), &input.rel_path, skip_changing);
+                        add_ticker.tick();
+                        op
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let mut added = Vec::with_capacity(ops.len());
+                for (input, op) in batch.iter().zip(ops) {
+                    match op {
+                        Some(op) => added.push(op),
+                        None => skipped.push(input.rel_path.clone()),
+                    }
+                }
+                if add_tx.send((added, reserved)).is_err() {
+                    break;
+                }
+            }
+            Ok((skipped, started.elapsed()))
+        }),
+        tokio::task::spawn_blocking(move || -> Result<(OutputPatch, Written, Duration)> {
+            let started = Instant::now();
+            let written = write_files(&mut writer, received(&add_rx), received(&diff_rx), &write_context)?;
+            Ok((writer, written, started.elapsed()))
         }),
     )?;
 
-    let diff_results = diff_results?;
-    let add_results = add_results?;
-    let num_files_modified = diff_results.len();
+    // A failed writer drops its channels and stops the other two, so its error goes first.
+    let (mut writer, written, write_elapsed) = r_write?;
+    let diff_elapsed = r_diff?;
+    let (skipped_adds, add_elapsed) = r_add?;
+    let Written {
+        files_modified: num_files_modified,
+        unchanged_files,
+        metadata_changes,
+    } = written;
+    let num_files_added = num_files_added - skipped_adds.len();
+    // A link to a skipped file would have nothing to point at.
+    hardlinks.retain(|(path, target)| {
+        let keep = !skipped_adds.contains(target);
+        if !keep {
+            eprintln!("warning: skipped {}: its link target {} was skipped", path, target);
+        }
+        keep
+    });
+    timer.concurrent(&[("hash+diff", diff_elapsed), ("add", add_elapsed), ("write files", write_elapsed)]);
+
+    // Patches are reproducible: identical inputs always produce byte-identical output.
+    // Every operation list is in path order rather than in the order the parallel
+    // stages happened to schedule or return their results.
+    // Deleted files' hashes are recorded for `apply --verify-before`. A snapshot base has
+    // them already; otherwise each base holding the file reads it once (unless the hash
+    // cache knows it), and a file the bases hold different versions of records none.
+    let read_buffer = options.read_buffer;
+    let cache_for_delete = hash_cache.clone();
+    // The files to delete, hashed, in path order.
+    let files_to_delete = tokio::task::spawn_blocking(
+        move || -> Result<Vec<HashedDelete>> {
+            let cache = cache_for_delete.as_deref();
+            let hash_copy = |copy: &util::DirEntry| -> Result<[u8; 32]> {
+                if let Some(link) = &copy.link_target {
+                    return Ok(util::link_hash(hash_algo, link));
+                }
+                if let Some(hash) = cache.and_then(|c| c.get(&copy.full_path, copy.size, copy.modified)) {
+                    return Ok(hash);
+                }
+                let hash = util::hash_file_buffered(hash_algo, &copy.full_path, read_buffer)?;
+                ensure_size(&copy.full_path, &copy.relative_path, copy.size)?;
+                if let Some(cache) = cache {
+                    cache.insert(&copy.full_path, copy.size, copy.modified, hash);
+                }
+                Ok(hash)
+            };
+            let hashes = files_to_delete
+                .par_iter()
+                .map(|input| {
+                    if let Some(hashes) = &old_hashes {
+                        return Ok(Some(hashes.get(&input.rel_path).copied()));
+                    }
+                    let hashes = input.copies.iter().map(hash_copy).collect::<Result<Vec<_>>>();
+                    Ok(skip_if_changed(hashes, &input.rel_path, skip_changing)?.map(|hashes| {
+                        let (first, rest) = hashes.split_first()?;
+                        rest.iter().all(|hash| hash == first).then_some(*first)
+                    }))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // A deleted file that changed under us is left alone, like an add or modify.
+            Ok(files_to_delete
+                .into_iter()
+                .zip(hashes)
+                .filter_map(|(input, hash)| Some((input.rel_path, hash?, input.may_be_absent)))
+                .collect())
+        },
+    )
+    .await??;
 
-    // Stage 5: Assemble operations in correct order
-    let mut operations: Vec<PatchOp> = Vec::new();
+    // Stage 5: Stream the remaining operations in order. CreateDir (1), AddFile (2) and
+    // ModifyFile (3, or a full-content AddFile when no diff was possible) are already
+    // written.
 
-    // 1. CreateDir (parent-first)
-    util::sort_dirs_parent_first(&mut dirs_to_create);
-    for path in &dirs_to_create {
-        operations.push(PatchOp::CreateDir {
+    // 3b. CreateHardlink
+    for (path, target) in &hardlinks {
+        if verbose {
+            say!(to_stderr, "+ linked {} => {}", path, target);
+        }
+        writer.write_op(&PatchOp::CreateHardlink {
             path: path.clone(),
-        });
+            target: target.clone(),
+        })?;
     }
 
-    // 2. AddFile
-    for (path, data, hash) in add_results {
-        operations.push(PatchOp::AddFile {
-            path,
-            data,
-            blake3_hash: hash,
-        });
+    // 3c. CreateSymlink
+    for (path, target) in &symlinks {
+        if verbose {
+            say!(to_stderr, "+ symlink {} -> {}", path, target);
+        }
+        writer.write_op(&PatchOp::CreateSymlink {
+            path: path.clone(),
+            target: target.clone(),
+        })?;
     }
 
-    // 3. ModifyFile
-    for (path, diff_chunks, new_hash) in diff_results {
-        operations.push(PatchOp::ModifyFile {
-            path,
-            diff_chunks,
-            new_blake3_hash: new_hash,
-        });
+    // 3d. SetMetadata (only with --metadata)
+    for (path, (mode, mtime)) in &metadata_changes {
+        if verbose {
+            match mode {
+                Some(mode) => say!(to_stderr, "* metadata {} (mode {:o})", path, mode),
+                None => say!(to_stderr, "* metadata {} (mtime)", path),
+            }
+        }
+        writer.write_op(&PatchOp::SetMetadata {
+            path: path.clone(),
+            mode: *mode,
+            mtime: *mtime,
+        })?;
     }
 
     // 4. DeleteFile
-    for path in &files_to_delete {
-        operations.push(PatchOp::DeleteFile {
+    for (path, old_hash, may_be_absent) in &files_to_delete {
+        if verbose {
+            say!(to_stderr, "- deleted {}", path);
+        }
+        writer.write_op(&PatchOp::DeleteFile {
             path: path.clone(),
-        });
+            old_blake3_hash: *old_hash,
+            may_be_absent: *may_be_absent,
+        })?;
     }
 
     // 5. DeleteDir (deepest-first)
     util::sort_dirs_deepest_first(&mut dirs_to_delete);
     for path in &dirs_to_delete {
-        operations.push(PatchOp::DeleteDir {
+        if verbose {
+            say!(to_stderr, "- deleted dir {}", path);
+        }
+        writer.write_op(&PatchOp::DeleteDir {
             path: path.clone(),
-        });
+        })?;
     }
 
-    let manifest = PatchManifest {
-        version: FORMAT_VERSION,
-        operations,
-    };
+    // 6. VerifyFile (only with --full-verify; already in path order)
+    for (path, hash) in unchanged_files {
+        writer.write_op(&PatchOp::VerifyFile {
+            path,
+            blake3_hash: hash,
+        })?;
+    }
 
-    // Serialize, compress, write
-    let encoded =
-        bincode::serialize(&manifest).context("Failed to serialize patch manifest")?;
+    timer.mark("write");
+    let largest_ops = std::mem::take(&mut writer.largest);
+    let touched = writer.touched.take().unwrap_or_default();
+    let mut parts = Vec::new();
+    let bytes = writer.finish(&mut parts)?;
+    timer.mark("finish");
+    if verbose && !parts.is_empty() {
+        say!(to_stderr, "  Split into {} parts:", parts.len());
+        for part in &parts {
+            say!(to_stderr, "    {}", part.display());
+        }
+    }
 
-    let compressed =
-        zstd::bulk::compress(&encoded, 3).context("Failed to compress patch data")?;
+    // Saved only once the patch is complete, so a failed run leaves the old cache.
+    if let (Some(path), Some(cache)) = (&options.hash_cache, &hash_cache) {
+        if verbose {
+            say!(to_stderr, "  Hash cache: {} hashes reused", cache.hits());
+        }
+        cache.save(path)?;
+        timer.mark("save hash cache");
+    }
 
-    let mut file = std::fs::File::create(output)
-        .with_context(|| format!("Failed to create output file: {}", output.display()))?;
-    file.write_all(MAGIC)?;
-    file.write_all(&compressed)?;
-    file.flush()?;
+    if let (Some(path), Some(diffs)) = (&options.changelog, changelog) {
+        let mut diffs = std::mem::take(&mut *diffs.lock().unwrap_or_else(|e| e.into_inner()));
+        diffs.sort();
+        let text: String = diffs.into_iter().map(|(_, diff)| diff).collect();
+        std::fs::write(path, text)
+            .with_context(|| format!("Failed to write changelog: {}", path.display()))?;
+    }
 
     let summary = ApplySummary {
         dirs_created: dirs_to_create.len(),
@@ -265,7 +2452,460 @@ pub async fn create_patch(
         files_modified: num_files_modified,
         files_deleted: files_to_delete.len(),
         dirs_deleted: dirs_to_delete.len(),
+        hardlinks_created: hardlinks.len(),
+        symlinks_created: symlinks.len(),
+        metadata_updated: metadata_changes.len(),
+        skipped_mismatches: Vec::new(),
+        deletes_declined: 0,
+        bytes_processed: new_bytes,
+        timings: timer.into_phases(),
+        largest_ops,
+        touched,
     };
 
-    Ok(summary)
+    Ok((summary, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_overrides_extend_and_carve_out() {
+        let none = CompressionOverrides::default();
+        assert!(is_incompressible(Path::new("a/photo.PNG"), &none));
+        assert!(!is_incompressible(Path::new("app.wasm"), &none));
+        assert!(!is_incompressible(Path::new("Makefile"), &none));
+
+        let overrides = CompressionOverrides {
+            incompressible_exts: vec!["wasm".into()],
+            force_compress_exts: vec![".png".into()],
+        };
+        assert!(is_incompressible(Path::new("app.WASM"), &overrides));
+        assert!(!is_incompressible(Path::new("a/photo.PNG"), &overrides));
+        assert!(is_incompressible(Path::new("a/photo.jpg"), &overrides));
+    }
+
+    #[test]
+    fn test_sniff_content_classifies_by_bytes() {
+        for text in [
+            &b""[..],
+            b"key = value\n\tindented\r\n",
+            "caf\u{e9} na\u{ef}ve\n".as_bytes(),
+            b"\x1b[31merror\x1b[0m: disk full\x0c\n",
+        ] {
+            assert_eq!(sniff_content(text), ContentKind::Text, "{:?}", text);
+        }
+        assert_eq!(sniff_content(b"\x7fELF\x02\x01\x01\0\0\0"), ContentKind::Binary);
+        let mostly_text: Vec<u8> = b"abcdefghijk\x01".repeat(100);
+        assert_eq!(sniff_content(&mostly_text), ContentKind::Text);
+        let noisy: Vec<u8> = b"abcd\x01\x02".repeat(100);
+        assert_eq!(sniff_content(&noisy), ContentKind::Binary);
+        // Only the sample is looked at.
+        let mut late_nul = vec![b'a'; TEXT_SAMPLE_BYTES];
+        late_nul.push(0);
+        assert_eq!(sniff_content(&late_nul), ContentKind::Text);
+    }
+
+    #[test]
+    fn test_text_block_size_keeps_small_edits_small() {
+        let old: String = (0..400).map(|i| format!("setting_{} = {}\n", i, i * 7)).collect();
+        let new = old.replace("setting_200 = 1400", "setting_200 = 1401");
+        assert_eq!(sniff_content(new.as_bytes()), ContentKind::Text);
+        let inserted = |kind| {
+            let block_size = diff_block_size(kind, old.len());
+            let chunks =
+                binary_diff::compute_diff_with_block_size(old.as_bytes(), new.as_bytes(), block_size);
+            assert_eq!(crate::binary_patch::apply_diff(old.as_bytes(), &chunks).unwrap(), new.as_bytes());
+            chunks
+                .iter()
+                .map(|c| match c {
+                    DiffChunk::Insert { data } => data.len(),
+                    _ => 0,
+                })
+                .sum::<usize>()
+        };
+        assert!(inserted(ContentKind::Text) <= 2 * binary_diff::MIN_TEXT_BLOCK_SIZE);
+        // Matches extend up to the edit, so coarse blocks no longer resend a whole block.
+        assert!(inserted(ContentKind::Binary) <= 2 * binary_diff::MIN_TEXT_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_explain_keeps_the_smallest_candidate() {
+        // Incompressible content with a byte changed every 4 KB: blocks coarser than the
+        // gaps between edits resend a whole block of noise around each one.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let old: Vec<u8> = (0..64 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let mut new = old.clone();
+        for i in (100..new.len()).step_by(4096) {
+            new[i] ^= 0xff;
+        }
+        let (chunks, block_size, explanation) = smallest_diff(&old, &new, ContentKind::Binary, None, None).unwrap().unwrap();
+        assert_eq!(block_size as usize, binary_diff::text_block_size_for(old.len()));
+        assert_eq!(crate::binary_patch::apply_diff(&old, &chunks).unwrap(), new);
+        assert!(explanation.starts_with("chose 64-byte blocks ("), "{}", explanation);
+        assert!(explanation.contains(" vs 1024-byte blocks ") && explanation.contains(", whole file 64.0 KB)"), "{}", explanation);
+    }
+
+    #[test]
+    fn test_largest_ops_are_reported_largest_first() {
+        let temp = std::env::temp_dir().join("patcher_unit_largest_ops");
+        let _ = std::fs::remove_dir_all(&temp);
+        let text: String = (0..500).map(|i| format!("line {}\n", i)).collect();
+        let inserted = "x".repeat(200);
+        for (dir, edited) in [("old", false), ("new", true)] {
+            std::fs::create_dir_all(temp.join(dir)).unwrap();
+            let content = if edited { text.replace("line 250", &inserted) } else { text.clone() };
+            std::fs::write(temp.join(dir).join("edited.txt"), content).unwrap();
+        }
+        std::fs::write(temp.join("old/gone.txt"), b"gone").unwrap();
+        let mut state = 0x2545_f491_u32;
+        let noise: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        std::fs::write(temp.join("new/big.bin"), &noise).unwrap();
+        std::fs::write(temp.join("new/small.txt"), b"small").unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let create = |largest_ops| {
+            let options = CreateOptions {
+                largest_ops,
+                ..CreateOptions::default()
+            };
+            rt.block_on(create_patch_bytes(&temp.join("old"), &temp.join("new"), &options))
+                .unwrap()
+                .1
+                .largest_ops
+        };
+        assert!(create(0).is_empty());
+
+        // Content-free operations (the delete) aren't listed.
+        let all = create(10);
+        let listed: Vec<(&str, &str)> = all.iter().map(|op| (op.path.as_str(), op.kind)).collect();
+        assert_eq!(listed, [("big.bin", "add"), ("edited.txt", "modify"), ("small.txt", "add")]);
+        assert!(all[0].bytes >= noise.len() as u64, "{:?}", all[0]);
+        assert_eq!((all[2].bytes, all[2].inserts), (5, 0));
+        let slack = 2 * binary_diff::MIN_TEXT_BLOCK_SIZE as u64;
+        let near_edit = inserted.len() as u64..=inserted.len() as u64 + slack;
+        assert!(all[1].inserts >= 1 && near_edit.contains(&all[1].bytes), "{:?}", all[1]);
+
+        let top = create(1);
+        assert_eq!(top, all[..1]);
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_added_and_modified_files_stream_out_in_one_path_order() {
+        let temp = std::env::temp_dir().join("patcher_unit_stream_order");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(temp.join("old")).unwrap();
+        std::fs::create_dir_all(temp.join("new")).unwrap();
+        // Every third file is added, the rest edited; sizes vary so that windows and
+        // batches (under a budget far smaller than the tree) split at different paths.
+        for i in 0..60 {
+            let name = format!("f{:02}.txt", i);
+            let text: String = (0..20 + i * 7).map(|line| format!("{} line {}\n", name, line)).collect();
+            if i % 3 != 0 {
+                std::fs::write(temp.join("old").join(&name), &text).unwrap();
+            }
+            std::fs::write(temp.join("new").join(&name), text.replace("line 5\n", "line five\n")).unwrap();
+        }
+
+        let options = CreateOptions {
+            memory_budget: Some(4096),
+            ..CreateOptions::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let create = || {
+            rt.block_on(create_patch_bytes(&temp.join("old"), &temp.join("new"), &options))
+                .unwrap()
+        };
+        let (bytes, summary) = create();
+        assert_eq!((summary.files_added, summary.files_modified), (20, 40));
+        let limits = crate::apply::ApplyLimits::default();
+        let manifest = crate::apply::read_manifest_bytes(&bytes, &limits).unwrap();
+        let kinds: Vec<(&str, bool)> = manifest
+            .operations
+            .iter()
+            .map(|op| (op.path(), matches!(op, PatchOp::AddFile { .. })))
+            .collect();
+        let expected: Vec<(String, bool)> = (0..60).map(|i| (format!("f{:02}.txt", i), i % 3 == 0)).collect();
+        assert_eq!(
+            kinds,
+            expected.iter().map(|(path, add)| (path.as_str(), *add)).collect::<Vec<_>>()
+        );
+        assert_eq!(create().0, bytes);
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_force_full_stores_modified_files_whole() {
+        let temp = std::env::temp_dir().join("patcher_unit_force_full");
+        let _ = std::fs::remove_dir_all(&temp);
+        let text: String = (0..500).map(|i| format!("line {}\n", i)).collect();
+        for (dir, edited) in [("old", false), ("new", true)] {
+            std::fs::create_dir_all(temp.join(dir)).unwrap();
+            let content = if edited { text.replace("line 250", "line 250!") } else { text.clone() };
+            std::fs::write(temp.join(dir).join("edited.txt"), content).unwrap();
+            std::fs::write(temp.join(dir).join("same.txt"), b"same").unwrap();
+        }
+        std::fs::write(temp.join("new/added.txt"), b"added").unwrap();
+
+        let options = CreateOptions {
+            force_full: true,
+            ..CreateOptions::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (bytes, summary) = rt
+            .block_on(create_patch_bytes(&temp.join("old"), &temp.join("new"), &options))
+            .unwrap();
+        assert_eq!((summary.files_added, summary.files_modified), (1, 1));
+        let limits = crate::apply::ApplyLimits::default();
+        let manifest = crate::apply::read_manifest_bytes(&bytes, &limits).unwrap();
+        assert!(manifest.operations.iter().all(|op| matches!(op, PatchOp::AddFile { .. })));
+        let mut paths: Vec<&str> = manifest.operations.iter().map(|op| op.path()).collect();
+        paths.sort_unstable();
+        assert_eq!(paths, ["added.txt", "edited.txt"]);
+
+        let target = temp.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        for name in ["edited.txt", "same.txt"] {
+            std::fs::copy(temp.join("old").join(name), target.join(name)).unwrap();
+        }
+        rt.block_on(crate::apply::apply_patch_bytes(&target, &bytes, &Default::default()))
+            .unwrap();
+        for name in ["edited.txt", "same.txt", "added.txt"] {
+            assert_eq!(
+                std::fs::read(target.join(name)).unwrap(),
+                std::fs::read(temp.join("new").join(name)).unwrap()
+            );
+        }
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_skip_changing_leaves_out_files_written_during_create() {
+        let temp = std::env::temp_dir().join("patcher_unit_skip_changing");
+        let _ = std::fs::remove_dir_all(&temp);
+        for (dir, content) in [("old", &b"old text"[..]), ("new", &b"new text"[..])] {
+            std::fs::create_dir_all(temp.join(dir)).unwrap();
+            std::fs::write(temp.join(dir).join("mod.txt"), content).unwrap();
+        }
+        std::fs::write(temp.join("new/add.txt"), b"added").unwrap();
+        std::fs::write(temp.join("new/steady.txt"), b"steady").unwrap();
+        std::fs::write(temp.join("old/gone.txt"), b"gone").unwrap();
+
+        // Walked is reported between the walk and the first read: grow two files then,
+        // and remove the deleted one, whose hash is taken last.
+        let new_dir = temp.join("new");
+        let old_dir = temp.join("old");
+        let options = CreateOptions {
+            skip_changing: true,
+            progress: Some(ProgressCallback::new(move |event| {
+                if let CreateProgress::Walked { .. } = event {
+                    std::fs::remove_file(old_dir.join("gone.txt")).unwrap();
+                    for name in ["mod.txt", "add.txt"] {
+                        let mut file = std::fs::OpenOptions::new()
+                            .append(true)
+                            .open(new_dir.join(name))
+                            .unwrap();
+                        file.write_all(b" and more").unwrap();
+                    }
+                }
+            })),
+            ..CreateOptions::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (bytes, summary) = rt
+            .block_on(create_patch_bytes(&temp.join("old"), &temp.join("new"), &options))
+            .unwrap();
+        assert_eq!((summary.files_added, summary.files_modified), (1, 0));
+
+        let manifest =
+            crate::apply::read_manifest_bytes(&bytes, &crate::apply::ApplyLimits::default())
+                .unwrap();
+        let paths: Vec<&str> = manifest.operations.iter().map(|op| op.path()).collect();
+        assert_eq!(paths, ["steady.txt"]);
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_deletes_record_the_hash_every_base_holding_them_agrees_on_and_who_lacks_them() {
+        let temp = std::env::temp_dir().join("patcher_unit_multi_base_deletes");
+        let _ = std::fs::remove_dir_all(&temp);
+        for dir in ["a", "b", "new"] {
+            std::fs::create_dir_all(temp.join(dir)).unwrap();
+        }
+        std::fs::write(temp.join("a/same.txt"), b"same").unwrap();
+        std::fs::write(temp.join("b/same.txt"), b"same").unwrap();
+        std::fs::write(temp.join("a/differs.txt"), b"one").unwrap();
+        std::fs::write(temp.join("b/differs.txt"), b"two").unwrap();
+        std::fs::write(temp.join("b/only_b.txt"), b"b alone").unwrap();
+
+        let options = CreateOptions {
+            extra_bases: vec![temp.join("b")],
+            ..CreateOptions::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (bytes, summary) = rt
+            .block_on(create_patch_bytes(&temp.join("a"), &temp.join("new"), &options))
+            .unwrap();
+        assert_eq!(summary.files_deleted, 3);
+        let manifest =
+            crate::apply::read_manifest_bytes(&bytes, &crate::apply::ApplyLimits::default())
+                .unwrap();
+        let hash = |data: &[u8]| Some(util::hash_bytes(HashAlgo::Blake3, data));
+        let deletes: Vec<(&str, Option<[u8; 32]>, bool)> = manifest
+            .operations
+            .iter()
+            .filter_map(|op| match op {
+                PatchOp::DeleteFile {
+                    path,
+                    old_blake3_hash,
+                    may_be_absent,
+                } => Some((path.as_str(), *old_blake3_hash, *may_be_absent)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            deletes,
+            [
+                ("differs.txt", None, false),
+                ("only_b.txt", hash(b"b alone"), true),
+                ("same.txt", hash(b"same"), false)
+            ]
+        );
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_relative_link_target_walks_up_to_the_common_dir() {
+        let root = "/srv/app";
+        for (link, target, expected) in [
+            ("bin/run", "/srv/app/lib/run.sh", Some("../lib/run.sh")),
+            ("a/b/link", "/srv/app/a/c/d", Some("../c/d")),
+            ("a/link", "/srv/app/a/file", Some("file")),
+            ("link", "/srv/app/", Some(".")),
+            ("a/b/link", "/srv/app", Some("../..")),
+            ("link", "/srv/app/./x//y", Some("x/y")),
+            // Outside the tree, or not exactly the root.
+            ("link", "/srv/other/x", None),
+            ("link", "/srv/application/x", None),
+            ("link", "/srv/app/a/../b", None),
+        ] {
+            assert_eq!(relative_link_target(link, target, root).as_deref(), expected, "{} -> {}", link, target);
+        }
+        assert_eq!(relative_link_target("x/link", "/srv/app/y", "/srv/app/").as_deref(), Some("../y"));
+    }
+
+    #[test]
+    fn test_a_path_turning_between_dir_and_file_fails_create() {
+        let temp = std::env::temp_dir().join("patcher_unit_dir_file_swap");
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        for (dir_side, file_side) in [("old", "new"), ("new", "old")] {
+            let _ = std::fs::remove_dir_all(&temp);
+            std::fs::create_dir_all(temp.join(dir_side).join("x")).unwrap();
+            std::fs::write(temp.join(dir_side).join("x/inner.txt"), b"inner").unwrap();
+            std::fs::create_dir_all(temp.join(file_side)).unwrap();
+            std::fs::write(temp.join(file_side).join("x"), b"file").unwrap();
+            let err = rt
+                .block_on(create_patch_bytes(&temp.join("old"), &temp.join("new"), &CreateOptions::default()))
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("x is a directory on one side and a file on the other"),
+                "{} is the directory: {}",
+                dir_side,
+                err
+            );
+        }
+        #[cfg(unix)]
+        {
+            std::fs::remove_file(temp.join("old/x")).unwrap();
+            std::os::unix::fs::symlink("elsewhere", temp.join("old/x")).unwrap();
+            let err = rt
+                .block_on(create_patch_bytes(&temp.join("old"), &temp.join("new"), &CreateOptions::default()))
+                .unwrap_err();
+            assert!(err.to_string().contains("and a symbolic link on the other"), "{}", err);
+        }
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_round_trip_as_links() {
+        let temp = std::env::temp_dir().join("patcher_unit_symlinks");
+        let _ = std::fs::remove_dir_all(&temp);
+        for dir in ["old/lib", "new/lib", "new/bin"] {
+            std::fs::create_dir_all(temp.join(dir)).unwrap();
+        }
+        // Canonical, so the link into the tree is spelled the way the walk sees the root.
+        let new = temp.join("new").canonicalize().unwrap();
+        std::fs::write(new.join("lib/run.sh"), b"echo hi").unwrap();
+        std::fs::write(temp.join("old/lib/run.sh"), b"echo hi").unwrap();
+        let inside = new.join("lib/run.sh");
+        std::os::unix::fs::symlink(&inside, new.join("bin/run")).unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", new.join("host")).unwrap();
+        std::os::unix::fs::symlink("lib", new.join("libs")).unwrap();
+        // A link in the old tree that becomes a file.
+        std::os::unix::fs::symlink("lib/run.sh", temp.join("old/was_link")).unwrap();
+        std::fs::write(new.join("was_link"), b"now a file").unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        for (mode, run_target) in [
+            (SymlinkMode::Verbatim, inside.to_str().unwrap()),
+            (SymlinkMode::Relative, "../lib/run.sh"),
+        ] {
+            let options = CreateOptions {
+                symlink_mode: mode,
+                ..CreateOptions::default()
+            };
+            let (bytes, summary) = rt
+                .block_on(create_patch_bytes(&temp.join("old"), &new, &options))
+                .unwrap();
+            assert_eq!(summary.symlinks_created, 3);
+
+            let target = temp.join(format!("target_{:?}", mode));
+            let _ = std::fs::remove_dir_all(&target);
+            std::fs::create_dir_all(target.join("lib")).unwrap();
+            std::fs::write(target.join("lib/run.sh"), b"echo hi").unwrap();
+            std::os::unix::fs::symlink("lib/run.sh", target.join("was_link")).unwrap();
+            let applied = rt
+                .block_on(crate::apply::apply_patch_bytes(
+                    &target,
+                    &bytes,
+                    &crate::apply::ApplyOptions::default(),
+                ))
+                .unwrap();
+            assert_eq!(applied.symlinks_created, 3);
+            let link = |path: &str| std::fs::read_link(target.join(path)).unwrap();
+            assert_eq!(link("bin/run"), Path::new(run_target));
+            // Links out of the tree are kept as they are in either mode.
+            assert_eq!(link("host"), Path::new("/etc/hostname"));
+            assert_eq!(link("libs"), Path::new("lib"));
+            let was_link = std::fs::symlink_metadata(target.join("was_link")).unwrap();
+            assert!(was_link.file_type().is_file());
+            assert_eq!(std::fs::read(target.join("was_link")).unwrap(), b"now a file");
+            assert_eq!(std::fs::read(target.join("lib/run.sh")).unwrap(), b"echo hi");
+        }
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
 }
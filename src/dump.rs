@@ -0,0 +1,274 @@
+use std::fmt;
+
+use crate::patch_format::{BaseDiff, DiffChunk, PatchManifest, PatchOp, Recompress};
+use crate::util::{self, Xattrs};
+
+/// Byte fields up to this long are shown whole; longer ones show this many bytes and
+/// their length.
+pub const PREVIEW_BYTES: usize = 32;
+
+/// `manifest` pretty-printed as `{:#?}` prints it, for `patcher dump`, except that byte
+/// fields (added content, Insert data, xattr values, gzip headers) are shown as hex cut
+/// to [`PREVIEW_BYTES`] with their full length, and hashes as hex strings. The derived
+/// output spends a line per byte, which buries the operation list of any real patch.
+pub fn dump_manifest(manifest: &PatchManifest) -> String {
+    format!("{:#?}", Manifest(manifest))
+}
+
+struct Manifest<'a>(&'a PatchManifest);
+struct Op<'a>(&'a PatchOp);
+struct Chunk<'a>(&'a DiffChunk);
+struct Variant<'a>(&'a BaseDiff);
+struct Attrs<'a>(&'a Xattrs);
+struct Bytes<'a>(&'a [u8]);
+struct Hash<'a>(&'a [u8; 32]);
+
+/// A list shown with each item wrapped by the function.
+struct List<'a, T, D>(&'a [T], fn(&'a T) -> D);
+
+impl<'a, T, D: fmt::Debug> fmt::Debug for List<'a, T, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.iter().map(self.1)).finish()
+    }
+}
+
+impl fmt::Debug for Manifest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let PatchManifest {
+            version,
+            hash_algo,
+            full_file_set,
+            operations,
+        } = self.0;
+        f.debug_struct("PatchManifest")
+            .field("version", &format_args!("{}", version))
+            .field("hash_algo", hash_algo)
+            .field("full_file_set", full_file_set)
+            .field("operations", &List(operations, Op))
+            .finish()
+    }
+}
+
+impl fmt::Debug for Op<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Every field is named, so a field added to `PatchOp` fails to compile here
+        // rather than silently going missing from the dump.
+        match self.0 {
+            PatchOp::CreateDir { path, mode } => f
+                .debug_struct("CreateDir")
+                .field("path", path)
+                .field("mode", &Mode(*mode))
+                .finish(),
+            PatchOp::AddFile {
+                path,
+                data,
+                blake3_hash,
+                compressed,
+                xattrs,
+                external,
+            } => f
+                .debug_struct("AddFile")
+                .field("path", path)
+                .field("data", &Bytes(data))
+                .field("blake3_hash", &Hash(blake3_hash))
+                .field("compressed", compressed)
+                .field("xattrs", &Attrs(xattrs))
+                .field("external", external)
+                .finish(),
+            PatchOp::ModifyFile {
+                path,
+                diff_chunks,
+                new_blake3_hash,
+                block_size,
+                recompress,
+                xattrs,
+                old_blake3_hash,
+            } => f
+                .debug_struct("ModifyFile")
+                .field("path", path)
+                .field("diff_chunks", &List(diff_chunks, Chunk))
+                .field("new_blake3_hash", &Hash(new_blake3_hash))
+                .field("block_size", block_size)
+                .field(
+                    "recompress",
+                    &recompress.as_ref().map(|Recompress::Gzip { header, level }| {
+                        GzipRecompress(header, *level)
+                    }),
+                )
+                .field("xattrs", &Attrs(xattrs))
+                .field("old_blake3_hash", &old_blake3_hash.as_ref().map(Hash))
+                .finish(),
+            PatchOp::DeleteFile {
+                path,
+                old_blake3_hash,
+                may_be_absent,
+            } => f
+                .debug_struct("DeleteFile")
+                .field("path", path)
+                .field("old_blake3_hash", &old_blake3_hash.as_ref().map(Hash))
+                .field("may_be_absent", may_be_absent)
+                .finish(),
+            PatchOp::DeleteDir { path } => f.debug_struct("DeleteDir").field("path", path).finish(),
+            PatchOp::CreateHardlink { path, target } => f
+                .debug_struct("CreateHardlink")
+                .field("path", path)
+                .field("target", target)
+                .finish(),
+            PatchOp::ModifyFileMulti {
+                path,
+                variants,
+                new_blake3_hash,
+            } => f
+                .debug_struct("ModifyFileMulti")
+                .field("path", path)
+                .field("variants", &List(variants, Variant))
+                .field("new_blake3_hash", &Hash(new_blake3_hash))
+                .finish(),
+            PatchOp::SetMetadata { path, mode, mtime } => f
+                .debug_struct("SetMetadata")
+                .field("path", path)
+                .field("mode", &Mode(*mode))
+                .field("mtime", mtime)
+                .finish(),
+            PatchOp::VerifyFile { path, blake3_hash } => f
+                .debug_struct("VerifyFile")
+                .field("path", path)
+                .field("blake3_hash", &Hash(blake3_hash))
+                .finish(),
+            PatchOp::CreateSymlink { path, target } => f
+                .debug_struct("CreateSymlink")
+                .field("path", path)
+                .field("target", target)
+                .finish(),
+        }
+    }
+}
+
+impl fmt::Debug for Chunk<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            DiffChunk::Copy { offset, length } => f
+                .debug_struct("Copy")
+                .field("offset", offset)
+                .field("length", length)
+                .finish(),
+            DiffChunk::Insert { data } => f.debug_struct("Insert").field("data", &Bytes(data)).finish(),
+            DiffChunk::Zeros { length } => f.debug_struct("Zeros").field("length", length).finish(),
+        }
+    }
+}
+
+impl fmt::Debug for Variant<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let BaseDiff {
+            base,
+            base_hash,
+            diff_chunks,
+            block_size,
+        } = self.0;
+        f.debug_struct("BaseDiff")
+            .field("base", base)
+            .field("base_hash", &Hash(base_hash))
+            .field("diff_chunks", &List(diff_chunks, Chunk))
+            .field("block_size", block_size)
+            .finish()
+    }
+}
+
+struct GzipRecompress<'a>(&'a [u8], u32);
+
+impl fmt::Debug for GzipRecompress<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Gzip")
+            .field("header", &Bytes(self.0))
+            .field("level", &self.1)
+            .finish()
+    }
+}
+
+/// Permission bits in octal, as `chmod` takes them.
+struct Mode(Option<u32>);
+
+impl fmt::Debug for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(mode) => write!(f, "Some(0o{:o})", mode),
+            None => write!(f, "None"),
+        }
+    }
+}
+
+impl fmt::Debug for Attrs<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, value)| (name, Bytes(value))))
+            .finish()
+    }
+}
+
+impl fmt::Debug for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = &self.0[..self.0.len().min(PREVIEW_BYTES)];
+        let hex: String = shown.iter().map(|b| format!("{:02x}", b)).collect();
+        let more = if shown.len() < self.0.len() { "..." } else { "" };
+        write!(f, "<{} bytes: {}{}>", self.0.len(), hex, more)
+    }
+}
+
+impl fmt::Debug for Hash<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", util::hash_hex(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch_format::FORMAT_VERSION;
+    use crate::util::HashAlgo;
+
+    #[test]
+    fn test_dump_previews_bytes_and_keeps_the_structure() {
+        let manifest = PatchManifest {
+            version: FORMAT_VERSION,
+            hash_algo: HashAlgo::Blake3,
+            full_file_set: false,
+            operations: vec![
+                PatchOp::AddFile {
+                    path: "big.bin".to_string(),
+                    data: vec![0xab; 100_000],
+                    blake3_hash: [0x11; 32],
+                    compressed: false,
+                    xattrs: vec![("user.tag".to_string(), b"hi".to_vec())],
+                    external: false,
+                },
+                PatchOp::ModifyFile {
+                    path: "app.exe".to_string(),
+                    diff_chunks: vec![
+                        DiffChunk::Copy { offset: 0, length: 4096 },
+                        DiffChunk::Insert { data: b"new".to_vec() },
+                    ],
+                    new_blake3_hash: [0x22; 32],
+                    block_size: 1024,
+                    recompress: None,
+                    xattrs: Vec::new(),
+                    old_blake3_hash: None,
+                },
+                PatchOp::CreateDir {
+                    path: "dir".to_string(),
+                    mode: Some(0o755),
+                },
+            ],
+        };
+        let dump = dump_manifest(&manifest);
+        assert!(dump.contains(&format!("data: <100000 bytes: {}...>", "ab".repeat(PREVIEW_BYTES))), "{}", dump);
+        assert!(dump.contains(&format!("blake3_hash: {},", "11".repeat(32))), "{}", dump);
+        assert!(dump.contains("\"user.tag\": <2 bytes: 6869>"), "{}", dump);
+        assert!(dump.contains("data: <3 bytes: 6e6577>"), "{}", dump);
+        assert!(dump.contains("length: 4096"), "{}", dump);
+        assert!(dump.contains("mode: Some(0o755)"), "{}", dump);
+        assert!(dump.contains(&format!("version: {},", FORMAT_VERSION)), "{}", dump);
+        // A line per field, not per byte.
+        assert!(dump.lines().count() < 60, "{}", dump);
+    }
+}
@@ -0,0 +1,145 @@
+use std::io;
+
+/// Error returned by [`create_patch`](crate::create::create_patch) and
+/// [`apply_patch`](crate::apply::apply_patch), so library users can tell a corrupt
+/// patch from a missing target or a hash mismatch without matching on messages.
+///
+/// Internally each step still adds `anyhow` context as it goes. The typed variants are
+/// raised where the problem is detected and recovered at the public boundary; anything
+/// else is classified as [`PatchError::Io`] or kept as [`PatchError::Other`].
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+    /// The file doesn't start with the patch magic, so it isn't a patch at all.
+    #[error("Invalid patch file: missing magic header")]
+    InvalidMagic,
+    /// The patch was written by an incompatible format version: another major version,
+    /// or a newer minor one (see [`FormatVersion`](crate::patch_format::FormatVersion)).
+    #[error("Unsupported patch version: {found} (this build reads {}.0 to {expected})", expected.major)]
+    UnsupportedVersion {
+        found: crate::patch_format::FormatVersion,
+        expected: crate::patch_format::FormatVersion,
+    },
+    /// The patch is structurally broken: truncated, inconsistent or holding a bad diff.
+    #[error("Invalid patch file: {0}")]
+    Corrupt(String),
+    /// Apply was given a MAC key (`--mac-key`) and the patch has no MAC trailer, or
+    /// its MAC doesn't match: the wrong key, or a patch altered since it was created.
+    #[error("Patch authentication failed: {0}")]
+    MacMismatch(String),
+    /// The zstd (or gzip) stream couldn't be decompressed.
+    #[error("Failed to decompress patch data: {0}")]
+    Decompress(String),
+    /// The decompressed payload couldn't be decoded.
+    #[error("Failed to deserialize patch data: {0}")]
+    Deserialize(String),
+    /// The patch exceeds an [`ApplyLimits`](crate::apply::ApplyLimits) bound.
+    #[error("{0}")]
+    LimitExceeded(String),
+    /// A file's content doesn't match the hash the patch records for it: `actual` is
+    /// the hash of what the patch produced, or `None` when its diff couldn't be applied
+    /// to the file at all.
+    #[error(
+        "Hash mismatch for {path}: expected {}, got {}",
+        crate::util::short_hash_hex(expected),
+        actual.as_ref().map_or_else(|| "a file the diff doesn't apply to".to_string(), crate::util::short_hash_hex)
+    )]
+    HashMismatch {
+        path: String,
+        expected: [u8; 32],
+        actual: Option<[u8; 32]>,
+    },
+    /// The patch modifies a file the target doesn't have: the target drifted, or isn't
+    /// the tree the patch was made from. See
+    /// [`ApplyOptions::add_missing`](crate::apply::ApplyOptions::add_missing).
+    #[error("Cannot modify missing file: {path} (the target may not match the patch's base version)")]
+    MissingModifyTarget { path: String },
+    /// `--paranoid` re-read a file after writing it and got a different hash: the
+    /// content was correct in memory but didn't land on disk intact.
+    #[error("Hash mismatch re-reading {path} after writing it (corrupted on the way to disk)")]
+    WriteVerifyFailed { path: String },
+    /// `--verify-before` found target files, listed in `paths`, that don't have the
+    /// content the patch was made from (changed or missing). Nothing was written.
+    #[error("Target doesn't match the tree the patch was made from: {} file(s) differ: {}", paths.len(), paths.join(", "))]
+    SourceMismatch { paths: Vec<String> },
+    /// Apply was stopped through [`ApplyOptions::interrupt`](crate::apply::ApplyOptions)
+    /// (e.g. Ctrl-C). Operations in flight were finished, none were left half-done, and
+    /// `completed` counts what was applied before stopping.
+    #[error("Apply interrupted; the target is partially patched")]
+    Interrupted {
+        completed: crate::patch_format::ApplySummary,
+    },
+    /// A filesystem operation failed; `context` says which one.
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Any other failure (bad options, mismatched snapshot, internal task failure).
+    #[error("{0:#}")]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for PatchError {
+    fn from(err: anyhow::Error) -> Self {
+        // Context layers only add prose; the typed cause decides the variant.
+        let err = match err.downcast::<PatchError>() {
+            Ok(typed) => return typed,
+            Err(err) => err,
+        };
+        if err.root_cause().is::<io::Error>() {
+            let context = err
+                .chain()
+                .take_while(|cause| !cause.is::<io::Error>())
+                .map(|cause| cause.to_string())
+                .collect::<Vec<_>>();
+            let context = if context.is_empty() {
+                "I/O error".to_string()
+            } else {
+                context.join(": ")
+            };
+            let source = err.downcast::<io::Error>().expect("root cause is io::Error");
+            return PatchError::Io { context, source };
+        }
+        PatchError::Other(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_typed_error_survives_context() {
+        let err = anyhow::Error::new(PatchError::HashMismatch {
+            path: "a.txt".to_string(),
+            expected: [1; 32],
+            actual: None,
+        })
+        .context("Failed to apply patch");
+        let err = PatchError::from(err);
+        assert!(matches!(
+            &err,
+            PatchError::HashMismatch { path, actual: None, .. } if path == "a.txt"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Hash mismatch for a.txt: expected 0101010101010101..., got a file the diff doesn't apply to"
+        );
+    }
+
+    #[test]
+    fn test_io_error_keeps_context_and_kind() {
+        let err = std::fs::read("/nonexistent/patcher/file")
+            .context("Failed to read file")
+            .unwrap_err();
+        match PatchError::from(err) {
+            PatchError::Io { context, source } => {
+                assert_eq!(context, "Failed to read file");
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            other => panic!("expected Io, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{bail, Context, Result};
+use std::io::Write;
+use std::path::Path;
+
+use crate::apply::{self, ApplyLimits};
+use crate::error::PatchError;
+use crate::patch_format::{self, PatchOp};
+use crate::patch_index;
+use crate::util;
+
+/// Write the content of the file `path` adds, as stored in the patch, to `out` (`-` for
+/// stdout), without a target tree. The content is checked against its recorded hash
+/// before anything is written, so a corrupt patch leaves `out` untouched. Only
+/// `AddFile` content is self-contained: a modified file's diff needs the old file, and
+/// other operations carry no content. Returns the number of bytes written.
+///
+/// The operation is read through the patch's index where it has one, so a huge patch
+/// isn't decoded whole (see [`patch_index::read_op`]).
+pub fn extract_file(patch_path: &Path, path: &str, out: &Path, limits: &ApplyLimits) -> Result<u64> {
+    let path = util::normalize_relative_path(&path.replace('\\', "/"))?;
+    let (hash_algo, op) = match patch_index::read_op(patch_path, &path, limits)? {
+        Some(found) => (found.hash_algo, found.op),
+        None => {
+            let manifest = apply::read_manifest(patch_path, limits)?;
+            let Some(op) = manifest.operations.into_iter().find(|op| op.path() == path) else {
+                bail!("{} is not in the patch", path);
+            };
+            (manifest.hash_algo, op)
+        }
+    };
+    let PatchOp::AddFile {
+        data,
+        blake3_hash,
+        compressed,
+        external,
+        ..
+    } = &op
+    else {
+        bail!(
+            "{} is a {}, not an AddFile; only added content can be extracted{}",
+            path,
+            op.name(),
+            match op {
+                PatchOp::ModifyFile { .. } | PatchOp::ModifyFileMulti { .. } =>
+                    " (its diff needs the old file)",
+                _ => "",
+            }
+        );
+    };
+
+    if *external {
+        bail!(
+            "{} has its content in an objects directory (--objects-dir), not in the patch",
+            path
+        );
+    }
+
+    // Decompressed twice, as on apply: once to check the hash, once into `out`.
+    let mut hasher = util::StreamHasher::new(hash_algo);
+    std::io::copy(&mut patch_format::add_file_reader(data, *compressed)?, &mut hasher)
+        .map_err(|e| PatchError::Decompress(format!("{}: {}", path, e)))?;
+    let actual = hasher.finalize();
+    if actual != *blake3_hash {
+        bail!(PatchError::HashMismatch {
+            path,
+            expected: *blake3_hash,
+            actual: Some(actual),
+        });
+    }
+
+    let mut reader = patch_format::add_file_reader(data, *compressed)?;
+    let written = if util::is_stdio(out) {
+        let mut stdout = std::io::stdout().lock();
+        let written = std::io::copy(&mut reader, &mut stdout).context("Failed to write to stdout")?;
+        stdout.flush()?;
+        written
+    } else {
+        let mut file = std::fs::File::create(out)
+            .with_context(|| format!("Failed to create {}", out.display()))?;
+        std::io::copy(&mut reader, &mut file)
+            .with_context(|| format!("Failed to write {}", out.display()))?
+    };
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch_format::{add_file_op, PatchManifest, FORMAT_VERSION};
+    use crate::util::HashAlgo;
+
+    #[test]
+    fn test_extract_checks_hash_and_op_kind() {
+        let temp = std::env::temp_dir().join("patcher_unit_extract");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let algo = HashAlgo::Blake3;
+        let big = vec![7u8; 2 << 20];
+        let patch = temp.join("p.patch");
+        let manifest = PatchManifest {
+            version: FORMAT_VERSION,
+            hash_algo: algo,
+            full_file_set: false,
+            operations: vec![
+                add_file_op("big.bin".into(), &big, util::hash_bytes(algo, &big), false, Vec::new())
+                    .unwrap(),
+                add_file_op("bad.txt".into(), b"content", [0; 32], false, Vec::new()).unwrap(),
+                PatchOp::DeleteFile { path: "gone.txt".into(), old_blake3_hash: None, may_be_absent: false },
+            ],
+        };
+        crate::create::write_manifest(&patch, &manifest, false).unwrap();
+        let limits = ApplyLimits::default();
+
+        // Stored as its own zstd frame, and still extracted byte for byte.
+        assert!(matches!(&manifest.operations[0], PatchOp::AddFile { compressed: true, .. }));
+        let out = temp.join("big.out");
+        assert_eq!(extract_file(&patch, "./big.bin", &out, &limits).unwrap(), big.len() as u64);
+        assert_eq!(std::fs::read(&out).unwrap(), big);
+
+        let out = temp.join("bad.out");
+        let err = extract_file(&patch, "bad.txt", &out, &limits).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(PatchError::HashMismatch { .. })), "{:#}", err);
+        assert!(!out.exists());
+
+        let err = extract_file(&patch, "gone.txt", &out, &limits).unwrap_err();
+        assert!(format!("{:#}", err).contains("is a DeleteFile"), "{:#}", err);
+        assert!(extract_file(&patch, "missing.txt", &out, &limits).is_err());
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Include/exclude glob filter applied to relative paths (forward slashes) after the walk.
+///
+/// A path matches a pattern if the path itself or any of its ancestor directories does,
+/// so `assets` and `assets/**` both select the whole `assets/` subtree. `*` also matches
+/// `/`, so `*.log` matches log files at any depth.
+///
+/// Precedence: exclude wins over include. With no include patterns every path is included.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+fn build_set(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).with_context(|| format!("Invalid glob pattern: {}", pattern))?;
+        builder.add(glob);
+    }
+    Ok(Some(builder.build().context("Failed to compile glob patterns")?))
+}
+
+/// True if `path` or any of its ancestors matches `set`.
+fn matches_self_or_ancestor(set: &GlobSet, path: &str) -> bool {
+    let mut cur = path;
+    loop {
+        if set.is_match(cur) {
+            return true;
+        }
+        match cur.rfind('/') {
+            Some(idx) => cur = &cur[..idx],
+            None => return false,
+        }
+    }
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: build_set(include)?,
+            exclude: build_set(exclude)?,
+        })
+    }
+
+    /// True when no patterns are configured and every path passes.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none()
+    }
+
+    /// Whether `path` should take part in the patch.
+    pub fn allows(&self, path: &str) -> bool {
+        if let Some(exclude) = &self.exclude {
+            if matches_self_or_ancestor(exclude, path) {
+                return false;
+            }
+        }
+        match &self.include {
+            Some(include) => matches_self_or_ancestor(include, path),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let include: Vec<String> = include.iter().map(|s| s.to_string()).collect();
+        let exclude: Vec<String> = exclude.iter().map(|s| s.to_string()).collect();
+        PathFilter::new(&include, &exclude).unwrap()
+    }
+
+    #[test]
+    fn test_empty_filter_allows_everything() {
+        let f = filter(&[], &[]);
+        assert!(f.is_empty());
+        assert!(f.allows("anything/at/all.txt"));
+    }
+
+    #[test]
+    fn test_include_selects_subtree() {
+        let f = filter(&["assets/**"], &[]);
+        assert!(f.allows("assets/img/logo.png"));
+        assert!(!f.allows("src/main.rs"));
+
+        let f = filter(&["assets"], &[]);
+        assert!(f.allows("assets"));
+        assert!(f.allows("assets/img/logo.png"));
+        assert!(!f.allows("assets2/x"));
+    }
+
+    #[test]
+    fn test_exclude_wins_over_include() {
+        let f = filter(&["assets/**"], &["*.tmp", "assets/cache"]);
+        assert!(f.allows("assets/a.png"));
+        assert!(!f.allows("assets/a.tmp"));
+        assert!(!f.allows("assets/cache/blob.bin"));
+        assert!(!f.allows("other/b.png"));
+    }
+
+    #[test]
+    fn test_invalid_pattern_is_error() {
+        assert!(PathFilter::new(&["[".to_string()], &[]).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::util::HashAlgo;
+
+/// Magic bytes identifying a hash cache file (`--hash-cache`).
+pub const HASH_CACHE_MAGIC: &[u8; 8] = b"PATCHHC1";
+
+/// A file modified this close to the start of the run isn't cached: a write landing in
+/// the same mtime tick after it was hashed would leave size and mtime unchanged, and
+/// the next run would trust a stale hash. Coarser than any common mtime granularity.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// What a file looked like when it was hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedHash {
+    size: u64,
+    /// Modification time as (seconds, nanoseconds) since the Unix epoch.
+    mtime: (u64, u32),
+    hash: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    hash_algo: HashAlgo,
+    /// Keyed by the file's full (canonical) path, so one cache serves both trees.
+    entries: HashMap<String, CachedHash>,
+}
+
+/// Hashes from earlier create runs, keyed by path and valid while the file keeps its
+/// size and mtime (`--hash-cache`). Shared by the hashing workers; lookups take no lock.
+///
+/// Only the entries looked up or recorded during this run are saved, so files that
+/// have left both trees drop out of the cache instead of accumulating.
+pub struct HashCache {
+    hash_algo: HashAlgo,
+    known: HashMap<String, CachedHash>,
+    seen: Mutex<HashMap<String, CachedHash>>,
+    /// Files modified at or after this instant are not recorded; see [`RACY_WINDOW`].
+    cutoff: SystemTime,
+    hits: AtomicUsize,
+}
+
+fn mtime_key(modified: SystemTime) -> Option<(u64, u32)> {
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some((since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
+
+impl HashCache {
+    /// Load the cache at `path`. A missing file is an empty cache, as is one recorded
+    /// with another hash algorithm. An unreadable or corrupt one is an error: the
+    /// file may not be a cache at all, and saving would overwrite it.
+    pub fn load(path: &Path, hash_algo: HashAlgo) -> Result<Self> {
+        let mut cache = Self {
+            hash_algo,
+            known: HashMap::new(),
+            seen: Mutex::new(HashMap::new()),
+            cutoff: SystemTime::now() - RACY_WINDOW,
+            hits: AtomicUsize::new(0),
+        };
+        let raw = match std::fs::read(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read hash cache: {}", path.display()))
+            }
+        };
+        if raw.len() < HASH_CACHE_MAGIC.len() || &raw[..HASH_CACHE_MAGIC.len()] != HASH_CACHE_MAGIC {
+            bail!("Not a hash cache file: {}", path.display());
+        }
+        let decoder = zstd::Decoder::new(&raw[HASH_CACHE_MAGIC.len()..])
+            .context("Failed to create zstd decoder")?;
+        let file: CacheFile = bincode::deserialize_from(decoder)
+            .with_context(|| format!("Failed to decode hash cache: {}", path.display()))?;
+        if file.hash_algo == hash_algo {
+            cache.known = file.entries;
+        }
+        Ok(cache)
+    }
+
+    /// The cached hash of the file at `full_path`, if it still has `size` and `modified`.
+    pub fn get(&self, full_path: &Path, size: u64, modified: Option<SystemTime>) -> Option<[u8; 32]> {
+        let mtime = mtime_key(modified?)?;
+        let key = full_path.to_string_lossy();
+        let entry = self.known.get(key.as_ref())?;
+        if entry.size != size || entry.mtime != mtime {
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.seen
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key.into_owned(), *entry);
+        Some(entry.hash)
+    }
+
+    /// Remember `hash` for the file at `full_path` as it was walked (`size`, `modified`).
+    pub fn insert(&self, full_path: &Path, size: u64, modified: Option<SystemTime>, hash: [u8; 32]) {
+        let Some(modified) = modified.filter(|&m| m < self.cutoff) else {
+            return;
+        };
+        let Some(mtime) = mtime_key(modified) else {
+            return;
+        };
+        self.seen
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(full_path.to_string_lossy().into_owned(), CachedHash { size, mtime, hash });
+    }
+
+    /// Hashes answered from the cache so far.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Write this run's entries to `path`, through a temporary file renamed into place
+    /// so an interrupted save leaves the previous cache intact.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = CacheFile {
+            hash_algo: self.hash_algo,
+            entries: std::mem::take(&mut *self.seen.lock().unwrap_or_else(|e| e.into_inner())),
+        };
+        let encoded = bincode::serialize(&file).context("Failed to serialize hash cache")?;
+        let compressed = zstd::bulk::compress(&encoded, 3).context("Failed to compress hash cache")?;
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp = path.with_file_name(temp_name);
+        let mut out = std::fs::File::create(&temp)
+            .with_context(|| format!("Failed to create {}", temp.display()))?;
+        out.write_all(HASH_CACHE_MAGIC)?;
+        out.write_all(&compressed)?;
+        out.flush()?;
+        drop(out);
+        std::fs::rename(&temp, path)
+            .with_context(|| format!("Failed to write hash cache: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_hits_only_on_same_size_and_mtime() {
+        let temp = std::env::temp_dir().join("patcher_unit_hash_cache");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let file = temp.join("cache");
+        let old = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let a = Path::new("/tree/a.txt");
+
+        let cache = HashCache::load(&file, HashAlgo::Blake3).unwrap();
+        assert_eq!(cache.get(a, 5, Some(old)), None);
+        cache.insert(a, 5, Some(old), [1; 32]);
+        // Modified just now: could still change within the same mtime tick.
+        cache.insert(Path::new("/tree/fresh.txt"), 5, Some(SystemTime::now()), [2; 32]);
+        cache.save(&file).unwrap();
+
+        let cache = HashCache::load(&file, HashAlgo::Blake3).unwrap();
+        assert_eq!(cache.get(a, 5, Some(old)), Some([1; 32]));
+        assert_eq!(cache.get(a, 6, Some(old)), None);
+        assert_eq!(cache.get(a, 5, Some(old + Duration::from_nanos(1))), None);
+        assert_eq!(cache.get(a, 5, None), None);
+        assert_eq!(cache.get(Path::new("/tree/fresh.txt"), 5, Some(old)), None);
+        assert_eq!(cache.hits(), 1);
+
+        // Hashes of another algorithm don't carry over, and junk is refused.
+        let cache = HashCache::load(&file, HashAlgo::Sha256).unwrap();
+        assert_eq!(cache.get(a, 5, Some(old)), None);
+        std::fs::write(&file, b"something else").unwrap();
+        assert!(HashCache::load(&file, HashAlgo::Blake3).is_err());
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+}
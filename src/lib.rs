@@ -0,0 +1,29 @@
+//! Binary patching for directory trees: create and apply compact patches between
+//! directory snapshots. The `patcher` binary is a thin CLI over these modules.
+//!
+//! Concurrency: `create_patch` and `apply_patch` are async only to overlap independent
+//! stages. Each stage runs inside `tokio::task::spawn_blocking` and does its real work
+//! with Rayon `par_iter`, so all CPU-heavy parallelism comes from Rayon's global pool
+//! (sized to the core count). Only a handful of blocking tasks ever exist at once; the
+//! binary caps Tokio's blocking pool accordingly. Embedders should do the same rather
+//! than rely on Tokio's default of 512 blocking threads.
+
+pub mod apply;
+pub mod binary_diff;
+pub mod binary_patch;
+pub mod change_tree;
+pub mod create;
+pub mod dump;
+pub mod error;
+pub mod extract;
+pub mod filter;
+pub mod hash_cache;
+pub mod merge;
+pub mod patch_format;
+pub mod patch_index;
+pub mod recompress;
+pub mod rolling_hash;
+pub mod snapshot;
+pub mod util;
+pub mod validate;
+pub mod verify;
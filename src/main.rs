@@ -1,18 +1,31 @@
-mod apply;
-mod binary_diff;
-mod binary_patch;
-mod create;
-mod patch_format;
-mod rolling_hash;
-mod util;
-
+use anyhow::Context;
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use patcher::error::PatchError;
+use patcher::patch_format::{ApplySummary, MacKey, ManifestEncoding, PhaseTiming};
+use patcher::{apply, change_tree, create, dump, extract, merge, snapshot, util, validate, verify};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
 
+/// `println!`, switched to stderr when stdout carries the patch (`--output -`).
+macro_rules! say {
+    ($to_stderr:expr, $($arg:tt)*) => {
+        if $to_stderr {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
 #[derive(Parser)]
 #[command(name = "patcher", about = "Binary patch creator and applier")]
 struct Cli {
+    /// Print one line per operation (added, modified, deleted)
+    #[arg(long, short, global = true)]
+    verbose: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -21,68 +34,948 @@ struct Cli {
 enum Commands {
     /// Create a patch by comparing old and new directories
     Create {
-        /// Path to the old (original) directory
-        #[arg(long)]
-        old: PathBuf,
+        /// Path to the old (original) directory, or a snapshot file of it. Repeat to
+        /// build one patch that applies to any of several old directories
+        #[arg(long, required = true)]
+        old: Vec<PathBuf>,
         /// Path to the new (updated) directory
         #[arg(long)]
         new: PathBuf,
-        /// Output path for the patch file
-        #[arg(long, short)]
-        output: PathBuf,
+        /// Output path for the patch file, or `-` for stdout
+        #[arg(long, short, required_unless_present = "compare_only")]
+        output: Option<PathBuf>,
+        /// Hash algorithm used to verify file contents
+        #[arg(long = "hash", value_enum, default_value_t = util::HashAlgo::Blake3)]
+        hash_algo: util::HashAlgo,
+        /// Only consider paths matching this glob (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<String>,
+        /// Leave out paths matching this glob (repeatable; wins over --include)
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+        /// Warn about and skip entries that can't be read instead of failing
+        #[arg(long)]
+        skip_unreadable: bool,
+        /// Store paths that aren't valid UTF-8 with their odd bytes escaped instead of failing
+        #[arg(long)]
+        allow_non_utf8: bool,
+        /// Warn about and skip files that change size or vanish while the patch is created
+        #[arg(long)]
+        skip_changing: bool,
+        /// Cap the file bytes hashed, diffed or buffered at once (e.g. 512M)
+        #[arg(long, value_name = "BYTES", value_parser = util::parse_size)]
+        memory_budget: Option<u64>,
+        /// Also write a unified diff of the modified text files here, for release notes
+        #[arg(long, value_name = "FILE")]
+        changelog: Option<PathBuf>,
+        /// Diff gzip files with this extension by their decompressed content (repeatable, e.g. gz)
+        #[arg(long, value_name = "EXT")]
+        recompress_ext: Vec<String>,
+        /// Store files with this extension whole instead of diffing them, like .zip or .png (repeatable, e.g. wasm)
+        #[arg(long, value_name = "EXT")]
+        incompressible_ext: Vec<String>,
+        /// Diff and compress files with this extension even if it's on the built-in already-compressed list (repeatable)
+        #[arg(long, value_name = "EXT")]
+        force_compress_ext: Vec<String>,
+        /// Reuse hashes of files whose size and mtime are unchanged since the last run, kept in this file
+        #[arg(long, value_name = "FILE")]
+        hash_cache: Option<PathBuf>,
+        /// Write the patch as numbered parts (<output>.001, .002...) of at most this size (e.g. 100M)
+        #[arg(long, value_name = "BYTES", value_parser = util::parse_size)]
+        split_size: Option<u64>,
+        /// Store added files' content in this directory, named by hash and shared across patches, instead of in the patch
+        #[arg(long, value_name = "DIR")]
+        objects_dir: Option<PathBuf>,
+        /// Diff each modified file every candidate way, keep the smallest and print which won (slower)
+        #[arg(long)]
+        explain: bool,
+        /// Store every modified file whole instead of as a diff, so the patch doesn't depend on the old bytes
+        #[arg(long)]
+        force_full: bool,
+        /// Give up diffing a file after this many seconds and store it whole (guards against degenerate inputs)
+        #[arg(long, value_name = "SECS")]
+        diff_timeout: Option<u64>,
+        /// Keep block matches shorter than this inside the surrounding insert instead of
+        /// copying them (e.g. 16K), for files that share only coincidental runs
+        #[arg(long, value_name = "BYTES", value_parser = util::parse_size)]
+        min_match: Option<u64>,
+        /// Record hashes of unchanged files too, so `verify` can check the whole tree
+        #[arg(long)]
+        full_verify: bool,
+        /// Read buffer size for hashing files (e.g. 64K, 4M)
+        #[arg(long, value_name = "BYTES", value_parser = util::parse_size, default_value = "256K")]
+        read_buffer: u64,
+        /// Wrap the patch in gzip for CDNs and download tools (apply detects it)
+        #[arg(long)]
+        gzip: bool,
+        /// Manifest serialization: compact bincode, or self-describing CBOR for other tools
+        #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ManifestEncoding::Bincode)]
+        output_format: ManifestEncoding,
+        /// Append a BLAKE3 keyed MAC of the patch, using a key derived from this secret file
+        #[arg(long, value_name = "KEYFILE")]
+        mac_key: Option<PathBuf>,
+        /// Store hard-linked added files as links instead of duplicate content (Unix)
+        #[arg(long)]
+        preserve_hardlinks: bool,
+        /// Store symbolic links' targets as they are, or rewrite absolute ones into the tree as relative
+        #[arg(long, value_enum, default_value_t = create::SymlinkMode::Verbatim)]
+        symlink_mode: create::SymlinkMode,
+        /// Report walk totals and hashing progress (N/total files) on stderr
+        #[arg(long)]
+        progress: bool,
+        /// Treat same-size files not modified since this RFC 3339 time as unchanged, unhashed
+        #[arg(long, value_name = "TIMESTAMP", value_parser = util::parse_timestamp)]
+        since: Option<std::time::SystemTime>,
+        /// Record permission and mtime changes of files whose content is unchanged
+        #[arg(long)]
+        metadata: bool,
+        /// Record extended attributes (e.g. SELinux labels) of added and modified files
+        #[arg(long)]
+        preserve_xattrs: bool,
+        /// List what would change (added, modified, deleted paths) without writing a patch
+        #[arg(long, conflicts_with_all = ["output", "gzip", "changelog"])]
+        compare_only: bool,
+        /// With --compare-only, skip hashing: files differing in size or mtime count as modified
+        #[arg(long, requires = "compare_only")]
+        fast: bool,
+        /// Print how long each phase (walk, classify, hash+diff, write...) took
+        #[arg(long)]
+        timing: bool,
+        /// List the operations carrying the most content, to see what makes a patch large
+        #[arg(long)]
+        stats: bool,
+        /// How many operations --stats lists
+        #[arg(long, value_name = "N", default_value_t = 10, requires = "stats")]
+        stats_top: usize,
+        /// Print the paths the patch touches as an indented tree (+ added, ~ modified, - deleted)
+        #[arg(long, conflicts_with = "compare_only")]
+        print_tree: bool,
+        /// Overwrite the output file if it already exists
+        #[arg(long)]
+        force: bool,
     },
     /// Apply a patch to a target directory
     Apply {
         /// Path to the target directory to patch
         #[arg(long)]
         target: PathBuf,
+        /// Path to the patch file, or `-` for stdin
+        #[arg(long, short)]
+        patch: PathBuf,
+        /// Move deleted files into this directory instead of removing them
+        #[arg(long, value_name = "DIR")]
+        quarantine: Option<PathBuf>,
+        /// Refuse patches with more operations than this
+        #[arg(long, default_value_t = apply::ApplyLimits::default().max_files)]
+        max_files: u64,
+        /// Refuse patches that would write a single file larger than this (e.g. 512M, 4G)
+        #[arg(long, value_name = "BYTES", value_parser = util::parse_size, default_value = "4G")]
+        max_file_size: u64,
+        /// Refuse patches whose decompressed manifest is larger than this
+        #[arg(long, value_name = "BYTES", value_parser = util::parse_size, default_value = "4G")]
+        max_total_size: u64,
+        /// Retry writes and deletes this many times on transient errors (e.g. SMB/NFS)
+        #[arg(long, default_value_t = apply::DEFAULT_RETRIES)]
+        retries: u32,
+        /// Write the patched tree to this new directory, leaving the target untouched
+        #[arg(long, value_name = "DIR")]
+        out: Option<PathBuf>,
+        /// Re-read each added or modified file after writing and verify its hash again
+        #[arg(long)]
+        paranoid: bool,
+        /// Leave files that fail their hash check untouched, list them, and exit non-zero
+        #[arg(long)]
+        skip_mismatches: bool,
+        /// Print how long each phase (read, decompress, add, modify...) took
+        #[arg(long)]
+        timing: bool,
+        /// List the files and directories the patch deletes and ask before deleting them
+        #[arg(long)]
+        interactive: bool,
+        /// Answer yes to the --interactive prompt (needed when stdin isn't a terminal)
+        #[arg(long, requires = "interactive")]
+        yes: bool,
+        /// Stage rewritten files here before renaming them into place [default: TARGET/.patcher-tmp]
+        #[arg(long, value_name = "DIR")]
+        temp_dir: Option<PathBuf>,
+        /// Refuse the patch unless its MAC matches this secret file (see create --mac-key)
+        #[arg(long, value_name = "KEYFILE")]
+        mac_key: Option<PathBuf>,
+        /// Also delete files the patch doesn't list, leaving exactly the patched tree (needs create --full-verify)
+        #[arg(long)]
+        prune: bool,
+        /// Modify and delete read-only target files, making them writable first (modified ones stay read-only)
+        #[arg(long)]
+        force: bool,
+        /// Apply only the operations on paths under this prefix (repeatable, e.g. assets)
+        #[arg(long, value_name = "PREFIX")]
+        only: Vec<String>,
+        /// Directory holding added content for patches created with --objects-dir
+        #[arg(long, value_name = "DIR")]
+        objects_dir: Option<PathBuf>,
+        /// Before writing anything, check that every file to modify or delete still has its pre-patch content
+        #[arg(long)]
+        verify_before: bool,
+        /// Refuse a target that is a symlink instead of patching the directory it points to
+        #[arg(long)]
+        no_follow_target: bool,
+        /// Patch files that drifted from the version the patch was made against, as long as the result checks out
+        #[arg(long, conflicts_with = "verify_before")]
+        lenient_base: bool,
+        /// Write a file to modify that the target lacks from its diff, when the diff holds the whole new content
+        #[arg(long)]
+        add_missing: bool,
+        /// When to delete: alongside adds and modifies, or all before them to free disk space first
+        #[arg(long, value_enum, default_value_t = apply::ApplyOrder::Default)]
+        order: apply::ApplyOrder,
+        /// After a fully successful apply, run this shell command in the patched tree and exit with its status
+        #[arg(long, value_name = "CMD")]
+        post_apply: Option<String>,
+    },
+    /// List the changes between two trees as `STATUS<TAB>PATH` lines, without building a patch
+    Diff {
+        /// Path to the old directory (or a snapshot file)
+        #[arg(long)]
+        old: PathBuf,
+        /// Path to the new directory
+        #[arg(long)]
+        new: PathBuf,
+        /// Print a JSON array of {"status", "path"} objects instead
+        #[arg(long)]
+        json: bool,
+        /// End each record with a NUL byte instead of a newline, for `xargs -0`
+        #[arg(long, short = '0', conflicts_with = "json")]
+        null: bool,
+        /// Hash algorithm used to confirm modifications
+        #[arg(long = "hash", value_enum, default_value_t = util::HashAlgo::Blake3)]
+        hash_algo: util::HashAlgo,
+        /// Only consider paths matching this glob (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<String>,
+        /// Leave out paths matching this glob (repeatable; wins over --include)
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+        /// Warn about and skip entries that can't be read instead of failing
+        #[arg(long)]
+        skip_unreadable: bool,
+    },
+    /// Combine two sequential patches (A→B, B→C) into one A→C patch
+    Merge {
+        /// The earlier patch (A→B)
+        #[arg(long)]
+        first: PathBuf,
+        /// The later patch (B→C)
+        #[arg(long)]
+        second: PathBuf,
+        /// Output path for the merged patch, or `-` for stdout
+        #[arg(long, short)]
+        output: PathBuf,
+    },
+    /// Check that a directory matches the state a patch produces (read-only)
+    Verify {
+        /// Path to the directory to check
+        #[arg(long)]
+        target: PathBuf,
         /// Path to the patch file
         #[arg(long, short)]
         patch: PathBuf,
     },
+    /// Check a patch's internal consistency without a target (for CI before release)
+    Validate {
+        /// Path to the patch file
+        #[arg(long, short)]
+        patch: PathBuf,
+    },
+    /// Write one added file's content out of a patch, without applying it
+    Extract {
+        /// Path to the patch file
+        #[arg(long, short)]
+        patch: PathBuf,
+        /// The file's path inside the patch (as listed by --verbose)
+        #[arg(long)]
+        path: String,
+        /// Where to write the content, or `-` for stdout
+        #[arg(long, short)]
+        out: PathBuf,
+    },
+    /// Pretty-print a patch's decoded manifest, byte fields shortened (for debugging the format)
+    #[command(hide = true)]
+    Dump {
+        /// Path to the patch file, or `-` for stdin
+        #[arg(long, short)]
+        patch: PathBuf,
+    },
+    /// Record a directory's paths, sizes and hashes (no content) for later comparison
+    Snapshot {
+        /// Directory to snapshot
+        #[arg(long)]
+        dir: PathBuf,
+        /// Output path for the snapshot file
+        #[arg(long, short)]
+        output: PathBuf,
+        /// Hash algorithm for file contents
+        #[arg(long = "hash", value_enum, default_value_t = util::HashAlgo::Blake3)]
+        hash_algo: util::HashAlgo,
+    },
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// Upper bound on Tokio's blocking pool.
+///
+/// Tokio only orchestrates: every stage hands its work to a few `spawn_blocking` tasks
+/// (at most three run at once), and each of those immediately fans out onto Rayon's
+/// global pool, which already has one thread per core. Blocking threads therefore spend
+/// their time parked on Rayon; more of them would only add idle threads and contention,
+/// never more parallelism. Tokio's default cap of 512 is meant for blocking I/O, not for
+/// this pattern.
+const MAX_BLOCKING_THREADS: usize = 4;
+
+/// Async worker threads. They only await `spawn_blocking` handles, so two is plenty.
+const WORKER_THREADS: usize = 2;
+
+fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(WORKER_THREADS)
+        .max_blocking_threads(MAX_BLOCKING_THREADS)
+        .enable_all()
+        .build()?;
+    runtime.block_on(run(cli))
+}
+
+/// `--progress` output for create: the walk totals, then about 20 hashing updates.
+fn print_create_progress(event: create::CreateProgress) {
+    match event {
+        create::CreateProgress::Walked {
+            old_entries,
+            new_entries,
+            files_to_hash,
+        } => eprintln!(
+            "walked {} old and {} new entries; {} files to hash",
+            old_entries, new_entries, files_to_hash
+        ),
+        create::CreateProgress::Hashed { done, total } => {
+            if done == total || done % (total / 20).max(1) == 0 {
+                eprintln!("hashed {}/{} files", done, total);
+            }
+        }
+    }
+}
+
+/// Per-kind operation counts shared by the apply success and interrupted output.
+fn print_apply_counts(summary: &ApplySummary) {
+    println!("  Directories created: {}", summary.dirs_created);
+    println!("  Files added: {}", summary.files_added);
+    println!("  Files modified: {}", summary.files_modified);
+    println!("  Files deleted: {}", summary.files_deleted);
+    println!("  Directories deleted: {}", summary.dirs_deleted);
+    if summary.hardlinks_created > 0 {
+        println!("  Hard links created: {}", summary.hardlinks_created);
+    }
+    if summary.symlinks_created > 0 {
+        println!("  Symbolic links created: {}", summary.symlinks_created);
+    }
+    if summary.metadata_updated > 0 {
+        println!("  Metadata updated: {}", summary.metadata_updated);
+    }
+    if summary.deletes_declined > 0 {
+        println!("  Deletions declined (kept): {}", summary.deletes_declined);
+    }
+}
+
+/// `apply --interactive`: list what the patch deletes, then ask on stdin unless `yes`.
+/// Anything but `y` or `yes` declines.
+fn confirm_deletes(files: &[String], dirs: &[String], yes: bool) -> bool {
+    println!("\nThe patch deletes:");
+    for path in files {
+        println!("- {}", path);
+    }
+    for path in dirs {
+        println!("- {}/", path);
+    }
+    let question = format!("Delete {} file(s) and {} directory(ies)?", files.len(), dirs.len());
+    if yes {
+        println!("{} yes (--yes)", question);
+        return true;
+    }
+    print!("{} [y/N] ", question);
+    let mut answer = String::new();
+    if std::io::stdout().flush().is_err() || std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// `apply --post-apply`: run `command` through the platform shell (`sh -c`, or `cmd /C`
+/// on Windows) in `dir`, sharing patcher's stdin, stdout and stderr. A failing command
+/// ends patcher with the same exit code (1 if it was killed by a signal).
+fn run_post_apply(command: &str, dir: &Path) -> anyhow::Result<()> {
+    println!("\nRunning post-apply command: {}", command);
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let status = shell
+        .arg(command)
+        .current_dir(dir)
+        .status()
+        .with_context(|| format!("Failed to run post-apply command: {}", command))?;
+    if !status.success() {
+        eprintln!("post-apply command failed ({})", status);
+        std::process::exit(status.code().filter(|&code| code != 0).unwrap_or(1));
+    }
+    Ok(())
+}
+
+/// "1.234s (56.7 MB/s)": elapsed time and the rate `bytes` went by, in decimal megabytes.
+fn elapsed_with_rate(elapsed: std::time::Duration, bytes: u64) -> String {
+    let secs = elapsed.as_secs_f64();
+    format!("{:.3}s ({:.1} MB/s)", secs, bytes as f64 / 1e6 / secs.max(f64::EPSILON))
+}
+
+/// "42.0 MB", "8.5 KB", "20 bytes": `bytes` in decimal units, as in [`elapsed_with_rate`].
+fn human_size(bytes: u64) -> String {
+    match bytes {
+        0..=999 => format!("{} bytes", bytes),
+        1_000..=999_999 => format!("{:.1} KB", bytes as f64 / 1e3),
+        _ => format!("{:.1} MB", bytes as f64 / 1e6),
+    }
+}
+
+/// `--timing` breakdown, one row per phase with its share of the total. Concurrent
+/// phases overlap, so their shares can add up to more than their wall-clock span.
+fn print_timings(to_stderr: bool, timings: &[PhaseTiming], total: std::time::Duration) {
+    say!(to_stderr, "\nTiming:");
+    for t in timings {
+        let share = 100.0 * t.elapsed.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON);
+        say!(
+            to_stderr,
+            "  {:<14} {:>9.3}s {:>6.1}%{}",
+            t.phase,
+            t.elapsed.as_secs_f64(),
+            share,
+            if t.concurrent { "  (concurrent)" } else { "" }
+        );
+    }
+}
+
+/// `create --compare-only` output: one line per path, marked like `--verbose` lines.
+fn print_change_set(changes: &create::ChangeSet) {
+    for path in &changes.dirs_created {
+        println!("+ created dir {}", path);
+    }
+    for path in &changes.files_added {
+        println!("+ added {}", path);
+    }
+    for path in &changes.files_modified {
+        println!("~ modified {}", path);
+    }
+    for (path, target) in &changes.hardlinks {
+        println!("+ linked {} => {}", path, target);
+    }
+    for (path, target) in &changes.symlinks {
+        println!("+ symlink {} -> {}", path, target);
+    }
+    for path in &changes.metadata_changed {
+        println!("* metadata {}", path);
+    }
+    for path in &changes.files_deleted {
+        println!("- deleted {}", path);
+    }
+    for path in &changes.dirs_deleted {
+        println!("- deleted dir {}", path);
+    }
+    println!("\nChanges:");
+    println!("  Directories created: {}", changes.dirs_created.len());
+    println!("  Files added: {}", changes.files_added.len());
+    println!("  Files modified: {}", changes.files_modified.len());
+    println!("  Files deleted: {}", changes.files_deleted.len());
+    println!("  Directories deleted: {}", changes.dirs_deleted.len());
+    if !changes.hardlinks.is_empty() {
+        println!("  Hard links created: {}", changes.hardlinks.len());
+    }
+    if !changes.symlinks.is_empty() {
+        println!("  Symbolic links created: {}", changes.symlinks.len());
+    }
+    if !changes.metadata_changed.is_empty() {
+        println!("  Metadata updated: {}", changes.metadata_changed.len());
+    }
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
     match cli.command {
-        Commands::Create { old, new, output } => {
-            println!("Creating patch...");
-            println!("  Old: {}", old.display());
-            println!("  New: {}", new.display());
-            println!("  Output: {}", output.display());
+        Commands::Create {
+            old,
+            new,
+            output,
+            hash_algo,
+            include,
+            exclude,
+            skip_unreadable,
+            allow_non_utf8,
+            skip_changing,
+            memory_budget,
+            changelog,
+            recompress_ext,
+            incompressible_ext,
+            force_compress_ext,
+            hash_cache,
+            split_size,
+            objects_dir,
+            explain,
+            force_full,
+            diff_timeout,
+            min_match,
+            full_verify,
+            read_buffer,
+            gzip,
+            output_format,
+            mac_key,
+            preserve_hardlinks,
+            symlink_mode,
+            progress,
+            since,
+            metadata,
+            preserve_xattrs,
+            compare_only,
+            fast,
+            timing,
+            stats,
+            stats_top,
+            print_tree,
+            force,
+        } => {
+            let options = create::CreateOptions {
+                hash_algo,
+                verbose: cli.verbose,
+                include,
+                exclude,
+                skip_unreadable,
+                allow_non_utf8,
+                full_verify,
+                read_buffer: usize::try_from(read_buffer)?,
+                gzip,
+                output_format,
+                mac_key: mac_key.as_deref().map(MacKey::from_file).transpose()?,
+                preserve_hardlinks,
+                symlink_mode,
+                progress: progress.then(|| create::ProgressCallback::new(print_create_progress)),
+                since,
+                extra_bases: old[1..].to_vec(),
+                metadata,
+                skip_changing,
+                memory_budget,
+                changelog: changelog.clone(),
+                recompress_exts: recompress_ext,
+                compression_overrides: create::CompressionOverrides {
+                    incompressible_exts: incompressible_ext,
+                    force_compress_exts: force_compress_ext,
+                },
+                preserve_xattrs,
+                hash_cache,
+                split_size,
+                objects_dir: objects_dir.clone(),
+                explain,
+                force_full,
+                diff_timeout: diff_timeout.map(std::time::Duration::from_secs),
+                min_match_len: min_match.map(usize::try_from).transpose()?,
+                largest_ops: if stats { stats_top } else { 0 },
+                record_touched: print_tree,
+                force,
+            };
+
+            if compare_only {
+                let changes = create::compare_trees(&old[0], &new, &options, fast).await?;
+                print_change_set(&changes);
+                return Ok(());
+            }
+            let Some(output) = output else {
+                anyhow::bail!("--output is required unless --compare-only is given");
+            };
+
+            // With `--output -` stdout carries the patch, so messages go to stderr.
+            let to_stderr = util::is_stdio(&output);
+            say!(to_stderr, "Creating patch...");
+            for base in &old {
+                say!(to_stderr, "  Old: {}", base.display());
+            }
+            say!(to_stderr, "  New: {}", new.display());
+            say!(to_stderr, "  Output: {}", output.display());
+            if let Some(split_size) = split_size {
+                say!(to_stderr, "  Split: parts of at most {} bytes", split_size);
+            }
+            if let Some(dir) = &objects_dir {
+                say!(to_stderr, "  Objects: {}", dir.display());
+            }
+            say!(to_stderr, "  Hash: {}", hash_algo);
+            if let Some(changelog) = &changelog {
+                say!(to_stderr, "  Changelog: {}", changelog.display());
+            }
 
             let start = Instant::now();
-            let summary = create::create_patch(&old, &new, &output).await?;
+            let summary = create::create_patch(&old[0], &new, &output, &options).await?;
             let elapsed = start.elapsed();
 
-            println!("\nPatch created successfully!");
-            println!("  Directories created: {}", summary.dirs_created);
-            println!("  Files added: {}", summary.files_added);
-            println!("  Files modified: {}", summary.files_modified);
-            println!("  Files deleted: {}", summary.files_deleted);
-            println!("  Directories deleted: {}", summary.dirs_deleted);
-            println!("  Time elapsed: {:.3}s", elapsed.as_secs_f64());
+            say!(to_stderr, "\nPatch created successfully!");
+            say!(to_stderr, "  Directories created: {}", summary.dirs_created);
+            say!(to_stderr, "  Files added: {}", summary.files_added);
+            say!(to_stderr, "  Files modified: {}", summary.files_modified);
+            say!(to_stderr, "  Files deleted: {}", summary.files_deleted);
+            say!(to_stderr, "  Directories deleted: {}", summary.dirs_deleted);
+            if summary.hardlinks_created > 0 {
+                say!(to_stderr, "  Hard links created: {}", summary.hardlinks_created);
+            }
+            if summary.symlinks_created > 0 {
+                say!(to_stderr, "  Symbolic links created: {}", summary.symlinks_created);
+            }
+            if summary.metadata_updated > 0 {
+                say!(to_stderr, "  Metadata updated: {}", summary.metadata_updated);
+            }
+            say!(to_stderr, "  Time elapsed: {}", elapsed_with_rate(elapsed, summary.bytes_processed));
+            if timing {
+                print_timings(to_stderr, &summary.timings, elapsed);
+            }
+            if !summary.largest_ops.is_empty() {
+                say!(to_stderr, "\nLargest operations:");
+                for op in &summary.largest_ops {
+                    let inserts = match op.kind {
+                        "modify" if op.inserts == 1 => ", 1 insert".to_string(),
+                        "modify" => format!(", {} inserts", op.inserts),
+                        _ => String::new(),
+                    };
+                    say!(to_stderr, "  {}: {} ({}{})", op.path, human_size(op.bytes), op.kind, inserts);
+                }
+            }
+            if print_tree {
+                say!(to_stderr, "\nChanged paths:");
+                for line in change_tree::render(&summary.touched).lines() {
+                    say!(to_stderr, "  {}", line);
+                }
+            }
         }
-        Commands::Apply { target, patch } => {
+        Commands::Apply {
+            target,
+            patch,
+            quarantine,
+            max_files,
+            max_file_size,
+            max_total_size,
+            retries,
+            out,
+            paranoid,
+            skip_mismatches,
+            timing,
+            interactive,
+            yes,
+            temp_dir,
+            mac_key,
+            prune,
+            force,
+            only,
+            objects_dir,
+            verify_before,
+            no_follow_target,
+            lenient_base,
+            add_missing,
+            order,
+            post_apply,
+        } => {
+            if interactive && !yes && !std::io::stdin().is_terminal() {
+                anyhow::bail!(
+                    "--interactive needs a terminal on stdin to ask; pass --yes to confirm deletions"
+                );
+            }
             println!("Applying patch...");
             println!("  Target: {}", target.display());
             println!("  Patch: {}", patch.display());
+            if let Some(q) = &quarantine {
+                println!("  Quarantine: {}", q.display());
+            }
+            if let Some(o) = &out {
+                println!("  Out: {}", o.display());
+            }
+            for prefix in &only {
+                println!("  Only: {}", prefix);
+            }
+            let patched_tree = out.clone().unwrap_or_else(|| target.clone());
+            if let Some(dir) = &objects_dir {
+                println!("  Objects: {}", dir.display());
+            }
+
+            let options = apply::ApplyOptions {
+                verbose: cli.verbose,
+                limits: apply::ApplyLimits {
+                    max_files,
+                    max_file_size,
+                    max_total_size,
+                },
+                quarantine,
+                retries,
+                out,
+                paranoid,
+                skip_mismatches,
+                interrupt: Some(Arc::new(AtomicBool::new(false))),
+                confirm_deletes: interactive.then(|| {
+                    apply::ConfirmDeletes::new(move |files, dirs| confirm_deletes(files, dirs, yes))
+                }),
+                temp_dir,
+                mac_key: mac_key.as_deref().map(MacKey::from_file).transpose()?,
+                prune,
+                force,
+                only,
+                objects_dir,
+                verify_before,
+                no_follow_target,
+                lenient_base,
+                add_missing,
+                order,
+            };
+
+            // First Ctrl-C stops dispatching new operations and lets in-flight ones
+            // finish, so no file is left half-written; a second one exits immediately.
+            let interrupt = options.interrupt.clone().expect("set above");
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    eprintln!(
+                        "\nInterrupted: finishing in-flight operations \
+                         (press Ctrl-C again to abort immediately)..."
+                    );
+                    interrupt.store(true, Ordering::SeqCst);
+                    if tokio::signal::ctrl_c().await.is_ok() {
+                        std::process::exit(130);
+                    }
+                }
+            });
+
+            let start = Instant::now();
+            let summary = match apply::apply_patch(&target, &patch, &options).await {
+                Ok(summary) => summary,
+                Err(PatchError::Interrupted { completed }) => {
+                    println!("\nApply interrupted; completed before stopping:");
+                    print_apply_counts(&completed);
+                    anyhow::bail!("{}", PatchError::Interrupted { completed });
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let elapsed = start.elapsed();
+
+            if summary.skipped_mismatches.is_empty() {
+                println!("\nPatch applied successfully!");
+            } else {
+                println!("\nPatch applied, except for files that failed their hash check.");
+            }
+            print_apply_counts(&summary);
+            println!("  Time elapsed: {}", elapsed_with_rate(elapsed, summary.bytes_processed));
+            if timing {
+                print_timings(false, &summary.timings, elapsed);
+            }
+
+            if !summary.skipped_mismatches.is_empty() {
+                for path in &summary.skipped_mismatches {
+                    eprintln!("! skipped {}: hash mismatch", path);
+                }
+                anyhow::bail!(
+                    "{} file(s) skipped after a hash mismatch",
+                    summary.skipped_mismatches.len()
+                );
+            }
+            if let Some(command) = &post_apply {
+                run_post_apply(command, &patched_tree)?;
+            }
+        }
+        Commands::Merge {
+            first,
+            second,
+            output,
+        } => {
+            let to_stderr = util::is_stdio(&output);
+            say!(to_stderr, "Merging patches...");
+            say!(to_stderr, "  First: {}", first.display());
+            say!(to_stderr, "  Second: {}", second.display());
+            say!(to_stderr, "  Output: {}", output.display());
 
             let start = Instant::now();
-            let summary = apply::apply_patch(&target, &patch).await?;
+            let summary = tokio::task::spawn_blocking(move || {
+                merge::merge_patches(&first, &second, &output)
+            })
+            .await??;
             let elapsed = start.elapsed();
 
-            println!("\nPatch applied successfully!");
-            println!("  Directories created: {}", summary.dirs_created);
-            println!("  Files added: {}", summary.files_added);
-            println!("  Files modified: {}", summary.files_modified);
-            println!("  Files deleted: {}", summary.files_deleted);
-            println!("  Directories deleted: {}", summary.dirs_deleted);
+            say!(to_stderr, "\nPatches merged successfully!");
+            say!(to_stderr, "  Directories created: {}", summary.dirs_created);
+            say!(to_stderr, "  Files added: {}", summary.files_added);
+            say!(to_stderr, "  Files modified: {}", summary.files_modified);
+            say!(to_stderr, "  Files deleted: {}", summary.files_deleted);
+            say!(to_stderr, "  Directories deleted: {}", summary.dirs_deleted);
+            if summary.hardlinks_created > 0 {
+                say!(to_stderr, "  Hard links created: {}", summary.hardlinks_created);
+            }
+            if summary.symlinks_created > 0 {
+                say!(to_stderr, "  Symbolic links created: {}", summary.symlinks_created);
+            }
+            say!(to_stderr, "  Time elapsed: {:.3}s", elapsed.as_secs_f64());
+        }
+        Commands::Verify { target, patch } => {
+            println!("Verifying...");
+            println!("  Target: {}", target.display());
+            println!("  Patch: {}", patch.display());
+
+            let start = Instant::now();
+            let report = tokio::task::spawn_blocking(move || {
+                verify::verify_tree(&target, &patch, &apply::ApplyLimits::default())
+            })
+            .await??;
+            let elapsed = start.elapsed();
+
+            for failure in &report.failures {
+                println!("! {}: {}", failure.path, failure.problem);
+            }
+            if !report.is_ok() {
+                anyhow::bail!(
+                    "Verification failed: {} of {} checks failed",
+                    report.failures.len(),
+                    report.checked
+                );
+            }
+            println!("\nVerification passed!");
+            println!("  Checks: {}", report.checked);
+            println!("  Time elapsed: {:.3}s", elapsed.as_secs_f64());
+        }
+        Commands::Validate { patch } => {
+            println!("Validating...");
+            println!("  Patch: {}", patch.display());
+
+            let start = Instant::now();
+            let report = tokio::task::spawn_blocking(move || {
+                validate::validate_patch(&patch, &apply::ApplyLimits::default())
+            })
+            .await??;
+            let elapsed = start.elapsed();
+
+            for problem in &report.problems {
+                println!("! {}: {}", problem.path, problem.problem);
+            }
+            if !report.is_ok() {
+                anyhow::bail!(
+                    "Validation failed: {} problem(s) in {} operations",
+                    report.problems.len(),
+                    report.operations
+                );
+            }
+            println!("\nPatch is valid!");
+            println!("  Operations: {}", report.operations);
+            println!("  Time elapsed: {:.3}s", elapsed.as_secs_f64());
+        }
+        Commands::Extract { patch, path, out } => {
+            let to_stderr = util::is_stdio(&out);
+            say!(to_stderr, "Extracting...");
+            say!(to_stderr, "  Patch: {}", patch.display());
+            say!(to_stderr, "  File: {}", path);
+            say!(to_stderr, "  Output: {}", out.display());
+
+            let start = Instant::now();
+            let written = tokio::task::spawn_blocking(move || {
+                extract::extract_file(&patch, &path, &out, &apply::ApplyLimits::default())
+            })
+            .await??;
+            let elapsed = start.elapsed();
+
+            say!(to_stderr, "\nFile extracted!");
+            say!(to_stderr, "  Size: {} bytes", written);
+            say!(to_stderr, "  Time elapsed: {:.3}s", elapsed.as_secs_f64());
+        }
+        Commands::Dump { patch } => {
+            let manifest = tokio::task::spawn_blocking(move || {
+                apply::read_manifest(&patch, &apply::ApplyLimits::default())
+            })
+            .await??;
+            println!("{}", dump::dump_manifest(&manifest));
+        }
+        Commands::Diff {
+            old,
+            new,
+            json,
+            null,
+            hash_algo,
+            include,
+            exclude,
+            skip_unreadable,
+        } => {
+            let options = create::CreateOptions {
+                hash_algo,
+                include,
+                exclude,
+                skip_unreadable,
+                ..Default::default()
+            };
+            let changes = create::compare_trees(&old, &new, &options, false).await?;
+            let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+            if json {
+                #[derive(serde::Serialize)]
+                struct Record<'a> {
+                    status: &'a str,
+                    path: &'a str,
+                }
+                let records: Vec<Record> = changes
+                    .records()
+                    .into_iter()
+                    .map(|(status, path)| Record { status, path })
+                    .collect();
+                serde_json::to_writer_pretty(&mut out, &records)?;
+                writeln!(out)?;
+            } else {
+                let end = if null { '\0' } else { '\n' };
+                for (status, path) in changes.records() {
+                    write!(out, "{}\t{}{}", status, path, end)?;
+                }
+            }
+            out.flush()?;
+        }
+        Commands::Snapshot {
+            dir,
+            output,
+            hash_algo,
+        } => {
+            println!("Creating snapshot...");
+            println!("  Dir: {}", dir.display());
+            println!("  Output: {}", output.display());
+
+            let start = Instant::now();
+            let entries = tokio::task::spawn_blocking(move || {
+                snapshot::snapshot_directory(&dir, hash_algo)
+            })
+            .await??;
+            let count = |kind: util::EntryKind| entries.iter().filter(|e| e.kind == kind).count();
+            let (files, dirs, links) = (
+                count(util::EntryKind::File),
+                count(util::EntryKind::Dir),
+                count(util::EntryKind::Symlink),
+            );
+            snapshot::write_snapshot(
+                &output,
+                &snapshot::Snapshot {
+                    version: snapshot::SNAPSHOT_VERSION,
+                    hash_algo,
+                    entries,
+                },
+            )?;
+            let elapsed = start.elapsed();
+
+            println!("\nSnapshot created successfully!");
+            println!("  Files: {}", files);
+            println!("  Directories: {}", dirs);
+            if links > 0 {
+                println!("  Symbolic links: {}", links);
+            }
             println!("  Time elapsed: {:.3}s", elapsed.as_secs_f64());
         }
     }
 
     Ok(())
 }
+
@@ -0,0 +1,534 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::apply::{self, ApplyLimits};
+use crate::binary_patch;
+use crate::create::{self, is_incompressible, CompressionOverrides};
+use crate::patch_format::{
+    add_file_content, add_file_op, ApplySummary, DiffChunk, PatchManifest, PatchOp,
+    FORMAT_VERSION,
+};
+use crate::util::{self, HashAlgo};
+
+/// Net effect on one path of the patches composed so far.
+enum Net {
+    CreateDir { mode: Option<u32> },
+    DeleteDir,
+    Add { data: Vec<u8>, hash: [u8; 32] },
+    Modify { chunks: Vec<DiffChunk>, hash: [u8; 32] },
+    DeleteFile,
+    Verify { hash: [u8; 32] },
+    Hardlink { target: String },
+}
+
+impl Net {
+    fn from_op(op: PatchOp) -> Result<(String, Net)> {
+        Ok(match op {
+            PatchOp::CreateDir { path, mode } => (path, Net::CreateDir { mode }),
+            PatchOp::DeleteDir { path } => (path, Net::DeleteDir),
+            PatchOp::AddFile {
+                path,
+                data,
+                blake3_hash,
+                compressed,
+                ..
+            } => (
+                path,
+                Net::Add {
+                    data: add_file_content(data, compressed)?,
+                    hash: blake3_hash,
+                },
+            ),
+            PatchOp::ModifyFile {
+                path,
+                diff_chunks,
+                new_blake3_hash,
+                ..
+            } => (
+                path,
+                Net::Modify {
+                    chunks: diff_chunks,
+                    hash: new_blake3_hash,
+                },
+            ),
+            PatchOp::DeleteFile { path, .. } => (path, Net::DeleteFile),
+            PatchOp::VerifyFile { path, blake3_hash } => (path, Net::Verify { hash: blake3_hash }),
+            PatchOp::CreateHardlink { path, target } => (path, Net::Hardlink { target }),
+            PatchOp::ModifyFileMulti { .. }
+            | PatchOp::SetMetadata { .. }
+            | PatchOp::CreateSymlink { .. } => {
+                unreachable!("rejected by merge_patches")
+            }
+        })
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Net::CreateDir { .. } => "created as a directory",
+            Net::DeleteDir => "deleted as a directory",
+            Net::Add { .. } => "added",
+            Net::Modify { .. } => "modified",
+            Net::DeleteFile => "deleted",
+            Net::Verify { .. } => "left unchanged",
+            Net::Hardlink { .. } => "hard linked",
+        }
+    }
+}
+
+/// Combine the first patch's effect on `path` with the second's.
+/// `None` means the two cancel out and the path is left untouched.
+fn compose(path: &str, first: Option<Net>, second: Net, hash_algo: HashAlgo) -> Result<Option<Net>> {
+    let check_hash = |expected: &[u8; 32], actual: &[u8; 32]| -> Result<()> {
+        if expected != actual {
+            bail!(
+                "Cannot merge {}: the second patch expects different content than the first produces",
+                path
+            );
+        }
+        Ok(())
+    };
+
+    let Some(first) = first else {
+        return Ok(Some(second));
+    };
+    Ok(match (first, second) {
+        // A directory the first patch created and the second deleted (or vice versa)
+        // ends up as it started.
+        (Net::CreateDir { .. }, Net::DeleteDir) | (Net::DeleteDir, Net::CreateDir { .. }) => None,
+
+        // The second patch's full content or deletion wins over whatever came before.
+        (
+            Net::Add { .. }
+            | Net::Modify { .. }
+            | Net::Verify { .. }
+            | Net::DeleteFile
+            | Net::Hardlink { .. },
+            replace @ (Net::Add { .. } | Net::Hardlink { .. }),
+        ) => Some(replace),
+        (
+            Net::Add { .. } | Net::Modify { .. } | Net::Verify { .. } | Net::Hardlink { .. },
+            Net::DeleteFile,
+        ) => Some(Net::DeleteFile),
+        // The link still stands; its content is checked through the target.
+        (link @ Net::Hardlink { .. }, Net::Verify { .. }) => Some(link),
+
+        // Diffs against content the first patch added are resolved into a new add.
+        (Net::Add { data, .. }, Net::Modify { chunks, hash }) => {
+            let data = binary_patch::apply_diff(&data, &chunks)
+                .with_context(|| format!("Cannot merge {}: second diff doesn't fit", path))?;
+            check_hash(&util::hash_bytes(hash_algo, &data), &hash)?;
+            Some(Net::Add { data, hash })
+        }
+        (Net::Modify { chunks: first, .. }, Net::Modify { chunks: second, hash }) => {
+            let chunks = compose_chunks(&first, &second)
+                .with_context(|| format!("Cannot merge {}: second diff doesn't fit", path))?;
+            Some(Net::Modify { chunks, hash })
+        }
+        (Net::Verify { .. }, modify @ Net::Modify { .. }) => Some(modify),
+
+        // A verify in the second patch confirms the first patch's result.
+        (Net::Add { data, hash }, Net::Verify { hash: expected }) => {
+            check_hash(&expected, &hash)?;
+            Some(Net::Add { data, hash })
+        }
+        (Net::Modify { chunks, hash }, Net::Verify { hash: expected }) => {
+            check_hash(&expected, &hash)?;
+            Some(Net::Modify { chunks, hash })
+        }
+        (Net::Verify { .. }, verify @ Net::Verify { .. }) => Some(verify),
+
+        (first, second) => bail!(
+            "Cannot merge {}: {} by the first patch, then {} by the second (are the patches sequential?)",
+            path,
+            first.describe(),
+            second.describe()
+        ),
+    })
+}
+
+fn chunk_len(chunk: &DiffChunk) -> u64 {
+    match chunk {
+        DiffChunk::Copy { length, .. } => *length,
+        DiffChunk::Insert { data } => data.len() as u64,
+        DiffChunk::Zeros { length } => *length,
+    }
+}
+
+fn push_copy(out: &mut Vec<DiffChunk>, offset: u64, length: u64) {
+    if length == 0 {
+        return;
+    }
+    if let Some(DiffChunk::Copy {
+        offset: last_offset,
+        length: last_length,
+    }) = out.last_mut()
+    {
+        if *last_offset + *last_length == offset {
+            *last_length += length;
+            return;
+        }
+    }
+    out.push(DiffChunk::Copy { offset, length });
+}
+
+fn push_insert(out: &mut Vec<DiffChunk>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    if let Some(DiffChunk::Insert { data: last }) = out.last_mut() {
+        last.extend_from_slice(data);
+        return;
+    }
+    out.push(DiffChunk::Insert {
+        data: data.to_vec(),
+    });
+}
+
+fn push_zeros(out: &mut Vec<DiffChunk>, length: u64) {
+    if length == 0 {
+        return;
+    }
+    if let Some(DiffChunk::Zeros { length: last }) = out.last_mut() {
+        *last += length;
+        return;
+    }
+    out.push(DiffChunk::Zeros { length });
+}
+
+/// Compose two diffs: `first` turns A into B, `second` turns B into C; the result turns
+/// A into C directly. Copies in `second` refer to B, so each is mapped back through the
+/// segments of `first` to either a copy from A or the bytes `first` inserted.
+pub fn compose_chunks(first: &[DiffChunk], second: &[DiffChunk]) -> Result<Vec<DiffChunk>> {
+    // Start offset in B of each chunk of `first`.
+    let mut starts = Vec::with_capacity(first.len());
+    let mut mid_len: u64 = 0;
+    for chunk in first {
+        starts.push(mid_len);
+        mid_len = mid_len
+            .checked_add(chunk_len(chunk))
+            .context("First diff output length overflows")?;
+    }
+
+    let mut out = Vec::new();
+    for (n, chunk) in second.iter().enumerate() {
+        let (offset, length) = match chunk {
+            DiffChunk::Insert { data } => {
+                push_insert(&mut out, data);
+                continue;
+            }
+            DiffChunk::Zeros { length } => {
+                push_zeros(&mut out, *length);
+                continue;
+            }
+            DiffChunk::Copy { offset, length } => (*offset, *length),
+        };
+        let end = match offset.checked_add(length) {
+            Some(end) if end <= mid_len => end,
+            _ => bail!(
+                "Copy chunk {} out of bounds: offset {} + length {} exceeds intermediate size {}",
+                n,
+                offset,
+                length,
+                mid_len
+            ),
+        };
+
+        let mut pos = offset;
+        let mut i = starts.partition_point(|&s| s <= pos).saturating_sub(1);
+        while pos < end {
+            let within = pos - starts[i];
+            let take = (chunk_len(&first[i]) - within).min(end - pos);
+            match &first[i] {
+                DiffChunk::Copy { offset, .. } => push_copy(&mut out, offset + within, take),
+                DiffChunk::Insert { data } => {
+                    push_insert(&mut out, &data[within as usize..(within + take) as usize])
+                }
+                DiffChunk::Zeros { .. } => push_zeros(&mut out, take),
+            }
+            pos += take;
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+/// Merge two sequential patches (A→B, then B→C) into a single A→C patch at `output`,
+/// without access to the intermediate tree.
+pub fn merge_patches(first: &Path, second: &Path, output: &Path) -> Result<ApplySummary> {
+    let limits = ApplyLimits::default();
+    let first = apply::read_manifest(first, &limits)
+        .with_context(|| format!("Failed to read first patch: {}", first.display()))?;
+    let second = apply::read_manifest(second, &limits)
+        .with_context(|| format!("Failed to read second patch: {}", second.display()))?;
+    if first.hash_algo != second.hash_algo {
+        bail!(
+            "Cannot merge patches with different hash algorithms ({} and {})",
+            first.hash_algo,
+            second.hash_algo
+        );
+    }
+    // Which variant applies depends on the target, so there is no single net diff.
+    if let Some(op) = first
+        .operations
+        .iter()
+        .chain(&second.operations)
+        .find(|op| matches!(op, PatchOp::ModifyFileMulti { .. }))
+    {
+        bail!(
+            "Cannot merge multi-base patches ({} has one diff per old version)",
+            op.path()
+        );
+    }
+    // Those diffs are against decompressed content, so they don't compose with others.
+    if let Some(op) = first.operations.iter().chain(&second.operations).find(|op| {
+        matches!(
+            op,
+            PatchOp::ModifyFile {
+                recompress: Some(_),
+                ..
+            }
+        )
+    }) {
+        bail!(
+            "Cannot merge patches with recompressed diffs ({} was diffed decompressed)",
+            op.path()
+        );
+    }
+    // Nor are extended attributes tracked through composition.
+    if let Some(op) = first.operations.iter().chain(&second.operations).find(|op| {
+        matches!(op, PatchOp::AddFile { xattrs, .. } | PatchOp::ModifyFile { xattrs, .. } if !xattrs.is_empty())
+    }) {
+        bail!(
+            "Cannot merge patches with extended attributes ({} has some; see --preserve-xattrs)",
+            op.path()
+        );
+    }
+    // Content kept in an objects directory isn't at hand to compose.
+    if let Some(op) = first
+        .operations
+        .iter()
+        .chain(&second.operations)
+        .find(|op| matches!(op, PatchOp::AddFile { external: true, .. }))
+    {
+        bail!(
+            "Cannot merge patches with content in an objects directory ({} is stored there; see --objects-dir)",
+            op.path()
+        );
+    }
+    // Content ops carry no metadata, so a SetMetadata can't be folded into them.
+    if let Some(op) = first
+        .operations
+        .iter()
+        .chain(&second.operations)
+        .find(|op| matches!(op, PatchOp::SetMetadata { .. }))
+    {
+        bail!(
+            "Cannot merge patches with metadata changes ({} has a SetMetadata)",
+            op.path()
+        );
+    }
+    // Nor is a link's target, which composing would have to follow through both patches.
+    if let Some(op) = first
+        .operations
+        .iter()
+        .chain(&second.operations)
+        .find(|op| matches!(op, PatchOp::CreateSymlink { .. }))
+    {
+        bail!(
+            "Cannot merge patches with symbolic links ({} is one)",
+            op.path()
+        );
+    }
+    let hash_algo = first.hash_algo;
+    // The second patch's operations cover its whole post-patch tree, and composing
+    // keeps an operation for every path that ends up present.
+    let full_file_set = second.full_file_set;
+
+    let mut net: BTreeMap<String, Net> = first
+        .operations
+        .into_iter()
+        .map(Net::from_op)
+        .collect::<Result<_>>()?;
+    for op in second.operations {
+        let (path, second) = Net::from_op(op)?;
+        let first = net.remove(&path);
+        if let Some(merged) = compose(&path, first, second, hash_algo)? {
+            net.insert(path, merged);
+        }
+    }
+
+    // A link must point at something that survives the merged patch.
+    for (path, entry) in &net {
+        if let Net::Hardlink { target } = entry {
+            if matches!(net.get(target), Some(Net::DeleteFile | Net::DeleteDir)) {
+                bail!(
+                    "Cannot merge {}: hard link target {} is deleted by the merged patch",
+                    path,
+                    target
+                );
+            }
+        }
+    }
+
+    // Emit in the same category order as create, each in path order (the map is sorted).
+    let mut dirs_to_create = Vec::new();
+    // Added and modified files, interleaved by path as create writes them.
+    let mut files = Vec::new();
+    let (mut files_added, mut files_modified) = (0, 0);
+    let mut files_to_delete = Vec::new();
+    let mut dirs_to_delete = Vec::new();
+    let mut verifies = Vec::new();
+    let mut hardlinks = Vec::new();
+    let mut dir_modes = std::collections::HashMap::new();
+    for (path, entry) in net {
+        match entry {
+            Net::CreateDir { mode } => {
+                if let Some(mode) = mode {
+                    dir_modes.insert(path.clone(), mode);
+                }
+                dirs_to_create.push(path)
+            }
+            Net::DeleteDir => dirs_to_delete.push(path),
+            Net::Add { data, hash } => {
+                let incompressible = is_incompressible(Path::new(&path), &CompressionOverrides::default());
+                files_added += 1;
+                files.push(add_file_op(path, &data, hash, incompressible, Vec::new())?)
+            }
+            Net::Modify { chunks, hash } => {
+                files_modified += 1;
+                files.push(PatchOp::ModifyFile {
+                    path,
+                    diff_chunks: chunks,
+                    new_blake3_hash: hash,
+                    // Block sizes aren't tracked through composition.
+                    block_size: 0,
+                    recompress: None,
+                    xattrs: Vec::new(),
+                    old_blake3_hash: None,
+                })
+            }
+            Net::DeleteFile => files_to_delete.push(PatchOp::DeleteFile {
+                path,
+                old_blake3_hash: None,
+                may_be_absent: false,
+            }),
+            Net::Verify { hash } => verifies.push(PatchOp::VerifyFile {
+                path,
+                blake3_hash: hash,
+            }),
+            Net::Hardlink { target } => hardlinks.push(PatchOp::CreateHardlink { path, target }),
+        }
+    }
+    util::sort_dirs_parent_first(&mut dirs_to_create);
+    util::sort_dirs_deepest_first(&mut dirs_to_delete);
+
+    let summary = ApplySummary {
+        dirs_created: dirs_to_create.len(),
+        files_added,
+        files_modified,
+        files_deleted: files_to_delete.len(),
+        dirs_deleted: dirs_to_delete.len(),
+        hardlinks_created: hardlinks.len(),
+        symlinks_created: 0,
+        metadata_updated: 0,
+        skipped_mismatches: Vec::new(),
+        deletes_declined: 0,
+        bytes_processed: 0,
+        timings: Vec::new(),
+        largest_ops: Vec::new(),
+        touched: Vec::new(),
+    };
+
+    let mut operations = Vec::new();
+    operations.extend(dirs_to_create.into_iter().map(|path| PatchOp::CreateDir {
+        mode: dir_modes.remove(&path),
+        path,
+    }));
+    operations.extend(files);
+    operations.extend(hardlinks);
+    operations.extend(files_to_delete);
+    operations.extend(dirs_to_delete.into_iter().map(|path| PatchOp::DeleteDir { path }));
+    operations.extend(verifies);
+
+    create::write_manifest(
+        output,
+        &PatchManifest {
+            version: FORMAT_VERSION,
+            hash_algo,
+            full_file_set,
+            operations,
+        },
+        false,
+    )?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binary_diff::compute_diff;
+    use crate::binary_patch::apply_diff;
+
+    fn bytes(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| ((i * 31) as u8).wrapping_add(seed).wrapping_mul(7))
+            .collect()
+    }
+
+    #[test]
+    fn test_compose_matches_sequential_apply() {
+        let a = bytes(50_000, 1);
+        let mut b = a.clone();
+        b.splice(20_000..20_000, bytes(3_000, 9));
+        b.truncate(45_000);
+        let mut c = bytes(1_000, 5);
+        c.extend_from_slice(&b[10_000..40_000]);
+        c.extend_from_slice(&a[..8_192]);
+
+        let ab = compute_diff(&a, &b);
+        let bc = compute_diff(&b, &c);
+        let ac = compose_chunks(&ab, &bc).unwrap();
+        assert_eq!(apply_diff(&a, &ac).unwrap(), c);
+    }
+
+    #[test]
+    fn test_compose_coalesces_and_handles_inserts() {
+        let first = vec![
+            DiffChunk::Copy { offset: 0, length: 4 },
+            DiffChunk::Insert { data: b"XY".to_vec() },
+            DiffChunk::Copy { offset: 4, length: 4 },
+        ];
+        // Intermediate "0123XY4567"; take "23XY45" then insert "!".
+        let second = vec![
+            DiffChunk::Copy { offset: 2, length: 6 },
+            DiffChunk::Insert { data: b"!".to_vec() },
+        ];
+        let composed = compose_chunks(&first, &second).unwrap();
+        assert_eq!(apply_diff(b"01234567", &composed).unwrap(), b"23XY45!");
+        assert_eq!(composed.len(), 4);
+    }
+
+    #[test]
+    fn test_compose_rejects_out_of_bounds_copy() {
+        let first = vec![DiffChunk::Copy { offset: 0, length: 4 }];
+        let second = vec![DiffChunk::Copy { offset: 2, length: 3 }];
+        assert!(compose_chunks(&first, &second).is_err());
+    }
+
+    #[test]
+    fn test_compose_carries_zeros() {
+        let a = bytes(20_000, 3);
+        let mut b = a.clone();
+        b[8_192..16_384].fill(0);
+        let mut c = b.clone();
+        c.truncate(12_000);
+        c.extend(std::iter::repeat_n(0u8, 10_000));
+
+        let ab = compute_diff(&a, &b);
+        let bc = compute_diff(&b, &c);
+        let ac = compose_chunks(&ab, &bc).unwrap();
+        assert!(ac.iter().any(|c| matches!(c, DiffChunk::Zeros { .. })));
+        assert_eq!(apply_diff(&a, &ac).unwrap(), c);
+    }
+}
@@ -1,48 +1,1562 @@
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-pub const MAGIC: &[u8; 8] = b"PATCHV01";
-pub const FORMAT_VERSION: u32 = 1;
+use crate::change_tree::Touched;
+use crate::error::PatchError;
+use crate::util::{self, HashAlgo, Xattrs};
 
+pub const MAGIC: &[u8; 8] = b"PATCHV02";
+/// Magic of a patch whose payload is CBOR rather than bincode (`--output-format cbor`).
+pub const CBOR_MAGIC: &[u8; 8] = b"PATCHC02";
+/// Magics of patches from before format 13.5, whose header has no index offset.
+pub const LEGACY_MAGIC: &[u8; 8] = b"PATCHV01";
+pub const LEGACY_CBOR_MAGIC: &[u8; 8] = b"PATCHC01";
+/// Format version this build writes, and the newest it reads. See [`FormatVersion`].
+pub const FORMAT_VERSION: FormatVersion = FormatVersion {
+    major: 13,
+    minor: 8,
+};
+
+/// A patch format version. A reader accepts any patch with its own major version and a
+/// minor version no newer than its own. A minor bump may only append fields to the end
+/// of existing operations or of the preamble, and only fields whose all-zero encoding means "absent"
+/// (`Option` → `None`, `Vec` → empty, `false`, `0`): operations from an older minor are
+/// decoded as if those fields were zero. A new operation type may be appended after the
+/// existing ones too, which leaves how they encode unchanged. Anything else (a changed or
+/// removed field, new semantics for an old field) requires a new major version.
+///
+/// Encoded as two little-endian u16s, major first, which reads the same as the single
+/// u32 older versions stored, so their patches fail the major check cleanly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct FormatVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl FormatVersion {
+    /// Whether a reader at this version can decode a patch written at `patch`.
+    pub fn can_read(self, patch: FormatVersion) -> bool {
+        patch.major == self.major && patch.minor <= self.minor
+    }
+}
+
+impl std::fmt::Display for FormatVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Zero bytes appended to an operation frame from an older minor version before it is
+/// decoded, standing in for the fields added since. Enough for a few dozen fields.
+const OLDER_MINOR_PADDING: [u8; 256] = [0; 256];
+
+/// How the preamble and operation frames of the payload are serialized. Recorded in
+/// the header's magic, so apply picks the decoder without being told.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ManifestEncoding {
+    /// Compact and fast; only this crate's types can make sense of it.
+    #[default]
+    Bincode,
+    /// Self-describing CBOR (RFC 8949), readable by generic tools. File contents and
+    /// inserted data stay byte strings, so it is barely larger once compressed.
+    Cbor,
+}
+
+impl ManifestEncoding {
+    fn magic(self) -> &'static [u8; 8] {
+        match self {
+            ManifestEncoding::Bincode => MAGIC,
+            ManifestEncoding::Cbor => CBOR_MAGIC,
+        }
+    }
+}
+
+impl std::fmt::Display for ManifestEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestEncoding::Bincode => write!(f, "bincode"),
+            ManifestEncoding::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+/// Leading bytes of a gzip stream. A patch created with `--gzip` is the normal patch
+/// file wrapped in gzip; apply recognises this and unwraps it first.
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// AddFile contents at least this large (and not already-compressed formats) are
+/// compressed on their own. Apply then streams them to disk instead of holding the
+/// decompressed bytes in memory; smaller files are left to the payload's zstd pass.
+pub const ADD_COMPRESS_MIN: usize = 1024 * 1024;
+
+/// Last bytes of a patch created with `--mac-key`, after the 32-byte MAC.
+pub const MAC_MAGIC: &[u8; 8] = b"PATCHMAC";
+/// Length of the MAC trailer: the MAC followed by [`MAC_MAGIC`].
+pub const MAC_TRAILER_LEN: usize = 32 + MAC_MAGIC.len();
+
+/// Context string for deriving a [`MacKey`] from a key file.
+const MAC_KEY_CONTEXT: &str = "patcher 2026 patch MAC key";
+
+/// Secret key for the BLAKE3 keyed hash that authenticates a patch (`--mac-key`).
+/// Anyone holding it can both create and verify patches, so it only proves a patch came
+/// from a holder of the key and wasn't altered since; it is not a signature.
+#[derive(Clone)]
+pub struct MacKey([u8; 32]);
+
+impl MacKey {
+    /// Derive the key from a key file's contents, which may be any non-empty bytes
+    /// (32 random bytes, a passphrase...): the same file always gives the same key.
+    pub fn from_bytes(material: &[u8]) -> Result<Self> {
+        if material.is_empty() {
+            bail!("MAC key is empty");
+        }
+        Ok(Self(blake3::derive_key(MAC_KEY_CONTEXT, material)))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let material = std::fs::read(path)
+            .with_context(|| format!("Failed to read MAC key file: {}", path.display()))?;
+        Self::from_bytes(&material).with_context(|| path.display().to_string())
+    }
+
+    fn hasher(&self) -> blake3::Hasher {
+        blake3::Hasher::new_keyed(&self.0)
+    }
+}
+
+impl std::fmt::Debug for MacKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MacKey(..)")
+    }
+}
+
+/// Split the MAC trailer, if any, off the bytes following the header.
+pub fn split_mac_trailer(rest: &[u8]) -> (&[u8], Option<[u8; 32]>) {
+    match rest.strip_suffix(MAC_MAGIC) {
+        Some(body) if body.len() >= 32 => {
+            let (payload, mac) = body.split_at(body.len() - 32);
+            (payload, Some(mac.try_into().expect("32 bytes")))
+        }
+        _ => (rest, None),
+    }
+}
+
+/// Check `mac` against the compressed payload and the header, the bytes it covers.
+/// The comparison is constant-time.
+pub fn verify_mac(key: &MacKey, header: &[u8], payload: &[u8], mac: &[u8; 32]) -> bool {
+    let mut hasher = key.hasher();
+    hasher.update(payload);
+    hasher.update(header);
+    hasher.finalize() == blake3::Hash::from(*mac)
+}
+
+/// Passes the compressed payload through to the sink, feeding it to the MAC as it goes.
+struct MacWriter<W> {
+    inner: W,
+    mac: Option<blake3::Hasher>,
+    /// Payload bytes passed through so far.
+    written: u64,
+}
+
+impl<W: Write> Write for MacWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(mac) = &mut self.mac {
+            mac.update(&buf[..written]);
+        }
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Bytes preceding the zstd payload: MAGIC (or CBOR_MAGIC), the uncompressed manifest
+/// length (u64 LE), then the offset of the [`PatchIndex`] frame (u64 LE).
+pub const HEADER_LEN: usize = MAGIC.len() + 16;
+/// Header length of a patch with a legacy magic: no index offset.
+pub const LEGACY_HEADER_LEN: usize = MAGIC.len() + 8;
+
+/// Uncompressed header at the start of every patch file.
+/// Readable without touching the compressed payload, so tooling can report sizes cheaply.
+#[derive(Debug, Clone, Copy)]
+pub struct PatchHeader {
+    /// Size of the serialized manifest before compression.
+    pub uncompressed_len: u64,
+    pub encoding: ManifestEncoding,
+    /// Where the [`PatchIndex`] frame starts, from the start of the header. `None` for
+    /// patches from before format 13.5, which have none.
+    pub index_offset: Option<u64>,
+}
+
+impl PatchHeader {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[..MAGIC.len()].copy_from_slice(self.encoding.magic());
+        out[MAGIC.len()..LEGACY_HEADER_LEN].copy_from_slice(&self.uncompressed_len.to_le_bytes());
+        out[LEGACY_HEADER_LEN..].copy_from_slice(&self.index_offset.unwrap_or(0).to_le_bytes());
+        out
+    }
+
+    /// Parse the header from the start of a patch file.
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        let (encoding, legacy) = match raw.get(..MAGIC.len()) {
+            Some(magic) if magic == MAGIC => (ManifestEncoding::Bincode, false),
+            Some(magic) if magic == CBOR_MAGIC => (ManifestEncoding::Cbor, false),
+            Some(magic) if magic == LEGACY_MAGIC => (ManifestEncoding::Bincode, true),
+            Some(magic) if magic == LEGACY_CBOR_MAGIC => (ManifestEncoding::Cbor, true),
+            _ => bail!(PatchError::InvalidMagic),
+        };
+        let field = |at: usize| raw.get(at..at + 8).map(|b| u64::from_le_bytes(b.try_into().expect("8 bytes")));
+        let truncated = || PatchError::Corrupt("truncated header".to_string());
+        let uncompressed_len = field(MAGIC.len()).ok_or_else(truncated)?;
+        let index_offset = if legacy {
+            None
+        } else {
+            Some(field(LEGACY_HEADER_LEN).ok_or_else(truncated)?).filter(|&offset| offset != 0)
+        };
+        Ok(Self {
+            uncompressed_len,
+            encoding,
+            index_offset,
+        })
+    }
+
+    /// Length of this header in the file.
+    pub fn encoded_len(&self) -> usize {
+        match self.index_offset {
+            None => LEGACY_HEADER_LEN,
+            Some(_) => HEADER_LEN,
+        }
+    }
+
+    /// Split `body`, the bytes between the header and the MAC trailer, into the
+    /// payload frame and the index frame (empty without an index).
+    pub fn split_index<'a>(&self, body: &'a [u8]) -> Result<(&'a [u8], &'a [u8])> {
+        let Some(offset) = self.index_offset else {
+            return Ok((body, &[]));
+        };
+        match offset.checked_sub(HEADER_LEN as u64) {
+            Some(at) if at <= body.len() as u64 => Ok(body.split_at(at as usize)),
+            _ => bail!(PatchError::Corrupt(format!(
+                "index offset {} is outside the patch",
+                offset
+            ))),
+        }
+    }
+}
+
+/// Where each operation's frame lies in the uncompressed payload, so one operation can
+/// be read without decoding the others (see [`crate::patch_index`]). Written after the
+/// payload as a zstd frame of its own, in the patch's encoding, at the offset the
+/// header records.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PatchIndex {
+    /// Uncompressed length of the preamble, where the first frame's length prefix starts.
+    pub preamble_len: u64,
+    pub entries: Vec<IndexEntry>,
+}
+
+/// One operation's frame: its path, and the offset and length of the serialized
+/// operation (after its length prefix) in the uncompressed payload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub path: String,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Bytes the index's own framing may take beyond its entries.
+const INDEX_SLACK: u64 = 64;
+
+/// Fixed start of the payload, ahead of the operation frames. Its layout matches the
+/// start of older whole-manifest payloads, so those are rejected by the version check.
+/// Fields after `hash_algo` were appended by minor versions and are only read from
+/// patches at least that new (see [`decode_payload`]).
 #[derive(Debug, Serialize, Deserialize)]
+pub struct PatchPreamble {
+    pub version: FormatVersion,
+    pub hash_algo: HashAlgo,
+    /// Since 13.1: see [`PatchManifest::full_file_set`].
+    #[serde(default)]
+    pub full_file_set: bool,
+}
+
+/// A decoded patch: the preamble fields plus every operation, in file order.
+#[derive(Debug)]
 pub struct PatchManifest {
-    pub version: u32,
+    pub version: FormatVersion,
+    /// Algorithm used for every hash stored in `operations`.
+    pub hash_algo: HashAlgo,
+    /// Every file of the post-patch tree has an operation (unchanged ones a VerifyFile),
+    /// so a target file without one doesn't belong there (`apply --prune`). Only set by
+    /// `create --full-verify` without filters or skipped entries.
+    pub full_file_set: bool,
     pub operations: Vec<PatchOp>,
 }
 
+/// How a [`PatchOp::ModifyFile`] diffed against decompressed content is turned back
+/// into the file's bytes. See [`crate::recompress`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Recompress {
+    /// A single-member gzip file: `header` is its original header (name, mtime, flags)
+    /// and `level` the deflate level that reproduced its compressed stream.
+    Gzip {
+        #[serde(with = "serde_bytes")]
+        header: Vec<u8>,
+        level: u32,
+    },
+}
+
+/// One change to the target tree. Paths are relative, with forward slashes, in the form
+/// [`util::normalize_relative_path`] gives them. Since 13.6 they may hold non-UTF-8
+/// bytes escaped as [`util::PATH_ESCAPE`] and two hex digits (`create --allow-non-utf8`).
 #[derive(Debug, Serialize, Deserialize)]
 pub enum PatchOp {
     CreateDir {
         path: String,
+        /// Since 13.4: permission bits of the directory in the new tree, set by apply on
+        /// Unix. `None` where the platform has none, and in older patches.
+        #[serde(default)]
+        mode: Option<u32>,
     },
     AddFile {
         path: String,
+        /// Serialized as bytes rather than a sequence (the same in bincode, a byte
+        /// string instead of an array of integers in CBOR).
+        #[serde(with = "serde_bytes")]
         data: Vec<u8>,
         blake3_hash: [u8; 32],
+        /// `data` is a single zstd frame of the content rather than the content itself,
+        /// so apply can decompress it straight to disk. See [`ADD_COMPRESS_MIN`].
+        compressed: bool,
+        /// Extended attributes to set on the written file (`--preserve-xattrs`); empty
+        /// when none were recorded.
+        xattrs: Xattrs,
+        /// Since 13.2: the content isn't in `data` (left empty) but in an objects
+        /// directory, as a zstd frame named by `blake3_hash` (`--objects-dir`); see
+        /// [`object_path`].
+        #[serde(default)]
+        external: bool,
     },
     ModifyFile {
         path: String,
         diff_chunks: Vec<DiffChunk>,
         new_blake3_hash: [u8; 32],
+        /// Block size the diff was matched with, for introspection; apply ignores it.
+        /// 0 when no block matching was done (incompressible files, merged diffs).
+        block_size: u32,
+        /// Set when the diff is against the decompressed content (`--recompress-ext`):
+        /// apply decompresses the old file, patches that, and compresses the result.
+        recompress: Option<Recompress>,
+        /// As for [`PatchOp::AddFile`].
+        xattrs: Xattrs,
+        /// Since 13.3: hash of the old file the diff was made from, checked by
+        /// `apply --verify-before`. `None` in older and merged patches.
+        #[serde(default)]
+        old_blake3_hash: Option<[u8; 32]>,
     },
     DeleteFile {
         path: String,
+        /// Since 13.3: as for [`PatchOp::ModifyFile`], the hash of the file deleted.
+        #[serde(default)]
+        old_blake3_hash: Option<[u8; 32]>,
+        /// Since 13.8: some base of a multi-base patch doesn't have the file, so a target
+        /// built from that base lacks it too and `--verify-before` accepts its absence.
+        #[serde(default)]
+        may_be_absent: bool,
     },
     DeleteDir {
         path: String,
     },
+    /// Hard link `path` to `target`, another path in the patched tree
+    /// (`--preserve-hardlinks`). Applied after all file contents are in place.
+    CreateHardlink {
+        path: String,
+        target: String,
+    },
+    /// A modified file from a patch built against several old trees (`--old` given more
+    /// than once). Holds one diff per distinct old version of the file; apply hashes the
+    /// target file and uses the variant whose `base_hash` matches.
+    ModifyFileMulti {
+        path: String,
+        variants: Vec<BaseDiff>,
+        new_blake3_hash: [u8; 32],
+    },
+    /// New permission bits and/or modification time for a file whose content is
+    /// unchanged (`--metadata`). `None` fields are left as they are.
+    SetMetadata {
+        path: String,
+        mode: Option<u32>,
+        mtime: Option<std::time::SystemTime>,
+    },
+    /// Expected hash of a file the patch leaves untouched (`--full-verify`).
+    /// Ignored by apply; consumed by verify to check the whole post-patch tree.
+    VerifyFile {
+        path: String,
+        blake3_hash: [u8; 32],
+    },
+    /// Since 13.7: a symbolic link at `path` pointing at `target`, which is stored as
+    /// the link holds it (see [`util::read_link_target`]) and may lead anywhere, inside
+    /// the tree or not (`create --symlink-mode`). Applied after all file contents are in
+    /// place, replacing any file or link at `path`.
+    CreateSymlink {
+        path: String,
+        target: String,
+    },
+}
+
+/// Writes a patch file one operation at a time, so no more than one operation (e.g. one
+/// added file's content) has to be in memory at once.
+///
+/// Payload: bincode(PatchPreamble), then per operation a u64 LE length followed by
+/// bincode(PatchOp), all zstd-compressed; CBOR in place of bincode for
+/// [`ManifestEncoding::Cbor`]. The header's uncompressed length is only known
+/// at the end, so `finish` seeks back to fill it in; the sink must therefore be seekable.
+/// With a [`MacKey`], `finish` also appends the MAC trailer (see [`verify_mac`]).
+pub struct PatchWriter<W: Write + Seek = std::io::BufWriter<std::fs::File>> {
+    encoder: zstd::Encoder<'static, MacWriter<W>>,
+    uncompressed_len: u64,
+    encoding: ManifestEncoding,
+    index: PatchIndex,
+    /// Uncompressed size of the index entries so far.
+    index_len: u64,
+    /// Where in the sink the header starts (after a [`PartHeader`], for a split patch).
+    start: u64,
+}
+
+impl PatchWriter {
+    pub fn create(
+        path: &Path,
+        hash_algo: HashAlgo,
+        full_file_set: bool,
+        encoding: ManifestEncoding,
+        mac_key: Option<&MacKey>,
+    ) -> Result<Self> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create output file: {}", path.display()))?;
+        Self::new(std::io::BufWriter::new(file), hash_algo, full_file_set, encoding, mac_key)
+    }
+}
+
+impl<W: Write + Seek> PatchWriter<W> {
+    /// Start a patch in `out`, e.g. a `Cursor<Vec<u8>>` for an in-memory patch.
+    pub fn new(
+        mut out: W,
+        hash_algo: HashAlgo,
+        full_file_set: bool,
+        encoding: ManifestEncoding,
+        mac_key: Option<&MacKey>,
+    ) -> Result<Self> {
+        let start = out.stream_position()?;
+        out.write_all(
+            &PatchHeader {
+                uncompressed_len: 0,
+                encoding,
+                index_offset: None,
+            }
+            .encode(),
+        )?;
+        let out = MacWriter {
+            inner: out,
+            mac: mac_key.map(MacKey::hasher),
+            written: 0,
+        };
+        let mut encoder = zstd::Encoder::new(out, 3).context("Failed to create zstd encoder")?;
+
+        let preamble = PatchPreamble {
+            version: FORMAT_VERSION,
+            hash_algo,
+            full_file_set,
+        };
+        let uncompressed_len = match encoding {
+            ManifestEncoding::Bincode => {
+                bincode::serialize_into(&mut encoder, &preamble)
+                    .context("Failed to serialize patch preamble")?;
+                bincode::serialized_size(&preamble)?
+            }
+            ManifestEncoding::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(&preamble, &mut buf)
+                    .context("Failed to serialize patch preamble")?;
+                encoder.write_all(&buf)?;
+                buf.len() as u64
+            }
+        };
+        Ok(Self {
+            encoder,
+            uncompressed_len,
+            encoding,
+            index: PatchIndex {
+                preamble_len: uncompressed_len,
+                entries: Vec::new(),
+            },
+            index_len: 0,
+            start,
+        })
+    }
+
+    /// Uncompressed size of the index once an entry for `op` is added, for the split
+    /// writer's fit check.
+    fn index_len_with(&self, op: &PatchOp) -> Result<u64> {
+        let entry = IndexEntry {
+            path: op.path().to_string(),
+            offset: 0,
+            len: 0,
+        };
+        Ok(self.index_len + serialize(&entry, self.encoding)?.len() as u64 + INDEX_SLACK)
+    }
+
+    /// Uncompressed size of the frame [`write_op`](Self::write_op) would append for `op`.
+    pub fn frame_len(&self, op: &PatchOp) -> Result<u64> {
+        let len = match self.encoding {
+            ManifestEncoding::Bincode => {
+                bincode::serialized_size(op).context("Failed to size patch operation")?
+            }
+            ManifestEncoding::Cbor => {
+                let mut frame = Vec::new();
+                ciborium::into_writer(op, &mut frame)
+                    .with_context(|| format!("Failed to serialize operation for {}", op.path()))?;
+                frame.len() as u64
+            }
+        };
+        Ok(8 + len)
+    }
+
+    /// Flush the compressor and return the compressed payload's size so far. Each call
+    /// ends a zstd block, which costs a little compression.
+    pub fn compressed_len(&mut self) -> Result<u64> {
+        self.encoder.flush().context("Failed to flush zstd stream")?;
+        Ok(self.encoder.get_ref().written)
+    }
+
+    /// Append one length-prefixed operation frame.
+    pub fn write_op(&mut self, op: &PatchOp) -> Result<()> {
+        let len = match self.encoding {
+            ManifestEncoding::Bincode => {
+                let len =
+                    bincode::serialized_size(op).context("Failed to size patch operation")?;
+                self.encoder.write_all(&len.to_le_bytes())?;
+                bincode::serialize_into(&mut self.encoder, op)
+                    .with_context(|| format!("Failed to serialize operation for {}", op.path()))?;
+                len
+            }
+            ManifestEncoding::Cbor => {
+                // CBOR has no cheap size pass, so the frame is built in memory first.
+                let mut frame = Vec::new();
+                ciborium::into_writer(op, &mut frame)
+                    .with_context(|| format!("Failed to serialize operation for {}", op.path()))?;
+                self.encoder.write_all(&(frame.len() as u64).to_le_bytes())?;
+                self.encoder.write_all(&frame)?;
+                frame.len() as u64
+            }
+        };
+        let entry = IndexEntry {
+            path: op.path().to_string(),
+            offset: self.uncompressed_len + 8,
+            len,
+        };
+        self.index_len += serialize(&entry, self.encoding)?.len() as u64;
+        self.index.entries.push(entry);
+        self.uncompressed_len += 8 + len;
+        Ok(())
+    }
+
+    /// Finish the zstd stream, append the index frame and fill in the header, returning
+    /// the sink.
+    pub fn finish(self) -> Result<W> {
+        let out = self.encoder.finish().context("Failed to finish zstd stream")?;
+        let index_offset = HEADER_LEN as u64 + out.written;
+        let mut encoder = zstd::Encoder::new(out, 3).context("Failed to create zstd encoder")?;
+        encoder.write_all(&serialize(&self.index, self.encoding).context("Failed to serialize patch index")?)?;
+        let MacWriter {
+            inner: mut out, mac, ..
+        } = encoder.finish().context("Failed to finish zstd stream")?;
+        let header = PatchHeader {
+            uncompressed_len: self.uncompressed_len,
+            encoding: self.encoding,
+            index_offset: Some(index_offset),
+        }
+        .encode();
+        if let Some(mut mac) = mac {
+            mac.update(&header);
+            out.write_all(mac.finalize().as_bytes())?;
+            out.write_all(MAC_MAGIC)?;
+        }
+        out.seek(SeekFrom::Start(self.start))?;
+        out.write_all(&header)?;
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+/// Magic of one part of a split patch (`create --split-size`).
+pub const PART_MAGIC: &[u8; 8] = b"PATCHS01";
+
+/// Bytes of the [`PartHeader`] in front of each part.
+pub const PART_HEADER_LEN: usize = PART_MAGIC.len() + 16;
+
+/// Slack left for the end of the zstd frame, which [`PatchWriter::compressed_len`]
+/// doesn't count yet: the final block header, plus a checksum if one is ever enabled.
+const FRAME_END_RESERVE: u64 = 8;
+
+/// Uncompressed header of one part of a split patch. A part is PART_MAGIC, the set id
+/// (u64 LE), then the 1-based part index and the part count (u32 LE each), followed by
+/// a complete patch file holding the next run of operations, each part with its own
+/// preamble and MAC. The id is shared by every part of one patch, so parts of two
+/// different runs aren't mixed; the MAC covers each part's patch, not this header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartHeader {
+    pub set_id: u64,
+    pub index: u32,
+    pub count: u32,
+}
+
+impl PartHeader {
+    pub fn encode(&self) -> [u8; PART_HEADER_LEN] {
+        let mut out = [0u8; PART_HEADER_LEN];
+        out[..8].copy_from_slice(PART_MAGIC);
+        out[8..16].copy_from_slice(&self.set_id.to_le_bytes());
+        out[16..20].copy_from_slice(&self.index.to_le_bytes());
+        out[20..24].copy_from_slice(&self.count.to_le_bytes());
+        out
+    }
+
+    /// Parse the part header from the start of a part file.
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        if !raw.starts_with(PART_MAGIC) {
+            bail!(PatchError::InvalidMagic);
+        }
+        if raw.len() < PART_HEADER_LEN {
+            bail!(PatchError::Corrupt("truncated part header".to_string()));
+        }
+        Ok(Self {
+            set_id: u64::from_le_bytes(raw[8..16].try_into().expect("8 bytes")),
+            index: u32::from_le_bytes(raw[16..20].try_into().expect("4 bytes")),
+            count: u32::from_le_bytes(raw[20..24].try_into().expect("4 bytes")),
+        })
+    }
+}
+
+/// Path of part `index` (1-based) of the split patch `base`: `base.001`, `base.002`...
+pub fn part_path(base: &Path, index: u32) -> PathBuf {
+    let mut name = base.as_os_str().to_os_string();
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+/// Writes a patch as numbered parts of at most `max_part_size` bytes each, starting a
+/// new part whenever the next operation might not fit. Parts split at operation
+/// boundaries, so an operation too large for a part of its own is an error. The fit is
+/// judged by the operation's worst-case compressed size, so parts of well-compressing
+/// content can come out well under the limit.
+pub struct SplitPatchWriter {
+    base: PathBuf,
+    max_part_size: u64,
+    set_id: u64,
+    hash_algo: HashAlgo,
+    full_file_set: bool,
+    encoding: ManifestEncoding,
+    mac_key: Option<MacKey>,
+    part: PatchWriter,
+    /// Compressed payload of the current part so far, and its number of operations.
+    part_len: u64,
+    part_ops: usize,
+    parts: u32,
+}
+
+impl SplitPatchWriter {
+    pub fn create(
+        base: &Path,
+        max_part_size: u64,
+        hash_algo: HashAlgo,
+        full_file_set: bool,
+        encoding: ManifestEncoding,
+        mac_key: Option<&MacKey>,
+    ) -> Result<Self> {
+        // Unique enough to tell two runs apart; it guards against mix-ups, not attacks.
+        let mut seed = blake3::Hasher::new();
+        seed.update(&std::process::id().to_le_bytes());
+        seed.update(format!("{:?}", std::time::SystemTime::now()).as_bytes());
+        seed.update(base.as_os_str().as_encoded_bytes());
+        let set_id = u64::from_le_bytes(seed.finalize().as_bytes()[..8].try_into().expect("8 bytes"));
+        let (part, part_len) =
+            start_part(base, 1, set_id, hash_algo, full_file_set, encoding, mac_key)?;
+        let writer = Self {
+            base: base.to_path_buf(),
+            max_part_size,
+            set_id,
+            hash_algo,
+            full_file_set,
+            encoding,
+            mac_key: mac_key.cloned(),
+            part,
+            part_len,
+            part_ops: 0,
+            parts: 1,
+        };
+        let empty_len = writer.projected_len(0, INDEX_SLACK);
+        if empty_len > max_part_size {
+            bail!(
+                "Split size of {} bytes is too small for even an empty part ({} bytes)",
+                max_part_size,
+                empty_len
+            );
+        }
+        Ok(writer)
+    }
+
+    /// Worst-case size of the current part once `frame_len` more payload bytes are
+    /// appended and it is finished with an index of `index_len` bytes.
+    fn projected_len(&self, frame_len: u64, index_len: u64) -> u64 {
+        let bound = if frame_len == 0 {
+            0
+        } else {
+            zstd::zstd_safe::compress_bound(frame_len as usize) as u64
+        };
+        let index = zstd::zstd_safe::compress_bound(index_len as usize) as u64;
+        let mac = if self.mac_key.is_some() { MAC_TRAILER_LEN as u64 } else { 0 };
+        (PART_HEADER_LEN + HEADER_LEN) as u64 + self.part_len + bound + FRAME_END_RESERVE + index + mac
+    }
+
+    pub fn write_op(&mut self, op: &PatchOp) -> Result<()> {
+        let frame_len = self.part.frame_len(op)?;
+        if self.part_ops > 0
+            && self.projected_len(frame_len, self.part.index_len_with(op)?) > self.max_part_size
+        {
+            let (next, next_len) = start_part(
+                &self.base,
+                self.parts + 1,
+                self.set_id,
+                self.hash_algo,
+                self.full_file_set,
+                self.encoding,
+                self.mac_key.as_ref(),
+            )?;
+            std::mem::replace(&mut self.part, next).finish()?;
+            self.parts += 1;
+            self.part_len = next_len;
+            self.part_ops = 0;
+        }
+        let projected = self.projected_len(frame_len, self.part.index_len_with(op)?);
+        if projected > self.max_part_size {
+            bail!(
+                "Operation for {} takes up to {} bytes, more than a part of {} bytes can hold (see --split-size)",
+                op.path(),
+                projected,
+                self.max_part_size
+            );
+        }
+        self.part.write_op(op)?;
+        self.part_len = self.part.compressed_len()?;
+        self.part_ops += 1;
+        Ok(())
+    }
+
+    /// Finish the last part and fill the part count into every part's header. Returns
+    /// the part files in order.
+    pub fn finish(self) -> Result<Vec<PathBuf>> {
+        self.part.finish()?;
+        let mut paths = Vec::new();
+        for index in 1..=self.parts {
+            let path = part_path(&self.base, index);
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            let header = PartHeader {
+                set_id: self.set_id,
+                index,
+                count: self.parts,
+            };
+            file.write_all(&header.encode())
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+/// Create part `index` of a split patch, with its count left 0 until the end, and
+/// return its writer and how much payload it starts with (the preamble).
+fn start_part(
+    base: &Path,
+    index: u32,
+    set_id: u64,
+    hash_algo: HashAlgo,
+    full_file_set: bool,
+    encoding: ManifestEncoding,
+    mac_key: Option<&MacKey>,
+) -> Result<(PatchWriter, u64)> {
+    let path = part_path(base, index);
+    let file = std::fs::File::create(&path)
+        .with_context(|| format!("Failed to create output file: {}", path.display()))?;
+    let mut out = std::io::BufWriter::new(file);
+    out.write_all(&PartHeader { set_id, index, count: 0 }.encode())?;
+    let mut writer = PatchWriter::new(out, hash_algo, full_file_set, encoding, mac_key)?;
+    let len = writer.compressed_len()?;
+    Ok((writer, len))
+}
+
+/// Serialize `value` in `encoding`, as one frame.
+fn serialize<T: Serialize>(value: &T, encoding: ManifestEncoding) -> Result<Vec<u8>> {
+    Ok(match encoding {
+        ManifestEncoding::Bincode => bincode::serialize(value)?,
+        ManifestEncoding::Cbor => {
+            let mut frame = Vec::new();
+            ciborium::into_writer(value, &mut frame)?;
+            frame
+        }
+    })
+}
+
+/// Decode a decompressed [`PatchIndex`] frame.
+pub fn decode_index(data: &[u8], encoding: ManifestEncoding) -> Result<PatchIndex> {
+    match encoding {
+        ManifestEncoding::Bincode => decode_frame(data, false).map_err(|e| e.to_string()),
+        ManifestEncoding::Cbor => decode_cbor_frame(data),
+    }
+    .map_err(|e| PatchError::Deserialize(format!("index: {}", e)).into())
+}
+
+/// Decode one operation frame. A frame from the current minor version must be consumed
+/// exactly; one from an older minor is zero-padded first (see [`FormatVersion`]), so the
+/// fields it predates decode as absent and the unused padding is ignored.
+fn decode_frame<T: serde::de::DeserializeOwned>(
+    frame: &[u8],
+    older_minor: bool,
+) -> bincode::Result<T> {
+    use bincode::Options;
+    let options = bincode::DefaultOptions::new().with_fixint_encoding();
+    if older_minor {
+        let padded = [frame, &OLDER_MINOR_PADDING].concat();
+        options.allow_trailing_bytes().deserialize(&padded)
+    } else {
+        options.deserialize(frame)
+    }
+}
+
+/// [`decode_frame`] for CBOR, where nothing is padded: fields are named, so those an
+/// older minor predates are missing from the frame, and a field added by a minor bump
+/// needs `#[serde(default)]` to read as absent. The frame must be consumed exactly.
+fn decode_cbor_frame<T: serde::de::DeserializeOwned>(mut frame: &[u8]) -> Result<T, String> {
+    let value = ciborium::from_reader(&mut frame).map_err(|e| e.to_string())?;
+    if !frame.is_empty() {
+        return Err(format!("{} trailing bytes", frame.len()));
+    }
+    Ok(value)
+}
+
+/// Read the bincode preamble field by field: the version first, so a patch from another
+/// major version is rejected before anything else is decoded, then only the fields its
+/// minor version has. Unlike a frame, the preamble can't be zero-padded, since the
+/// operation frames follow it directly.
+fn decode_bincode_preamble(rest: &mut &[u8]) -> Result<PatchPreamble> {
+    let field = |e: bincode::Error| PatchError::Deserialize(format!("preamble: {}", e));
+    let version = bincode::deserialize_from(&mut *rest).map_err(field)?;
+    check_version(version)?;
+    let hash_algo = bincode::deserialize_from(&mut *rest).map_err(field)?;
+    let full_file_set = version.minor >= 1 && bincode::deserialize_from(&mut *rest).map_err(field)?;
+    Ok(PatchPreamble {
+        version,
+        hash_algo,
+        full_file_set,
+    })
+}
+
+fn check_version(version: FormatVersion) -> Result<()> {
+    if !FORMAT_VERSION.can_read(version) {
+        bail!(PatchError::UnsupportedVersion {
+            found: version,
+            expected: FORMAT_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Decode the preamble from the start of `rest`, advancing past it.
+pub(crate) fn decode_preamble(rest: &mut &[u8], encoding: ManifestEncoding) -> Result<PatchPreamble> {
+    match encoding {
+        ManifestEncoding::Bincode => decode_bincode_preamble(rest),
+        ManifestEncoding::Cbor => {
+            let preamble: PatchPreamble = ciborium::from_reader(&mut *rest)
+                .map_err(|e| PatchError::Deserialize(format!("preamble: {}", e)))?;
+            check_version(preamble.version)?;
+            Ok(preamble)
+        }
+    }
+}
+
+/// Decode one operation frame of a patch written at `version`, which has passed the
+/// version check: any other version than this build's is an older minor.
+pub(crate) fn decode_op(
+    frame: &[u8],
+    encoding: ManifestEncoding,
+    version: FormatVersion,
+) -> Result<PatchOp, String> {
+    match encoding {
+        ManifestEncoding::Bincode => {
+            decode_frame(frame, version != FORMAT_VERSION).map_err(|e| e.to_string())
+        }
+        ManifestEncoding::Cbor => decode_cbor_frame(frame),
+    }
+}
+
+/// Decode a decompressed payload: the preamble, then operation frames up to the end.
+pub fn decode_payload(data: &[u8], encoding: ManifestEncoding) -> Result<PatchManifest> {
+    let mut rest = data;
+    let preamble = decode_preamble(&mut rest, encoding)?;
+
+    let mut operations = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 8 {
+            bail!(PatchError::Corrupt("truncated operation frame".to_string()));
+        }
+        let (len, body) = rest.split_at(8);
+        let len = u64::from_le_bytes(len.try_into().expect("split at 8"));
+        if len > body.len() as u64 {
+            bail!(PatchError::Corrupt(format!(
+                "operation {} declares {} bytes, only {} remain",
+                operations.len(),
+                len,
+                body.len()
+            )));
+        }
+        let (frame, tail) = body.split_at(len as usize);
+        let op = decode_op(frame, encoding, preamble.version)
+            .map_err(|e| PatchError::Deserialize(format!("operation {}: {}", operations.len(), e)))?;
+        operations.push(op);
+        rest = tail;
+    }
+
+    Ok(PatchManifest {
+        version: preamble.version,
+        hash_algo: preamble.hash_algo,
+        full_file_set: preamble.full_file_set,
+        operations,
+    })
+}
+
+impl PatchOp {
+    /// The path this operation creates, writes, checks or removes.
+    pub fn path(&self) -> &str {
+        match self {
+            PatchOp::CreateDir { path, .. }
+            | PatchOp::AddFile { path, .. }
+            | PatchOp::ModifyFile { path, .. }
+            | PatchOp::ModifyFileMulti { path, .. }
+            | PatchOp::DeleteFile { path, .. }
+            | PatchOp::DeleteDir { path }
+            | PatchOp::CreateHardlink { path, .. }
+            | PatchOp::SetMetadata { path, .. }
+            | PatchOp::VerifyFile { path, .. }
+            | PatchOp::CreateSymlink { path, .. } => path,
+        }
+    }
+
+    /// Variant name, for messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PatchOp::CreateDir { .. } => "CreateDir",
+            PatchOp::AddFile { .. } => "AddFile",
+            PatchOp::ModifyFile { .. } => "ModifyFile",
+            PatchOp::ModifyFileMulti { .. } => "ModifyFileMulti",
+            PatchOp::DeleteFile { .. } => "DeleteFile",
+            PatchOp::DeleteDir { .. } => "DeleteDir",
+            PatchOp::CreateHardlink { .. } => "CreateHardlink",
+            PatchOp::SetMetadata { .. } => "SetMetadata",
+            PatchOp::VerifyFile { .. } => "VerifyFile",
+            PatchOp::CreateSymlink { .. } => "CreateSymlink",
+        }
+    }
+}
+
+/// Build an AddFile, compressing `content` on its own when it is at least
+/// [`ADD_COMPRESS_MIN`] bytes, `incompressible` is false and compression saves space.
+pub fn add_file_op(
+    path: String,
+    content: &[u8],
+    blake3_hash: [u8; 32],
+    incompressible: bool,
+    xattrs: Xattrs,
+) -> Result<PatchOp> {
+    if content.len() >= ADD_COMPRESS_MIN && !incompressible {
+        let frame = zstd::bulk::compress(content, 3)
+            .with_context(|| format!("Failed to compress {}", path))?;
+        if frame.len() < content.len() {
+            return Ok(PatchOp::AddFile {
+                path,
+                data: frame,
+                blake3_hash,
+                compressed: true,
+                xattrs,
+                external: false,
+            });
+        }
+    }
+    Ok(PatchOp::AddFile {
+        path,
+        data: content.to_vec(),
+        blake3_hash,
+        compressed: false,
+        xattrs,
+        external: false,
+    })
+}
+
+/// Where the object holding content with hash `hash` lives under the objects directory
+/// `dir`: the first two hex digits name a subdirectory and the other 62 the file, so no
+/// one directory holds every object.
+pub fn object_path(dir: &Path, hash: &[u8; 32]) -> PathBuf {
+    let hex = util::hash_hex(hash);
+    dir.join(&hex[..2]).join(&hex[2..])
+}
+
+/// Build an AddFile whose content is stored in the objects directory `dir` rather than
+/// in the patch (`--objects-dir`). The object is only written if none with this hash
+/// exists yet, so patches sharing content share one copy of it.
+pub fn add_object_op(
+    dir: &Path,
+    path: String,
+    content: &[u8],
+    blake3_hash: [u8; 32],
+    xattrs: Xattrs,
+) -> Result<PatchOp> {
+    static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+    let object = object_path(dir, &blake3_hash);
+    if !object.exists() {
+        let parent = object.parent().unwrap_or(dir);
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+        let frame = zstd::bulk::compress(content, 3)
+            .with_context(|| format!("Failed to compress {}", path))?;
+        // Written under a unique name and renamed into place, so a reader never sees a
+        // partial object and two writers of the same content don't interleave.
+        let temp = parent.join(format!(
+            ".tmp-{}-{}",
+            std::process::id(),
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&temp, &frame)
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+        if let Err(e) = std::fs::rename(&temp, &object) {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e).with_context(|| format!("Failed to write {}", object.display()));
+        }
+    }
+    Ok(PatchOp::AddFile {
+        path,
+        data: Vec::new(),
+        blake3_hash,
+        compressed: false,
+        xattrs,
+        external: true,
+    })
+}
+
+/// Map the object holding an external AddFile's content, a zstd frame to read with
+/// [`add_file_len`] and [`add_file_reader`] like compressed content in the patch.
+pub fn open_object(dir: &Path, path: &str, hash: &[u8; 32]) -> Result<util::FileData> {
+    let object = object_path(dir, hash);
+    if !object.is_file() {
+        bail!(
+            "Content of {} is missing from the objects directory (expected {})",
+            path,
+            object.display()
+        );
+    }
+    util::mmap_file(&object)
+}
+
+/// Size of an AddFile's content once decompressed. Compressed frames record it in their
+/// header; one that doesn't is rejected, so limits can be checked before decompressing.
+pub fn add_file_len(data: &[u8], compressed: bool) -> Result<u64> {
+    if !compressed {
+        return Ok(data.len() as u64);
+    }
+    match zstd::zstd_safe::get_frame_content_size(data) {
+        Ok(Some(len)) => Ok(len),
+        _ => bail!(PatchError::Corrupt(
+            "compressed file content without a recorded size".to_string()
+        )),
+    }
+}
+
+/// Reader over an AddFile's content, decompressing it on the fly when needed.
+pub fn add_file_reader(data: &[u8], compressed: bool) -> Result<Box<dyn Read + '_>> {
+    if !compressed {
+        return Ok(Box::new(data));
+    }
+    let decoder = zstd::Decoder::with_buffer(data)
+        .map_err(|e| PatchError::Decompress(e.to_string()))?;
+    Ok(Box::new(decoder.single_frame()))
+}
+
+/// An AddFile's content in memory, for callers that need all of it (merge).
+pub fn add_file_content(data: Vec<u8>, compressed: bool) -> Result<Vec<u8>> {
+    if !compressed {
+        return Ok(data);
+    }
+    let mut out = Vec::with_capacity(add_file_len(&data, true)? as usize);
+    add_file_reader(&data, true)?
+        .read_to_end(&mut out)
+        .map_err(|e| PatchError::Decompress(e.to_string()))?;
+    Ok(out)
+}
+
+/// One variant of a [`PatchOp::ModifyFileMulti`]: the diff from the old file whose
+/// hash is `base_hash` to the new file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BaseDiff {
+    /// Index (in `--old` order) of the first old tree holding this version.
+    pub base: u32,
+    pub base_hash: [u8; 32],
+    pub diff_chunks: Vec<DiffChunk>,
+    /// As for [`PatchOp::ModifyFile`].
+    pub block_size: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DiffChunk {
     Copy { offset: u64, length: u64 },
-    Insert { data: Vec<u8> },
+    Insert {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// A run of zero bytes, stored as its length. Apply leaves it as a hole in the
+    /// output file, so sparse files (VM images, databases) stay sparse.
+    Zeros { length: u64 },
+}
+
+/// Count (Copy, Insert) chunks in a diff, for reporting. Zeros count as inserts.
+pub fn chunk_counts(chunks: &[DiffChunk]) -> (usize, usize) {
+    let copies = chunks
+        .iter()
+        .filter(|c| matches!(c, DiffChunk::Copy { .. }))
+        .count();
+    (copies, chunks.len() - copies)
 }
 
+/// How much content one operation carries, for `create --stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpSize {
+    pub path: String,
+    /// `"add"` or `"modify"`.
+    pub kind: &'static str,
+    /// Length of an add's `data`, or of a modify's Insert data (across every variant
+    /// of a multi-base one): what the operation stores, before the payload's zstd.
+    pub bytes: u64,
+    /// Insert chunks of a modify; 0 for an add.
+    pub inserts: usize,
+}
+
+impl OpSize {
+    /// The size of `op`, or `None` for an operation that carries no file content.
+    pub fn of(op: &PatchOp) -> Option<Self> {
+        let inserted = |chunks: &[DiffChunk]| {
+            chunks.iter().fold((0, 0), |(bytes, inserts), chunk| match chunk {
+                DiffChunk::Insert { data } => (bytes + data.len() as u64, inserts + 1),
+                _ => (bytes, inserts),
+            })
+        };
+        let (kind, (bytes, inserts)) = match op {
+            PatchOp::AddFile { data, .. } => ("add", (data.len() as u64, 0)),
+            PatchOp::ModifyFile { diff_chunks, .. } => ("modify", inserted(diff_chunks)),
+            PatchOp::ModifyFileMulti { variants, .. } => (
+                "modify",
+                variants
+                    .iter()
+                    .map(|variant| inserted(&variant.diff_chunks))
+                    .fold((0, 0), |(b, i), (vb, vi)| (b + vb, i + vi)),
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            path: op.path().to_string(),
+            kind,
+            bytes,
+            inserts,
+        })
+    }
+}
+
+#[derive(Debug)]
 pub struct ApplySummary {
     pub dirs_created: usize,
     pub files_added: usize,
     pub files_modified: usize,
     pub files_deleted: usize,
     pub dirs_deleted: usize,
+    pub hardlinks_created: usize,
+    pub symlinks_created: usize,
+    /// Files whose permissions or mtime were set without touching their content.
+    pub metadata_updated: usize,
+    /// Files left untouched because their hash check failed (apply `--skip-mismatches`),
+    /// in path order. Not counted as added or modified.
+    pub skipped_mismatches: Vec<String>,
+    /// Files and directories the patch would have deleted but that were kept because
+    /// the deletion was declined (apply `--interactive`).
+    pub deletes_declined: usize,
+    /// Bytes of file content processed: the new tree's files for create, the content
+    /// written to added and modified files for apply. Basis of the MB/s figure.
+    pub bytes_processed: u64,
+    /// Wall-clock time per phase, in the order the phases ran (`--timing`).
+    pub timings: Vec<PhaseTiming>,
+    /// The operations carrying the most content, largest first, at most
+    /// [`CreateOptions::largest_ops`](crate::create::CreateOptions::largest_ops) of them
+    /// (create `--stats`). Empty for apply.
+    pub largest_ops: Vec<OpSize>,
+    /// Every path the patch touches, in the order it was written, with
+    /// [`CreateOptions::record_touched`](crate::create::CreateOptions::record_touched)
+    /// (create `--print-tree`). Empty for apply.
+    pub touched: Vec<Touched>,
+}
+
+/// How long one phase of create or apply took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhaseTiming {
+    pub phase: &'static str,
+    pub elapsed: std::time::Duration,
+    /// Ran alongside the adjacent concurrent phases, so their times overlap rather than add up.
+    pub concurrent: bool,
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        for encoding in [ManifestEncoding::Bincode, ManifestEncoding::Cbor] {
+            let header = PatchHeader {
+                uncompressed_len: 0x0102_0304_0506_0708,
+                encoding,
+                index_offset: Some(1234),
+            };
+            let parsed = PatchHeader::parse(&header.encode()).unwrap();
+            assert_eq!(parsed.uncompressed_len, header.uncompressed_len);
+            assert_eq!(parsed.encoding, encoding);
+            assert_eq!(parsed.index_offset, Some(1234));
+            assert_eq!(parsed.encoded_len(), HEADER_LEN);
+        }
+        // Before 13.5 the header ends after the length.
+        let mut legacy = LEGACY_MAGIC.to_vec();
+        legacy.extend_from_slice(&7u64.to_le_bytes());
+        let parsed = PatchHeader::parse(&legacy).unwrap();
+        assert_eq!((parsed.uncompressed_len, parsed.index_offset), (7, None));
+        assert_eq!(parsed.encoded_len(), LEGACY_HEADER_LEN);
+    }
+
+    #[test]
+    fn test_header_rejects_bad_magic_and_truncation() {
+        assert!(PatchHeader::parse(b"NOTAPATCH0000000").is_err());
+        assert!(PatchHeader::parse(&MAGIC[..]).is_err());
+    }
+
+    #[test]
+    fn test_writer_frames_round_trip() {
+        for encoding in [ManifestEncoding::Bincode, ManifestEncoding::Cbor] {
+            let path = std::env::temp_dir()
+                .join(format!("patcher_format_writer_test_{}.patch", encoding));
+            let mut writer = PatchWriter::create(&path, HashAlgo::Sha256, false, encoding, None).unwrap();
+            writer
+                .write_op(&PatchOp::CreateDir { path: "d".into(), mode: None })
+                .unwrap();
+            writer
+                .write_op(&PatchOp::AddFile {
+                    path: "d/f".into(),
+                    data: vec![9; 1000],
+                    blake3_hash: [1; 32],
+                    compressed: false,
+                    xattrs: Vec::new(),
+                    external: false,
+                })
+                .unwrap();
+            writer.finish().unwrap();
+
+            let raw = std::fs::read(&path).unwrap();
+            let header = PatchHeader::parse(&raw).unwrap();
+            assert_eq!(header.encoding, encoding);
+            let (payload, index) = header.split_index(&raw[HEADER_LEN..]).unwrap();
+            let payload = zstd::decode_all(payload).unwrap();
+            assert_eq!(header.uncompressed_len, payload.len() as u64);
+
+            let index = decode_index(&zstd::decode_all(index).unwrap(), encoding).unwrap();
+            let paths: Vec<&str> = index.entries.iter().map(|e| e.path.as_str()).collect();
+            assert_eq!(paths, ["d", "d/f"]);
+            let entry = &index.entries[1];
+            let frame = &payload[entry.offset as usize..(entry.offset + entry.len) as usize];
+            assert!(matches!(decode_op(frame, encoding, FORMAT_VERSION), Ok(PatchOp::AddFile { .. })));
+
+            let manifest = decode_payload(&payload, encoding).unwrap();
+            assert_eq!(manifest.hash_algo, HashAlgo::Sha256);
+            assert_eq!(manifest.operations.len(), 2);
+            assert!(matches!(&manifest.operations[1], PatchOp::AddFile { data, .. } if data.len() == 1000));
+
+            // Cutting the last frame short is detected, not silently dropped.
+            assert!(decode_payload(&payload[..payload.len() - 1], encoding).is_err());
+
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn test_split_writer_keeps_parts_under_the_limit() {
+        let temp = std::env::temp_dir().join("patcher_unit_split_writer");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let base = temp.join("p.patch");
+        let key = MacKey::from_bytes(b"k").unwrap();
+        let limit = 4096;
+        let mut writer =
+            SplitPatchWriter::create(&base, limit, HashAlgo::Blake3, true, ManifestEncoding::Bincode, Some(&key))
+                .unwrap();
+        // Incompressible content, so each op takes about its own size.
+        let mut state = 1u64;
+        for i in 0..10 {
+            let data = (0..1000)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    (state >> 56) as u8
+                })
+                .collect();
+            let op = PatchOp::AddFile {
+                path: format!("f{}", i),
+                data,
+                blake3_hash: [0; 32],
+                compressed: false,
+                xattrs: Vec::new(),
+                external: false,
+            };
+            writer.write_op(&op).unwrap();
+        }
+        let parts = writer.finish().unwrap();
+        assert!(parts.len() >= 3, "{:?}", parts);
+        assert_eq!(parts[0], temp.join("p.patch.001"));
+
+        let mut ops = 0;
+        let mut set_id = None;
+        for (i, part) in parts.iter().enumerate() {
+            let raw = std::fs::read(part).unwrap();
+            assert!(raw.len() as u64 <= limit, "{} is {} bytes", part.display(), raw.len());
+            let header = PartHeader::parse(&raw).unwrap();
+            assert_eq!((header.index, header.count), (i as u32 + 1, parts.len() as u32));
+            assert_eq!(*set_id.get_or_insert(header.set_id), header.set_id);
+            // Each part is a complete patch of its own.
+            let inner = &raw[PART_HEADER_LEN..];
+            let (body, mac) = split_mac_trailer(&inner[HEADER_LEN..]);
+            assert!(verify_mac(&key, &inner[..HEADER_LEN], body, &mac.unwrap()));
+            let (payload, _) = PatchHeader::parse(inner).unwrap().split_index(body).unwrap();
+            let manifest = decode_payload(&zstd::decode_all(payload).unwrap(), ManifestEncoding::Bincode).unwrap();
+            assert!(manifest.full_file_set);
+            ops += manifest.operations.len();
+        }
+        assert_eq!(ops, 10);
+
+        let mut writer =
+            SplitPatchWriter::create(&base, limit, HashAlgo::Blake3, false, ManifestEncoding::Bincode, None)
+                .unwrap();
+        let big = PatchOp::AddFile {
+            path: "big".into(),
+            data: vec![0; 8192],
+            blake3_hash: [0; 32],
+            compressed: false,
+            xattrs: Vec::new(),
+            external: false,
+        };
+        let err = writer.write_op(&big).unwrap_err();
+        assert!(err.to_string().contains("Operation for big"), "{}", err);
+        assert!(SplitPatchWriter::create(&base, 40, HashAlgo::Blake3, false, ManifestEncoding::Bincode, None).is_err());
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_mac_trailer_covers_header_and_payload() {
+        let key = MacKey::from_bytes(b"shared secret").unwrap();
+        let sink = std::io::Cursor::new(Vec::new());
+        let mut writer =
+            PatchWriter::new(sink, HashAlgo::Blake3, false, ManifestEncoding::Bincode, Some(&key)).unwrap();
+        writer
+            .write_op(&PatchOp::DeleteFile { path: "x".into(), old_blake3_hash: None, may_be_absent: false })
+            .unwrap();
+        let raw = writer.finish().unwrap().into_inner();
+        assert!(raw.ends_with(MAC_MAGIC));
+
+        let check = |raw: &[u8], key: &MacKey| {
+            let (payload, mac) = split_mac_trailer(&raw[HEADER_LEN..]);
+            verify_mac(key, &raw[..HEADER_LEN], payload, &mac.unwrap())
+        };
+        assert!(check(&raw, &key));
+        assert!(!check(&raw, &MacKey::from_bytes(b"other secret").unwrap()));
+        // Tampering with the header or the payload is caught.
+        for at in [MAGIC.len(), HEADER_LEN + 2] {
+            let mut tampered = raw.clone();
+            tampered[at] ^= 1;
+            assert!(!check(&tampered, &key));
+        }
+        let (payload, _) = split_mac_trailer(&raw[HEADER_LEN..]);
+        assert_eq!(payload.len(), raw.len() - HEADER_LEN - MAC_TRAILER_LEN);
+        assert!(zstd::decode_all(payload).is_ok());
+        assert!(MacKey::from_bytes(b"").is_err());
+    }
+
+    #[test]
+    fn test_cbor_stores_contents_as_byte_strings() {
+        let op = PatchOp::AddFile {
+            path: "f".into(),
+            data: vec![0xff; 100],
+            blake3_hash: [0; 32],
+            compressed: false,
+            xattrs: Vec::new(),
+            external: false,
+        };
+        let mut frame = Vec::new();
+        ciborium::into_writer(&op, &mut frame).unwrap();
+        // Major type 2 (byte string) with a one-byte length: 0x58 0x64. As an array,
+        // each 0xff would take two bytes.
+        assert!(frame.windows(3).any(|w| w == [0x58, 100, 0xff]));
+        assert!(frame.len() < 250);
+        // And bincode is unchanged by the attribute: length prefix, then the bytes.
+        let encoded = bincode::serialize(&DiffChunk::Insert { data: vec![7; 3] }).unwrap();
+        assert_eq!(encoded, [1, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 7, 7, 7]);
+    }
+
+    #[test]
+    fn test_version_check_accepts_same_major_and_older_minor() {
+        let current = FORMAT_VERSION;
+        let with = |major, minor| FormatVersion { major, minor };
+        assert!(current.can_read(current));
+        assert!(with(current.major, 3).can_read(with(current.major, 2)));
+        assert!(!current.can_read(with(current.major, current.minor + 1)));
+        assert!(!current.can_read(with(current.major - 1, current.minor)));
+        assert!(!current.can_read(with(current.major + 1, 0)));
+
+        let payload = |version| {
+            let mut payload = bincode::serialize(&PatchPreamble {
+                version,
+                hash_algo: HashAlgo::Blake3,
+                full_file_set: true,
+            })
+            .unwrap();
+            let op = bincode::serialize(&PatchOp::DeleteFile { path: "x".into(), old_blake3_hash: None, may_be_absent: false }).unwrap();
+            payload.extend_from_slice(&(op.len() as u64).to_le_bytes());
+            payload.extend_from_slice(&op);
+            payload
+        };
+        assert_eq!(decode_payload(&payload(current), ManifestEncoding::Bincode).unwrap().operations.len(), 1);
+        for version in [with(current.major, current.minor + 1), with(current.major - 1, 0)] {
+            let err = decode_payload(&payload(version), ManifestEncoding::Bincode).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(PatchError::UnsupportedVersion { found, .. }) if *found == version),
+                "{:#}",
+                err
+            );
+        }
+        // The pre-minor u32 encoding of 12 reads as 12.0.
+        let mut old = payload(current);
+        old[..4].copy_from_slice(&12u32.to_le_bytes());
+        assert!(decode_payload(&old, ManifestEncoding::Bincode).is_err());
+
+        // A 13.0 preamble ends at the hash algorithm; the 13.1 flag reads as unset.
+        let mut v13_0 = payload(with(13, 0));
+        v13_0.remove(4 + 4);
+        let manifest = decode_payload(&v13_0, ManifestEncoding::Bincode).unwrap();
+        assert!(!manifest.full_file_set);
+        assert_eq!(manifest.operations.len(), 1);
+        assert!(decode_payload(&payload(current), ManifestEncoding::Bincode).unwrap().full_file_set);
+    }
+
+    #[test]
+    fn test_older_minor_frames_read_added_fields_as_absent() {
+        #[derive(Serialize)]
+        enum OpV0 {
+            Write { path: String, len: u32 },
+        }
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum OpV1 {
+            Write {
+                path: String,
+                len: u32,
+                owner: Option<String>,
+                tags: Vec<String>,
+                sparse: bool,
+            },
+        }
+        let frame = bincode::serialize(&OpV0::Write {
+            path: "a/b".into(),
+            len: 7,
+        })
+        .unwrap();
+        assert_eq!(
+            decode_frame::<OpV1>(&frame, true).unwrap(),
+            OpV1::Write {
+                path: "a/b".into(),
+                len: 7,
+                owner: None,
+                tags: Vec::new(),
+                sparse: false,
+            }
+        );
+        // From the current minor, a short frame is corrupt rather than padded.
+        assert!(decode_frame::<OpV1>(&frame, false).is_err());
+        // And stray bytes after an operation aren't ignored.
+        let mut long = bincode::serialize(&PatchOp::DeleteFile { path: "x".into(), old_blake3_hash: None, may_be_absent: false }).unwrap();
+        long.push(0);
+        assert!(decode_frame::<PatchOp>(&long, false).is_err());
+    }
+
+    #[test]
+    fn test_add_file_compression_round_trip() {
+        let big = vec![7u8; ADD_COMPRESS_MIN];
+        let op = add_file_op("big.bin".into(), &big, [0; 32], false, Vec::new()).unwrap();
+        let PatchOp::AddFile {
+            data, compressed, ..
+        } = op
+        else {
+            panic!("expected AddFile");
+        };
+        assert!(compressed && data.len() < big.len());
+        assert_eq!(add_file_len(&data, true).unwrap(), big.len() as u64);
+        assert_eq!(add_file_content(data, true).unwrap(), big);
+
+        // Small or incompressible content is stored as is.
+        for (content, incompressible) in [(&big[..10], false), (&big[..], true)] {
+            let op = add_file_op("f".into(), content, [0; 32], incompressible, Vec::new()).unwrap();
+            assert!(matches!(op, PatchOp::AddFile { compressed: false, data, .. } if data == content));
+        }
+        assert!(add_file_len(b"not zstd", true).is_err());
+    }
+
+    #[test]
+    fn test_objects_are_stored_once_by_hash() {
+        let dir = std::env::temp_dir().join("patcher_unit_objects");
+        let _ = std::fs::remove_dir_all(&dir);
+        let hash = util::hash_bytes(HashAlgo::Blake3, b"shared");
+        let object = object_path(&dir, &hash);
+        let hex = util::hash_hex(&hash);
+        assert_eq!(object, dir.join(&hex[..2]).join(&hex[2..]));
+
+        for path in ["a.txt", "b.txt"] {
+            let op = add_object_op(&dir, path.into(), b"shared", hash, Vec::new()).unwrap();
+            assert!(matches!(op, PatchOp::AddFile { external: true, data, .. } if data.is_empty()));
+        }
+        // One object, and no temporary file left beside it.
+        assert_eq!(std::fs::read_dir(object.parent().unwrap()).unwrap().count(), 1);
+        let data = open_object(&dir, "a.txt", &hash).unwrap();
+        assert_eq!(add_file_len(&data, true).unwrap(), 6);
+        assert_eq!(add_file_content(data.to_vec(), true).unwrap(), b"shared");
+
+        let Err(err) = open_object(&dir, "c.txt", &[0; 32]) else {
+            panic!("expected a missing object");
+        };
+        assert!(format!("{:#}", err).contains("missing from the objects directory"), "{:#}", err);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{bail, Context, Result};
+use std::io::Read;
+use std::path::Path;
+
+use crate::apply::ApplyLimits;
+use crate::error::PatchError;
+use crate::patch_format::{self, PatchHeader, PatchIndex, PatchOp, GZIP_MAGIC, PART_MAGIC};
+use crate::util::{self, HashAlgo};
+
+/// An operation read on its own through a patch's index.
+#[derive(Debug)]
+pub struct IndexedOp {
+    /// Algorithm of the hashes `op` records.
+    pub hash_algo: HashAlgo,
+    pub op: PatchOp,
+}
+
+/// The header and index of the patch file `raw`, or `None` if it has no index to
+/// read: written before format 13.5, gzip-wrapped or one part of a split patch. The
+/// index is decompressed under `limits.max_total_size`.
+pub fn read_index(raw: &[u8], limits: &ApplyLimits) -> Result<Option<(PatchHeader, PatchIndex)>> {
+    if raw.starts_with(&GZIP_MAGIC) || raw.starts_with(PART_MAGIC) {
+        return Ok(None);
+    }
+    let header = PatchHeader::parse(raw)?;
+    if header.index_offset.is_none() {
+        return Ok(None);
+    }
+    let (body, _) = patch_format::split_mac_trailer(&raw[header.encoded_len()..]);
+    let (_, frame) = header.split_index(body)?;
+    let mut decoded = Vec::new();
+    zstd::Decoder::new(frame)
+        .and_then(|decoder| decoder.take(limits.max_total_size + 1).read_to_end(&mut decoded))
+        .map_err(|e| PatchError::Decompress(format!("index: {}", e)))?;
+    if decoded.len() as u64 > limits.max_total_size {
+        bail!(PatchError::LimitExceeded(format!(
+            "Patch index exceeds the limit of {} bytes (see --max-total-size)",
+            limits.max_total_size
+        )));
+    }
+    Ok(Some((header, patch_format::decode_index(&decoded, header.encoding)?)))
+}
+
+/// Read the operation on `path` from the patch file at `patch_path` through its index,
+/// without a full read of the manifest: the payload is decompressed only up to that
+/// operation's frame, and no other operation is deserialized, so memory stays bounded
+/// by the one operation however large the patch. Returns `None` when the patch can't be
+/// read this way (see [`read_index`], or stdin); use
+/// [`read_manifest`](crate::apply::read_manifest) then. No MAC is checked.
+pub fn read_op(patch_path: &Path, path: &str, limits: &ApplyLimits) -> Result<Option<IndexedOp>> {
+    if util::is_stdio(patch_path) || !patch_path.is_file() {
+        return Ok(None);
+    }
+    let raw = util::mmap_file(patch_path)?;
+    let Some((header, index)) = read_index(&raw, limits)? else {
+        return Ok(None);
+    };
+    let Some(entry) = index.entries.iter().find(|entry| entry.path == path) else {
+        bail!("{} is not in the patch", path);
+    };
+    if entry.len > limits.max_total_size {
+        bail!(PatchError::LimitExceeded(format!(
+            "Operation for {} is {} bytes, exceeding the limit of {} (see --max-total-size)",
+            path, entry.len, limits.max_total_size
+        )));
+    }
+    let in_bounds = entry.offset >= index.preamble_len + 8
+        && entry.offset.checked_add(entry.len).is_some_and(|end| end <= header.uncompressed_len);
+    if !in_bounds {
+        bail!(PatchError::Corrupt(format!(
+            "index entry for {} points outside the payload",
+            path
+        )));
+    }
+
+    let (body, _) = patch_format::split_mac_trailer(&raw[header.encoded_len()..]);
+    let (payload, _) = header.split_index(body)?;
+    let mut decoder = zstd::Decoder::new(payload)
+        .map_err(|e| PatchError::Decompress(e.to_string()))?;
+    let preamble = read_len(&mut decoder, index.preamble_len)?;
+    let preamble = patch_format::decode_preamble(&mut preamble.as_slice(), header.encoding)?;
+    // Everything between the preamble and the frame is decompressed and dropped.
+    let skip = entry.offset - 8 - index.preamble_len;
+    let skipped = std::io::copy(&mut (&mut decoder).take(skip), &mut std::io::sink())
+        .map_err(|e| PatchError::Decompress(e.to_string()))?;
+    if skipped < skip {
+        bail!(PatchError::Corrupt("payload ends before the indexed operation".to_string()));
+    }
+    let prefix = u64::from_le_bytes(read_len(&mut decoder, 8)?.try_into().expect("8 bytes"));
+    if prefix != entry.len {
+        bail!(PatchError::Corrupt(format!(
+            "index entry for {} doesn't match the frame it points at",
+            path
+        )));
+    }
+    let frame = read_len(&mut decoder, entry.len)?;
+    let op = patch_format::decode_op(&frame, header.encoding, preamble.version)
+        .map_err(|e| PatchError::Deserialize(format!("operation for {}: {}", path, e)))
+        .with_context(|| patch_path.display().to_string())?;
+    if op.path() != path {
+        bail!(PatchError::Corrupt(format!(
+            "index entry for {} points at the operation for {}",
+            path,
+            op.path()
+        )));
+    }
+    Ok(Some(IndexedOp {
+        hash_algo: preamble.hash_algo,
+        op,
+    }))
+}
+
+/// Read exactly `len` bytes of the decompressed payload.
+fn read_len(decoder: &mut impl Read, len: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(len as usize);
+    decoder
+        .take(len)
+        .read_to_end(&mut buf)
+        .map_err(|e| PatchError::Decompress(e.to_string()))?;
+    if (buf.len() as u64) < len {
+        bail!(PatchError::Corrupt("payload ends before the indexed operation".to_string()));
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch_format::{ManifestEncoding, PatchManifest, FORMAT_VERSION};
+
+    #[test]
+    fn test_read_op_decodes_one_operation_through_the_index() {
+        let temp = std::env::temp_dir().join("patcher_unit_patch_index");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let limits = ApplyLimits::default();
+        let operations: Vec<PatchOp> = (0..50)
+            .map(|i| PatchOp::AddFile {
+                path: format!("dir/f{}.txt", i),
+                data: format!("content {}", i).repeat(i + 1).into_bytes(),
+                blake3_hash: [i as u8; 32],
+                compressed: false,
+                xattrs: Vec::new(),
+                external: false,
+            })
+            .collect();
+
+        for encoding in [ManifestEncoding::Bincode, ManifestEncoding::Cbor] {
+            let patch = temp.join(format!("p.{}.patch", encoding));
+            let mut writer =
+                patch_format::PatchWriter::create(&patch, HashAlgo::Sha256, false, encoding, None).unwrap();
+            for op in &operations {
+                writer.write_op(op).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let found = read_op(&patch, "dir/f37.txt", &limits).unwrap().unwrap();
+            assert_eq!(found.hash_algo, HashAlgo::Sha256);
+            let PatchOp::AddFile { path, data, blake3_hash, .. } = found.op else {
+                panic!("{:?}", found.op);
+            };
+            assert_eq!(path, "dir/f37.txt");
+            assert_eq!(data, "content 37".repeat(38).into_bytes());
+            assert_eq!(blake3_hash, [37; 32]);
+            assert!(read_op(&patch, "dir/missing.txt", &limits).is_err());
+
+            // The full read still sees every operation past the index.
+            let manifest = crate::apply::read_manifest(&patch, &limits).unwrap();
+            assert_eq!(manifest.operations.len(), operations.len());
+        }
+
+        // A patch from before 13.5 has no index and is still read whole.
+        let raw = std::fs::read(temp.join("p.bincode.patch")).unwrap();
+        let header = PatchHeader::parse(&raw).unwrap();
+        let (payload, _) = header.split_index(&raw[header.encoded_len()..]).unwrap();
+        let mut legacy = patch_format::LEGACY_MAGIC.to_vec();
+        legacy.extend_from_slice(&header.uncompressed_len.to_le_bytes());
+        legacy.extend_from_slice(payload);
+        assert!(read_index(&legacy, &limits).unwrap().is_none());
+        let manifest = crate::apply::read_manifest_bytes(&legacy, &limits).unwrap();
+        assert_eq!(manifest.operations.len(), operations.len());
+
+        // A gzip-wrapped patch has to be read whole.
+        let gzipped = temp.join("p.patch.gz");
+        let manifest = PatchManifest {
+            version: FORMAT_VERSION,
+            hash_algo: HashAlgo::Blake3,
+            full_file_set: false,
+            operations,
+        };
+        crate::create::write_manifest(&gzipped, &manifest, true).unwrap();
+        assert!(read_op(&gzipped, "dir/f1.txt", &limits).unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+}
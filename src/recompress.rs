@@ -0,0 +1,155 @@
+//! Content filters for `create --recompress-ext`: compressed files are diffed by their
+//! decompressed content, and apply compresses the patched content again. Only gzip is
+//! supported. Apply must reproduce the new file byte for byte (its hash is checked),
+//! so create only takes this route when re-deflating the new content with this
+//! crate's encoder gives back exactly the original file.
+
+use std::io::{Read, Write};
+
+use crate::patch_format::Recompress;
+
+/// Deflate levels tried when looking for the one a file was written with; the
+/// default level first, since most encoders use it.
+const LEVELS: [u32; 10] = [6, 9, 1, 2, 3, 4, 5, 7, 8, 0];
+
+/// Length of the gzip header (RFC 1952) at the start of `data`, if it is one.
+fn header_len(data: &[u8]) -> Option<usize> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    if data.len() < 10 || data[..3] != [0x1f, 0x8b, 8] || data[3] & 0xe0 != 0 {
+        return None;
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let xlen = u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?) as usize;
+        pos += 2 + xlen;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            pos += data.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    (pos <= data.len()).then_some(pos)
+}
+
+/// Split a single-member gzip file into its header and decompressed content. `None`
+/// if `data` isn't exactly one well-formed gzip member with a matching CRC and size.
+pub fn gunzip(data: &[u8]) -> Option<(&[u8], Vec<u8>)> {
+    let header_len = header_len(data)?;
+    let mut decoder = flate2::bufread::DeflateDecoder::new(&data[header_len..]);
+    let mut content = Vec::new();
+    decoder.read_to_end(&mut content).ok()?;
+    let trailer: [u8; 8] = decoder.into_inner().try_into().ok()?;
+    let mut crc = flate2::Crc::new();
+    crc.update(&content);
+    let expected_crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let expected_len = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    (crc.sum() == expected_crc && content.len() as u32 == expected_len)
+        .then_some((&data[..header_len], content))
+}
+
+/// A writer that checks its input against `expected` and fails at the first
+/// difference, so a wrong deflate level is abandoned early.
+struct Compare<'a> {
+    expected: &'a [u8],
+}
+
+impl Write for Compare<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.expected.strip_prefix(buf) {
+            Some(rest) => {
+                self.expected = rest;
+                Ok(buf.len())
+            }
+            None => Err(std::io::ErrorKind::InvalidData.into()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Deflate `content` at `level` into `out`. Create and apply both go through here, so
+/// the stream is fed to the encoder the same way on both sides.
+fn deflate<W: Write>(content: &[u8], level: u32, out: W) -> std::io::Result<W> {
+    let mut encoder = flate2::write::DeflateEncoder::new(out, flate2::Compression::new(level));
+    encoder.write_all(content)?;
+    encoder.finish()
+}
+
+/// Decompress a gzip file for diffing, with the marker apply needs to rebuild it
+/// exactly. `None` if `data` isn't a single gzip member or no deflate level of this
+/// crate's encoder reproduces its compressed stream.
+pub fn gzip_params(data: &[u8]) -> Option<(Recompress, Vec<u8>)> {
+    let (header, content) = gunzip(data)?;
+    let stream = &data[header.len()..data.len() - 8];
+    let level = LEVELS.into_iter().find(|&level| {
+        deflate(&content, level, Compare { expected: stream })
+            .is_ok_and(|rest| rest.expected.is_empty())
+    })?;
+    let marker = Recompress::Gzip {
+        header: header.to_vec(),
+        level,
+    };
+    Some((marker, content))
+}
+
+/// The content a ModifyFile with this marker diffs against, from the target's old file.
+pub fn decompress(marker: &Recompress, data: &[u8]) -> Option<Vec<u8>> {
+    match marker {
+        Recompress::Gzip { .. } => gunzip(data).map(|(_, content)| content),
+    }
+}
+
+/// Rebuild the file bytes from patched content, as recorded by [`gzip_params`].
+pub fn compress(marker: &Recompress, content: &[u8]) -> std::io::Result<Vec<u8>> {
+    match marker {
+        Recompress::Gzip { header, level } => {
+            let mut out = deflate(content, *level, header.clone())?;
+            let mut crc = flate2::Crc::new();
+            crc.update(content);
+            out.extend_from_slice(&crc.sum().to_le_bytes());
+            out.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip_file(content: &[u8], level: u32) -> Vec<u8> {
+        let mut encoder = flate2::GzBuilder::new()
+            .filename("data.txt")
+            .write(Vec::new(), flate2::Compression::new(level));
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_gzip_params_round_trip() {
+        let content: Vec<u8> = (0..50_000u32)
+            .flat_map(|i| format!("line {}\n", i % 977).into_bytes())
+            .collect();
+        for level in [1, 6, 9] {
+            let file = gzip_file(&content, level);
+            let (marker, decompressed) = gzip_params(&file).expect("reproducible");
+            assert_eq!(decompressed, content);
+            assert_eq!(compress(&marker, &decompressed).unwrap(), file);
+        }
+
+        let mut appended = gzip_file(&content, 6);
+        appended.push(0);
+        assert!(gzip_params(&appended).is_none());
+        assert!(gzip_params(b"not gzip at all").is_none());
+    }
+}
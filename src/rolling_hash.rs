@@ -7,9 +7,17 @@ const MOD_ADLER: u32 = 65521;
 pub struct RollingHash {
     a: u32,
     b: u32,
+    /// Window length modulo `MOD_ADLER`, which is all `rotate` needs; reducing it up
+    /// front keeps `old * window_size` within u32 for any window length.
     window_size: u32,
 }
 
+impl Default for RollingHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RollingHash {
     pub fn new() -> Self {
         Self {
@@ -21,7 +29,7 @@ impl RollingHash {
 
     /// Compute hash over an initial block of data.
     pub fn init(&mut self, data: &[u8]) {
-        self.window_size = data.len() as u32;
+        self.window_size = (data.len() % MOD_ADLER as usize) as u32;
         // Accumulate in u64 to defer all modular reductions to a single pair of operations
         // at the end, rather than reducing on every byte.
         let mut a: u64 = 1;
@@ -73,6 +81,52 @@ mod tests {
         assert_ne!(h1.digest(), h2.digest());
     }
 
+    /// Slide a window of `window` bytes across all of `data` with `rotate`, checking it
+    /// against a fresh `init` at every position.
+    fn assert_rotation_matches_init(data: &[u8], window: usize) {
+        let mut rolling = RollingHash::new();
+        rolling.init(&data[..window]);
+        for start in 1..=data.len() - window {
+            rolling.rotate(data[start - 1], data[start + window - 1]);
+            let mut fresh = RollingHash::new();
+            fresh.init(&data[start..start + window]);
+            assert_eq!(rolling.digest(), fresh.digest(), "window {} at {}", window, start);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_rotate_equals_fresh_init(
+            data in proptest::collection::vec(proptest::num::u8::ANY, 1..600),
+            window_seed: usize,
+        ) {
+            assert_rotation_matches_init(&data, 1 + window_seed % data.len());
+        }
+
+        /// Windows of 0xff bytes push both sums over MOD_ADLER fastest.
+        #[test]
+        fn prop_rotate_equals_fresh_init_saturated(len in 1usize..6000, window_seed: usize) {
+            let data = vec![0xff; len];
+            assert_rotation_matches_init(&data, 1 + window_seed % len);
+        }
+    }
+
+    #[test]
+    fn test_rotate_with_window_beyond_u32_product() {
+        // 255 * window overflowed u32 once the window passed ~16 MiB.
+        let window = 17 * 1024 * 1024 + 3;
+        let mut data = vec![0xff; window + 4];
+        data[window..].copy_from_slice(b"tail");
+        let mut rolling = RollingHash::new();
+        rolling.init(&data[..window]);
+        for start in 1..=4 {
+            rolling.rotate(data[start - 1], data[start + window - 1]);
+        }
+        let mut fresh = RollingHash::new();
+        fresh.init(&data[4..]);
+        assert_eq!(rolling.digest(), fresh.digest());
+    }
+
     #[test]
     fn test_rotate_equals_fresh_init() {
         let data = b"ABCDE";
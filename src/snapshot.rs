@@ -0,0 +1,136 @@
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::util::{self, EntryKind, HashAlgo};
+
+/// Magic bytes identifying a snapshot file (distinct from patch files).
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"PATCHSS1";
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// State of one entry in a snapshotted tree. Directories carry size 0 and an all-zero hash,
+/// symbolic links size 0 and the [`util::link_hash`] of their target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileSnapshot {
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub hash: [u8; 32],
+}
+
+/// A directory's recorded state: every path with its size and content hash, but no content.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub hash_algo: HashAlgo,
+    pub entries: Vec<FileSnapshot>,
+}
+
+/// Walk `root` and hash every file in parallel, returning entries in path order.
+pub fn snapshot_directory(root: &Path, hash_algo: HashAlgo) -> Result<Vec<FileSnapshot>> {
+    let entries = util::walk_directory(root)?;
+    let mut snapshots = entries
+        .par_iter()
+        .map(|e| -> Result<FileSnapshot> {
+            let hash = match e.kind {
+                EntryKind::File => util::hash_file_streaming(hash_algo, &e.full_path)?,
+                EntryKind::Dir => [0u8; 32],
+                EntryKind::Symlink => {
+                    util::link_hash(hash_algo, e.link_target.as_deref().unwrap_or_default())
+                }
+            };
+            Ok(FileSnapshot {
+                path: e.relative_path.clone(),
+                kind: e.kind.clone(),
+                size: e.size,
+                hash,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    snapshots.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(snapshots)
+}
+
+/// Write a snapshot: magic + zstd-compressed bincode.
+pub fn write_snapshot(output: &Path, snapshot: &Snapshot) -> Result<()> {
+    let encoded = bincode::serialize(snapshot).context("Failed to serialize snapshot")?;
+    let compressed = zstd::bulk::compress(&encoded, 3).context("Failed to compress snapshot")?;
+
+    let mut file = std::fs::File::create(output)
+        .with_context(|| format!("Failed to create snapshot file: {}", output.display()))?;
+    file.write_all(SNAPSHOT_MAGIC)?;
+    file.write_all(&compressed)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// True if the file at `path` starts with the snapshot magic.
+pub fn is_snapshot_file(path: &Path) -> bool {
+    let mut magic = [0u8; 8];
+    path.is_file()
+        && std::fs::File::open(path)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .is_ok()
+        && &magic == SNAPSHOT_MAGIC
+}
+
+/// Read a snapshot written by [`write_snapshot`].
+pub fn read_snapshot(path: &Path) -> Result<Snapshot> {
+    let raw = std::fs::read(path)
+        .with_context(|| format!("Failed to read snapshot file: {}", path.display()))?;
+    if raw.len() < SNAPSHOT_MAGIC.len() || &raw[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+        bail!("Invalid snapshot file: missing magic header");
+    }
+    let decoder = zstd::Decoder::new(&raw[SNAPSHOT_MAGIC.len()..])
+        .context("Failed to create zstd decoder")?;
+    let snapshot: Snapshot =
+        bincode::deserialize_from(decoder).context("Failed to deserialize snapshot")?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        bail!(
+            "Unsupported snapshot version: {} (expected {})",
+            snapshot.version,
+            SNAPSHOT_VERSION
+        );
+    }
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let temp = std::env::temp_dir().join("patcher_snapshot_round_trip");
+        let _ = std::fs::remove_dir_all(&temp);
+        let tree = temp.join("tree");
+        std::fs::create_dir_all(tree.join("sub/empty")).unwrap();
+        std::fs::write(tree.join("a.txt"), b"alpha").unwrap();
+        std::fs::write(tree.join("sub/b.bin"), vec![7u8; 10_000]).unwrap();
+
+        let entries = snapshot_directory(&tree, HashAlgo::Blake3).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "sub", "sub/b.bin", "sub/empty"]);
+        assert_eq!(entries[0].hash, util::hash_bytes(HashAlgo::Blake3, b"alpha"));
+        assert_eq!(entries[2].size, 10_000);
+        assert_eq!(entries[1].kind, EntryKind::Dir);
+
+        let file = temp.join("tree.snapshot");
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            hash_algo: HashAlgo::Blake3,
+            entries: entries.clone(),
+        };
+        write_snapshot(&file, &snapshot).unwrap();
+        assert!(is_snapshot_file(&file));
+        assert!(!is_snapshot_file(&tree));
+
+        let loaded = read_snapshot(&file).unwrap();
+        assert_eq!(loaded.hash_algo, HashAlgo::Blake3);
+        assert_eq!(loaded.entries, entries);
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+}
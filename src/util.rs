@@ -1,13 +1,81 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
 use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+use crate::patch_format::PhaseTiming;
+
+/// Content hash algorithm used for every digest stored in a patch.
+/// Both produce 32-byte digests, so the manifest layout is the same for either.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum HashAlgo {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
+impl std::fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgo::Blake3 => write!(f, "blake3"),
+            HashAlgo::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+/// Incremental hasher for the selected algorithm. Implements `Write` so it can be
+/// fed directly by `std::io::copy`.
+pub enum StreamHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl StreamHasher {
+    pub fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Blake3 => StreamHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgo::Sha256 => StreamHasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            StreamHasher::Blake3(h) => {
+                h.update(data);
+            }
+            StreamHasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        match self {
+            StreamHasher::Blake3(h) => *h.finalize().as_bytes(),
+            StreamHasher::Sha256(h) => h.finalize().into(),
+        }
+    }
+}
+
+impl std::io::Write for StreamHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EntryKind {
     File,
     Dir,
+    /// A symbolic link, recorded as the link itself: walks never follow one.
+    Symlink,
 }
 
 #[derive(Debug, Clone)]
@@ -15,73 +83,641 @@ pub struct DirEntry {
     pub relative_path: String,
     pub kind: EntryKind,
     pub full_path: PathBuf,
+    /// What a [`EntryKind::Symlink`] points at, as [`read_link_target`] gives it. `None`
+    /// for other entries, and for links from a snapshot, which records only its hash.
+    pub link_target: Option<String>,
     /// File size in bytes (0 for directories). Free from the OS directory scan.
     pub size: u64,
+    /// (device, inode) for files with more than one hard link; see [`hardlink_id`].
+    pub hardlink_id: Option<(u64, u64)>,
+    /// Last modification time, when the platform reports one (never for snapshot entries).
+    pub modified: Option<SystemTime>,
+    /// Permission bits; see [`file_mode`].
+    pub mode: Option<u32>,
+}
+
+/// Permission bits (`mode & 0o7777`) on Unix, `None` on other platforms.
+#[cfg(unix)]
+pub fn file_mode(meta: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_meta: &std::fs::Metadata) -> Option<u32> {
+    None
 }
 
-/// Walk a directory tree and collect all entries with relative paths.
+/// Set a file's permission bits and/or modification time. The mode is ignored on
+/// platforms without Unix permissions.
+pub fn set_metadata(path: &Path, mode: Option<u32>, mtime: Option<SystemTime>) -> Result<()> {
+    if let Some(mtime) = mtime {
+        // Windows needs write access to change timestamps; Unix only needs ownership,
+        // so read-only files work there.
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(cfg!(windows))
+            .open(path)
+            .and_then(|file| file.set_modified(mtime))
+            .with_context(|| format!("Failed to set mtime: {}", path.display()))?;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions: {}", path.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+/// A file's extended attributes as (name, value) pairs, in name order.
+pub type Xattrs = Vec<(String, Vec<u8>)>;
+
+/// Extended attributes of `path` (`--preserve-xattrs`). `Ok(None)` when this build
+/// (non-Unix, or without the `xattrs` feature) or the filesystem doesn't support them.
+/// Names that aren't UTF-8 are left out.
+#[cfg(all(unix, feature = "xattrs"))]
+pub fn read_xattrs(path: &Path) -> Result<Option<Xattrs>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to list extended attributes: {}", path.display()))
+        }
+    };
+    let mut xattrs = Vec::new();
+    for name in names {
+        let Some(utf8) = name.to_str() else { continue };
+        let value = xattr::get(path, &name).with_context(|| {
+            format!("Failed to read extended attribute {} of {}", utf8, path.display())
+        })?;
+        if let Some(value) = value {
+            xattrs.push((utf8.to_string(), value));
+        }
+    }
+    xattrs.sort();
+    Ok(Some(xattrs))
+}
+
+#[cfg(not(all(unix, feature = "xattrs")))]
+pub fn read_xattrs(_path: &Path) -> Result<Option<Xattrs>> {
+    Ok(None)
+}
+
+/// Set each of `xattrs` on `path`, leaving its other attributes alone. Returns false,
+/// having set nothing, where [`read_xattrs`] would return `None`. Other failures, such
+/// as a `security.*` attribute without the privilege to set it, are errors.
+#[cfg(all(unix, feature = "xattrs"))]
+pub fn write_xattrs(path: &Path, xattrs: &[(String, Vec<u8>)]) -> Result<bool> {
+    for (name, value) in xattrs {
+        match xattr::set(path, name, value) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(false),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to set extended attribute {} on {}", name, path.display())
+                })
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(not(all(unix, feature = "xattrs")))]
+pub fn write_xattrs(_path: &Path, xattrs: &[(String, Vec<u8>)]) -> Result<bool> {
+    Ok(xattrs.is_empty())
+}
+
+/// Identity shared by every hard link to the same file: (device, inode) on Unix when
+/// the file has more than one link, `None` otherwise and on other platforms.
+#[cfg(unix)]
+pub fn hardlink_id(meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (meta.is_file() && meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn hardlink_id(_meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// An entry that couldn't be read during a lenient walk. Its subtree is left out of
+/// the result.
+#[derive(Debug, Clone)]
+pub struct SkippedEntry {
+    /// Forward-slash path relative to the walk root.
+    pub relative_path: String,
+    /// The underlying I/O error, for display.
+    pub reason: String,
+}
+
+/// Walk a directory tree and collect all entries with relative paths, depth-first
+/// in directory-listing order. Directories are read in parallel on the rayon pool.
 /// Paths use forward slashes for cross-platform consistency in the patch format.
+/// Fails on the first entry that can't be read.
 pub fn walk_directory(root: &Path) -> Result<Vec<DirEntry>> {
+    walk_directory_with(root, WalkOptions::default()).map(|(entries, _)| entries)
+}
+
+/// Like [`walk_directory`], but entries that can't be read (e.g. permission denied)
+/// are collected as [`SkippedEntry`] and their subtree is skipped instead of failing
+/// the whole walk. The root itself must still be readable.
+pub fn walk_directory_lenient(root: &Path) -> Result<(Vec<DirEntry>, Vec<SkippedEntry>)> {
+    walk_directory_with(
+        root,
+        WalkOptions {
+            skip_unreadable: true,
+            ..WalkOptions::default()
+        },
+    )
+}
+
+/// How [`walk_directory_with`] treats entries it can't read or name. The default fails
+/// on both, as [`walk_directory`] does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WalkOptions {
+    /// Collect unreadable entries as [`SkippedEntry`], as [`walk_directory_lenient`] does.
+    pub skip_unreadable: bool,
+    /// Name entries whose path isn't valid UTF-8 with escapes (see [`path_to_string`])
+    /// instead of failing on them.
+    pub allow_non_utf8: bool,
+}
+
+/// Walk a directory tree as [`walk_directory`] does, with the leniency `options` asks for.
+pub fn walk_directory_with(root: &Path, options: WalkOptions) -> Result<(Vec<DirEntry>, Vec<SkippedEntry>)> {
+    use rayon::prelude::*;
+
     let root = root
         .canonicalize()
         .with_context(|| format!("Failed to canonicalize path: {}", root.display()))?;
+    if !root.is_dir() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+
+    // Directories are listed a level at a time, every directory of a level in parallel,
+    // so wide trees with millions of entries aren't read one directory after another.
+    // `listings[i]` holds the entries of the i-th directory found; each subdirectory
+    // entry records the index of its own listing, from which the result is assembled
+    // in the same depth-first order a sequential walk produces.
+    let mut listings: Vec<Vec<(DirEntry, Option<usize>)>> = vec![Vec::new()];
+    let mut skipped = Vec::new();
+    let mut level = vec![(0, root.clone())];
+    while !level.is_empty() {
+        let results: Vec<_> = level
+            .par_iter()
+            .map(|(_, dir)| list_dir(&root, dir, options))
+            .collect();
+        let mut next = Vec::new();
+        for ((slot, _), result) in level.into_iter().zip(results) {
+            let (entries, dir_skipped) = result?;
+            skipped.extend(dir_skipped);
+            listings[slot] = entries
+                .into_iter()
+                .map(|entry| {
+                    let child = (entry.kind == EntryKind::Dir).then(|| {
+                        next.push((listings.len(), entry.full_path.clone()));
+                        listings.push(Vec::new());
+                        listings.len() - 1
+                    });
+                    (entry, child)
+                })
+                .collect();
+        }
+        level = next;
+    }
 
+    let mut entries = Vec::with_capacity(listings.iter().map(Vec::len).sum());
+    let mut listings: Vec<_> = listings.into_iter().map(Vec::into_iter).collect();
+    let mut stack = vec![0];
+    while let Some(&slot) = stack.last() {
+        match listings[slot].next() {
+            Some((entry, child)) => {
+                entries.push(entry);
+                stack.extend(child);
+            }
+            None => {
+                stack.pop();
+            }
+        }
+    }
+    Ok((entries, skipped))
+}
+
+/// One directory's entries, for [`walk_directory_with`]. With `skip_unreadable`, a
+/// directory or entry that can't be read becomes a [`SkippedEntry`] (the root excepted)
+/// instead of an error.
+fn list_dir(
+    root: &Path,
+    dir: &Path,
+    options: WalkOptions,
+) -> Result<(Vec<DirEntry>, Vec<SkippedEntry>)> {
+    let WalkOptions {
+        skip_unreadable,
+        allow_non_utf8,
+    } = options;
     let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    let relative_of = |path: &Path| {
+        path.strip_prefix(root)
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default()
+    };
+    let skip = |skipped: &mut Vec<SkippedEntry>, path: &Path, err: std::io::Error| {
+        let relative = relative_of(path);
+        if !skip_unreadable || relative.is_empty() {
+            return Err(anyhow::Error::new(err)
+                .context(format!("Failed to read directory entry in {}", path.display())));
+        }
+        skipped.push(SkippedEntry {
+            relative_path: relative,
+            reason: err.to_string(),
+        });
+        Ok(())
+    };
 
-    for entry in WalkDir::new(&root).min_depth(1) {
-        let entry = entry.with_context(|| format!("Failed to read directory entry in {}", root.display()))?;
+    let read_dir = match std::fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(err) => {
+            skip(&mut skipped, dir, err)?;
+            return Ok((entries, skipped));
+        }
+    };
+    for entry in read_dir {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                skip(&mut skipped, dir, err)?;
+                continue;
+            }
+        };
+        let full_path = entry.path();
 
-        let full_path = entry.path().to_path_buf();
         let relative = full_path
-            .strip_prefix(&root)
+            .strip_prefix(root)
             .with_context(|| "Failed to compute relative path")?;
+        let relative_str = normalize_relative_path(
+            &path_to_string(relative, allow_non_utf8)
+                .with_context(|| {
+                    format!("Non-UTF-8 path: {} (see --allow-non-utf8)", relative.display())
+                })?
+                .replace('\\', "/"),
+        )?;
 
-        let relative_str = relative
-            .to_str()
-            .with_context(|| format!("Non-UTF8 path: {}", relative.display()))?
-            .replace('\\', "/");
-
-        let kind = if entry.file_type().is_dir() {
+        // Neither call follows symlinks, so a link is a file entry of its own.
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            Err(err) if skip_unreadable => {
+                skipped.push(SkippedEntry {
+                    relative_path: relative_str,
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read metadata: {}", full_path.display()))
+            }
+        };
+        let kind = if meta.file_type().is_symlink() {
+            EntryKind::Symlink
+        } else if meta.is_dir() {
             EntryKind::Dir
         } else {
             EntryKind::File
         };
-
-        let meta = entry
-            .metadata()
-            .with_context(|| format!("Failed to read metadata: {}", full_path.display()))?;
         let size = if kind == EntryKind::File { meta.len() } else { 0 };
+        // A link's own inode says nothing about the file it points at.
+        let (link_target, hardlink_id) = match kind {
+            EntryKind::Symlink => (Some(read_link_target(&full_path, allow_non_utf8)?), None),
+            _ => (None, hardlink_id(&meta)),
+        };
 
         entries.push(DirEntry {
             relative_path: relative_str,
             kind,
             full_path,
+            link_target,
             size,
+            hardlink_id,
+            modified: meta.modified().ok(),
+            mode: file_mode(&meta),
         });
     }
+    Ok((entries, skipped))
+}
+
+/// Starts an escaped byte in a path string: NUL, which no file name can contain, then
+/// the byte as two lowercase hex digits.
+pub const PATH_ESCAPE: char = '\0';
+
+/// `path` as a string. A path that isn't valid UTF-8 is `None` unless `allow_non_utf8`,
+/// in which case each byte that isn't part of a UTF-8 character is written as
+/// [`PATH_ESCAPE`] and its value in hex, which [`join_relative`] turns back into the byte.
+/// Valid stretches are kept as they are, so the rest of the name stays readable.
+pub fn path_to_string(path: &Path, allow_non_utf8: bool) -> Option<String> {
+    if let Some(s) = path.to_str() {
+        return Some(s.to_string());
+    }
+    if !allow_non_utf8 {
+        return None;
+    }
+    Some(escape_bytes(path.as_os_str().as_encoded_bytes()))
+}
+
+/// `bytes` with every byte that isn't part of a UTF-8 character escaped, as
+/// [`path_to_string`] writes them: the one spelling [`unescape_component`] accepts.
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.utf8_chunks() {
+        out.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            out.push_str(&format!("{}{:02x}", PATH_ESCAPE, byte));
+        }
+    }
+    out
+}
 
-    Ok(entries)
+/// The bytes of a path component escaped by [`path_to_string`], or `None` if an escape
+/// is malformed or isn't how [`path_to_string`] spells those bytes: escaped bytes that
+/// form valid UTF-8 (`\0c3\0a9` for `é`, or `\02f` for `/`) would give one file a second
+/// name, or change what the path names.
+fn unescape_component(part: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(part.len());
+    let mut rest = part;
+    while let Some(at) = rest.find(PATH_ESCAPE) {
+        bytes.extend_from_slice(&rest.as_bytes()[..at]);
+        let hex = rest.get(at + 1..at + 3)?;
+        if !hex.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+            return None;
+        }
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &rest[at + 3..];
+    }
+    bytes.extend_from_slice(rest.as_bytes());
+    (escape_bytes(&bytes) == part).then_some(bytes)
 }
 
-/// Memory-map a file for read-only access.
+/// A manifest path component as a file name. Escaped bytes come back exactly on Unix;
+/// Windows names are UTF-16, so there each becomes U+FFFD.
+fn native_component(part: &str) -> std::ffi::OsString {
+    if !part.contains(PATH_ESCAPE) {
+        return part.into();
+    }
+    // Checked by `normalize_relative_path`; a malformed escape is kept as written, and
+    // the NUL makes the OS refuse the name.
+    let Some(bytes) = unescape_component(part) else {
+        return part.into();
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        std::ffi::OsString::from_vec(bytes)
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(&bytes).into_owned().into()
+    }
+}
+
+/// Canonical form of a forward-slash relative path, so every producer of a path string
+/// (walks, snapshots, patches) spells the same path the same way: empty components
+/// (from `//` or a trailing `/`) and `.` components are dropped. Absolute paths, `..`
+/// components and paths naming the root itself are rejected, since they would resolve
+/// outside the tree, and so are malformed [`PATH_ESCAPE`]s.
+pub fn normalize_relative_path(path: &str) -> Result<String> {
+    if path.starts_with('/') {
+        bail!("Absolute path where a relative one was expected: {}", path);
+    }
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => bail!("Path has a '..' component: {}", path),
+            part if part.contains(PATH_ESCAPE) && unescape_component(part).is_none() => {
+                bail!("Path has a malformed escape: {:?}", path)
+            }
+            part => parts.push(part),
+        }
+    }
+    if parts.is_empty() {
+        bail!("Path {:?} names the tree root, not an entry in it", path);
+    }
+    Ok(parts.join("/"))
+}
+
+/// Windows' legacy MAX_PATH limit; longer paths need the `\\?\` prefix.
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+/// Join a forward-slash manifest path onto `root`, one component at a time, undoing
+/// the escapes of [`path_to_string`].
+///
+/// Joining the raw string would leave `/` separators in the result, which Windows
+/// treats as literal characters in `\\?\` paths (what `canonicalize` returns there).
+/// Pushing components keeps native separators, and the result is passed through
+/// [`extended_length_path`] so deep trees past MAX_PATH stay reachable.
+pub fn join_relative(root: &Path, relative: &str) -> PathBuf {
+    let mut out = root.to_path_buf();
+    for part in relative.split('/').filter(|p| !p.is_empty()) {
+        out.push(native_component(part));
+    }
+    extended_length_path(out)
+}
+
+/// The target of the symbolic link at `path` as a patch stores it: with forward slashes,
+/// and with the escapes of [`path_to_string`] where it isn't valid UTF-8, which only
+/// `allow_non_utf8` permits.
+pub fn read_link_target(path: &Path, allow_non_utf8: bool) -> Result<String> {
+    let target = std::fs::read_link(path)
+        .with_context(|| format!("Failed to read symbolic link: {}", path.display()))?;
+    let target = path_to_string(&target, allow_non_utf8).with_context(|| {
+        format!(
+            "Non-UTF-8 symbolic link target: {} -> {} (see --allow-non-utf8)",
+            path.display(),
+            target.display()
+        )
+    })?;
+    Ok(target.replace('\\', "/"))
+}
+
+/// The hash a patch or snapshot records for a symbolic link: that of its target string,
+/// so links compare by where they point, like files by their content.
+pub fn link_hash(algo: HashAlgo, target: &str) -> [u8; 32] {
+    hash_bytes(algo, target.as_bytes())
+}
+
+/// Create a symbolic link at `link` pointing at `target`, a string from
+/// [`read_link_target`]. On Windows, where links to files and to directories differ,
+/// the kind is taken from what the target currently is, and a target that doesn't
+/// exist yet gets a file link.
+pub fn create_symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        // Component by component, so escaped bytes come back as they were.
+        let mut native = std::ffi::OsString::new();
+        for (i, part) in target.split('/').enumerate() {
+            if i > 0 {
+                native.push("/");
+            }
+            native.push(native_component(part));
+        }
+        std::os::unix::fs::symlink(native, link)
+    }
+    #[cfg(windows)]
+    {
+        let native = PathBuf::from(target.replace('/', "\\"));
+        let resolved = link.parent().map(|parent| parent.join(&native)).unwrap_or_default();
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(native, link)
+        } else {
+            std::os::windows::fs::symlink_file(native, link)
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (target, link);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "symbolic links aren't supported on this platform",
+        ))
+    }
+}
+
+/// On Windows, prefix absolute paths at or over MAX_PATH with `\\?\` (`\\?\UNC\` for
+/// network shares). Everywhere else, and for short or already-prefixed paths, this is
+/// the identity.
+#[cfg(windows)]
+pub fn extended_length_path(path: PathBuf) -> PathBuf {
+    let s = path.as_os_str().to_string_lossy();
+    if s.len() < MAX_PATH || s.starts_with(r"\\?\") || !path.is_absolute() {
+        return path;
+    }
+    match s.strip_prefix(r"\\") {
+        Some(share) => PathBuf::from(format!(r"\\?\UNC\{}", share)),
+        None => PathBuf::from(format!(r"\\?\{}", s)),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn extended_length_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// Copy `src` to `dst`, as a reflink (copy-on-write clone, near-instant on Btrfs, XFS
+/// and APFS) when the filesystem supports it, falling back to a byte copy otherwise.
+/// Returns true if the file was reflinked.
+pub fn clone_file(src: &Path, dst: &Path) -> Result<bool> {
+    let copied = reflink_copy::reflink_or_copy(src, dst)
+        .with_context(|| format!("Failed to copy {} to {}", src.display(), dst.display()))?;
+    Ok(copied.is_none())
+}
+
+/// Fail with a plain "`what` does not exist" or "`what` is not a directory" message
+/// unless `path` is an existing directory (possibly empty), so a mistyped path is
+/// reported as such rather than as an OS error from deep inside a walk. A missing path
+/// keeps its `NotFound` error as the cause, so it still surfaces as [`PatchError::Io`].
+///
+/// [`PatchError::Io`]: crate::error::PatchError::Io
+pub fn ensure_dir(path: &Path, what: &str) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => Ok(()),
+        Ok(_) => bail!("{} is not a directory: {}", what, path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("{} does not exist: {}", what, path.display()))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}: {}", what, path.display())),
+    }
+}
+
+/// True for the path `-`, which stands for stdout (`--output -`) or stdin (`--patch -`).
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// A file's contents, memory-mapped where possible and read into memory otherwise.
+/// Derefs to the bytes either way.
+pub enum FileData {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for FileData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileData::Mapped(mmap) => mmap,
+            FileData::Owned(data) => data,
+        }
+    }
+}
+
+/// Memory-map a file for read-only access. Empty files, which some platforms refuse to
+/// map, and files on filesystems without mmap support (some network and virtual
+/// filesystems) are read into memory instead.
 ///
 /// # Safety
 /// The mapping is read-only. Callers must not concurrently truncate or replace
-/// the underlying file while the `Mmap` is live.
-pub fn mmap_file(path: &Path) -> Result<Mmap> {
-    let file = std::fs::File::open(path)
+/// the underlying file while the mapping is live.
+pub fn mmap_file(path: &Path) -> Result<FileData> {
+    let mut file = std::fs::File::open(path)
         .with_context(|| format!("Failed to open file: {}", path.display()))?;
-    // SAFETY: We only read from this mapping; no concurrent modification of these files.
-    unsafe {
-        Mmap::map(&file)
-            .with_context(|| format!("Failed to memory-map file: {}", path.display()))
+    let is_empty = file.metadata().is_ok_and(|meta| meta.len() == 0);
+    if !is_empty {
+        // SAFETY: We only read from this mapping; no concurrent modification of these files.
+        if let Ok(mmap) = unsafe { Mmap::map(&file) } {
+            return Ok(FileData::Mapped(mmap));
+        }
     }
+    // Some files report a size of 0 yet have content (procfs), so read to the end
+    // rather than trusting the length.
+    let mut data = Vec::new();
+    std::io::Read::read_to_end(&mut file, &mut data)
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(FileData::Owned(data))
 }
 
+/// A hash as 64 lowercase hex digits.
+pub fn hash_hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The first 16 hex digits of a hash followed by `...`, for messages: enough to tell
+/// two hashes apart at a glance without a 64-digit run.
+pub fn short_hash_hex(hash: &[u8; 32]) -> String {
+    format!("{}...", hash_hex(hash).split_at(16).0)
+}
+
+/// Compute the hash of a byte slice with the given algorithm.
+pub fn hash_bytes(algo: HashAlgo, data: &[u8]) -> [u8; 32] {
+    match algo {
+        HashAlgo::Blake3 => *blake3::hash(data).as_bytes(),
+        HashAlgo::Sha256 => sha2::Sha256::digest(data).into(),
+    }
+}
+
+/// Default read buffer for streaming hashes: 256 KB cuts syscall overhead vs the
+/// standard 8 KB without costing much memory per Rayon thread.
+pub const DEFAULT_READ_BUFFER: usize = 256 * 1024;
+
+/// Stream-hash a file with the given algorithm, using [`DEFAULT_READ_BUFFER`].
+pub fn hash_file_streaming(algo: HashAlgo, path: &Path) -> Result<[u8; 32]> {
+    hash_file_buffered(algo, path, DEFAULT_READ_BUFFER)
+}
 
-/// Compute the BLAKE3 hash of a byte slice.
-pub fn hash_bytes(data: &[u8]) -> [u8; 32] {
-    *blake3::hash(data).as_bytes()
+/// Stream-hash a file through a `BufReader` of `buffer_size` bytes.
+/// Larger buffers help on fast storage; smaller ones suit memory-constrained hosts.
+pub fn hash_file_buffered(algo: HashAlgo, path: &Path, buffer_size: usize) -> Result<[u8; 32]> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open file for hashing: {}", path.display()))?;
+    let mut reader = std::io::BufReader::with_capacity(buffer_size, file);
+    let mut hasher = StreamHasher::new(algo);
+    std::io::copy(&mut reader, &mut hasher)
+        .with_context(|| format!("Failed to hash file: {}", path.display()))?;
+    Ok(hasher.finalize())
 }
 
 /// Collect just the relative paths as a set for fast lookup.
@@ -89,6 +725,282 @@ pub fn path_set(entries: &[DirEntry]) -> BTreeSet<String> {
     entries.iter().map(|e| e.relative_path.clone()).collect()
 }
 
+/// Parse a byte size such as `4096`, `64K`, `256M` or `4G` (binary multiples).
+/// Used as a clap value parser for size-valued flags.
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let mult: u64 = match c.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(format!("unknown size suffix in {:?} (use K, M, G or T)", s)),
+            };
+            (&s[..i], mult)
+        }
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size: {:?}", s))?;
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size too large: {:?}", s))
+}
+
+/// Parse an RFC 3339 timestamp such as `2024-05-01T12:00:00Z` or
+/// `2024-05-01T14:30:00.5+02:00`. Used as a clap value parser for `--since`.
+pub fn parse_timestamp(s: &str) -> std::result::Result<SystemTime, String> {
+    let invalid = || format!("invalid RFC 3339 timestamp: {:?}", s);
+    let s = s.trim();
+    let b = s.as_bytes();
+    if !s.is_ascii()
+        || b.len() < 20
+        || b[4] != b'-'
+        || b[7] != b'-'
+        || !matches!(b[10], b'T' | b't' | b' ')
+    {
+        return Err(invalid());
+    }
+    if b[13] != b':' || b[16] != b':' {
+        return Err(invalid());
+    }
+    let num = |range: std::ops::Range<usize>| -> std::result::Result<i64, String> {
+        let part = &s[range];
+        if !part.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        part.parse().map_err(|_| invalid())
+    };
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(invalid());
+    }
+
+    // Optional fraction, then `Z` or a `±HH:MM` offset.
+    let mut rest = &s[19..];
+    let mut nanos = 0u32;
+    if let Some(frac) = rest.strip_prefix('.') {
+        let len = frac.bytes().take_while(|c| c.is_ascii_digit()).count();
+        if len == 0 {
+            return Err(invalid());
+        }
+        let digits = &frac[..len.min(9)];
+        nanos = digits.parse::<u32>().map_err(|_| invalid())? * 10u32.pow(9 - digits.len() as u32);
+        rest = &frac[len..];
+    }
+    let offset_secs = match rest.as_bytes() {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2]
+            if [h1, h2, m1, m2].iter().all(|c| c.is_ascii_digit()) =>
+        {
+            let h = ((h1 - b'0') * 10 + (h2 - b'0')) as i64;
+            let m = ((m1 - b'0') * 10 + (m2 - b'0')) as i64;
+            let secs = h * 3600 + m * 60;
+            if *sign == b'+' {
+                secs
+            } else {
+                -secs
+            }
+        }
+        _ => return Err(invalid()),
+    };
+
+    // Days since the Unix epoch for a proleptic Gregorian date (Howard Hinnant's algorithm).
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second - offset_secs;
+    let since_epoch = Duration::new(secs.unsigned_abs(), 0);
+    let time = if secs >= 0 {
+        UNIX_EPOCH + since_epoch
+    } else {
+        UNIX_EPOCH - since_epoch
+    };
+    Ok(time + Duration::from_nanos(nanos as u64))
+}
+
+/// Thread-safe collector for `--verbose` operation lines.
+/// Parallel phases record into it; `flush` prints everything sorted by path so the
+/// output is stable regardless of Rayon scheduling.
+pub struct OpLog {
+    enabled: bool,
+    lines: Mutex<Vec<(String, String)>>,
+}
+
+impl OpLog {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            lines: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Record a line for `path`. No-op when verbose output is disabled.
+    pub fn record(&self, path: &str, line: String) {
+        if self.enabled {
+            self.lines
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push((path.to_string(), line));
+        }
+    }
+
+    /// Print all recorded lines sorted by path and clear the buffer.
+    pub fn flush(&self) {
+        let mut lines = std::mem::take(&mut *self.lines.lock().unwrap_or_else(|e| e.into_inner()));
+        lines.sort();
+        for (_, line) in lines {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Caps the bytes of file content being processed at once (`--memory-budget`). Work
+/// blocks in [`acquire`](Self::acquire) until enough of the budget is free; a request
+/// larger than the whole budget waits until it can run alone.
+pub struct ByteBudget {
+    limit: u64,
+    in_use: Mutex<u64>,
+    freed: Condvar,
+}
+
+impl ByteBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit: limit.max(1),
+            in_use: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Reserve `bytes` (capped at the limit) until the returned guard is dropped.
+    pub fn acquire(&self, bytes: u64) -> BudgetGuard<'_> {
+        BudgetGuard {
+            budget: self,
+            bytes: self.reserve(bytes),
+        }
+    }
+
+    /// Like [`acquire`](Self::acquire), but the guard holds its own handle on the
+    /// budget, so it can travel to another thread with the content it covers.
+    pub fn acquire_owned(self: &Arc<Self>, bytes: u64) -> OwnedBudgetGuard {
+        OwnedBudgetGuard {
+            bytes: self.reserve(bytes),
+            budget: Arc::clone(self),
+        }
+    }
+
+    fn reserve(&self, bytes: u64) -> u64 {
+        let bytes = bytes.min(self.limit);
+        let mut in_use = self.in_use.lock().unwrap_or_else(|e| e.into_inner());
+        while *in_use + bytes > self.limit {
+            in_use = self.freed.wait(in_use).unwrap_or_else(|e| e.into_inner());
+        }
+        *in_use += bytes;
+        bytes
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut in_use = self.in_use.lock().unwrap_or_else(|e| e.into_inner());
+        *in_use -= bytes;
+        self.freed.notify_all();
+    }
+}
+
+/// A reservation from a [`ByteBudget`], returned to it on drop.
+pub struct BudgetGuard<'a> {
+    budget: &'a ByteBudget,
+    bytes: u64,
+}
+
+impl Drop for BudgetGuard<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+/// A [`BudgetGuard`] that owns a handle on its budget; see [`ByteBudget::acquire_owned`].
+pub struct OwnedBudgetGuard {
+    budget: Arc<ByteBudget>,
+    bytes: u64,
+}
+
+impl Drop for OwnedBudgetGuard {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+/// Records wall-clock time per phase for `--timing`. Sequential phases are closed with
+/// [`mark`](Self::mark); phases running side by side measure themselves and are added
+/// with [`concurrent`](Self::concurrent).
+pub struct PhaseTimer {
+    last: Instant,
+    phases: Vec<PhaseTiming>,
+}
+
+impl PhaseTimer {
+    pub fn new() -> Self {
+        Self {
+            last: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// Close `phase`, which ran since the previous mark.
+    pub fn mark(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push(PhaseTiming {
+            phase,
+            elapsed: now - self.last,
+            concurrent: false,
+        });
+        self.last = now;
+    }
+
+    /// Add phases that ran concurrently (each with its own duration) and restart the
+    /// sequential clock, since the time they overlapped is already accounted for.
+    pub fn concurrent(&mut self, phases: &[(&'static str, Duration)]) {
+        for &(phase, elapsed) in phases {
+            self.phases.push(PhaseTiming {
+                phase,
+                elapsed,
+                concurrent: true,
+            });
+        }
+        self.last = Instant::now();
+    }
+
+    pub fn into_phases(self) -> Vec<PhaseTiming> {
+        self.phases
+    }
+}
+
+impl Default for PhaseTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Sort directory paths so parents come before children.
 pub fn sort_dirs_parent_first(dirs: &mut [String]) {
     dirs.sort();
@@ -99,3 +1011,297 @@ pub fn sort_dirs_deepest_first(dirs: &mut [String]) {
     dirs.sort();
     dirs.reverse();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streaming_hash_matches_in_memory() {
+        let path = std::env::temp_dir().join("patcher_util_hash_test.bin");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        for algo in [HashAlgo::Blake3, HashAlgo::Sha256] {
+            let streamed = hash_file_streaming(algo, &path).unwrap();
+            assert_eq!(streamed, hash_bytes(algo, &data), "{} mismatch", algo);
+            // Buffer size must never change the digest, including tiny odd sizes.
+            for buffer_size in [1, 4093, 4 << 20] {
+                assert_eq!(hash_file_buffered(algo, &path, buffer_size).unwrap(), streamed);
+            }
+        }
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_walk_matches_sequential_walkdir_order() {
+        let root = std::env::temp_dir().join("patcher_util_walk_order");
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in ["a/b/c", "a/d", "e", "f/g/h/i"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for (i, file) in ["a/1.txt", "a/b/2.txt", "a/b/c/3.txt", "a/d/4.txt", "f/g/5.txt", "6.txt"]
+            .iter()
+            .enumerate()
+        {
+            std::fs::write(root.join(file), vec![b'x'; i]).unwrap();
+        }
+
+        let canonical = root.canonicalize().unwrap();
+        let expected: Vec<String> = walkdir::WalkDir::new(&canonical)
+            .min_depth(1)
+            .into_iter()
+            .map(|e| {
+                let e = e.unwrap();
+                e.path().strip_prefix(&canonical).unwrap().to_str().unwrap().replace('\\', "/")
+            })
+            .collect();
+        let entries = walk_directory(&root).unwrap();
+        let paths: Vec<_> = entries.iter().map(|e| e.relative_path.clone()).collect();
+        assert_eq!(paths, expected);
+        let sized = entries.iter().find(|e| e.relative_path == "f/g/5.txt").unwrap();
+        assert!(sized.kind == EntryKind::File && sized.size == 4);
+        assert!(entries.iter().any(|e| e.relative_path == "f/g/h/i" && e.kind == EntryKind::Dir));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lenient_walk_skips_unreadable_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = std::env::temp_dir().join("patcher_util_lenient_walk");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("locked")).unwrap();
+        std::fs::write(root.join("locked/secret.txt"), b"x").unwrap();
+        std::fs::write(root.join("open.txt"), b"y").unwrap();
+        std::fs::set_permissions(root.join("locked"), std::fs::Permissions::from_mode(0o000))
+            .unwrap();
+
+        // Privileged users (e.g. root in CI containers) can read it anyway.
+        if std::fs::read_dir(root.join("locked")).is_err() {
+            assert!(walk_directory(&root).is_err());
+
+            let (entries, skipped) = walk_directory_lenient(&root).unwrap();
+            let paths: Vec<_> = entries.iter().map(|e| e.relative_path.as_str()).collect();
+            assert!(paths.contains(&"open.txt"));
+            assert!(!paths.contains(&"locked/secret.txt"));
+            assert_eq!(skipped.len(), 1);
+            assert_eq!(skipped[0].relative_path, "locked");
+        }
+
+        std::fs::set_permissions(root.join("locked"), std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_mmap_file_falls_back_to_reading() {
+        let dir = std::env::temp_dir().join("patcher_util_mmap_fallback");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("empty"), b"").unwrap();
+        std::fs::write(dir.join("full"), b"content").unwrap();
+
+        let empty = mmap_file(&dir.join("empty")).unwrap();
+        assert!(matches!(empty, FileData::Owned(_)) && empty.is_empty());
+        let full = mmap_file(&dir.join("full")).unwrap();
+        assert!(matches!(full, FileData::Mapped(_)));
+        assert_eq!(&*full, b"content");
+        // procfs files report a length of 0 and can't be mapped, but have content.
+        #[cfg(target_os = "linux")]
+        assert!(!mmap_file(Path::new("/proc/self/status")).unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_join_relative_uses_native_separators() {
+        let root = Path::new("base");
+        assert_eq!(
+            join_relative(root, "a/b/c.txt"),
+            root.join("a").join("b").join("c.txt")
+        );
+        assert_eq!(join_relative(root, "a//b/"), root.join("a").join("b"));
+    }
+
+    #[test]
+    fn test_normalize_relative_path() {
+        for (raw, normalized) in [
+            ("a/b.txt", "a/b.txt"),
+            ("a//b", "a/b"),
+            ("./a/./b/", "a/b"),
+            ("dir/", "dir"),
+            (".hidden/a..b", ".hidden/a..b"),
+            ("caf\0e9/x", "caf\0e9/x"),
+        ] {
+            assert_eq!(normalize_relative_path(raw).unwrap(), normalized, "{}", raw);
+        }
+        for bad in ["/etc/passwd", "a/../b", "..", "", ".", "./", "//"] {
+            assert!(normalize_relative_path(bad).is_err(), "{:?}", bad);
+        }
+        // Escapes must be two lowercase hex digits for a byte UTF-8 couldn't spell.
+        for bad in ["a\0", "a\0e", "a\0E9", "a\0zz", "a\x002f..", "a\0\0e9"] {
+            assert!(normalize_relative_path(bad).is_err(), "{:?}", bad);
+        }
+        // Escaped bytes that form valid UTF-8 would be a second name for a real file.
+        for bad in ["caf\0c3\0a9", "\0e2\082\0ac", "a\0c3\0a9/b"] {
+            assert!(normalize_relative_path(bad).is_err(), "{:?}", bad);
+        }
+        // An invalid byte next to a valid character is still fine.
+        assert!(normalize_relative_path("\0c3caf\u{e9}\0ff").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths_round_trip_through_escapes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let raw = Path::new(std::ffi::OsStr::from_bytes(b"dir/caf\xe9 \xff\xfe.txt"));
+        assert_eq!(path_to_string(raw, false), None);
+        let escaped = path_to_string(raw, true).unwrap();
+        assert_eq!(escaped, "dir/caf\0e9 \0ff\0fe.txt");
+        assert_eq!(normalize_relative_path(&escaped).unwrap(), escaped);
+        assert_eq!(join_relative(Path::new("/root"), &escaped), Path::new("/root").join(raw));
+        // Valid names are left alone either way.
+        assert_eq!(path_to_string(Path::new("caf\u{e9}"), true).unwrap(), "caf\u{e9}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_records_symlinks_without_following_them() {
+        let root = std::env::temp_dir().join("patcher_util_walk_symlinks");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/file.txt"), b"data").unwrap();
+        std::os::unix::fs::symlink("dir", root.join("to_dir")).unwrap();
+        std::os::unix::fs::symlink("/nowhere/at/all", root.join("dangling")).unwrap();
+
+        let entries = walk_directory(&root).unwrap();
+        let link = |path: &str| {
+            let entry = entries.iter().find(|e| e.relative_path == path).unwrap();
+            (entry.kind.clone(), entry.link_target.clone())
+        };
+        assert_eq!(link("to_dir"), (EntryKind::Symlink, Some("dir".to_string())));
+        assert_eq!(link("dangling"), (EntryKind::Symlink, Some("/nowhere/at/all".to_string())));
+        assert_eq!(link("dir/file.txt"), (EntryKind::File, None));
+        // The linked directory isn't walked a second time.
+        assert!(!entries.iter().any(|e| e.relative_path.starts_with("to_dir/")));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_extended_length_path() {
+        let short = PathBuf::from(r"C:\short\path");
+        assert_eq!(extended_length_path(short.clone()), short);
+
+        let deep = format!(r"C:\{}", "d".repeat(300));
+        assert_eq!(
+            extended_length_path(PathBuf::from(&deep)),
+            PathBuf::from(format!(r"\\?\{}", deep))
+        );
+
+        let unc = format!(r"\\server\share\{}", "d".repeat(300));
+        assert_eq!(
+            extended_length_path(PathBuf::from(&unc)),
+            PathBuf::from(format!(r"\\?\UNC\server\share\{}", "d".repeat(300)))
+        );
+
+        let verbatim = PathBuf::from(format!(r"\\?\C:\{}", "d".repeat(300)));
+        assert_eq!(extended_length_path(verbatim.clone()), verbatim);
+    }
+
+    #[test]
+    fn test_clone_file_copies_content() {
+        // Temp dirs are usually tmpfs or ext4, so this mostly covers the byte-copy
+        // fallback; on a CoW filesystem it covers the reflink path instead.
+        let dir = std::env::temp_dir().join("patcher_util_clone_file");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        std::fs::write(dir.join("src.bin"), &data).unwrap();
+
+        clone_file(&dir.join("src.bin"), &dir.join("dst.bin")).unwrap();
+        assert_eq!(std::fs::read(dir.join("dst.bin")).unwrap(), data);
+
+        // The copy is independent of the source.
+        std::fs::write(dir.join("dst.bin"), b"changed").unwrap();
+        assert_eq!(std::fs::read(dir.join("src.bin")).unwrap(), data);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("256m"), Ok(256 * 1024 * 1024));
+        assert_eq!(parse_size("4G"), Ok(4 << 30));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("12Q").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Ok(at(0)));
+        assert_eq!(
+            parse_timestamp("2024-02-29T12:00:00Z"),
+            Ok(at(1_709_208_000))
+        );
+        assert_eq!(
+            parse_timestamp("2024-02-29T14:30:00+02:30"),
+            Ok(at(1_709_208_000))
+        );
+        assert_eq!(
+            parse_timestamp("2024-02-29T12:00:00.25Z"),
+            Ok(at(1_709_208_000) + Duration::from_millis(250))
+        );
+        assert!(parse_timestamp("2024-02-29").is_err());
+        assert!(parse_timestamp("2024-13-01T00:00:00Z").is_err());
+        assert!(parse_timestamp("2024-02-29T12:00:00").is_err());
+        assert!(parse_timestamp("2024-02-29T12:00:00+0200").is_err());
+    }
+
+    #[test]
+    fn test_algorithms_differ() {
+        assert_ne!(
+            hash_bytes(HashAlgo::Blake3, b"abc"),
+            hash_bytes(HashAlgo::Sha256, b"abc")
+        );
+    }
+
+    #[test]
+    fn test_byte_budget_caps_bytes_in_flight() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        let budget = Arc::new(ByteBudget::new(100));
+        let in_flight = Arc::new(AtomicU64::new(0));
+        let peak = Arc::new(AtomicU64::new(0));
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let (budget, in_flight, peak) = (budget.clone(), in_flight.clone(), peak.clone());
+                std::thread::spawn(move || {
+                    let _reserved = budget.acquire(40);
+                    let now = in_flight.fetch_add(40, Ordering::SeqCst) + 40;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(5));
+                    in_flight.fetch_sub(40, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= 80);
+
+        // More than the whole budget is clamped to it rather than waiting forever.
+        drop(budget.acquire(1000));
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::apply::{self, ApplyLimits};
+use crate::patch_format::PatchOp;
+
+/// A structural problem found in a patch.
+#[derive(Debug, Clone)]
+pub struct ValidateProblem {
+    pub path: String,
+    pub problem: String,
+}
+
+/// Outcome of checking a patch on its own, without a target tree.
+#[derive(Debug, Default)]
+pub struct ValidateReport {
+    /// Number of operations checked.
+    pub operations: usize,
+    /// Problems found, in patch order.
+    pub problems: Vec<ValidateProblem>,
+}
+
+impl ValidateReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Why `path` isn't a normalized relative manifest path, if it isn't: it must be
+/// non-empty, use single forward slashes, and contain no `.` or `..` components.
+/// Backslashes are rejected too, since Windows would treat them as separators.
+pub fn path_problem(path: &str) -> Option<&'static str> {
+    if path.is_empty() {
+        return Some("empty path");
+    }
+    if path.starts_with('/') {
+        return Some("absolute path");
+    }
+    if path.contains('\\') {
+        return Some("path contains a backslash");
+    }
+    if path.contains('\0') {
+        return Some("path contains a NUL byte");
+    }
+    for part in path.split('/') {
+        match part {
+            "" => return Some("path has an empty component"),
+            "." => return Some("path has a '.' component"),
+            ".." => return Some("path has a '..' component"),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Check a patch's internal consistency without a target: the header, version and
+/// compression must decode (errors there are returned as `Err`), every path and hard
+/// link target must be a normalized relative path, and no path may appear in more than
+/// one operation. Copy offsets can't be checked without the old tree. Hashes are
+/// fixed-size arrays in the format, so their length needs no check.
+pub fn validate_patch(patch_path: &Path, limits: &ApplyLimits) -> Result<ValidateReport> {
+    let manifest = apply::read_manifest(patch_path, limits)?;
+    let mut report = ValidateReport {
+        operations: manifest.operations.len(),
+        problems: Vec::new(),
+    };
+    let mut problem = |path: &str, problem: String| {
+        report.problems.push(ValidateProblem {
+            path: path.to_string(),
+            problem,
+        })
+    };
+
+    let mut seen: HashMap<&str, &PatchOp> = HashMap::with_capacity(manifest.operations.len());
+    for op in &manifest.operations {
+        let path = op.path();
+        if let Some(why) = path_problem(path) {
+            problem(path, format!("{}: {}", op.name(), why));
+        }
+        if let PatchOp::CreateHardlink { target, .. } = op {
+            if let Some(why) = path_problem(target) {
+                problem(path, format!("hard link target {:?}: {}", target, why));
+            }
+        }
+        if let PatchOp::ModifyFileMulti { variants, .. } = op {
+            if variants.is_empty() {
+                problem(path, "ModifyFileMulti has no variants".to_string());
+            }
+        }
+        if let Some(prev) = seen.insert(path, op) {
+            problem(
+                path,
+                format!("duplicate path: {} and {}", prev.name(), op.name()),
+            );
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch_format::{PatchManifest, FORMAT_VERSION};
+    use crate::util::HashAlgo;
+
+    #[test]
+    fn test_path_problem() {
+        for ok in ["a", "a/b.txt", "dir/.hidden", "a..b"] {
+            assert_eq!(path_problem(ok), None, "{}", ok);
+        }
+        for bad in ["", "/etc/passwd", "a/../b", "..", "./a", "a//b", "a/", "a\\b"] {
+            assert!(path_problem(bad).is_some(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_validate_reports_bad_paths_and_duplicates() {
+        let temp = std::env::temp_dir().join("patcher_unit_validate");
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).unwrap();
+        let patch = temp.join("p.patch");
+        let manifest = PatchManifest {
+            version: FORMAT_VERSION,
+            hash_algo: HashAlgo::Blake3,
+            full_file_set: false,
+            operations: vec![
+                PatchOp::CreateDir { path: "d".into(), mode: None },
+                PatchOp::DeleteFile {
+                    path: "d/../../escape".into(),
+                    old_blake3_hash: None,
+                    may_be_absent: false,
+                },
+                PatchOp::DeleteFile { path: "x".into(), old_blake3_hash: None, may_be_absent: false },
+                PatchOp::DeleteFile { path: "x".into(), old_blake3_hash: None, may_be_absent: false },
+                PatchOp::CreateHardlink {
+                    path: "d/link".into(),
+                    target: "/abs".into(),
+                },
+            ],
+        };
+        crate::create::write_manifest(&patch, &manifest, false).unwrap();
+
+        let report = validate_patch(&patch, &ApplyLimits::default()).unwrap();
+        assert_eq!(report.operations, 5);
+        let paths: Vec<&str> = report.problems.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(paths, ["d/../../escape", "x", "d/link"]);
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use std::path::Path;
+
+use crate::apply::{self, ApplyLimits};
+use crate::patch_format::PatchOp;
+use crate::util;
+
+/// A single check that failed during verification.
+#[derive(Debug, Clone)]
+pub struct VerifyFailure {
+    pub path: String,
+    pub problem: String,
+}
+
+/// Outcome of checking a target tree against a patch's intended post-patch state.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of operations checked.
+    pub checked: usize,
+    /// Failed checks, in path order.
+    pub failures: Vec<VerifyFailure>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Check that `target_dir` is in the state the patch produces: created directories
+/// exist, added/modified/verified files have the recorded hash, and deleted paths are
+/// gone. Files the patch doesn't mention are only covered if it was created with
+/// `--full-verify`. Nothing is written.
+pub fn verify_tree(
+    target_dir: &Path,
+    patch_path: &Path,
+    limits: &ApplyLimits,
+) -> Result<VerifyReport> {
+    util::ensure_dir(target_dir, "target")?;
+    let manifest = apply::read_manifest(patch_path, limits)?;
+    let hash_algo = manifest.hash_algo;
+    let target = target_dir
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize target: {}", target_dir.display()))?;
+
+    let check = |op: &PatchOp| -> Option<VerifyFailure> {
+        let fail = |path: &str, problem: &str| {
+            Some(VerifyFailure {
+                path: path.to_string(),
+                problem: problem.to_string(),
+            })
+        };
+        match op {
+            PatchOp::CreateDir { path, .. } => {
+                if util::join_relative(&target, path).is_dir() {
+                    None
+                } else {
+                    fail(path, "directory missing")
+                }
+            }
+            PatchOp::AddFile {
+                path, blake3_hash, ..
+            }
+            | PatchOp::VerifyFile { path, blake3_hash }
+            | PatchOp::ModifyFile {
+                path,
+                new_blake3_hash: blake3_hash,
+                ..
+            }
+            | PatchOp::ModifyFileMulti {
+                path,
+                new_blake3_hash: blake3_hash,
+                ..
+            } => {
+                let full = util::join_relative(&target, path);
+                if !full.is_file() {
+                    return fail(path, "file missing");
+                }
+                match util::hash_file_streaming(hash_algo, &full) {
+                    Ok(hash) if hash == *blake3_hash => None,
+                    Ok(_) => fail(path, "hash mismatch"),
+                    Err(e) => fail(path, &format!("{:#}", e)),
+                }
+            }
+            PatchOp::CreateHardlink {
+                path,
+                target: link_target,
+            } => {
+                let link = std::fs::metadata(util::join_relative(&target, path));
+                let original = std::fs::metadata(util::join_relative(&target, link_target));
+                match (link, original) {
+                    (Err(_), _) => fail(path, "hard link missing"),
+                    (_, Err(_)) => fail(path, "hard link target missing"),
+                    // Link identity is only observable on Unix; elsewhere existence must do.
+                    (Ok(l), Ok(o)) if util::hardlink_id(&l) != util::hardlink_id(&o) => {
+                        fail(path, "not a hard link to its target")
+                    }
+                    _ => None,
+                }
+            }
+            PatchOp::SetMetadata { path, mode, mtime } => {
+                let Ok(meta) = std::fs::metadata(util::join_relative(&target, path)) else {
+                    return fail(path, "file missing");
+                };
+                // Modes only exist on Unix; elsewhere `file_mode` is None and matches.
+                if mode.is_some() && util::file_mode(&meta).is_some_and(|m| Some(m) != *mode) {
+                    fail(path, "mode mismatch")
+                } else if mtime.is_some() && meta.modified().ok() != *mtime {
+                    fail(path, "mtime mismatch")
+                } else {
+                    None
+                }
+            }
+            PatchOp::CreateSymlink {
+                path,
+                target: link_target,
+            } => match util::read_link_target(&util::join_relative(&target, path), true) {
+                Ok(found) if found == *link_target => None,
+                Ok(_) => fail(path, "symbolic link points elsewhere"),
+                Err(_) => fail(path, "symbolic link missing"),
+            },
+            PatchOp::DeleteFile { path, .. } | PatchOp::DeleteDir { path } => {
+                if std::fs::symlink_metadata(util::join_relative(&target, path)).is_ok() {
+                    fail(path, "should have been deleted")
+                } else {
+                    None
+                }
+            }
+        }
+    };
+
+    let mut failures: Vec<VerifyFailure> =
+        manifest.operations.par_iter().filter_map(check).collect();
+    failures.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(VerifyReport {
+        checked: manifest.operations.len(),
+        failures,
+    })
+}
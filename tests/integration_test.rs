@@ -6,10 +6,54 @@ fn patcher_exe() -> std::path::PathBuf {
     let mut path = std::env::current_exe().unwrap();
     path.pop(); // remove test binary name
     path.pop(); // remove 'deps'
-    path.push("patcher.exe");
+    path.push(format!("patcher{}", std::env::consts::EXE_SUFFIX));
     path
 }
 
+fn run_patcher(args: &[&str]) -> std::process::Output {
+    Command::new(patcher_exe())
+        .args(args)
+        .output()
+        .expect("Failed to run patcher")
+}
+
+/// Run `create` then `apply` with extra arguments, asserting both succeed.
+fn create_and_apply(
+    old_dir: &Path,
+    new_dir: &Path,
+    target_dir: &Path,
+    patch_file: &Path,
+    create_args: &[&str],
+    apply_args: &[&str],
+) {
+    let mut args = vec![
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ];
+    args.extend_from_slice(create_args);
+    let output = run_patcher(&args);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mut args = vec!["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()];
+    args.extend_from_slice(apply_args);
+    let output = run_patcher(&args);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// Deterministic pseudo-random bytes (xorshift64), so block matching has no accidental
+/// duplicate blocks to latch onto.
+fn pseudo_random(len: usize, mut seed: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len + 8);
+    while out.len() < len {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        out.extend_from_slice(&seed.to_le_bytes());
+    }
+    out.truncate(len);
+    out
+}
+
 fn create_dir_tree(root: &Path, files: &[(&str, &[u8])]) {
     for (rel_path, content) in files {
         let full = root.join(rel_path);
@@ -108,6 +152,8 @@ fn test_end_to_end_full_patch_cycle() {
         "Patch file should be larger than just the magic header"
     );
 
+    assert!(stdout.contains(" MB/s)"), "create should report throughput:\n{}", stdout);
+
     println!("Create output:\n{}", stdout);
     println!(
         "Patch file size: {} bytes",
@@ -128,6 +174,7 @@ fn test_end_to_end_full_patch_cycle() {
         stdout,
         stderr
     );
+    assert!(stdout.contains(" MB/s)"), "apply should report throughput:\n{}", stdout);
 
     println!("Apply output:\n{}", stdout);
 
@@ -165,6 +212,50 @@ fn test_end_to_end_full_patch_cycle() {
     let _ = fs::remove_dir_all(&temp);
 }
 
+#[test]
+fn test_missing_or_non_directory_inputs_are_reported_plainly() {
+    let temp = std::env::temp_dir().join("patcher_e2e_missing_dirs");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let missing = temp.join("missing");
+    let file = temp.join("file.txt");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("a.txt", b"a")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"b")]);
+    fs::write(&file, b"not a dir").unwrap();
+
+    let create = |old: &Path, new: &Path| {
+        run_patcher(&[
+            "create", "--old", old.to_str().unwrap(), "--new", new.to_str().unwrap(),
+            "--output", patch_file.to_str().unwrap(),
+        ])
+    };
+    for (output, message) in [
+        (create(&missing, &new_dir), "old directory does not exist: "),
+        (create(&old_dir, &missing), "new directory does not exist: "),
+        (create(&old_dir, &file), "new directory is not a directory: "),
+    ] {
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(message), "expected {:?} in:\n{}", message, stderr);
+    }
+    assert!(!patch_file.exists());
+
+    assert!(create(&old_dir, &new_dir).status.success());
+    for (target, message) in [(&missing, "target does not exist: "), (&file, "target is not a directory: ")] {
+        let output = run_patcher(&["apply", "--target", target.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(message), "expected {:?} in:\n{}", message, stderr);
+    }
+    assert!(!missing.exists());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
 #[test]
 fn test_empty_to_full() {
     let temp = std::env::temp_dir().join("patcher_e2e_empty_to_full");
@@ -248,16 +339,2510 @@ fn test_no_changes() {
     let _ = fs::remove_dir_all(&temp);
 }
 
-fn copy_dir_recursive(src: &Path, dst: &Path) {
-    fs::create_dir_all(dst).unwrap();
-    for entry in fs::read_dir(src).unwrap() {
-        let entry = entry.unwrap();
-        let src_path = entry.path();
-        let dst_path = dst.join(entry.file_name());
-        if src_path.is_dir() {
-            copy_dir_recursive(&src_path, &dst_path);
-        } else {
-            fs::copy(&src_path, &dst_path).unwrap();
-        }
+#[test]
+fn test_sha256_round_trip() {
+    let temp = std::env::temp_dir().join("patcher_e2e_sha256");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+
+    create_dir_tree(&old_dir, &[("a.txt", b"version one"), ("gone.txt", b"bye")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"version two"), ("b/new.txt", b"hello")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(), "--hash", "sha256",
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_verbose_lists_operations() {
+    let temp = std::env::temp_dir().join("patcher_e2e_verbose");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+
+    create_dir_tree(&old_dir, &[("keep.txt", b"v1"), ("gone.txt", b"bye")]);
+    create_dir_tree(&new_dir, &[("keep.txt", b"v2"), ("added/new.txt", b"hi")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    let output = run_patcher(&[
+        "--verbose", "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in ["+ created dir added", "+ added added/new.txt", "~ modified keep.txt (text, ", "- deleted gone.txt"] {
+        assert!(stdout.contains(line), "missing {:?} in create output:\n{}", line, stdout);
+    }
+
+    let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(), "-v"]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in ["+ created dir added", "+ added added/new.txt", "~ modified keep.txt (", "- deleted gone.txt"] {
+        assert!(stdout.contains(line), "missing {:?} in apply output:\n{}", line, stdout);
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_quarantine_keeps_deleted_files() {
+    let temp = std::env::temp_dir().join("patcher_e2e_quarantine");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let quarantine = temp.join("quarantine");
+    let patch_file = temp.join("test.patch");
+
+    create_dir_tree(
+        &old_dir,
+        &[
+            ("keep.txt", b"kept"),
+            ("loose.txt", b"orphan file"),
+            ("obsolete/deep/a.txt", b"tree file a"),
+            ("obsolete/b.txt", b"tree file b"),
+        ],
+    );
+    create_dir_tree(&new_dir, &[("keep.txt", b"kept")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = run_patcher(&[
+        "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+        "--quarantine", quarantine.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Target matches new; every deleted path is recoverable from the quarantine.
+    assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+    let quarantined = collect_dir_tree(&quarantine);
+    let expected: Vec<(String, Vec<u8>)> = vec![
+        ("loose.txt".into(), b"orphan file".to_vec()),
+        ("obsolete/b.txt".into(), b"tree file b".to_vec()),
+        ("obsolete/deep/a.txt".into(), b"tree file a".to_vec()),
+    ];
+    assert_eq!(quarantined, expected);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_include_only_patches_matching_subtree() {
+    let temp = std::env::temp_dir().join("patcher_e2e_include");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+
+    create_dir_tree(
+        &old_dir,
+        &[
+            ("assets/logo.png", b"old logo"),
+            ("assets/old.txt", b"removed asset"),
+            ("src/main.rs", b"fn main() {}"),
+            ("src/gone.rs", b"left alone"),
+        ],
+    );
+    create_dir_tree(
+        &new_dir,
+        &[
+            ("assets/logo.png", b"new logo"),
+            ("assets/sub/new.txt", b"added asset"),
+            ("src/main.rs", b"fn main() { changed(); }"),
+            ("src/added.rs", b"not included"),
+        ],
+    );
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &["--include", "assets/**"], &[]);
+
+    let expected: Vec<(String, Vec<u8>)> = vec![
+        ("assets/logo.png".into(), b"new logo".to_vec()),
+        ("assets/sub/new.txt".into(), b"added asset".to_vec()),
+        ("src/gone.rs".into(), b"left alone".to_vec()),
+        ("src/main.rs".into(), b"fn main() {}".to_vec()),
+    ];
+    assert_eq!(collect_dir_tree(&target_dir), expected);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_include_and_exclude_exclude_wins() {
+    let temp = std::env::temp_dir().join("patcher_e2e_include_exclude");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+
+    create_dir_tree(
+        &old_dir,
+        &[
+            ("assets/a.png", b"old a"),
+            ("assets/cache/blob.tmp", b"old cache"),
+            ("assets/tmpdir/keep.tmp", b"excluded, must survive"),
+            ("assets/tmpdir/drop.png", b"included, deleted"),
+        ],
+    );
+    create_dir_tree(
+        &new_dir,
+        &[
+            ("assets/a.png", b"new a"),
+            ("assets/cache/blob.tmp", b"new cache"),
+            ("assets/b.tmp", b"excluded add"),
+        ],
+    );
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(
+        &old_dir,
+        &new_dir,
+        &target_dir,
+        &patch_file,
+        &["--include", "assets/**", "--exclude", "*.tmp"],
+        &[],
+    );
+
+    // `assets/tmpdir` vanished from new, but it still holds an excluded file, so it must
+    // not be bulk-removed; only its included file goes away.
+    let expected: Vec<(String, Vec<u8>)> = vec![
+        ("assets/a.png".into(), b"new a".to_vec()),
+        ("assets/cache/blob.tmp".into(), b"old cache".to_vec()),
+        ("assets/tmpdir/keep.tmp".into(), b"excluded, must survive".to_vec()),
+    ];
+    assert_eq!(collect_dir_tree(&target_dir), expected);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_large_same_size_edit_is_patched_in_place() {
+    let temp = std::env::temp_dir().join("patcher_e2e_in_place");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+
+    let old = pseudo_random(2 * 1024 * 1024, 42);
+    let mut new = old.clone();
+    for b in &mut new[40_000..40_100] {
+        *b ^= 0xFF;
+    }
+    create_dir_tree(&old_dir, &[("big.bin", &old)]);
+    create_dir_tree(&new_dir, &[("big.bin", &new)]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &[], &[]);
+    assert_eq!(fs::read(target_dir.join("big.bin")).unwrap(), new);
+
+    // Re-run with --verbose to confirm the in-place path was taken.
+    copy_dir_recursive(&old_dir, &target_dir);
+    let output = run_patcher(&["-v", "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("in place"), "expected in-place patching:\n{}", stdout);
+    assert_eq!(fs::read(target_dir.join("big.bin")).unwrap(), new);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_patch_output_is_reproducible() {
+    let temp = std::env::temp_dir().join("patcher_e2e_reproducible");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+
+    for i in 0..64 {
+        create_dir_tree(&old_dir, &[(&format!("d{}/f{}.bin", i % 7, i), &pseudo_random(5000 + i * 131, i as u64 + 1))]);
+        create_dir_tree(&new_dir, &[(&format!("d{}/f{}.bin", i % 7, i), &pseudo_random(5000 + i * 97, i as u64 + 1))]);
+        create_dir_tree(&new_dir, &[(&format!("added{}/n{}.txt", i % 5, i), format!("new {}", i).as_bytes())]);
+        create_dir_tree(&old_dir, &[(&format!("gone{}/o{}.txt", i % 3, i), format!("old {}", i).as_bytes())]);
+    }
+
+    let mut patches = Vec::new();
+    for run in 0..2 {
+        let patch_file = temp.join(format!("run{}.patch", run));
+        let output = run_patcher(&[
+            "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--output", patch_file.to_str().unwrap(),
+        ]);
+        assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+        patches.push(fs::read(&patch_file).unwrap());
+    }
+    assert!(patches[0] == patches[1], "two runs over identical inputs produced different patches");
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_apply_limits_reject_oversized_patches() {
+    let temp = std::env::temp_dir().join("patcher_e2e_limits");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+
+    fs::create_dir_all(&old_dir).unwrap();
+    create_dir_tree(&new_dir, &[("a.bin", &vec![1u8; 2048]), ("b.txt", b"b"), ("c.txt", b"c")]);
+    fs::create_dir_all(&target_dir).unwrap();
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    for (flag, value, needle) in [
+        ("--max-files", "2", "--max-files"),
+        ("--max-file-size", "1K", "--max-file-size"),
+        ("--max-total-size", "64", "--max-total-size"),
+    ] {
+        let output = run_patcher(&[
+            "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+            flag, value,
+        ]);
+        assert!(!output.status.success(), "{} {} should have been rejected", flag, value);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(needle), "unexpected error for {}: {}", flag, stderr);
+        assert!(collect_dir_tree(&target_dir).is_empty(), "target must be untouched");
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_preserve_hardlinks() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = std::env::temp_dir().join("patcher_e2e_hardlinks");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let big = pseudo_random(200_000, 21);
+    create_dir_tree(&old_dir, &[("keep.txt", b"k")]);
+    create_dir_tree(&new_dir, &[("keep.txt", b"k"), ("a/payload.bin", &big)]);
+    fs::create_dir_all(new_dir.join("b")).unwrap();
+    fs::hard_link(new_dir.join("a/payload.bin"), new_dir.join("b/payload.bin")).unwrap();
+    fs::hard_link(new_dir.join("a/payload.bin"), new_dir.join("copy.bin")).unwrap();
+
+    let mut sizes = Vec::new();
+    for preserve in [false, true] {
+        let target_dir = temp.join(format!("target_{}", preserve));
+        let patch_file = temp.join(format!("{}.patch", preserve));
+        copy_dir_recursive(&old_dir, &target_dir);
+        let create_args: &[&str] = if preserve { &["--preserve-hardlinks"] } else { &[] };
+        create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, create_args, &[]);
+        assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+
+        let inode = |p: &str| fs::metadata(target_dir.join(p)).unwrap().ino();
+        let linked = inode("a/payload.bin") == inode("b/payload.bin")
+            && inode("a/payload.bin") == inode("copy.bin");
+        assert_eq!(linked, preserve);
+
+        let output = run_patcher(&[
+            "verify", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+        ]);
+        assert!(output.status.success(), "verify failed: {}", String::from_utf8_lossy(&output.stdout));
+        // zstd dedupes identical content within its window, so compare the manifest
+        // size recorded in the header rather than the compressed file size.
+        let bytes = fs::read(&patch_file).unwrap();
+        sizes.push(u64::from_le_bytes(bytes[8..16].try_into().unwrap()));
     }
+    assert!(sizes[1] * 2 < sizes[0], "links should avoid duplicate content: {:?}", sizes);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_create_progress_reports_totals() {
+    let temp = std::env::temp_dir().join("patcher_e2e_progress");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    create_dir_tree(&old_dir, &[("same.txt", b"s"), ("edit.txt", b"1"), ("gone.txt", b"g")]);
+    create_dir_tree(&new_dir, &[("same.txt", b"s"), ("edit.txt", b"2"), ("sub/new.txt", b"n")]);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", temp.join("p.patch").to_str().unwrap(), "--progress",
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("walked 3 old and 4 new entries; 3 files to hash"), "{}", stderr);
+    assert!(stderr.contains("hashed 3/3 files"), "{}", stderr);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_since_skips_files_not_modified_after_timestamp() {
+    let temp = std::env::temp_dir().join("patcher_e2e_since");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    // Same size, different content: only hashing can tell them apart.
+    create_dir_tree(&old_dir, &[("same_size.txt", b"aaaa"), ("grown.txt", b"1")]);
+    create_dir_tree(&new_dir, &[("same_size.txt", b"bbbb"), ("grown.txt", b"12")]);
+
+    let create = |since: &str| {
+        let output = run_patcher(&[
+            "-v", "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--output", temp.join("p.patch").to_str().unwrap(), "--since", since, "--force",
+        ]);
+        assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    // Everything was modified after 1970: nothing is taken on trust.
+    let stdout = create("1970-01-01T00:00:00Z");
+    assert!(stdout.contains("~ modified same_size.txt"), "{}", stdout);
+    assert!(stdout.contains("~ modified grown.txt"), "{}", stdout);
+
+    // Nothing was modified after 2999: the same-size file is assumed unchanged, but a
+    // size change is still detected.
+    let stdout = create("2999-01-01T00:00:00+01:00");
+    assert!(!stdout.contains("same_size.txt"), "{}", stdout);
+    assert!(stdout.contains("~ modified grown.txt"), "{}", stdout);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", temp.join("p.patch").to_str().unwrap(), "--since", "yesterday",
+    ]);
+    assert!(!output.status.success());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_paranoid_apply_round_trip() {
+    let temp = std::env::temp_dir().join("patcher_e2e_paranoid");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    let base = pseudo_random(40_000, 11);
+    let mut changed = base.clone();
+    changed[20_000] ^= 0xff;
+    create_dir_tree(&old_dir, &[("data.bin", &base), ("grow.txt", b"short")]);
+    create_dir_tree(&new_dir, &[("data.bin", &changed), ("grow.txt", b"much longer now"), ("new/added.txt", b"added")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &[], &["--paranoid"]);
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_zero_runs_stay_sparse() {
+    let temp = std::env::temp_dir().join("patcher_e2e_sparse");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    let base = pseudo_random(4 << 20, 21);
+    let mut image = base.clone();
+    image[1 << 20..3 << 20].fill(0);
+    create_dir_tree(&old_dir, &[("disk.img", &base)]);
+    create_dir_tree(&new_dir, &[("disk.img", &image)]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &[], &[]);
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+    // 2 MiB of zeros is stored as a length, not as data.
+    assert!(fs::metadata(&patch_file).unwrap().len() < 1 << 20);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let meta = fs::metadata(target_dir.join("disk.img")).unwrap();
+        assert!(meta.blocks() * 512 < meta.len() * 3 / 4, "{} blocks allocated", meta.blocks());
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_large_added_files_round_trip_compressed() {
+    let temp = std::env::temp_dir().join("patcher_e2e_add_compressed");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    // Large enough to be compressed on its own; the small file and the random one
+    // (which doesn't compress) are stored as is.
+    let log = b"2026-01-01 INFO request served\n".repeat(100_000);
+    let noise = pseudo_random(2 << 20, 34);
+    create_dir_tree(&old_dir, &[("keep.txt", b"keep")]);
+    create_dir_tree(
+        &new_dir,
+        &[("keep.txt", b"keep"), ("big.log", &log), ("noise.bin", &noise), ("small.txt", b"small")],
+    );
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &[], &[]);
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+    assert!(fs::metadata(&patch_file).unwrap().len() < (noise.len() + (1 << 20)) as u64);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_validate_accepts_created_patch_and_rejects_junk() {
+    let temp = std::env::temp_dir().join("patcher_e2e_validate");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("a.txt", b"a"), ("gone/b.txt", b"b")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"A"), ("sub/c.txt", b"c")]);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = run_patcher(&["validate", "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "validate failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Patch is valid!"));
+
+    let junk = temp.join("junk.patch");
+    fs::write(&junk, b"not a patch at all").unwrap();
+    let output = run_patcher(&["validate", "--patch", junk.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing magic header"));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_compression_overrides_change_chunk_output() {
+    let temp = std::env::temp_dir().join("patcher_e2e_compression_overrides");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let old = pseudo_random(64 * 1024, 3);
+    let mut new = old.clone();
+    new[1000] ^= 0xff;
+    create_dir_tree(&old_dir, &[("app.wasm", &old), ("logo.png", &old)]);
+    create_dir_tree(&new_dir, &[("app.wasm", &new), ("logo.png", &new)]);
+
+    // (create args, does app.wasm get copy chunks, does logo.png)
+    for (args, wasm_diffed, png_diffed) in [
+        (&[][..], true, false),
+        (&["--incompressible-ext", "wasm"][..], false, false),
+        (&["--force-compress-ext", "PNG"][..], true, true),
+    ] {
+        let target_dir = temp.join("target");
+        let patch_file = temp.join("test.patch");
+        let _ = fs::remove_dir_all(&target_dir);
+        copy_dir_recursive(&old_dir, &target_dir);
+        let mut create = vec![
+            "-v", "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--output", patch_file.to_str().unwrap(), "--force",
+        ];
+        create.extend_from_slice(args);
+        let output = run_patcher(&create);
+        assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        for (name, diffed) in [("app.wasm", wasm_diffed), ("logo.png", png_diffed)] {
+            let whole = format!("~ modified {} (binary, 0 copy, 1 insert chunks)", name);
+            assert_eq!(!stdout.contains(&whole), diffed, "{:?} {}:\n{}", args, name, stdout);
+        }
+
+        let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+        assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(fs::read(target_dir.join("app.wasm")).unwrap(), new);
+        assert_eq!(fs::read(target_dir.join("logo.png")).unwrap(), new);
+    }
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", temp.join("x.patch").to_str().unwrap(),
+        "--incompressible-ext", "bin", "--force-compress-ext", ".BIN",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("both incompressible and force-compressed"));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_hash_cache_reuses_hashes_while_size_and_mtime_match() {
+    let temp = std::env::temp_dir().join("patcher_e2e_hash_cache");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let cache = temp.join("hashes.cache");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("same.txt", b"unchanged content"), ("edit.txt", b"version one")]);
+    create_dir_tree(&new_dir, &[("same.txt", b"unchanged content"), ("edit.txt", b"version two")]);
+    // Old enough to be cached; files written just now are left out.
+    let mtime = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    for path in [old_dir.join("same.txt"), new_dir.join("same.txt"), old_dir.join("edit.txt"), new_dir.join("edit.txt")] {
+        fs::File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    let create = |expect: &str| {
+        let output = run_patcher(&[
+            "-v", "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--output", patch_file.to_str().unwrap(), "--hash-cache", cache.to_str().unwrap(), "--force",
+        ]);
+        assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        assert!(stdout.contains(expect), "expected {:?}:\n{}", expect, stdout);
+        stdout
+    };
+    create("Hash cache: 0 hashes reused");
+    assert!(cache.is_file());
+    create("Hash cache: 4 hashes reused");
+
+    // Same size and mtime: the cached hash is trusted and the edit goes unnoticed.
+    fs::write(new_dir.join("same.txt"), b"CHANGED content!!").unwrap();
+    fs::File::options().write(true).open(new_dir.join("same.txt")).unwrap().set_modified(mtime).unwrap();
+    let stdout = create("Hash cache: 4 hashes reused");
+    assert!(!stdout.contains("same.txt"), "{}", stdout);
+
+    // A new mtime invalidates the entry.
+    fs::File::options()
+        .write(true)
+        .open(new_dir.join("same.txt"))
+        .unwrap()
+        .set_modified(mtime + std::time::Duration::from_secs(1))
+        .unwrap();
+    let stdout = create("Hash cache: 3 hashes reused");
+    assert!(stdout.contains("~ modified same.txt"), "{}", stdout);
+
+    fs::write(&cache, b"not a cache").unwrap();
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(), "--hash-cache", cache.to_str().unwrap(), "--force",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Not a hash cache file"));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_split_patch_is_written_and_applied_in_parts() {
+    let temp = std::env::temp_dir().join("patcher_e2e_split_size");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("keep.txt", b"same"), ("gone.txt", b"bye")]);
+    // Incompressible, so two files fit in a 128K part and a third doesn't.
+    let contents: Vec<Vec<u8>> = (0..5).map(|i| pseudo_random(60 * 1024, i + 1)).collect();
+    let names: Vec<String> = (0..5).map(|i| format!("data/file{}.bin", i)).collect();
+    let mut files: Vec<(&str, &[u8])> = vec![("keep.txt", b"same")];
+    files.extend(names.iter().map(String::as_str).zip(contents.iter().map(Vec::as_slice)));
+    create_dir_tree(&new_dir, &files);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    let output = run_patcher(&[
+        "-v", "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(), "--split-size", "128K",
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Split into 3 parts"));
+    assert!(!patch_file.exists());
+    for n in 1..=3 {
+        let part = temp.join(format!("test.patch.00{}", n));
+        assert!(fs::metadata(&part).unwrap().len() <= 128 * 1024, "{}", part.display());
+    }
+    assert!(!temp.join("test.patch.004").exists());
+
+    // Parts are only read through the base name.
+    let part = temp.join("test.patch.002");
+    let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", part.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("pass its base name"));
+
+    let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+
+    // A missing part stops apply before it touches anything.
+    let _ = fs::remove_dir_all(&target_dir);
+    copy_dir_recursive(&old_dir, &target_dir);
+    fs::remove_file(temp.join("test.patch.003")).unwrap();
+    let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("test.patch.003"));
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&old_dir));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_force_patches_read_only_target_files() {
+    let temp = std::env::temp_dir().join("patcher_e2e_force_read_only");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    let old = pseudo_random(32 * 1024, 7);
+    let mut new = old.clone();
+    new[100..108].copy_from_slice(b"changed!");
+    create_dir_tree(&old_dir, &[("locked.bin", &old), ("rewritten.txt", b"short"), ("gone.txt", b"bye")]);
+    create_dir_tree(&new_dir, &[("locked.bin", &new), ("rewritten.txt", b"a longer line")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+    let set_read_only = |path: &Path, read_only: bool| {
+        let mut perms = fs::metadata(path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(read_only);
+        fs::set_permissions(path, perms).unwrap();
+    };
+    for name in ["locked.bin", "rewritten.txt", "gone.txt"] {
+        set_read_only(&target_dir.join(name), true);
+    }
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    let output = run_patcher(&[
+        "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(), "--force",
+    ]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+    // Patched in place and rewritten alike, the files stay read-only.
+    for name in ["locked.bin", "rewritten.txt"] {
+        assert!(fs::metadata(target_dir.join(name)).unwrap().permissions().readonly(), "{}", name);
+        set_read_only(&target_dir.join(name), false);
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_apply_only_patches_the_given_subtree() {
+    let temp = std::env::temp_dir().join("patcher_e2e_apply_only");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[
+        ("assets/logo.txt", b"old logo"),
+        ("assets/old/sprite.txt", b"retired"),
+        ("assets2/keep.txt", b"old"),
+        ("bin/app.txt", b"app v1"),
+        ("legacy/a.txt", b"legacy"),
+    ]);
+    create_dir_tree(&new_dir, &[
+        ("assets/logo.txt", b"new logo"),
+        ("assets/new/sprite.txt", b"fresh"),
+        ("assets2/keep.txt", b"new"),
+        ("bin/app.txt", b"app v2"),
+    ]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    let output = run_patcher(&[
+        "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+        "--only", "assets/",
+    ]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let tree = collect_dir_tree(&target_dir);
+    let expected: Vec<(String, Vec<u8>)> = [
+        ("assets/logo.txt", &b"new logo"[..]),
+        ("assets/new/sprite.txt", b"fresh"),
+        // Outside the prefix, even where the name starts the same: untouched.
+        ("assets2/keep.txt", b"old"),
+        ("bin/app.txt", b"app v1"),
+        ("legacy/a.txt", b"legacy"),
+    ]
+    .iter()
+    .map(|(p, c)| (p.to_string(), c.to_vec()))
+    .collect();
+    assert_eq!(tree, expected);
+    assert!(!target_dir.join("assets/old").exists());
+
+    // The rest of the patch still applies afterwards.
+    let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_existing_output_needs_force() {
+    let temp = std::env::temp_dir().join("patcher_e2e_existing_output");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("a.txt", b"old a")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"new a")]);
+    fs::write(&patch_file, b"an earlier patch").unwrap();
+
+    let create = |extra: &[&str]| {
+        let mut args = vec![
+            "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--output", patch_file.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        run_patcher(&args)
+    };
+    for extra in [&[][..], &["--split-size", "1M"]] {
+        if !extra.is_empty() {
+            fs::write(temp.join("test.patch.001"), b"an earlier part").unwrap();
+        }
+        let output = create(extra);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("Output file exists: ") && stderr.contains("(use --force to overwrite)"), "{}", stderr);
+    }
+    assert_eq!(fs::read(&patch_file).unwrap(), b"an earlier patch");
+    assert_eq!(fs::read(temp.join("test.patch.001")).unwrap(), b"an earlier part");
+
+    let output = create(&["--force"]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(fs::read(&patch_file).unwrap().starts_with(b"PATCHV02"));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_print_tree_shows_what_the_patch_touches() {
+    let temp = std::env::temp_dir().join("patcher_e2e_print_tree");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("src/app/main.rs", b"fn main() {}"), ("docs/old.md", b"old"), ("same.txt", b"same")]);
+    create_dir_tree(&new_dir, &[("src/app/main.rs", b"fn main() { run() }"), ("assets/logo.png", b"png"), ("same.txt", b"same")]);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(), "--print-tree",
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let tree = stdout.split("Changed paths:\n").nth(1).expect("no tree printed");
+    assert_eq!(
+        tree.lines().collect::<Vec<_>>(),
+        ["  + assets/", "    + logo.png", "  - docs/", "    - old.md", "    src/app/", "    ~ main.rs"],
+        "{}",
+        stdout
+    );
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_diff_timeout_stores_the_file_whole() {
+    let temp = std::env::temp_dir().join("patcher_e2e_diff_timeout");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    // Unrelated content, so the scan tries every position and is still running at
+    // the (immediate) deadline.
+    create_dir_tree(&old_dir, &[("data.bin", &pseudo_random(1 << 20, 1)), ("small.txt", b"old")]);
+    create_dir_tree(&new_dir, &[("data.bin", &pseudo_random(1 << 20, 2)), ("small.txt", b"new")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(), "--diff-timeout", "0",
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: diffing data.bin took over 0ns (--diff-timeout)"), "{}", stderr);
+    assert!(!stderr.contains("small.txt"), "{}", stderr);
+
+    let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_objects_dir_stores_shared_content_once() {
+    let temp = std::env::temp_dir().join("patcher_e2e_objects_dir");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let objects = temp.join("objects");
+    let shared = pseudo_random(200 * 1024, 7);
+    create_dir_tree(&old_dir, &[("keep.txt", b"same"), ("edit.txt", b"version one")]);
+    let mut patches = Vec::new();
+    for (name, extra) in [("a", b"only in a" as &[u8]), ("b", b"only in b")] {
+        let new_dir = temp.join(format!("new_{}", name));
+        let patch_file = temp.join(format!("{}.patch", name));
+        create_dir_tree(
+            &new_dir,
+            &[("keep.txt", b"same"), ("edit.txt", b"version two"), ("shared.bin", &shared), ("extra.txt", extra)],
+        );
+        let output = run_patcher(&[
+            "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--output", patch_file.to_str().unwrap(), "--objects-dir", objects.to_str().unwrap(),
+        ]);
+        assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+        // The shared content lives in the objects directory, not in the patch.
+        assert!(fs::metadata(&patch_file).unwrap().len() < 16 * 1024);
+        patches.push((new_dir, patch_file));
+    }
+    // shared.bin once, plus each patch's extra.txt.
+    let stored = walkdir::WalkDir::new(&objects)
+        .into_iter()
+        .filter(|e| e.as_ref().unwrap().file_type().is_file())
+        .count();
+    assert_eq!(stored, 3);
+
+    for (new_dir, patch_file) in &patches {
+        let target_dir = temp.join("target");
+        let _ = fs::remove_dir_all(&target_dir);
+        copy_dir_recursive(&old_dir, &target_dir);
+
+        let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--objects-dir"));
+        assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&old_dir));
+
+        let output = run_patcher(&[
+            "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+            "--objects-dir", objects.to_str().unwrap(),
+        ]);
+        assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(new_dir));
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_verify_before_refuses_a_drifted_target() {
+    let temp = std::env::temp_dir().join("patcher_e2e_verify_before");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("edit.txt", b"version one"), ("gone.txt", b"bye"), ("keep.txt", b"same")]);
+    create_dir_tree(&new_dir, &[("edit.txt", b"version two"), ("keep.txt", b"same"), ("added.txt", b"new")]);
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Both files the patch touches have drifted; neither is written, nor is anything else.
+    copy_dir_recursive(&old_dir, &target_dir);
+    fs::write(target_dir.join("edit.txt"), b"version one, edited").unwrap();
+    fs::write(target_dir.join("gone.txt"), b"changed before deletion").unwrap();
+    let drifted = collect_dir_tree(&target_dir);
+    let args = ["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(), "--verify-before"];
+    let output = run_patcher(&args);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("2 file(s) differ: edit.txt, gone.txt"), "{}", stderr);
+    assert_eq!(collect_dir_tree(&target_dir), drifted);
+
+    // The untouched pre-patch tree passes and is patched.
+    let _ = fs::remove_dir_all(&target_dir);
+    copy_dir_recursive(&old_dir, &target_dir);
+    let output = run_patcher(&args);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_extract_writes_added_file_content() {
+    let temp = std::env::temp_dir().join("patcher_e2e_extract");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    let blob = pseudo_random(300_000, 9);
+    create_dir_tree(&old_dir, &[("a.txt", b"before")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"after"), ("sub/blob.bin", &blob)]);
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let out = temp.join("blob.out");
+    let output = run_patcher(&[
+        "extract", "--patch", patch_file.to_str().unwrap(), "--path", "sub/blob.bin", "--out", out.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "extract failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&out).unwrap(), blob);
+
+    // To stdout, with the report moved to stderr.
+    let output = run_patcher(&["extract", "--patch", patch_file.to_str().unwrap(), "--path", "sub/blob.bin", "--out", "-"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, blob);
+
+    // A modified file has only a diff in the patch.
+    let output = run_patcher(&[
+        "extract", "--patch", patch_file.to_str().unwrap(), "--path", "a.txt", "--out", out.to_str().unwrap(),
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs the old file"));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_patch_through_stdout_and_stdin() {
+    let temp = std::env::temp_dir().join("patcher_e2e_stdio");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("piped.patch");
+    let base = pseudo_random(50_000, 13);
+    let mut changed = base.clone();
+    changed[25_000..25_100].fill(7);
+    create_dir_tree(&old_dir, &[("a.bin", &base), ("gone.txt", b"bye")]);
+    create_dir_tree(&new_dir, &[("a.bin", &changed), ("sub/new.txt", b"hi")]);
+
+    for gzip in [false, true] {
+        let mut args = vec![
+            "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--output", "-", "--verbose",
+        ];
+        if gzip {
+            args.push("--gzip");
+        }
+        let output = run_patcher(&args);
+        assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+        // Only the patch is on stdout; messages and verbose lines moved to stderr.
+        assert!(output.stdout.starts_with(if gzip { &[0x1f, 0x8b][..] } else { b"PATCHV02" }));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("~ modified a.bin"));
+        fs::write(&patch_file, &output.stdout).unwrap();
+
+        let target = temp.join(format!("target_{}", gzip));
+        copy_dir_recursive(&old_dir, &target);
+        let output = Command::new(patcher_exe())
+            .args(["apply", "--target", target.to_str().unwrap(), "--patch", "-"])
+            .stdin(fs::File::open(&patch_file).unwrap())
+            .output()
+            .expect("Failed to run patcher");
+        assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(collect_dir_tree(&target), collect_dir_tree(&new_dir));
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_metadata_only_change_sets_executable_bit() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = std::env::temp_dir().join("patcher_e2e_metadata");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    let script = pseudo_random(100_000, 55);
+    create_dir_tree(&old_dir, &[("run.sh", &script), ("data.txt", b"same")]);
+    copy_dir_recursive(&old_dir, &new_dir);
+    copy_dir_recursive(&old_dir, &target_dir);
+    fs::set_permissions(new_dir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::set_permissions(old_dir.join("run.sh"), fs::Permissions::from_mode(0o644)).unwrap();
+    fs::set_permissions(target_dir.join("run.sh"), fs::Permissions::from_mode(0o644)).unwrap();
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &["--metadata"], &[]);
+    let mode = fs::metadata(target_dir.join("run.sh")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o755);
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+    // Only metadata travels, not the 100 KB of content.
+    assert!(fs::metadata(&patch_file).unwrap().len() < 1000);
+
+    let output = run_patcher(&["verify", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "verify failed: {}", String::from_utf8_lossy(&output.stdout));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_created_directories_keep_their_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = std::env::temp_dir().join("patcher_e2e_dir_mode");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("keep.txt", b"same")]);
+    create_dir_tree(
+        &new_dir,
+        &[("keep.txt", b"same"), ("secrets/key.pem", b"private"), ("frozen/notes.txt", b"read only")],
+    );
+    fs::set_permissions(new_dir.join("secrets"), fs::Permissions::from_mode(0o700)).unwrap();
+    // Not writable even by its owner, so it only gets this mode once its file is in.
+    fs::set_permissions(new_dir.join("frozen"), fs::Permissions::from_mode(0o555)).unwrap();
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &[], &[]);
+    let mode = |dir: &str| fs::metadata(target_dir.join(dir)).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode("secrets"), 0o700);
+    assert_eq!(mode("frozen"), 0o555);
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+
+    for tree in [&new_dir, &target_dir] {
+        fs::set_permissions(tree.join("frozen"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinked_target_patches_the_directory_it_points_to() {
+    let temp = std::env::temp_dir().join("patcher_e2e_symlink_target");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let real_dir = temp.join("app-1.0");
+    let link = temp.join("app");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("bin/app", b"v1"), ("plugins/old.so", b"gone")]);
+    create_dir_tree(&new_dir, &[("bin/app", b"v2"), ("docs/readme.txt", b"new")]);
+    copy_dir_recursive(&old_dir, &real_dir);
+    std::os::unix::fs::symlink(&real_dir, &link).unwrap();
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // Refused: nothing is touched behind the link.
+    let output = run_patcher(&[
+        "apply", "--target", &format!("{}/", link.display()), "--patch", patch_file.to_str().unwrap(),
+        "--no-follow-target",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Target is a symlink"));
+    assert_eq!(collect_dir_tree(&real_dir), collect_dir_tree(&old_dir));
+
+    // Followed: the real directory is patched, deletions included, and the link stays.
+    let output = run_patcher(&["apply", "--target", link.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(collect_dir_tree(&real_dir), collect_dir_tree(&new_dir));
+    assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read_link(&link).unwrap(), real_dir);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_skip_mismatches_leaves_drifted_files() {
+    let temp = std::env::temp_dir().join("patcher_e2e_skip_mismatches");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    let base = pseudo_random(20_000, 8);
+    let mut changed = base.clone();
+    changed[15_000] ^= 0x0f;
+    create_dir_tree(&old_dir, &[("drifted.bin", &base), ("fine.bin", &base)]);
+    create_dir_tree(&new_dir, &[("drifted.bin", &changed), ("fine.bin", &changed), ("added.txt", b"new")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+    let mut drifted = base.clone();
+    drifted[100] ^= 0xff;
+    fs::write(target_dir.join("drifted.bin"), &drifted).unwrap();
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let apply = |extra: &[&str]| {
+        let mut args = vec!["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()];
+        args.extend_from_slice(extra);
+        run_patcher(&args)
+    };
+    let output = apply(&["--skip-mismatches"]);
+    assert!(!output.status.success(), "skipped files must fail the run");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("! skipped drifted.bin: hash mismatch"), "{}", stderr);
+    assert!(stderr.contains("1 file(s) skipped"), "{}", stderr);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Files modified: 1"));
+    assert_eq!(fs::read(target_dir.join("drifted.bin")).unwrap(), drifted);
+    assert_eq!(fs::read(target_dir.join("fine.bin")).unwrap(), changed);
+    assert_eq!(fs::read(target_dir.join("added.txt")).unwrap(), b"new");
+
+    // Without the flag the mismatch aborts the apply.
+    let output = apply(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Hash mismatch for "));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_multi_base_patch_applies_to_every_base() {
+    let temp = std::env::temp_dir().join("patcher_e2e_multi_base");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let base = pseudo_random(30_000, 13);
+    let mut v2 = base.clone();
+    v2[5_000] ^= 0x11;
+    let mut v4 = base.clone();
+    v4[15_000] ^= 0x22;
+    let bases = [temp.join("v1"), temp.join("v2"), temp.join("v3")];
+    let new_dir = temp.join("v4");
+    create_dir_tree(&bases[0], &[("big.bin", &base), ("common.txt", b"same"), ("only_v1/old.txt", b"gone")]);
+    create_dir_tree(&bases[1], &[("big.bin", &v2), ("common.txt", b"same"), ("later.txt", b"v2")]);
+    create_dir_tree(&bases[2], &[("big.bin", &v2), ("common.txt", b"same"), ("later.txt", b"v3")]);
+    create_dir_tree(&new_dir, &[("big.bin", &v4), ("common.txt", b"same"), ("later.txt", b"v4")]);
+
+    let patch_file = temp.join("multi.patch");
+    let output = run_patcher(&[
+        "-v", "create",
+        "--old", bases[0].to_str().unwrap(),
+        "--old", bases[1].to_str().unwrap(),
+        "--old", bases[2].to_str().unwrap(),
+        "--new", new_dir.to_str().unwrap(), "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // v2 and v3 share big.bin, so there are two variants, not three.
+    assert!(stdout.contains("~ modified big.bin (2 base versions)"), "{}", stdout);
+
+    for (i, old_dir) in bases.iter().enumerate() {
+        let target_dir = temp.join(format!("target{}", i));
+        copy_dir_recursive(old_dir, &target_dir);
+        let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+        assert!(output.status.success(), "apply to base {} failed: {}", i, String::from_utf8_lossy(&output.stderr));
+        assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir), "base {}", i);
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_multi_base_patch_verifies_before_applying_to_every_base() {
+    let temp = std::env::temp_dir().join("patcher_e2e_multi_base_verify_before");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let base = pseudo_random(30_000, 17);
+    let mut edited = base.clone();
+    edited[10_000] ^= 0x33;
+    let bases = [temp.join("a"), temp.join("b")];
+    let new_dir = temp.join("n");
+    create_dir_tree(&bases[0], &[("c", &base), ("shared.txt", b"shared")]);
+    create_dir_tree(&bases[1], &[("c", &base), ("shared.txt", b"shared"), ("only_b", b"b alone")]);
+    create_dir_tree(&new_dir, &[("c", &edited)]);
+
+    let patch_file = temp.join("multi.patch");
+    let output = run_patcher(&[
+        "create",
+        "--old", bases[0].to_str().unwrap(),
+        "--old", bases[1].to_str().unwrap(),
+        "--new", new_dir.to_str().unwrap(), "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let apply = |target_dir: &Path| {
+        run_patcher(&[
+            "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+            "--verify-before",
+        ])
+    };
+    for (i, old_dir) in bases.iter().enumerate() {
+        let target_dir = temp.join(format!("target{}", i));
+        copy_dir_recursive(old_dir, &target_dir);
+        let output = apply(&target_dir);
+        assert!(output.status.success(), "apply to base {} failed: {}", i, String::from_utf8_lossy(&output.stderr));
+        assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir), "base {}", i);
+    }
+
+    // Only the absence of a file some base lacks is accepted, not a changed copy of it.
+    let target_dir = temp.join("drifted");
+    copy_dir_recursive(&bases[1], &target_dir);
+    fs::write(target_dir.join("only_b"), b"edited").unwrap();
+    let output = apply(&target_dir);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("1 file(s) differ: only_b"), "{}", stderr);
+    fs::remove_file(target_dir.join("shared.txt")).unwrap();
+    let stderr = String::from_utf8_lossy(&apply(&target_dir).stderr).to_string();
+    assert!(stderr.contains("2 file(s) differ: only_b, shared.txt"), "{}", stderr);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_apply_into_separate_out_dir() {
+    let temp = std::env::temp_dir().join("patcher_e2e_out_dir");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let out_dir = temp.join("out");
+    let patch_file = temp.join("test.patch");
+    let base = pseudo_random(30_000, 5);
+    let mut changed = base.clone();
+    changed[15_000] ^= 0x55;
+    create_dir_tree(&old_dir, &[
+        ("same.txt", b"same"),
+        ("data.bin", &base),
+        ("gone/file.txt", b"deleted"),
+        ("replaced.txt", b"old"),
+    ]);
+    create_dir_tree(&new_dir, &[
+        ("same.txt", b"same"),
+        ("data.bin", &changed),
+        ("added/file.txt", b"added"),
+        ("replaced.txt", b"new"),
+    ]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &[], &["--out", out_dir.to_str().unwrap()]);
+    assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&out_dir));
+    assert_eq!(collect_dir_tree(&old_dir), collect_dir_tree(&target_dir), "target must be untouched");
+
+    // A non-empty output directory is refused.
+    let output = run_patcher(&[
+        "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+        "--out", out_dir.to_str().unwrap(),
+    ]);
+    assert!(!output.status.success());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_gzip_wrapped_patch() {
+    let temp = std::env::temp_dir().join("patcher_e2e_gzip");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch.gz");
+    let base = pseudo_random(30_000, 3);
+    let mut changed = base.clone();
+    changed[100] ^= 1;
+    create_dir_tree(&old_dir, &[("data.bin", &base), ("old.txt", b"old")]);
+    create_dir_tree(&new_dir, &[("data.bin", &changed), ("new.txt", b"new")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &["--gzip"], &[]);
+
+    let bytes = fs::read(&patch_file).unwrap();
+    assert_eq!(&bytes[..2], &[0x1f, 0x8b], "output should be a gzip stream");
+    assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_cbor_output_format_round_trip() {
+    let temp = std::env::temp_dir().join("patcher_e2e_cbor");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    let base = pseudo_random(30_000, 4);
+    let mut changed = base.clone();
+    changed[20_000..20_050].fill(0);
+    create_dir_tree(&old_dir, &[("data.bin", &base), ("gone.txt", b"old")]);
+    create_dir_tree(&new_dir, &[("data.bin", &changed), ("sub/new.txt", b"new")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &["--output-format", "cbor"], &[]);
+
+    let bytes = fs::read(&patch_file).unwrap();
+    assert_eq!(&bytes[..8], b"PATCHC02");
+    assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+    let output = run_patcher(&["verify", "--patch", patch_file.to_str().unwrap(), "--target", target_dir.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_mac_key_authenticates_patch() {
+    let temp = std::env::temp_dir().join("patcher_e2e_mac_key");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    let key = temp.join("mac.key");
+    let wrong_key = temp.join("wrong.key");
+    fs::write(&key, b"correct horse battery staple").unwrap();
+    fs::write(&wrong_key, b"not the key").unwrap();
+    create_dir_tree(&old_dir, &[("a.txt", b"one"), ("b.txt", b"two")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"one, changed"), ("c.txt", b"three")]);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    let apply = |patch: &Path, key: Option<&Path>| {
+        let mut args = vec!["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch.to_str().unwrap()];
+        if let Some(key) = key {
+            args.extend(["--mac-key", key.to_str().unwrap()]);
+        }
+        run_patcher(&args)
+    };
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(), "--mac-key", key.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(fs::read(&patch_file).unwrap().ends_with(b"PATCHMAC"));
+
+    let output = apply(&patch_file, Some(&wrong_key));
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("MAC doesn't match"));
+
+    let mut tampered = fs::read(&patch_file).unwrap();
+    tampered[20] ^= 1;
+    let tampered_file = temp.join("tampered.patch");
+    fs::write(&tampered_file, &tampered).unwrap();
+    assert!(!apply(&tampered_file, Some(&key)).status.success());
+    assert_eq!(collect_dir_tree(&old_dir), collect_dir_tree(&target_dir));
+
+    let output = apply(&patch_file, Some(&key));
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+
+    // A patch without a MAC is refused when a key is required.
+    let plain_file = temp.join("plain.patch");
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", plain_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let output = apply(&plain_file, Some(&key));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("has no MAC"));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_apply_temp_dir_is_cleaned_up() {
+    let temp = std::env::temp_dir().join("patcher_e2e_temp_dir");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("a.txt", b"short"), ("sub/b.txt", b"before")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"a good deal longer"), ("sub/b.txt", b"after, rewritten")]);
+
+    // Default: staged inside the target, and gone afterwards.
+    let target_dir = temp.join("target");
+    copy_dir_recursive(&old_dir, &target_dir);
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &[], &[]);
+    assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+    assert!(!target_dir.join(".patcher-tmp").exists());
+
+    // An explicit --temp-dir is created if needed and removed again, but one that
+    // already existed is left in place.
+    for (scratch, pre_existing) in [(temp.join("scratch"), false), (temp.join("kept"), true)] {
+        if pre_existing {
+            fs::create_dir_all(&scratch).unwrap();
+        }
+        let target_dir = temp.join(format!("target_{}", pre_existing));
+        copy_dir_recursive(&old_dir, &target_dir);
+        let output = run_patcher(&[
+            "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+            "--temp-dir", scratch.to_str().unwrap(),
+        ]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+        assert_eq!(scratch.exists(), pre_existing);
+        if pre_existing {
+            assert_eq!(fs::read_dir(&scratch).unwrap().count(), 0);
+        }
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_merge_sequential_patches() {
+    let temp = std::env::temp_dir().join("patcher_e2e_merge");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let base = pseudo_random(40_000, 7);
+    let mut base_v2 = base.clone();
+    base_v2[5_000..5_100].fill(0xAA);
+    let mut base_v3 = base_v2.clone();
+    base_v3.splice(30_000..30_000, pseudo_random(2_000, 8));
+
+    let v1 = temp.join("v1");
+    let v2 = temp.join("v2");
+    let v3 = temp.join("v3");
+    create_dir_tree(&v1, &[
+        ("twice.bin", &base),
+        ("then_deleted.txt", b"modify me, then delete me"),
+        ("recreated/file.txt", b"old"),
+        ("recreated/gone.txt", b"only in v1"),
+        ("untouched.txt", b"same everywhere"),
+    ]);
+    create_dir_tree(&v2, &[
+        ("twice.bin", &base_v2),
+        ("then_deleted.txt", b"modified"),
+        ("added_then_modified.txt", b"first version"),
+        ("transient/file.txt", b"exists only in v2"),
+        ("untouched.txt", b"same everywhere"),
+    ]);
+    create_dir_tree(&v3, &[
+        ("twice.bin", &base_v3),
+        ("added_then_modified.txt", b"second version"),
+        ("recreated/file.txt", b"new"),
+        ("untouched.txt", b"same everywhere"),
+    ]);
+
+    let p12 = temp.join("v1-v2.patch");
+    let p23 = temp.join("v2-v3.patch");
+    let p13 = temp.join("v1-v3.patch");
+    for (old, new, patch) in [(&v1, &v2, &p12), (&v2, &v3, &p23)] {
+        let output = run_patcher(&[
+            "create", "--old", old.to_str().unwrap(), "--new", new.to_str().unwrap(),
+            "--output", patch.to_str().unwrap(),
+        ]);
+        assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    let output = run_patcher(&[
+        "merge", "--first", p12.to_str().unwrap(), "--second", p23.to_str().unwrap(),
+        "--output", p13.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "merge failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let target = temp.join("target");
+    copy_dir_recursive(&v1, &target);
+    let output = run_patcher(&["apply", "--target", target.to_str().unwrap(), "--patch", p13.to_str().unwrap()]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(collect_dir_tree(&v3), collect_dir_tree(&target));
+    assert!(!target.join("transient").exists());
+
+    // Merging in the wrong order doesn't compose and must be refused.
+    let output = run_patcher(&[
+        "merge", "--first", p23.to_str().unwrap(), "--second", p12.to_str().unwrap(),
+        "--output", temp.join("bad.patch").to_str().unwrap(),
+    ]);
+    assert!(!output.status.success());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_paths_longer_than_max_path() {
+    // Six 50-character components: well past Windows' 260-character MAX_PATH once joined
+    // onto the target, but each component stays under the 255-byte name limit.
+    let deep_dir = (0..6)
+        .map(|i| format!("{}{}", i, "d".repeat(49)))
+        .collect::<Vec<_>>()
+        .join("/");
+    let added = format!("{}/added.txt", deep_dir);
+    let modified = format!("{}/modified.bin", deep_dir);
+    let deleted = format!("{}/deleted.txt", deep_dir);
+    assert!(added.len() > 260);
+
+    let base = pseudo_random(20_000, 99);
+    let mut changed = base.clone();
+    changed[10_000] ^= 0xFF;
+
+    assert_round_trip(
+        "patcher_e2e_long_paths",
+        &[(modified.as_str(), base.as_slice()), (deleted.as_str(), b"bye")],
+        &[(modified.as_str(), changed.as_slice()), (added.as_str(), b"hi")],
+    );
+}
+
+#[test]
+fn test_full_verify_covers_unchanged_files() {
+    let temp = std::env::temp_dir().join("patcher_e2e_full_verify");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    create_dir_tree(&old_dir, &[("keep.txt", b"same"), ("edit.txt", b"before")]);
+    create_dir_tree(&new_dir, &[("keep.txt", b"same"), ("edit.txt", b"after!")]);
+
+    for (full_verify, tampered_detected) in [(false, false), (true, true)] {
+        let target_dir = temp.join(format!("target_{}", full_verify));
+        let patch_file = temp.join(format!("{}.patch", full_verify));
+        let create_args: &[&str] = if full_verify { &["--full-verify"] } else { &[] };
+        copy_dir_recursive(&old_dir, &target_dir);
+        create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, create_args, &[]);
+        let verify_args = [
+            "verify", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+        ];
+
+        let output = run_patcher(&verify_args);
+        assert!(output.status.success(), "verify failed: {}", String::from_utf8_lossy(&output.stdout));
+
+        // Tamper with the file the patch left alone.
+        fs::write(target_dir.join("keep.txt"), b"oops").unwrap();
+        let output = run_patcher(&verify_args);
+        assert_eq!(!output.status.success(), tampered_detected, "full_verify={}", full_verify);
+        if tampered_detected {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("! keep.txt: hash mismatch"), "{}", stdout);
+        }
+
+        // A file the patch wrote is always checked.
+        fs::write(target_dir.join("edit.txt"), b"broken").unwrap();
+        assert!(!run_patcher(&verify_args).status.success());
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_apply_prune_removes_stray_files() {
+    let temp = std::env::temp_dir().join("patcher_e2e_prune");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    create_dir_tree(&old_dir, &[("keep.txt", b"same"), ("sub/edit.txt", b"before"), ("gone.txt", b"x")]);
+    create_dir_tree(&new_dir, &[("keep.txt", b"same"), ("sub/edit.txt", b"after!"), ("add.txt", b"new")]);
+
+    let target_dir = temp.join("target");
+    copy_dir_recursive(&old_dir, &target_dir);
+    create_dir_tree(&target_dir, &[("stray.log", b"junk"), ("sub/stray.tmp", b"junk"), ("cache/a/b.bin", b"junk")]);
+    fs::create_dir_all(target_dir.join("empty")).unwrap();
+    let patch_file = temp.join("full.patch");
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &["--full-verify"], &["--prune"]);
+
+    let mut expected = collect_dir_tree(&new_dir);
+    expected.sort();
+    let mut actual = collect_dir_tree(&target_dir);
+    actual.sort();
+    assert_eq!(actual, expected);
+    assert!(!target_dir.join("cache").exists());
+    // Empty directories aren't recorded by the patch, so they are left in place.
+    assert!(target_dir.join("empty").is_dir());
+
+    // Without --full-verify the patch doesn't list unchanged files, so --prune is refused
+    // before anything is touched.
+    let target_dir = temp.join("target_partial");
+    copy_dir_recursive(&old_dir, &target_dir);
+    fs::write(target_dir.join("stray.log"), b"junk").unwrap();
+    let patch_file = temp.join("partial.patch");
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let output = run_patcher(&[
+        "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(), "--prune",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--full-verify"));
+    assert!(target_dir.join("stray.log").exists());
+    assert!(target_dir.join("gone.txt").exists());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_create_from_snapshot() {
+    let temp = std::env::temp_dir().join("patcher_e2e_from_snapshot");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let snapshot_file = temp.join("old.snapshot");
+    let patch_file = temp.join("test.patch");
+
+    create_dir_tree(
+        &old_dir,
+        &[
+            ("same.txt", b"unchanged"),
+            ("changed.bin", &vec![3u8; 9000]),
+            ("gone/x.txt", b"deleted"),
+        ],
+    );
+    create_dir_tree(
+        &new_dir,
+        &[
+            ("same.txt", b"unchanged"),
+            ("changed.bin", &vec![4u8; 9000]),
+            ("fresh/y.txt", b"added"),
+        ],
+    );
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    let output = run_patcher(&["snapshot", "--dir", old_dir.to_str().unwrap(), "--output", snapshot_file.to_str().unwrap()]);
+    assert!(output.status.success(), "snapshot failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // The old tree is no longer needed once the snapshot exists.
+    fs::remove_dir_all(&old_dir).unwrap();
+
+    let output = run_patcher(&[
+        "-v", "create", "--old", snapshot_file.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("~ modified changed.bin (full content)"), "{}", stdout);
+    assert!(!stdout.contains("same.txt"), "unchanged file should not appear:\n{}", stdout);
+
+    let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+    assert!(!target_dir.join("gone").exists());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+/// Create a patch from `old_files` to `new_files`, apply it to a copy of old, and
+/// assert the target ends up identical to new (including zero-byte files).
+fn assert_round_trip(name: &str, old_files: &[(&str, &[u8])], new_files: &[(&str, &[u8])]) {
+    let temp = std::env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+
+    fs::create_dir_all(&old_dir).unwrap();
+    fs::create_dir_all(&new_dir).unwrap();
+    create_dir_tree(&old_dir, old_files);
+    create_dir_tree(&new_dir, new_files);
+    copy_dir_recursive(&old_dir, &target_dir);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = run_patcher(&["apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(collect_dir_tree(&new_dir), collect_dir_tree(&target_dir));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_zero_length_added() {
+    assert_round_trip("patcher_e2e_zero_added", &[], &[("empty.txt", b"")]);
+}
+
+#[test]
+fn test_zero_length_empty_to_nonempty() {
+    assert_round_trip("patcher_e2e_zero_grow", &[("f.txt", b"")], &[("f.txt", b"now has content")]);
+}
+
+#[test]
+fn test_zero_length_nonempty_to_empty() {
+    let big = vec![0x5A; 3 * 4096 + 17];
+    assert_round_trip(
+        "patcher_e2e_zero_truncate",
+        &[("small.txt", b"short"), ("big.bin", &big)],
+        &[("small.txt", b""), ("big.bin", b"")],
+    );
+}
+
+#[test]
+fn test_zero_length_empty_to_empty() {
+    assert_round_trip(
+        "patcher_e2e_zero_unchanged",
+        &[("f.txt", b""), ("other.txt", b"old")],
+        &[("f.txt", b""), ("other.txt", b"new")],
+    );
+}
+
+fn copy_dir_recursive(src: &Path, dst: &Path) {
+    fs::create_dir_all(dst).unwrap();
+    for entry in fs::read_dir(src).unwrap() {
+        let entry = entry.unwrap();
+        let src_path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        if src_path.is_dir() {
+            copy_dir_recursive(&src_path, &dst_path);
+        } else {
+            fs::copy(&src_path, &dst_path).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_output_inside_new_dir_is_left_out_of_patch() {
+    let temp = std::env::temp_dir().join("patcher_e2e_output_inside");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    create_dir_tree(&old_dir, &[("a.txt", b"old a"), ("keep.txt", b"same")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"new a"), ("keep.txt", b"same"), ("sub/c.txt", b"c")]);
+    create_dir_tree(&target_dir, &[("a.txt", b"old a"), ("keep.txt", b"same")]);
+
+    // Reached through a `..` detour so only canonicalization tells it's inside `new`.
+    let patch_file = new_dir.join("sub").join("..").join("patch.bin");
+    let args = [
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(), "--verbose", "--force",
+    ];
+    // The second run finds the first run's patch already sitting in `new`.
+    for _ in 0..2 {
+        let output = run_patcher(&args);
+        assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("added patch.bin"));
+    }
+
+    let output = run_patcher(&[
+        "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    let mut expected = collect_dir_tree(&new_dir);
+    expected.retain(|(path, _)| path != "patch.bin");
+    assert_eq!(collect_dir_tree(&target_dir), expected);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_compare_only_lists_changes_without_writing_a_patch() {
+    let temp = std::env::temp_dir().join("patcher_e2e_compare_only");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    create_dir_tree(&old_dir, &[("a.txt", b"old a"), ("same.txt", b"same"), ("gone/b.txt", b"b")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"new a"), ("same.txt", b"same"), ("sub/c.txt", b"c")]);
+    // Identical content, different mtime: only --fast takes it for a modification.
+    let touched = fs::File::options().write(true).open(new_dir.join("same.txt")).unwrap();
+    touched
+        .set_modified(std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000))
+        .unwrap();
+    drop(touched);
+
+    let compare = |extra: &[&str]| {
+        let mut args = vec![
+            "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--compare-only",
+        ];
+        args.extend_from_slice(extra);
+        let output = run_patcher(&args);
+        assert!(output.status.success(), "compare failed: {}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = compare(&[]);
+    for line in ["+ created dir sub", "+ added sub/c.txt", "~ modified a.txt", "- deleted gone/b.txt", "- deleted dir gone"] {
+        assert!(stdout.lines().any(|l| l == line), "missing {:?} in:\n{}", line, stdout);
+    }
+    assert!(!stdout.contains("same.txt"), "{}", stdout);
+
+    let stdout = compare(&["--fast"]);
+    assert!(stdout.lines().any(|l| l == "~ modified same.txt"), "{}", stdout);
+
+    let entries: Vec<_> = fs::read_dir(&temp).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(entries.len(), 2, "compare-only wrote files: {:?}", entries);
+
+    let output = run_patcher(&["create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(), "--fast"]);
+    assert!(!output.status.success());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_timing_prints_phase_breakdown() {
+    let temp = std::env::temp_dir().join("patcher_e2e_timing");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("a.txt", b"old a"), ("b.txt", b"b")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"new a"), ("c.txt", b"c")]);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(), "--timing",
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for phase in ["walk", "classify", "hash+diff", "write", "finish"] {
+        assert!(stdout.lines().any(|l| l.trim_start().starts_with(phase)), "no {} in:\n{}", phase, stdout);
+    }
+
+    let output = run_patcher(&[
+        "apply", "--target", old_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(), "--timing",
+    ]);
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for phase in ["read", "decompress", "decode", "create dirs", "add", "modify", "delete"] {
+        assert!(stdout.lines().any(|l| l.trim_start().starts_with(phase)), "no {} in:\n{}", phase, stdout);
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_memory_budget_smaller_than_files_round_trips() {
+    let temp = std::env::temp_dir().join("patcher_e2e_memory_budget");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    let mut old_files: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut new_files: Vec<(String, Vec<u8>)> = Vec::new();
+    for i in 0..6u64 {
+        let base = pseudo_random(200_000, i + 1);
+        let mut changed = base.clone();
+        changed[1000..1100].copy_from_slice(&pseudo_random(100, i + 50));
+        old_files.push((format!("mod{}.bin", i), base));
+        new_files.push((format!("mod{}.bin", i), changed));
+        new_files.push((format!("add{}.bin", i), pseudo_random(150_000, i + 100)));
+    }
+    let old_tree: Vec<(&str, &[u8])> = old_files.iter().map(|(p, d)| (p.as_str(), d.as_slice())).collect();
+    let new_tree: Vec<(&str, &[u8])> = new_files.iter().map(|(p, d)| (p.as_str(), d.as_slice())).collect();
+    create_dir_tree(&old_dir, &old_tree);
+    create_dir_tree(&new_dir, &new_tree);
+    create_dir_tree(&target_dir, &old_tree);
+
+    // Every file is larger than the budget, so each is processed on its own.
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &["--memory-budget", "64K"], &[]);
+    assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir));
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_changelog_has_text_diffs_only() {
+    let temp = std::env::temp_dir().join("patcher_e2e_changelog");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    let changelog = temp.join("CHANGES.diff");
+    let old_bin = pseudo_random(5000, 3);
+    let mut new_bin = old_bin.clone();
+    new_bin[100] ^= 0xff;
+    new_bin[200] = 0;
+    create_dir_tree(&old_dir, &[
+        ("docs/readme.txt", b"line one\nline two\nline three\n"),
+        ("same.txt", b"unchanged\n"),
+        ("data.bin", &old_bin),
+    ]);
+    create_dir_tree(&new_dir, &[
+        ("docs/readme.txt", b"line one\nline 2\nline three\n"),
+        ("same.txt", b"unchanged\n"),
+        ("data.bin", &new_bin),
+    ]);
+
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(), "--changelog", changelog.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let text = fs::read_to_string(&changelog).unwrap();
+    assert!(text.contains("--- a/docs/readme.txt\n+++ b/docs/readme.txt\n"), "{}", text);
+    assert!(text.contains("-line two\n+line 2\n"), "{}", text);
+    assert!(!text.contains("data.bin") && !text.contains("same.txt"), "{}", text);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_bytes_appended_to_patch_are_rejected() {
+    let temp = std::env::temp_dir().join("patcher_e2e_trailing_bytes");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("a.txt", b"old a")]);
+    create_dir_tree(&new_dir, &[("a.txt", b"new a")]);
+
+    for gzip in [false, true] {
+        let mut args = vec![
+            "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--output", patch_file.to_str().unwrap(), "--force",
+        ];
+        if gzip {
+            args.push("--gzip");
+        }
+        let output = run_patcher(&args);
+        assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+        let patch = fs::read(&patch_file).unwrap();
+
+        // Plain junk, and an empty zstd frame the decoder alone would accept.
+        let trailers: [&[u8]; 2] = [b"SIGNATURE", &[0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x00, 0x01, 0x00, 0x00]];
+        for trailer in trailers {
+            let tampered = temp.join("tampered.patch");
+            fs::write(&tampered, [patch.as_slice(), trailer].concat()).unwrap();
+            let output = run_patcher(&[
+                "apply", "--target", old_dir.to_str().unwrap(), "--patch", tampered.to_str().unwrap(),
+            ]);
+            assert!(!output.status.success());
+            assert!(
+                String::from_utf8_lossy(&output.stderr).contains("unexpected bytes after the"),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+    }
+    assert_eq!(fs::read(old_dir.join("a.txt")).unwrap(), b"old a");
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_recompress_ext_diffs_gzip_content() {
+    use std::io::Write;
+
+    let temp = std::env::temp_dir().join("patcher_e2e_recompress");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let gzip = |content: &[u8]| {
+        let mut encoder = flate2::GzBuilder::new()
+            .filename("log.txt")
+            .write(Vec::new(), flate2::Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    };
+    let hex = |bytes: &[u8]| -> Vec<u8> { bytes.iter().flat_map(|b| format!("{:02x}", b).into_bytes()).collect() };
+    let old_text = hex(&pseudo_random(150_000, 11));
+    let mut new_text = old_text.clone();
+    new_text[1000..1010].copy_from_slice(b"0123456789");
+    // Bytes after the gzip member make the file unreproducible, so it is diffed as is.
+    let other_text = hex(&pseudo_random(150_000, 12));
+    let mut other_old = gzip(&other_text[1..]);
+    other_old.extend_from_slice(b"junk");
+    let mut other_new = gzip(&other_text);
+    other_new.extend_from_slice(b"junk");
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    create_dir_tree(&old_dir, &[("log.gz", &gzip(&old_text)), ("other.gz", &other_old)]);
+    create_dir_tree(&new_dir, &[("log.gz", &gzip(&new_text)), ("other.gz", &other_new)]);
+
+    let plain = temp.join("plain.patch");
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", plain.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let target = temp.join("target");
+    create_dir_tree(&target, &[("log.gz", &gzip(&old_text)), ("other.gz", &other_old)]);
+    let patch_file = temp.join("recompress.patch");
+    create_and_apply(&old_dir, &new_dir, &target, &patch_file, &["--recompress-ext", "gz"], &[]);
+    assert_eq!(collect_dir_tree(&target), collect_dir_tree(&new_dir));
+
+    // Only other.gz is stored whole; log.gz shrinks to a small diff.
+    let plain_len = fs::metadata(&plain).unwrap().len();
+    let recompressed_len = fs::metadata(&patch_file).unwrap().len();
+    assert!(
+        recompressed_len < plain_len * 2 / 3,
+        "recompressed {} vs plain {}",
+        recompressed_len,
+        plain_len
+    );
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_diff_prints_tab_separated_and_json_records() {
+    let temp = std::env::temp_dir().join("patcher_e2e_diff_command");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    create_dir_tree(&old_dir, &[
+        ("gone/file.txt", b"bye"),
+        ("same.txt", b"same"),
+        ("changed.txt", b"old"),
+    ]);
+    create_dir_tree(&new_dir, &[
+        ("same.txt", b"same"),
+        ("changed.txt", b"new"),
+        ("fresh/added.txt", b"hello"),
+    ]);
+
+    let output = run_patcher(&["diff", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap()]);
+    assert!(output.status.success(), "diff failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "dir-created\tfresh\nadded\tfresh/added.txt\nmodified\tchanged.txt\ndeleted\tgone/file.txt\ndir-deleted\tgone\n"
+    );
+
+    let output = run_patcher(&[
+        "diff", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(), "--json",
+        "--exclude", "gone/**", "--exclude", "gone",
+    ]);
+    assert!(output.status.success(), "diff failed: {}", String::from_utf8_lossy(&output.stderr));
+    let records: Vec<(String, String)> = serde_json::from_slice::<Vec<serde_json::Value>>(&output.stdout)
+        .unwrap()
+        .iter()
+        .map(|r| (r["status"].as_str().unwrap().to_string(), r["path"].as_str().unwrap().to_string()))
+        .collect();
+    assert_eq!(records, [
+        ("dir-created".to_string(), "fresh".to_string()),
+        ("added".to_string(), "fresh/added.txt".to_string()),
+        ("modified".to_string(), "changed.txt".to_string()),
+    ]);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_diff_null_separates_records() {
+    let temp = std::env::temp_dir().join("patcher_e2e_diff_null");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    create_dir_tree(&old_dir, &[("changed.txt", b"old"), ("gone.txt", b"bye")]);
+    create_dir_tree(&new_dir, &[("changed.txt", b"new"), ("line\nbreak.txt", b"hello")]);
+
+    let output = run_patcher(&["diff", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(), "-0"]);
+    assert!(output.status.success(), "diff failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        output.stdout,
+        b"added\tline\nbreak.txt\0modified\tchanged.txt\0deleted\tgone.txt\0"
+    );
+
+    let output = run_patcher(&[
+        "diff", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(), "--null", "--json",
+    ]);
+    assert!(!output.status.success());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_delete_first_order_gives_the_same_tree() {
+    let temp = std::env::temp_dir().join("patcher_e2e_order");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    let big = pseudo_random(200_000, 7);
+    let mut edited = big.clone();
+    edited[100_000..100_010].copy_from_slice(b"0123456789");
+    create_dir_tree(&old_dir, &[
+        ("gone/deep/file.bin", &big),
+        ("gone.txt", b"bye"),
+        ("kept/edited.bin", &big),
+        ("kept/same.txt", b"same"),
+    ]);
+    create_dir_tree(&new_dir, &[
+        ("kept/edited.bin", &edited),
+        ("kept/same.txt", b"same"),
+        ("fresh/added.bin", &big),
+    ]);
+
+    for order in ["default", "delete-first"] {
+        let target_dir = temp.join(format!("target-{}", order));
+        copy_dir_recursive(&old_dir, &target_dir);
+        create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &["--force"], &["--order", order]);
+        assert_eq!(collect_dir_tree(&target_dir), collect_dir_tree(&new_dir), "--order {}", order);
+        assert!(!target_dir.join("gone").exists());
+    }
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_post_apply_runs_in_the_patched_tree_and_forwards_its_status() {
+    let temp = std::env::temp_dir().join("patcher_e2e_post_apply");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    let v1 = pseudo_random(100_000, 11);
+    let mut v2 = v1.clone();
+    v2[50_000..50_009].copy_from_slice(b"version 2");
+    create_dir_tree(&old_dir, &[("app.bin", &v1)]);
+    create_dir_tree(&new_dir, &[("app.bin", &v2)]);
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let apply = |target: &Path, command: &str| {
+        copy_dir_recursive(&old_dir, target);
+        run_patcher(&[
+            "apply", "--target", target.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+            "--post-apply", command,
+        ])
+    };
+
+    // The command sees the patched tree as its working directory.
+    let target_dir = temp.join("target");
+    let output = apply(&target_dir, "cp app.bin hook.bin");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(target_dir.join("hook.bin")).unwrap(), v2);
+
+    // A failing command's exit code becomes patcher's.
+    let output = apply(&temp.join("failing"), "exit 3");
+    assert_eq!(output.status.code(), Some(3));
+
+    // An apply that fails doesn't run it.
+    let drifted = temp.join("drifted");
+    copy_dir_recursive(&old_dir, &drifted);
+    fs::write(drifted.join("app.bin"), b"local edit").unwrap();
+    let output = run_patcher(&[
+        "apply", "--target", drifted.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+        "--post-apply", "touch hook.txt",
+    ]);
+    assert!(!output.status.success());
+    assert!(!drifted.join("hook.txt").exists());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_non_utf8_names_need_allow_non_utf8_and_round_trip() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp = std::env::temp_dir().join("patcher_e2e_non_utf8");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    let latin1 = std::ffi::OsStr::from_bytes(b"caf\xe9");
+    let big = pseudo_random(100_000, 5);
+    let mut edited = big.clone();
+    edited[40_000..40_004].copy_from_slice(b"edit");
+    create_dir_tree(&old_dir, &[("plain.txt", b"same")]);
+    fs::create_dir_all(old_dir.join(latin1)).unwrap();
+    fs::write(old_dir.join(latin1).join("data.bin"), &big).unwrap();
+    fs::write(old_dir.join(std::ffi::OsStr::from_bytes(b"gone\xff.txt")), b"bye").unwrap();
+    create_dir_tree(&new_dir, &[("plain.txt", b"same")]);
+    fs::create_dir_all(new_dir.join(latin1)).unwrap();
+    fs::write(new_dir.join(latin1).join("data.bin"), &edited).unwrap();
+    fs::write(new_dir.join(std::ffi::OsStr::from_bytes(b"new\xfe.txt")), b"hello").unwrap();
+
+    let create = |extra: &[&str]| {
+        let mut args = vec![
+            "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+            "--output", patch_file.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        run_patcher(&args)
+    };
+    let output = create(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--allow-non-utf8"));
+
+    let output = create(&["--allow-non-utf8"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    copy_dir_recursive(&old_dir, &target_dir);
+    let output = run_patcher(&[
+        "apply", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(fs::read(target_dir.join(latin1).join("data.bin")).unwrap(), edited);
+    assert_eq!(fs::read(target_dir.join(std::ffi::OsStr::from_bytes(b"new\xfe.txt"))).unwrap(), b"hello");
+    assert!(!target_dir.join(std::ffi::OsStr::from_bytes(b"gone\xff.txt")).exists());
+    let mut names: Vec<_> = fs::read_dir(&target_dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    names.sort();
+    let mut expected: Vec<_> = fs::read_dir(&new_dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    expected.sort();
+    assert_eq!(names, expected);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_dump_prints_the_manifest_with_byte_previews() {
+    let temp = std::env::temp_dir().join("patcher_e2e_dump");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let patch_file = temp.join("test.patch");
+    let big = pseudo_random(200_000, 3);
+    let mut edited = big.clone();
+    edited[100_000..100_004].copy_from_slice(b"edit");
+    create_dir_tree(&old_dir, &[("app.bin", &big), ("old.txt", b"bye")]);
+    create_dir_tree(&new_dir, &[("app.bin", &edited), ("data/added.bin", &pseudo_random(50_000, 4))]);
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = run_patcher(&["dump", "--patch", patch_file.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let dump = String::from_utf8(output.stdout).unwrap();
+    for expected in ["PatchManifest {", "CreateDir {", "AddFile {", "ModifyFile {", "Copy {", "Insert {", "DeleteFile {"] {
+        assert!(dump.contains(expected), "{} missing from:\n{}", expected, dump);
+    }
+    assert!(dump.contains("path: \"data/added.bin\""), "{}", dump);
+    assert!(dump.contains(" bytes: "), "{}", dump);
+    assert!(dump.lines().count() < 200, "{}", dump);
+
+    // It's left out of the help.
+    let help = String::from_utf8(run_patcher(&["--help"]).stdout).unwrap();
+    assert!(!help.contains("dump"), "{}", help);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_mode_relative_keeps_links_into_the_tree_working() {
+    use std::os::unix::fs::symlink;
+
+    let temp = std::env::temp_dir().join("patcher_e2e_symlinks");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target_dir = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("releases/v1/app", b"v1")]);
+    create_dir_tree(&target_dir, &[("releases/v1/app", b"v1")]);
+    create_dir_tree(&new_dir, &[("releases/v1/app", b"v1"), ("releases/v2/app", b"v2")]);
+    let new_dir = new_dir.canonicalize().unwrap();
+    symlink(new_dir.join("releases/v2"), new_dir.join("current")).unwrap();
+    symlink("/etc/hosts", new_dir.join("releases/hosts")).unwrap();
+
+    create_and_apply(&old_dir, &new_dir, &target_dir, &patch_file, &["--symlink-mode", "relative"], &[]);
+    // The absolute link into `new` now points into the target instead.
+    assert_eq!(fs::read_link(target_dir.join("current")).unwrap(), Path::new("releases/v2"));
+    assert_eq!(fs::read(target_dir.join("current/app")).unwrap(), b"v2");
+    assert_eq!(fs::read_link(target_dir.join("releases/hosts")).unwrap(), Path::new("/etc/hosts"));
+
+    let verify = || {
+        run_patcher(&["verify", "--target", target_dir.to_str().unwrap(), "--patch", patch_file.to_str().unwrap()])
+    };
+    let output = verify();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    fs::remove_file(target_dir.join("current")).unwrap();
+    symlink("releases/v1", target_dir.join("current")).unwrap();
+    let output = verify();
+    assert!(!output.status.success());
+    let report = String::from_utf8_lossy(&output.stdout).to_string() + &String::from_utf8_lossy(&output.stderr);
+    assert!(report.contains("symbolic link points elsewhere"), "{}", report);
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[test]
+fn test_interactive_apply_needs_a_terminal_or_yes() {
+    let temp = std::env::temp_dir().join("patcher_e2e_interactive");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("keep.txt", b"keep"), ("gone/file.txt", b"bye")]);
+    create_dir_tree(&new_dir, &[("keep.txt", b"keep")]);
+    create_dir_tree(&target, &[("keep.txt", b"keep"), ("gone/file.txt", b"bye")]);
+    let output = run_patcher(&[
+        "create", "--old", old_dir.to_str().unwrap(), "--new", new_dir.to_str().unwrap(),
+        "--output", patch_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "create failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    // The test's stdin is not a terminal, so there is no one to ask.
+    let apply = ["apply", "--target", target.to_str().unwrap(), "--patch", patch_file.to_str().unwrap(), "--interactive"];
+    let output = run_patcher(&apply);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--yes"));
+    assert!(target.join("gone/file.txt").exists());
+
+    let output = run_patcher(&[&apply[..], &["--yes"]].concat());
+    assert!(output.status.success(), "apply failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("- gone/file.txt\n- gone/\n"), "{}", stdout);
+    assert!(!target.join("gone").exists());
+
+    let _ = fs::remove_dir_all(&temp);
+}
+
+#[cfg(all(target_os = "linux", feature = "xattrs"))]
+#[test]
+fn test_preserve_xattrs_round_trip() {
+    let temp = std::env::temp_dir().join("patcher_e2e_xattrs");
+    let _ = fs::remove_dir_all(&temp);
+    fs::create_dir_all(&temp).unwrap();
+
+    let old_dir = temp.join("old");
+    let new_dir = temp.join("new");
+    let target = temp.join("target");
+    let patch_file = temp.join("test.patch");
+    create_dir_tree(&old_dir, &[("changed.txt", b"old content")]);
+    create_dir_tree(&new_dir, &[("changed.txt", b"new content"), ("added.txt", b"added")]);
+    create_dir_tree(&target, &[("changed.txt", b"old content")]);
+    match xattr::set(new_dir.join("added.txt"), "user.patcher.test", b"label-a") {
+        Ok(()) => {}
+        // The temp filesystem doesn't do user xattrs; nothing to check here.
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return,
+        Err(e) => panic!("setting xattr: {}", e),
+    }
+    xattr::set(new_dir.join("changed.txt"), "user.patcher.test", b"label-m").unwrap();
+
+    create_and_apply(&old_dir, &new_dir, &target, &patch_file, &["--preserve-xattrs"], &[]);
+    for (file, label) in [("added.txt", &b"label-a"[..]), ("changed.txt", b"label-m")] {
+        assert_eq!(
+            xattr::get(target.join(file), "user.patcher.test").unwrap().as_deref(),
+            Some(label),
+            "{}",
+            file
+        );
+    }
+    assert_eq!(fs::read(target.join("changed.txt")).unwrap(), b"new content");
+
+    let _ = fs::remove_dir_all(&temp);
 }